@@ -1,15 +1,12 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use lobster::{OrderBook, OrderType, Side};
+use lobster::replay::read_orders;
+use lobster::{OrderBook, OrderType};
 use std::fs::File;
 
-type Record = (u128, String, u64, u64);
-
 fn all_orders(c: &mut Criterion) {
     c.bench_function("all orders", |b| {
-        let mut orders: Vec<OrderType> = Vec::new();
-        let mut ord_id = 0;
+        let orders = load_orders("data/orders.csv");
         let mut ob = OrderBook::default();
-        load_orders("data/orders.csv", &mut orders, &mut ord_id);
 
         b.iter(|| {
             for ord in &orders {
@@ -21,11 +18,9 @@ fn all_orders(c: &mut Criterion) {
 
 fn all_orders_with_stats(c: &mut Criterion) {
     c.bench_function("all orders with stats tracking", |b| {
-        let mut orders: Vec<OrderType> = Vec::new();
-        let mut ord_id = 0;
+        let orders = load_orders("data/orders.csv");
         let mut ob = OrderBook::default();
         ob.track_stats(true);
-        load_orders("data/orders.csv", &mut orders, &mut ord_id);
 
         b.iter(|| {
             for ord in &orders {
@@ -37,11 +32,9 @@ fn all_orders_with_stats(c: &mut Criterion) {
 
 fn all_orders_with_stats_and_queries(c: &mut Criterion) {
     c.bench_function("all orders with stats tracking and queries", |b| {
-        let mut orders: Vec<OrderType> = Vec::new();
-        let mut ord_id = 0;
+        let orders = load_orders("data/orders.csv");
         let mut ob = OrderBook::default();
         ob.track_stats(true);
-        load_orders("data/orders.csv", &mut orders, &mut ord_id);
 
         b.iter(|| {
             for ord in &orders {
@@ -56,33 +49,11 @@ fn all_orders_with_stats_and_queries(c: &mut Criterion) {
     });
 }
 
-fn load_orders(path: &str, orders: &mut Vec<OrderType>, mut ord_id: &mut u128) {
-    let file = File::open(path).unwrap();
-    let mut rdr = csv::ReaderBuilder::new()
-        .has_headers(true)
-        .from_reader(file);
-    for result in rdr.deserialize() {
-        let record = result.unwrap();
-        orders.push(convert_to_order(&mut ord_id, record));
-    }
-}
-
-fn convert_to_order(id: &mut u128, record: Record) -> OrderType {
-    if record.2 == 0 {
-        OrderType::Cancel { id: record.3 as u128 }
-    } else {
-        *id += 1;
-        OrderType::Limit {
-            id: *id,
-            side: match record.1.as_str() {
-                "Bid" => Side::Bid,
-                "Ask" => Side::Ask,
-                _ => panic!("the side can only be 'Bid' or 'Ask'"),
-            },
-            qty: record.3,
-            price: record.2,
-        }
-    }
+fn load_orders(path: &str) -> Vec<OrderType> {
+    let file = File::open(path).expect("cannot open the orders file");
+    read_orders(file)
+        .collect::<Result<_, _>>()
+        .expect("cannot read the orders file")
 }
 
 criterion_group!(
@@ -1,4 +1,6 @@
-use criterion::{criterion_group, criterion_main, Criterion};
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, Criterion,
+};
 use lobster::{OrderBook, OrderType, Side};
 
 fn small_limit_ladder(c: &mut Criterion) {
@@ -11,6 +13,10 @@ fn small_limit_ladder(c: &mut Criterion) {
                     price: 12345 + i as u64,
                     qty: i as u64,
                     side: Side::Bid,
+                    display_qty: None,
+                    owner: None,
+                    tag: None,
+                    reduce_only: None,
                 });
             }
         });
@@ -27,11 +33,63 @@ fn big_limit_ladder(c: &mut Criterion) {
                     price: 12345 + i as u64,
                     qty: i as u64,
                     side: Side::Bid,
+                    display_qty: None,
+                    owner: None,
+                    tag: None,
+                    reduce_only: None,
                 });
             }
         });
     });
 }
 
-criterion_group!(benches, small_limit_ladder, big_limit_ladder);
+fn deep_touch_book() -> OrderBook {
+    let mut ob = OrderBook::default();
+    for i in 0..10_000 {
+        ob.execute(OrderType::Limit {
+            id: i as u128,
+            price: 12345,
+            qty: 1 + i as u64 % 7,
+            side: Side::Ask,
+            display_qty: None,
+            owner: None,
+            tag: None,
+            reduce_only: None,
+        });
+    }
+    ob
+}
+
+fn best_ask_deep_touch(c: &mut Criterion) {
+    let ob = deep_touch_book();
+    c.bench_function("best ask deep touch (cached)", |b| {
+        b.iter(|| black_box(ob.best_ask()));
+    });
+    c.bench_function("best ask deep touch (summed)", |b| {
+        b.iter(|| black_box(ob.depth(1).asks.first().cloned()));
+    });
+}
+
+fn cancel_deep_level(c: &mut Criterion) {
+    c.bench_function("cancel in deep level", |b| {
+        b.iter_batched(
+            deep_touch_book,
+            |mut ob| {
+                for id in (0..10_000).step_by(1_000) {
+                    ob.execute(OrderType::Cancel { id });
+                }
+                ob
+            },
+            BatchSize::LargeInput,
+        );
+    });
+}
+
+criterion_group!(
+    benches,
+    small_limit_ladder,
+    big_limit_ladder,
+    best_ask_deep_touch,
+    cancel_deep_level
+);
 criterion_main!(benches);
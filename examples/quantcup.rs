@@ -1,20 +1,12 @@
-use lobster::{OrderBook, OrderType, Side};
+use lobster::replay::read_orders;
+use lobster::{OrderBook, OrderType};
+use std::error::Error;
 use std::fs::File;
 use std::time::Instant;
 
-type Record = (u128, String, u64, u64);
-
-fn main() {
-    let file = File::open("data/orders.csv").unwrap();
-    let mut rdr = csv::ReaderBuilder::new()
-        .has_headers(true)
-        .from_reader(file);
-    let mut orders: Vec<OrderType> = Vec::new();
-    let mut ord_id = 0;
-    for result in rdr.deserialize() {
-        let record = result.unwrap();
-        orders.push(convert_to_order(&mut ord_id, record));
-    }
+fn main() -> Result<(), Box<dyn Error>> {
+    let file = File::open("data/orders.csv")?;
+    let orders: Vec<OrderType> = read_orders(file).collect::<Result<_, _>>()?;
 
     let total_orders = orders.len();
 
@@ -52,26 +44,7 @@ fn main() {
     println!("{: <15} = {:>12.0} ns", "Mean per batch", mean);
     println!("{: <15} = {:>12.0} ns", "SD", std_dev);
     println!("{: <15} = {:>12.0}\n", "Score", 0.5 * (mean + std_dev));
-}
-
-fn convert_to_order(id: &mut u128, record: Record) -> OrderType {
-    if record.2 == 0 {
-        OrderType::Cancel {
-            id: record.3 as u128,
-        }
-    } else {
-        *id += 1;
-        OrderType::Limit {
-            id: *id,
-            side: match record.1.as_str() {
-                "Bid" => Side::Bid,
-                "Ask" => Side::Ask,
-                _ => panic!("the side can only be 'Bid' or 'Ask'"),
-            },
-            qty: record.3,
-            price: record.2,
-        }
-    }
+    Ok(())
 }
 
 // Code below is directly copied from https://github.com/rust-lang/rust/blob/master/src/libtest/stats.rs
@@ -1,89 +1,308 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, TryReserveError};
 use std::ops::{Index, IndexMut};
 
-use crate::models::LimitOrder;
+use crate::models::{ArenaStats, LimitOrder, OrderId, Side};
 
-#[derive(Debug)]
-pub struct OrderArena {
-    orders: Vec<LimitOrder>,
+/// Storage for the resting orders, indexed by position.
+///
+/// Removing an order leaves a tombstone (an order with zero quantity) in its
+/// slot, which is only reused once the slot is released, i.e. once the order
+/// book has dropped the slot index from its price level queue.
+///
+/// Freed slots are reused in LIFO order by default, so the index assigned to a
+/// new order depends on the order in which previous orders were deleted. When
+/// `sorted_free` is set, new orders always take the lowest free index instead,
+/// so the layout only depends on the set of orders currently stored.
+#[derive(Debug, Clone)]
+pub struct OrderArena<Id = u128> {
+    orders: Vec<LimitOrder<Id>>,
     free: Vec<usize>,
-    order_map: HashMap<u128, usize>,
+    order_map: HashMap<Id, usize>,
+    /// The IDs of the orders that expire at each time.
+    expiries: BTreeMap<u64, Vec<Id>>,
+    sorted_free: bool,
+    tombstones: usize,
+    /// The number of preallocated slots, kept by `shrink_to_fit`.
+    capacity: usize,
+    /// The largest number of orders stored at the same time.
+    high_water_mark: usize,
 }
 
-impl OrderArena {
+impl<Id: OrderId> OrderArena<Id> {
     pub fn new(capacity: usize) -> Self {
-        let mut list = Self {
+        // The slots are only filled on the first insertion, since there is no
+        // ID to put in them before, but the storage is allocated upfront.
+        Self {
             orders: Vec::with_capacity(capacity),
-            free: Vec::with_capacity(capacity),
+            free: (0..capacity).collect(),
             order_map: HashMap::with_capacity(capacity),
-        };
+            expiries: BTreeMap::new(),
+            sorted_free: false,
+            tombstones: 0,
+            capacity,
+            high_water_mark: 0,
+        }
+    }
+
+    pub fn set_sorted_free(&mut self, sorted: bool) {
+        self.sorted_free = sorted;
+        if sorted {
+            // Keep the free list in descending order, so that `pop` returns
+            // the lowest free index.
+            self.free.sort_unstable_by(|a, b| b.cmp(a));
+        }
+    }
+
+    pub fn sorted_free(&self) -> bool {
+        self.sorted_free
+    }
+
+    /// Remove all the orders and release all the slots, keeping the allocated
+    /// storage. Slots are then reused in the same order as in a new arena.
+    pub fn clear(&mut self) {
+        self.order_map.clear();
+        self.expiries.clear();
+        self.tombstones = 0;
+        self.high_water_mark = 0;
+        for order in &mut self.orders {
+            order.qty = 0;
+            order.visible_qty = 0;
+            order.expires_at = None;
+        }
+        self.free.clear();
+        self.free.extend(0..self.slots());
+        if self.sorted_free {
+            self.free.reverse();
+        }
+    }
+
+    /// Move all the orders to the lowest slots, keeping their relative order,
+    /// and free the slots allocated past the initial capacity. Return the new
+    /// index of each slot, indexed by the old one; the indices of free slots
+    /// are meaningless.
+    ///
+    /// All the removed orders must have been released beforehand, since their
+    /// tombstones would be overwritten.
+    pub fn shrink_to_fit(&mut self) -> Vec<usize> {
+        debug_assert_eq!(self.tombstones, 0);
+        let mut live: Vec<usize> = self.order_map.values().copied().collect();
+        live.sort_unstable();
+        let mut remap = vec![usize::MAX; self.orders.len()];
+        // Live slots only ever move down, onto a free slot or onto themselves
+        for (new, old) in live.iter().copied().enumerate() {
+            self.orders.swap(new, old);
+            remap[old] = new;
+        }
+        for idx in self.order_map.values_mut() {
+            *idx = remap[*idx];
+        }
 
-        // Preallocate
-        for i in 0..capacity {
-            list.orders.push(LimitOrder {
-                id: 0,
-                price: 0,
-                qty: 0,
-            });
-            list.free.push(i);
+        let len = live.len().max(self.capacity);
+        self.orders.truncate(len);
+        self.orders.shrink_to_fit();
+        for order in &mut self.orders[live.len()..] {
+            order.qty = 0;
+            order.visible_qty = 0;
         }
-        list
+        self.free.clear();
+        self.free.extend(live.len()..len);
+        if self.sorted_free {
+            self.free.reverse();
+        }
+        self.free.shrink_to_fit();
+        self.order_map.shrink_to_fit();
+        remap
+    }
+
+    /// Reserve the memory needed to store `additional` more orders and to
+    /// release all the slots afterwards, returning an error instead of
+    /// aborting if it cannot be allocated.
+    pub fn try_reserve(
+        &mut self,
+        additional: usize,
+    ) -> Result<(), TryReserveError> {
+        let slots = self.slots() + additional.saturating_sub(self.free.len());
+        self.orders.try_reserve(slots - self.orders.len())?;
+        self.free.try_reserve(slots - self.free.len())?;
+        self.order_map.try_reserve(additional)
+    }
+
+    /// Return the number of orders stored in the arena.
+    pub fn len(&self) -> usize {
+        self.order_map.len()
     }
 
-    pub fn get(&self, id: u128) -> Option<(u64, usize)> {
+    /// Return the number of removed orders whose slot was not released yet.
+    pub fn tombstones(&self) -> usize {
+        self.tombstones
+    }
+
+    pub fn stats(&self) -> ArenaStats {
+        ArenaStats {
+            capacity: self.slots(),
+            live_orders: self.len(),
+            free_slots: self.free.len(),
+            high_water_mark: self.high_water_mark,
+        }
+    }
+
+    pub fn get(&self, id: Id) -> Option<(u64, usize)> {
         self.order_map.get(&id).map(|i| (self.orders[*i].price, *i))
     }
 
+    /// Return the number of slots, including the preallocated ones that were
+    /// never filled.
+    pub fn slots(&self) -> usize {
+        self.orders.len().max(self.capacity)
+    }
+
     #[cfg(test)]
-    pub fn get_full(&self, id: u128) -> Option<(u64, u64, usize)> {
+    pub fn get_full(&self, id: Id) -> Option<(u64, u64, usize)> {
         self.order_map
             .get(&id)
             .map(|i| (self.orders[*i].price, self.orders[*i].qty, *i))
     }
 
-    pub fn insert(&mut self, id: u128, price: u64, qty: u64) -> usize {
-        match self.free.pop() {
+    pub fn insert(&mut self, id: Id, price: u64, qty: u64) -> usize {
+        self.high_water_mark = self.high_water_mark.max(self.len() + 1);
+        let order = LimitOrder {
+            id,
+            side: Side::Bid,
+            price,
+            qty,
+            seq: 0,
+            display_qty: qty,
+            visible_qty: qty,
+            queued_qty: qty,
+            owner: None,
+            tag: None,
+            all_or_none: false,
+            expires_at: None,
+        };
+        let index = match self.free.pop() {
             None => {
-                self.orders.push(LimitOrder { id, price, qty });
-                let index = self.orders.len() - 1;
-                self.order_map.insert(id, index);
-                index
+                self.orders.push(order);
+                self.orders.len() - 1
             }
             Some(index) => {
-                let ord = &mut self.orders[index];
-                ord.id = id;
-                ord.qty = qty;
-                ord.price = price;
-                self.order_map.insert(id, index);
+                if index >= self.orders.len() {
+                    // Fill the preallocated slots with tombstones
+                    let tombstone = LimitOrder {
+                        qty: 0,
+                        visible_qty: 0,
+                        ..order.clone()
+                    };
+                    self.orders.resize(self.capacity, tombstone);
+                }
+                self.orders[index] = order;
                 index
             }
+        };
+        self.order_map.insert(id, index);
+        index
+    }
+
+    /// Remove the order with the given ID and release its slot.
+    pub fn delete(&mut self, id: &Id) -> bool {
+        match self.remove(id) {
+            Some(idx) => {
+                self.release(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove the order with the given ID, leaving a tombstone in its slot
+    /// until the slot is released with [`release`](Self::release).
+    pub fn remove(&mut self, id: &Id) -> Option<usize> {
+        let idx = *self.order_map.get(id)?;
+        self.unmap(idx);
+        let ord = &mut self.orders[idx];
+        ord.qty = 0;
+        ord.visible_qty = 0;
+        self.tombstones += 1;
+        Some(idx)
+    }
+
+    /// Make the slot at `idx` available for new orders. If the slot still
+    /// holds an order, the order is removed first.
+    pub fn release(&mut self, idx: usize) {
+        let id = self.orders[idx].id;
+        if self.order_map.get(&id) == Some(&idx) {
+            self.unmap(idx);
+        } else {
+            self.tombstones -= 1;
+        }
+        let ord = &mut self.orders[idx];
+        ord.qty = 0;
+        ord.visible_qty = 0;
+        if self.sorted_free {
+            let pos = self.free.partition_point(|i| *i > idx);
+            self.free.insert(pos, idx);
+        } else {
+            self.free.push(idx);
         }
     }
 
-    pub fn delete(&mut self, id: &u128) -> bool {
-        if let Some(idx) = self.order_map.remove(id) {
-            if let Some(mut ord) = self.orders.get_mut(idx) {
-                self.free.push(idx);
-                ord.qty = 0;
-                return true;
+    /// Set the time at which the order stored at `idx` expires.
+    pub fn set_expiry(&mut self, idx: usize, expires_at: u64) {
+        let ord = &mut self.orders[idx];
+        ord.expires_at = Some(expires_at);
+        self.expiries.entry(expires_at).or_default().push(ord.id);
+    }
+
+    /// Return the IDs of the orders expiring at or before `now`, in expiry
+    /// order, and stop tracking their expiry.
+    pub fn take_expired(&mut self, now: u64) -> Vec<Id> {
+        let pending = match now.checked_add(1) {
+            Some(next) => self.expiries.split_off(&next),
+            None => BTreeMap::new(),
+        };
+        let expired = std::mem::replace(&mut self.expiries, pending);
+        let ids: Vec<Id> = expired.into_values().flatten().collect();
+        for id in &ids {
+            let idx = self.order_map[id];
+            self.orders[idx].expires_at = None;
+        }
+        ids
+    }
+
+    /// Iterate over the expiry times of the stored orders, as `(expires_at,
+    /// id)` pairs in expiry order.
+    pub fn expiries(&self) -> impl Iterator<Item = (u64, Id)> + '_ {
+        self.expiries
+            .iter()
+            .flat_map(|(at, ids)| ids.iter().map(move |id| (*at, *id)))
+    }
+
+    /// Drop the stored order at `idx` from the ID map and the expiries.
+    fn unmap(&mut self, idx: usize) {
+        let ord = &mut self.orders[idx];
+        self.order_map.remove(&ord.id);
+        if let Some(expires_at) = ord.expires_at.take() {
+            if let Some(ids) = self.expiries.get_mut(&expires_at) {
+                ids.retain(|id| *id != ord.id);
+                if ids.is_empty() {
+                    self.expiries.remove(&expires_at);
+                }
             }
         }
-        false
     }
 }
 
-impl Index<usize> for OrderArena {
-    type Output = LimitOrder;
+impl<Id> Index<usize> for OrderArena<Id> {
+    type Output = LimitOrder<Id>;
 
     #[inline]
-    fn index(&self, index: usize) -> &LimitOrder {
+    fn index(&self, index: usize) -> &LimitOrder<Id> {
         &self.orders[index]
     }
 }
 
-impl IndexMut<usize> for OrderArena {
+impl<Id> IndexMut<usize> for OrderArena<Id> {
     #[inline]
-    fn index_mut(&mut self, index: usize) -> &mut LimitOrder {
+    fn index_mut(&mut self, index: usize) -> &mut LimitOrder<Id> {
         &mut self.orders[index]
     }
 }
@@ -133,4 +352,20 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn sorted_free_list() {
+        let mut arena = OrderArena::new(4);
+        arena.set_sorted_free(true);
+        for i in 0..4 {
+            assert_eq!(arena.insert(i, 100, 1), i as usize);
+        }
+        arena.delete(&2);
+        arena.delete(&0);
+        arena.delete(&3);
+        assert_eq!(arena.insert(4, 100, 1), 0);
+        assert_eq!(arena.insert(5, 100, 1), 2);
+        assert_eq!(arena.insert(6, 100, 1), 3);
+        assert_eq!(arena.insert(7, 100, 1), 4);
+    }
 }
@@ -2,21 +2,55 @@
 //! order book instance with default parameters, and send orders for execution:
 //!
 //! ```rust
-//! use lobster::{FillMetadata, OrderBook, OrderEvent, OrderType, Side};
+//! use lobster::{
+//!     FillMetadata, OrderBook, OrderEvent, OrderType, Side, UnfilledReason,
+//! };
 //!
 //! let mut ob = OrderBook::default();
-//! let event = ob.execute(OrderType::Market { id: 0, qty: 1, side: Side::Bid });
-//! assert_eq!(event, OrderEvent::Unfilled { id: 0 });
+//! let event = ob.execute(OrderType::Market {
+//!     id: 0,
+//!     qty: 1,
+//!     side: Side::Bid,
+//!     tag: None,
+//!     owner: None,
+//!     protection_price: None,
+//!     reduce_only: None,
+//! });
+//! assert_eq!(
+//!     event,
+//!     OrderEvent::Unfilled {
+//!         id: 0,
+//!         reason: UnfilledReason::NoLiquidity,
+//!     }
+//! );
 //!
-//! let event = ob.execute(OrderType::Limit { id: 1, price: 120, qty: 3, side: Side::Ask });
+//! let event = ob.execute(OrderType::Limit {
+//!     id: 1,
+//!     price: 120,
+//!     qty: 3,
+//!     side: Side::Ask,
+//!     display_qty: None,
+//!     owner: None,
+//!     tag: None,
+//!     reduce_only: None,
+//! });
 //! assert_eq!(event, OrderEvent::Placed { id: 1 });
 //!
-//! let event = ob.execute(OrderType::Market { id: 2, qty: 4, side: Side::Bid });
+//! let event = ob.execute(OrderType::Market {
+//!     id: 2,
+//!     qty: 4,
+//!     side: Side::Bid,
+//!     tag: None,
+//!     owner: None,
+//!     protection_price: None,
+//!     reduce_only: None,
+//! });
 //! assert_eq!(
 //!     event,
 //!     OrderEvent::PartiallyFilled {
 //!         id: 2,
 //!         filled_qty: 3,
+//!         resting_qty: 0,
 //!         fills: vec![
 //!             FillMetadata {
 //!                 order_1: 2,
@@ -25,8 +59,11 @@
 //!                 price: 120,
 //!                 taker_side: Side::Bid,
 //!                 total_fill: true,
+//!                 taker_tag: None,
+//!                 maker_tag: None,
 //!             }
 //!         ],
+//!         trade: None,
 //!     },
 //! );
 //! ```
@@ -34,15 +71,49 @@
 //! Lobster only deals in integer price points and quantities. Prices and
 //! quantities are represented as unsigned 64-bit integers. If the traded
 //! instrument supports fractional prices and quantities, the conversion needs to
-//! be handled by the user. At this time, Lobster does not support negative prices.
+//! be handled by the user, e.g. with a [`PriceScale`]. Negative prices can be
+//! mapped to the unsigned price domain with a price offset (see
+//! [`OrderBook::set_price_offset`]).
+//!
+//! Order IDs are `u128` by default. Any other [`OrderId`] type, such as `u32`
+//! or `u64`, can be used with a [`GenericOrderBook`] to save memory.
+//!
+//! Matching is deterministic. By default, resting orders are filled by price,
+//! then in time priority: orders at the same price are filled in the order
+//! they were placed, and an iceberg order loses its priority every time its
+//! displayed slice is replenished. Alternatively, orders at the same price
+//! can be filled proportionally to their size (see
+//! [`OrderBook::set_matching_policy`]). In both cases, all-or-none orders (see
+//! [`OrderType::AllOrNone`]) are skipped by the incoming orders too small to
+//! fill them completely.
+//!
+//! Enabling the `serde` feature implements `Serialize` and `Deserialize` for
+//! all the public model types. Enums use the default, externally tagged
+//! representation, e.g. `{"Limit": {"id": 1, ...}}`.
+//!
+//! Enabling the `invariants` feature exposes `OrderBook::validate`, which
+//! checks the internal consistency of an order book and is meant to be used as
+//! an oracle when testing or fuzzing code built on top of Lobster.
+//!
+//! Enabling the `csv` feature exposes the `replay` module, which reads orders
+//! from CSV files in the format of the QuantCup order book challenge.
 
 #![warn(missing_docs, missing_debug_implementations, rustdoc::broken_intra_doc_links)]
 
 mod arena;
 mod models;
 mod orderbook;
+mod orderbookset;
+#[cfg(feature = "csv")]
+pub mod replay;
 
 pub use models::{
-    BookDepth, BookLevel, FillMetadata, OrderEvent, OrderType, Side, Trade,
+    AmendPolicy, ArenaStats, BookConfig, BookDelta, BookDepth, BookEvent,
+    BookLevel, BookSnapshot, BookSummary, CancelReason, DetailedBookDepth,
+    DetailedBookLevel, ExecutionError, FillMetadata, FillOrdering,
+    MatchingPolicy, OrderEvent, OrderId, OrderInfo, OrderType, OwnerId,
+    PriceScale, RejectReason, RoundingMode, Side, StpMode, Trade, TradePrint,
+    TradeStats, UnfilledReason,
 };
-pub use orderbook::OrderBook;
+pub use orderbook::{GenericOrderBook, OrderBook};
+pub use orderbookset::OrderBookSet;
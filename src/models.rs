@@ -1,5 +1,12 @@
+use std::cmp::Reverse;
+use std::hash::Hash;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
 /// An order book side.
 #[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Side {
     /// The bid (or buy) side.
     Bid,
@@ -18,25 +25,186 @@ impl std::ops::Not for Side {
     }
 }
 
+/// The identifier of the owner (e.g. a participant or an account) of an order.
+pub type OwnerId = u64;
+
+/// The type of the unique IDs of the orders, implemented by all the types
+/// meeting its bounds. Order books use `u128` IDs by default (see
+/// [`GenericOrderBook`]).
+///
+/// [`GenericOrderBook`]: crate::GenericOrderBook
+pub trait OrderId: Copy + Eq + Hash {}
+
+impl<T: Copy + Eq + Hash> OrderId for T {}
+
+/// The rounding mode used when converting a fractional price to a price point.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum RoundingMode {
+    /// Round towards negative infinity.
+    Floor,
+    /// Round towards positive infinity.
+    Ceil,
+    /// Round to the nearest value, with ties rounded away from zero.
+    Nearest,
+    /// Round to the nearest value, with ties rounded to the even value.
+    NearestEven,
+}
+
+impl RoundingMode {
+    /// Round `value` to an integer according to this rounding mode.
+    pub fn round(self, value: f64) -> f64 {
+        match self {
+            RoundingMode::Floor => value.floor(),
+            RoundingMode::Ceil => value.ceil(),
+            RoundingMode::Nearest => value.round(),
+            RoundingMode::NearestEven => value.round_ties_even(),
+        }
+    }
+}
+
+/// A conversion between fractional prices and the integer price points used by
+/// the order book, e.g. with 2 decimal places the price 123.45 is mapped to the
+/// price point 12345.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PriceScale {
+    multiplier: u64,
+    rounding: RoundingMode,
+}
+
+impl PriceScale {
+    /// Create a scale with the given number of decimal places, rounding
+    /// fractional prices to price points with `rounding`. Return `None` if
+    /// there are more than 19 decimal places.
+    pub fn from_decimals(
+        decimals: u32,
+        rounding: RoundingMode,
+    ) -> Option<Self> {
+        Self::from_multiplier(10u64.checked_pow(decimals)?, rounding)
+    }
+
+    /// Create a scale with the given number of price points per unit of
+    /// price, rounding fractional prices to price points with `rounding`.
+    /// Return `None` if the multiplier is zero.
+    pub fn from_multiplier(
+        multiplier: u64,
+        rounding: RoundingMode,
+    ) -> Option<Self> {
+        if multiplier == 0 {
+            return None;
+        }
+        Some(PriceScale {
+            multiplier,
+            rounding,
+        })
+    }
+
+    /// Return the number of price points per unit of price.
+    pub fn multiplier(&self) -> u64 {
+        self.multiplier
+    }
+
+    /// Convert a fractional price to a price point. Prices within floating
+    /// point error of a price point are mapped to it regardless of the
+    /// rounding mode, so that 1.15 is not floored to 114 at 2 decimal places.
+    /// Return `None` if the price is negative, not finite, or larger than the
+    /// largest price point.
+    pub fn to_ticks(&self, price: f64) -> Option<u64> {
+        if !price.is_finite() || price < 0.0 {
+            return None;
+        }
+        let scaled = price * self.multiplier as f64;
+        let nearest = scaled.round();
+        let ticks = if (scaled - nearest).abs() <= 4.0 * f64::EPSILON * scaled {
+            nearest
+        } else {
+            self.rounding.round(scaled)
+        };
+        // `u64::MAX as f64` is rounded up to 2^64, which does not fit.
+        if ticks >= u64::MAX as f64 {
+            return None;
+        }
+        Some(ticks as u64)
+    }
+
+    /// Convert a price point to a fractional price.
+    pub fn to_price(&self, ticks: u64) -> f64 {
+        ticks as f64 / self.multiplier as f64
+    }
+}
+
 /// An order to be executed by the order book.
 #[derive(Debug, Copy, Clone)]
-pub enum OrderType {
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum OrderType<Id = u128> {
     /// A market order, which is either filled immediately (even partially), or
     /// canceled.
     Market {
         /// The unique ID of this order.
-        id: u128,
+        id: Id,
         /// The order side. It will be matched against the resting orders on the
         /// other side of the order book.
         side: Side,
         /// The order quantity.
         qty: u64,
+        /// The owner of the order, if any.
+        owner: Option<OwnerId>,
+        /// An opaque tag echoed back in the fills of the order (see
+        /// [`FillMetadata::taker_tag`]).
+        tag: Option<u64>,
+        /// The worst price the order may trade at, if any: the highest price
+        /// for a buy order and the lowest price for a sell order. Matching
+        /// stops at this price, and the remaining quantity is discarded.
+        protection_price: Option<u64>,
+        /// The size of the position reduced by the order, if it is a
+        /// reduce-only order. The order quantity is capped to it, so that the
+        /// order never flips the sign of the position. Lobster does not track
+        /// positions: keeping this size up to date is the responsibility of
+        /// the caller.
+        reduce_only: Option<u64>,
     },
     /// A limit order, which is either filled immediately, or added to the order
     /// book.
     Limit {
         /// The unique ID of this order.
-        id: u128,
+        id: Id,
+        /// The order side. It will be matched against the resting orders on the
+        /// other side of the order book.
+        side: Side,
+        /// The order quantity.
+        qty: u64,
+        /// The limit price. The order book will only match this order with
+        /// other orders at this price or better.
+        price: u64,
+        /// The quantity displayed on the order book while the order is
+        /// resting, if smaller than the order quantity, making the order an
+        /// iceberg (or reserve) order. Only the displayed quantity is visible
+        /// in the book depth; once it is consumed, it is replenished from the
+        /// hidden reserve and the order moves to the back of its price level
+        /// queue. `None` means the order is fully displayed.
+        display_qty: Option<u64>,
+        /// The owner of the order, if any.
+        owner: Option<OwnerId>,
+        /// An opaque tag echoed back in the fills of the order, both as a
+        /// taker and as a maker (see [`FillMetadata`]).
+        tag: Option<u64>,
+        /// The size of the position reduced by the order, if it is a
+        /// reduce-only order. The order quantity is capped to it, so that the
+        /// order never flips the sign of the position. Lobster does not track
+        /// positions: keeping this size up to date is the responsibility of
+        /// the caller.
+        reduce_only: Option<u64>,
+    },
+    /// A limit order that may only be filled in its entirety. It is matched
+    /// when placed only if it can be filled completely, and is otherwise added
+    /// to the order book without trading. While resting, it is skipped by the
+    /// incoming orders too small to fill it, which match the orders behind it
+    /// instead, even at worse prices. The book can thus be locked or crossed
+    /// by all-or-none orders.
+    AllOrNone {
+        /// The unique ID of this order.
+        id: Id,
         /// The order side. It will be matched against the resting orders on the
         /// other side of the order book.
         side: Side,
@@ -45,66 +213,568 @@ pub enum OrderType {
         /// The limit price. The order book will only match this order with
         /// other orders at this price or better.
         price: u64,
+        /// The owner of the order, if any.
+        owner: Option<OwnerId>,
+        /// An opaque tag echoed back in the fills of the order, both as a
+        /// taker and as a maker (see [`FillMetadata`]).
+        tag: Option<u64>,
     },
     /// A cancel order, which removes the order with the specified ID from the
     /// order book.
     Cancel {
         /// The unique ID of the order to be canceled.
-        id: u128,
+        id: Id,
+    },
+    /// An amend order, which modifies the quantity and price of the resting
+    /// order with the specified ID in place. Reducing the quantity at the same
+    /// price keeps the time priority of the order, while increasing the
+    /// quantity or changing the price moves it to the back of the queue at the
+    /// new price, where it can be matched like a new limit order.
+    Amend {
+        /// The unique ID of the order to be amended.
+        id: Id,
+        /// The new remaining quantity of the order.
+        new_qty: u64,
+        /// The new limit price of the order.
+        new_price: u64,
+    },
+    /// An atomic cancel-replace, which cancels the resting order `old_id` and
+    /// places a new limit order `new_id`, which may trade immediately like any
+    /// limit order. The new order inherits the owner and the tag of the
+    /// canceled one. If `old_id` is not resting on the order book, the
+    /// cancel-replace is rejected, unless configured otherwise with
+    /// [`OrderBook::set_replace_if_missing`].
+    ///
+    /// [`OrderBook::set_replace_if_missing`]:
+    /// crate::OrderBook::set_replace_if_missing
+    CancelReplace {
+        /// The unique ID of the order to be canceled.
+        old_id: Id,
+        /// The unique ID of the new order. It may be equal to `old_id`.
+        new_id: Id,
+        /// The side of the new order.
+        side: Side,
+        /// The quantity of the new order.
+        qty: u64,
+        /// The limit price of the new order.
+        price: u64,
+    },
+    /// A limit order that is automatically canceled once the logical time of
+    /// the order book (see [`OrderBook::set_time`]) reaches `expires_at`.
+    ///
+    /// [`OrderBook::set_time`]: crate::OrderBook::set_time
+    GoodTillTime {
+        /// The unique ID of this order.
+        id: Id,
+        /// The order side. It will be matched against the resting orders on the
+        /// other side of the order book.
+        side: Side,
+        /// The order quantity.
+        qty: u64,
+        /// The limit price. The order book will only match this order with
+        /// other orders at this price or better.
+        price: u64,
+        /// The logical time at which the order expires.
+        expires_at: u64,
+    },
+    /// A stop limit order, which is kept aside until a trade happens at or
+    /// through the trigger price (at or above it for a buy stop, at or below
+    /// it for a sell stop), and is then executed as a limit order.
+    StopLimit {
+        /// The unique ID of this order.
+        id: Id,
+        /// The order side. It will be matched against the resting orders on the
+        /// other side of the order book once triggered.
+        side: Side,
+        /// The order quantity.
+        qty: u64,
+        /// The limit price of the order, once triggered.
+        price: u64,
+        /// The trade price that triggers the order.
+        trigger: u64,
+    },
+    /// A stop market order, which is kept aside until a trade happens at or
+    /// through the trigger price, and is then executed as a market order.
+    StopMarket {
+        /// The unique ID of this order.
+        id: Id,
+        /// The order side. It will be matched against the resting orders on the
+        /// other side of the order book once triggered.
+        side: Side,
+        /// The order quantity.
+        qty: u64,
+        /// The trade price that triggers the order.
+        trigger: u64,
     },
 }
 
+impl<Id: OrderId> OrderType<Id> {
+    /// Return the ID of the order. For a cancel-replace, this is the ID of the
+    /// new order.
+    pub fn id(&self) -> Id {
+        match *self {
+            OrderType::Market { id, .. }
+            | OrderType::Limit { id, .. }
+            | OrderType::AllOrNone { id, .. }
+            | OrderType::CancelReplace { new_id: id, .. }
+            | OrderType::Cancel { id }
+            | OrderType::Amend { id, .. }
+            | OrderType::GoodTillTime { id, .. }
+            | OrderType::StopLimit { id, .. }
+            | OrderType::StopMarket { id, .. } => id,
+        }
+    }
+}
+
 /// An event resulting from the execution of an order.
 #[derive(Debug, PartialEq, Clone)]
-pub enum OrderEvent {
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum OrderEvent<Id = u128> {
     /// Indicating that the corresponding order was not filled. It is only sent
     /// in response to market orders.
     Unfilled {
         /// The ID of the order this event is referring to.
-        id: u128,
+        id: Id,
+        /// The reason why the order was not filled.
+        reason: UnfilledReason,
     },
     /// Indicating that the corresponding order was placed on the order book. It
-    /// is only send in response to limit orders.
+    /// is only send in response to limit and stop orders.
     Placed {
         /// The ID of the order this event is referring to.
-        id: u128,
+        id: Id,
     },
     /// Indicating that the corresponding order was removed from the order book.
     /// It is only sent in response to cancel orders.
     Canceled {
         /// The ID of the order this event is referring to.
-        id: u128,
+        id: Id,
+    },
+    /// Indicating that the corresponding resting order was modified. It is only
+    /// sent in response to amend orders that did not generate any fill.
+    Amended {
+        /// The ID of the order this event is referring to.
+        id: Id,
+    },
+    /// Indicating that the corresponding order was removed from the order book
+    /// by the order book itself, rather than by a cancel order.
+    SystemCanceled {
+        /// The ID of the order this event is referring to.
+        id: Id,
+        /// The reason why the order was canceled.
+        reason: CancelReason,
+    },
+    /// Indicating that the corresponding order was rejected by the order book
+    /// and had no effect on it.
+    Rejected {
+        /// The ID of the order this event is referring to.
+        id: Id,
+        /// The reason why the order was rejected.
+        reason: RejectReason,
     },
     /// Indicating that the corresponding order was only partially filled. It is
     /// sent in response to market or limit orders.
     PartiallyFilled {
         /// The ID of the order this event is referring to.
-        id: u128,
+        id: Id,
         /// The filled quantity.
         filled_qty: u64,
+        /// The quantity left resting on the order book after matching. It is
+        /// zero if the unfilled quantity was canceled, e.g. for market
+        /// orders.
+        resting_qty: u64,
         /// A vector with information on the order fills.
-        fills: Vec<FillMetadata>,
+        fills: Vec<FillMetadata<Id>>,
+        /// The summary of the fills, only present if stats tracking is
+        /// enabled (see [`OrderBook::last_trade`]).
+        ///
+        /// [`OrderBook::last_trade`]: crate::OrderBook::last_trade
+        trade: Option<Trade>,
     },
     /// Indicating that the corresponding order was filled completely. It is
     /// sent in response to market or limit orders.
     Filled {
         /// The ID of the order this event is referring to.
-        id: u128,
+        id: Id,
         /// The filled quantity.
         filled_qty: u64,
         /// A vector with information on the order fills.
-        fills: Vec<FillMetadata>,
+        fills: Vec<FillMetadata<Id>>,
+        /// The summary of the fills, only present if stats tracking is
+        /// enabled (see [`OrderBook::last_trade`]).
+        ///
+        /// [`OrderBook::last_trade`]: crate::OrderBook::last_trade
+        trade: Option<Trade>,
+    },
+    /// Indicating that the corresponding stop order was triggered and
+    /// executed. It is returned by [`OrderBook::take_triggered`].
+    ///
+    /// [`OrderBook::take_triggered`]: crate::OrderBook::take_triggered
+    Triggered {
+        /// The ID of the order this event is referring to.
+        id: Id,
+        /// The event resulting from the execution of the triggered order.
+        event: Box<OrderEvent<Id>>,
     },
+    /// Indicating that the resting order `canceled` was canceled and replaced
+    /// by a new order. It is only sent in response to cancel-replace orders.
+    Replaced {
+        /// The ID of the canceled order.
+        canceled: Id,
+        /// The event resulting from the placement of the new order.
+        placement: Box<OrderEvent<Id>>,
+    },
+}
+
+impl<Id: OrderId> OrderEvent<Id> {
+    /// Return the fills carried by this event, in execution order. Events that
+    /// do not involve any fill return an empty slice. For a triggered stop
+    /// order or a cancel-replace, these are the fills of the new order.
+    pub fn fills(&self) -> &[FillMetadata<Id>] {
+        match self {
+            OrderEvent::PartiallyFilled { fills, .. }
+            | OrderEvent::Filled { fills, .. } => fills,
+            OrderEvent::Triggered { event, .. }
+            | OrderEvent::Replaced {
+                placement: event, ..
+            } => event.fills(),
+            _ => &[],
+        }
+    }
+
+    /// Return the fills carried by this event sorted according to `ordering`.
+    /// Fills that compare equal keep their execution order.
+    pub fn fills_ordered(&self, ordering: FillOrdering) -> Vec<FillMetadata<Id>>
+    where
+        Id: Ord,
+    {
+        let mut fills = self.fills().to_vec();
+        match ordering {
+            FillOrdering::ExecutionTime => {}
+            FillOrdering::PriceAscending => fills.sort_by_key(|fm| fm.price),
+            FillOrdering::PriceDescending => {
+                fills.sort_by_key(|fm| Reverse(fm.price))
+            }
+            FillOrdering::MakerId => fills.sort_by_key(|fm| fm.order_2),
+        }
+        fills
+    }
+
+    /// Return a copy of this event where consecutive fills at the same price
+    /// are merged into a single fill with the summed quantity. The per-maker
+    /// detail is lost: a merged fill reports the ID of the first maker and is
+    /// only marked as a total fill if all the merged fills were.
+    pub fn merge_same_price_fills(&self) -> OrderEvent<Id> {
+        let mut event = self.clone();
+        if let OrderEvent::PartiallyFilled { fills, .. }
+        | OrderEvent::Filled { fills, .. } = &mut event
+        {
+            let mut merged: Vec<FillMetadata<Id>> =
+                Vec::with_capacity(fills.len());
+            for fill in fills.iter() {
+                match merged.last_mut() {
+                    Some(last) if last.price == fill.price => {
+                        last.qty += fill.qty;
+                        last.total_fill &= fill.total_fill;
+                    }
+                    _ => merged.push(*fill),
+                }
+            }
+            *fills = merged;
+        }
+        event
+    }
+
+    /// Return the trade summarizing the fills carried by this event, if
+    /// present. It is only recorded while stats tracking is enabled. For a
+    /// triggered stop order or a cancel-replace, this is the trade of the new
+    /// order.
+    pub fn trade(&self) -> Option<Trade> {
+        match self {
+            OrderEvent::PartiallyFilled { trade, .. }
+            | OrderEvent::Filled { trade, .. } => *trade,
+            OrderEvent::Triggered { event, .. }
+            | OrderEvent::Replaced {
+                placement: event, ..
+            } => event.trade(),
+            _ => None,
+        }
+    }
+
+    /// Summarize the fills carried by this event as a single aggregated trade
+    /// print, as reported on a consolidated tape. Return `None` if the event
+    /// does not involve any fill.
+    pub fn trade_print(&self) -> Option<TradePrint> {
+        let fills = self.fills();
+        let first = fills.first()?;
+        let last = fills.last()?;
+        let total_qty = fills.iter().map(|fm| fm.qty).sum::<u64>();
+        let notional = fills
+            .iter()
+            .map(|fm| u128::from(fm.price) * u128::from(fm.qty))
+            .sum::<u128>();
+        Some(TradePrint {
+            total_qty,
+            vwap: notional as f64 / total_qty as f64,
+            first_price: first.price,
+            last_price: last.price,
+        })
+    }
+
+    /// Return the realized slippage of this event, i.e. the difference between
+    /// the VWAP of its fills and the mid price at the time the order was sent,
+    /// signed so that a positive value is adverse to the taker (buying above
+    /// or selling below `arrival_mid`). Return `None` if the event does not
+    /// involve any fill.
+    pub fn slippage(&self, arrival_mid: u64) -> Option<f64> {
+        let vwap = self.trade_print()?.vwap;
+        let taker_side = self.fills().first()?.taker_side;
+        Some(match taker_side {
+            Side::Bid => vwap - arrival_mid as f64,
+            Side::Ask => arrival_mid as f64 - vwap,
+        })
+    }
+}
+
+/// A mutation of the order book, reported to the observer registered with
+/// [`OrderBook::set_observer`] at the moment it happens.
+///
+/// [`OrderBook::set_observer`]: crate::OrderBook::set_observer
+#[derive(Debug, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum BookEvent<Id = u128> {
+    /// An order started resting on the order book.
+    OrderAdded {
+        /// The ID of the order.
+        id: Id,
+        /// The side of the order.
+        side: Side,
+        /// The price of the order.
+        price: u64,
+        /// The quantity left resting on the order book.
+        qty: u64,
+    },
+    /// A resting order was modified without leaving the order book, because
+    /// its quantity was amended while keeping its time priority, or because
+    /// it was moved to the back of its price level queue by a refresh.
+    OrderUpdated {
+        /// The ID of the order.
+        id: Id,
+        /// The side of the order.
+        side: Side,
+        /// The price of the order.
+        price: u64,
+        /// The quantity left resting on the order book, including any hidden
+        /// reserve.
+        qty: u64,
+    },
+    /// A resting order was removed from the order book, because it was
+    /// completely filled or canceled.
+    OrderRemoved {
+        /// The ID of the order.
+        id: Id,
+        /// The side of the order.
+        side: Side,
+        /// The price of the order.
+        price: u64,
+    },
+    /// An incoming order was matched against a resting order.
+    Trade {
+        /// Information on the fill.
+        fill: FillMetadata<Id>,
+    },
+    /// A resting order was filled, completely or partially, by an incoming
+    /// order. It is reported right after the corresponding [`Trade`] event,
+    /// and lets makers track their fills without looking for their ID in
+    /// every trade.
+    ///
+    /// [`Trade`]: BookEvent::Trade
+    OrderFilled {
+        /// The ID of the resting order.
+        id: Id,
+        /// The side of the resting order.
+        side: Side,
+        /// The price of the fill.
+        price: u64,
+        /// The filled quantity.
+        qty: u64,
+        /// The quantity still resting on the order book, including any
+        /// hidden reserve. It is zero if the order was completely filled.
+        remaining_qty: u64,
+    },
+    /// The best price on one side of the order book changed.
+    BestPriceChanged {
+        /// The side of the order book.
+        side: Side,
+        /// The new best price, or `None` if that side is now empty.
+        price: Option<u64>,
+    },
+}
+
+/// The order in which fills are returned by [`OrderEvent::fills_ordered`], to
+/// match the layout expected by downstream (e.g. drop-copy) formats.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum FillOrdering {
+    /// The order in which the fills were generated by the matching engine.
+    ExecutionTime,
+    /// Ascending maker price, regardless of the taker side.
+    PriceAscending,
+    /// Descending maker price, regardless of the taker side.
+    PriceDescending,
+    /// Ascending ID of the maker order.
+    MakerId,
+}
+
+/// The reason why an order was canceled by the order book itself.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum CancelReason {
+    /// The order reached its expiry time.
+    Expired,
+    /// The order would have traded against a resting order of the same owner
+    /// (see [`OrderBook::set_stp_mode`]).
+    ///
+    /// [`OrderBook::set_stp_mode`]: crate::OrderBook::set_stp_mode
+    SelfTrade,
+    /// The order could not rest because the order book is draining (see
+    /// [`OrderBook::enter_drain_mode`]).
+    ///
+    /// [`OrderBook::enter_drain_mode`]: crate::OrderBook::enter_drain_mode
+    Draining,
+}
+
+/// The reason why a market order was not filled at all.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum UnfilledReason {
+    /// The opposite side of the book had no liquidity the order could trade
+    /// against.
+    NoLiquidity,
+    /// The best opposite price was beyond the protection price of the order.
+    PriceProtection,
+}
+
+/// The self-trade prevention policy applied when an incoming order would match
+/// a resting order of the same owner.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum StpMode {
+    /// Cancel the remaining quantity of the incoming order, leaving the
+    /// resting order untouched.
+    CancelNewest,
+    /// Cancel the resting order, and keep matching the incoming order against
+    /// the rest of the book.
+    CancelResting,
+}
+
+/// The rule used to allocate an incoming order among the resting orders at
+/// the same price level (see [`OrderBook::set_matching_policy`]).
+///
+/// [`OrderBook::set_matching_policy`]: crate::OrderBook::set_matching_policy
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum MatchingPolicy {
+    /// Fill the resting orders in time priority: an order is only filled once
+    /// all the orders that arrived before it at the same price are filled.
+    Fifo,
+    /// Allocate the incoming quantity across all the resting orders at the
+    /// best price, proportionally to their displayed quantity. The units left
+    /// by rounding down are given one each to the orders with the largest
+    /// fractional part, ties being broken by time priority.
+    ProRata,
+}
+
+/// The rule deciding whether amending a resting order at the same price keeps
+/// its time priority (see [`OrderBook::set_amend_policy`]). Reducing the
+/// quantity always keeps the priority, and changing the price always loses it.
+///
+/// [`OrderBook::set_amend_policy`]: crate::OrderBook::set_amend_policy
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum AmendPolicy {
+    /// Increasing the quantity moves the order to the back of the queue.
+    AlwaysLosePriorityOnIncrease,
+    /// Increasing the quantity keeps the priority as long as the new quantity
+    /// does not exceed the quantity the order was queued with, less the
+    /// quantity filled since then. Exceeding it moves the order to the back
+    /// of the queue.
+    KeepPriorityUpToOriginal,
+}
+
+/// The reason why an order was rejected by the order book.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum RejectReason {
+    /// The order refers to an ID that is not resting on the order book.
+    OrderNotFound,
+    /// The displayed quantity of the order is zero or greater than the order
+    /// quantity.
+    InvalidDisplayQty,
+    /// The new quantity of an amend is zero.
+    InvalidQty,
+    /// The order quantity is zero, or a reduce-only order was clamped down to
+    /// a zero quantity.
+    ZeroQuantity,
+    /// The order price is not a multiple of the tick size.
+    InvalidTick,
+    /// The order has the same ID as an order resting on the order book.
+    DuplicateId,
+    /// The limit price is too far from the reference price of the order book.
+    PriceOutsideBand,
+    /// The order would rest on an order book in drain mode (see
+    /// [`OrderBook::enter_drain_mode`]).
+    ///
+    /// [`OrderBook::enter_drain_mode`]: crate::OrderBook::enter_drain_mode
+    Draining,
+}
+
+/// An error returned by [`OrderBook::try_execute`] and
+/// [`OrderBook::try_execute_checked`] when an order could not be executed at
+/// all.
+///
+/// [`OrderBook::try_execute`]: crate::OrderBook::try_execute
+/// [`OrderBook::try_execute_checked`]: crate::OrderBook::try_execute_checked
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ExecutionError {
+    /// The order refers to an ID that is not resting on the order book.
+    OrderNotFound,
+    /// Executing the order would overflow a quantity or a counter of the
+    /// order book. Only returned by [`OrderBook::try_execute_checked`].
+    ///
+    /// [`OrderBook::try_execute_checked`]:
+    /// crate::OrderBook::try_execute_checked
+    Overflow,
+    /// The memory needed to store the order could not be allocated. Only
+    /// returned by [`OrderBook::try_execute_checked`].
+    ///
+    /// [`OrderBook::try_execute_checked`]:
+    /// crate::OrderBook::try_execute_checked
+    OutOfMemory,
+}
+
+impl std::fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExecutionError::OrderNotFound => write!(f, "order not found"),
+            ExecutionError::Overflow => write!(f, "arithmetic overflow"),
+            ExecutionError::OutOfMemory => write!(f, "out of memory"),
+        }
+    }
 }
 
+impl std::error::Error for ExecutionError {}
+
 /// Information on a single order fill. When an order is matched with multiple
 /// resting orders, it generates multiple `FillMetadata` values.
 #[derive(Debug, PartialEq, Copy, Clone)]
-pub struct FillMetadata {
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FillMetadata<Id = u128> {
     /// The ID of the order that triggered the fill (taker).
-    pub order_1: u128,
+    pub order_1: Id,
     /// The ID of the matching order.
-    pub order_2: u128,
+    pub order_2: Id,
     /// The quantity that was traded.
     pub qty: u64,
     /// The price at which the trade happened.
@@ -114,6 +784,10 @@ pub struct FillMetadata {
     /// Whether this order was a total (true) or partial (false) fill of the
     /// maker order.
     pub total_fill: bool,
+    /// The tag of the taker order (order 1), if any.
+    pub taker_tag: Option<u64>,
+    /// The tag of the maker order (order 2), if any.
+    pub maker_tag: Option<u64>,
 }
 
 /// A snapshot of the order book up to a certain depth level. Multiple orders at
@@ -121,6 +795,7 @@ pub struct FillMetadata {
 ///
 /// [`BookLevel`]: /struct.BookLevel.html
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct BookDepth {
     /// The requested level. This field will always contain the level that was
     /// requested, even if some or all levels are empty.
@@ -131,9 +806,70 @@ pub struct BookDepth {
     pub bids: Vec<BookLevel>,
 }
 
+impl std::fmt::Display for BookDepth {
+    /// Render the depth as a price ladder, one level per line: the asks from
+    /// the highest to the lowest price, then the spread, then the bids from
+    /// the highest to the lowest price. The spread is `-` if a side is empty,
+    /// and `crossed` if the best bid is above the best ask.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let levels = || self.asks.iter().chain(&self.bids);
+        let width = |value: u64| value.to_string().len();
+        let pw = levels().map(|l| width(l.price)).max().unwrap_or(0);
+        let qw = levels().map(|l| width(l.qty)).max().unwrap_or(0);
+        let write_level = |f: &mut std::fmt::Formatter<'_>, side, level| {
+            let &BookLevel { price, qty } = level;
+            write!(f, "{} {:>pw$} {:>qw$}", side, price, qty, pw = pw, qw = qw)
+        };
+        for level in self.asks.iter().rev() {
+            write_level(f, "ask", level)?;
+            writeln!(f)?;
+        }
+        match (self.asks.first(), self.bids.first()) {
+            (Some(ask), Some(bid)) => match ask.price.checked_sub(bid.price) {
+                Some(spread) => write!(f, "spread {}", spread)?,
+                None => write!(f, "spread crossed")?,
+            },
+            _ => write!(f, "spread -")?,
+        }
+        for level in &self.bids {
+            writeln!(f)?;
+            write_level(f, "bid", level)?;
+        }
+        Ok(())
+    }
+}
+
+/// A snapshot of the order book up to a certain depth level, with the
+/// individual orders resting at each price point, as returned by
+/// [`OrderBook::depth_detailed`].
+///
+/// [`OrderBook::depth_detailed`]: crate::OrderBook::depth_detailed
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DetailedBookDepth<Id = u128> {
+    /// The requested level. This field will always contain the level that was
+    /// requested, even if some or all levels are empty.
+    pub levels: usize,
+    /// The ask price points, lowest price first.
+    pub asks: Vec<DetailedBookLevel<Id>>,
+    /// The bid price points, highest price first.
+    pub bids: Vec<DetailedBookLevel<Id>>,
+}
+
+/// A single price point of a [`DetailedBookDepth`].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DetailedBookLevel<Id = u128> {
+    /// The price point this level represents.
+    pub price: u64,
+    /// The orders resting at this price point, in time priority order.
+    pub orders: Vec<OrderInfo<Id>>,
+}
+
 /// A single level in the order book. This struct is used both for the bid and
 /// ask side.
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct BookLevel {
     /// The price point this level represents.
     pub price: u64,
@@ -141,8 +877,34 @@ pub struct BookLevel {
     pub qty: u64,
 }
 
+/// A summary of the top of the order book, returned by
+/// [`OrderBook::summary`].
+///
+/// [`OrderBook::summary`]: crate::OrderBook::summary
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BookSummary {
+    /// The highest bid price, if present.
+    pub best_bid: Option<u64>,
+    /// The lowest ask price, if present.
+    pub best_ask: Option<u64>,
+    /// The difference of the best ask and bid, if both are present and the
+    /// book is not crossed.
+    pub spread: Option<u64>,
+    /// The average of the best ask and bid, if both are present.
+    pub mid_price: Option<f64>,
+    /// The total displayed quantity of the top bid price levels.
+    pub bid_qty: u64,
+    /// The total displayed quantity of the top ask price levels.
+    pub ask_qty: u64,
+    /// The share of the bid side in the total displayed quantity of the top
+    /// price levels, `None` if either side is empty.
+    pub imbalance: Option<f64>,
+}
+
 /// A trade that happened as part of the matching process.
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Trade {
     /// The total quantity transacted as part of this trade.
     pub total_qty: u64,
@@ -155,20 +917,533 @@ pub struct Trade {
     pub last_qty: u64,
 }
 
-#[derive(Debug, PartialEq)]
-pub struct LimitOrder {
-    pub id: u128,
+// In general, floating point values cannot be compared for equality, so the
+// average prices of two trades only need to be close for the trades to be
+// equal. This keeps the equality of the events carrying a trade independent of
+// the rounding of the average price.
+impl PartialEq for Trade {
+    fn eq(&self, other: &Self) -> bool {
+        self.total_qty == other.total_qty
+            && (self.avg_price - other.avg_price).abs() < 1.0e-6
+            && self.last_qty == other.last_qty
+            && self.last_price == other.last_price
+    }
+}
+
+impl std::fmt::Display for Trade {
+    /// Render the trade as its total quantity at the average price, followed
+    /// by the last fill, e.g. `5 @ 100.4 (last 2 @ 101)`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} @ {} (last {} @ {})",
+            self.total_qty, self.avg_price, self.last_qty, self.last_price
+        )
+    }
+}
+
+/// Summary statistics computed over a sequence of trades, for example a trade
+/// tape exported with [`OrderBook::export_tape`].
+///
+/// [`OrderBook::export_tape`]: crate::OrderBook::export_tape
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TradeStats {
+    /// The number of trades.
+    pub count: usize,
+    /// The total quantity transacted.
+    pub total_qty: u64,
+    /// The volume-weighted average price across all the trades.
+    pub vwap: f64,
+    /// The price of the last fill of the last trade.
+    pub last_price: u64,
+}
+
+impl TradeStats {
+    /// Compute the statistics for the given trades, in chronological order,
+    /// without requiring a live order book. Return `None` if there are no
+    /// trades.
+    pub fn from_trades<'a, I>(trades: I) -> Option<Self>
+    where
+        I: IntoIterator<Item = &'a Trade>,
+    {
+        let mut count = 0;
+        let mut total_qty = 0;
+        let mut notional = 0.0;
+        let mut last_price = None;
+        for trade in trades {
+            count += 1;
+            total_qty += trade.total_qty;
+            notional += trade.avg_price * trade.total_qty as f64;
+            last_price = Some(trade.last_price);
+        }
+        Some(TradeStats {
+            count,
+            total_qty,
+            vwap: notional / total_qty as f64,
+            last_price: last_price?,
+        })
+    }
+}
+
+/// A single aggregated trade print summarizing all the fills generated by the
+/// execution of one order, possibly across multiple makers and price levels.
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TradePrint {
+    /// The total quantity transacted.
+    pub total_qty: u64,
+    /// The volume-weighted average price of all the fills.
+    pub vwap: f64,
+    /// The price of the first fill.
+    pub first_price: u64,
+    /// The price of the last fill.
+    pub last_price: u64,
+}
+
+/// Information about a resting order, as returned by [`OrderBook::order`].
+///
+/// [`OrderBook::order`]: crate::OrderBook::order
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct OrderInfo<Id = u128> {
+    /// The unique ID of the order.
+    pub id: Id,
+    /// The side of the book the order is resting on.
+    pub side: Side,
+    /// The limit price of the order.
+    pub price: u64,
+    /// The total remaining quantity, including any hidden reserve.
+    pub qty: u64,
+    /// The size of each displayed slice of the order.
+    pub display_qty: u64,
+    /// The remaining quantity of the currently displayed slice.
+    pub visible_qty: u64,
+    /// The sequence number of the order (see [`OrderBook::order_seq`]).
+    ///
+    /// [`OrderBook::order_seq`]: crate::OrderBook::order_seq
+    pub seq: u64,
+    /// The owner of the order, if any.
+    pub owner: Option<OwnerId>,
+    /// The tag of the order, if any.
+    pub tag: Option<u64>,
+    /// Whether the order may only be filled in its entirety (see
+    /// [`OrderType::AllOrNone`]).
+    pub all_or_none: bool,
+}
+
+/// A snapshot of the state of an order book, as returned by
+/// [`OrderBook::snapshot`], from which an identical order book can be rebuilt
+/// with [`OrderBook::restore`]. The configuration of the order book (see
+/// [`BookConfig`]) is not part of the snapshot.
+///
+/// [`OrderBook::snapshot`]: crate::OrderBook::snapshot
+/// [`OrderBook::restore`]: crate::OrderBook::restore
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BookSnapshot<Id = u128> {
+    /// All the resting orders, asks first, each side in priority order.
+    pub orders: Vec<OrderInfo<Id>>,
+    /// The lowest ask price, if any.
+    pub min_ask: Option<u64>,
+    /// The highest bid price, if any.
+    pub max_bid: Option<u64>,
+    /// Whether volume and trades tracking is enabled.
+    pub track_stats: bool,
+    /// The total traded volume.
+    pub traded_volume: u64,
+    /// The total volume traded by buy takers.
+    pub taker_buy_volume: u64,
+    /// The total volume traded by sell takers.
+    pub taker_sell_volume: u64,
+    /// The last trade, if any.
+    pub last_trade: Option<Trade>,
+    /// The last sequence number assigned to an order.
+    pub seq: u64,
+    /// The logical time of the order book.
+    pub time: u64,
+    /// The expiry time and ID of every pending good-till-time order.
+    pub expiries: Vec<(u64, Id)>,
+    /// The stop orders waiting to be triggered.
+    pub stops: Vec<OrderType<Id>>,
+}
+
+/// The changes of an order book since a snapshot, as returned by
+/// [`OrderBook::delta_since`].
+///
+/// [`OrderBook::delta_since`]: crate::OrderBook::delta_since
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BookDelta<Id = u128> {
+    /// The IDs of the resting orders that were not in the snapshot.
+    pub added: Vec<Id>,
+    /// The IDs of the orders of the snapshot that are no longer resting.
+    pub removed: Vec<Id>,
+    /// The ask levels whose displayed quantity changed, in ascending price
+    /// order, with their new quantity. A quantity of zero means the level was
+    /// emptied.
+    pub asks: Vec<BookLevel>,
+    /// The bid levels whose displayed quantity changed, in descending price
+    /// order, with their new quantity. A quantity of zero means the level was
+    /// emptied.
+    pub bids: Vec<BookLevel>,
+}
+
+impl<Id> BookDelta<Id> {
+    /// Apply the level changes to the full depth of the order book at the time
+    /// of the snapshot (see [`OrderBook::full_depth`]), turning it into the
+    /// current depth.
+    ///
+    /// [`OrderBook::full_depth`]: crate::OrderBook::full_depth
+    pub fn apply(&self, depth: &mut BookDepth) {
+        apply_level_changes(&mut depth.asks, &self.asks, false);
+        apply_level_changes(&mut depth.bids, &self.bids, true);
+    }
+}
+
+/// Update the levels of one side of a depth, sorted by price in ascending
+/// order, or descending order if `descending` is set.
+fn apply_level_changes(
+    levels: &mut Vec<BookLevel>,
+    changes: &[BookLevel],
+    descending: bool,
+) {
+    for change in changes {
+        let position = levels.binary_search_by(|level| {
+            let ordering = level.price.cmp(&change.price);
+            if descending {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        });
+        match position {
+            Ok(i) if change.qty == 0 => {
+                levels.remove(i);
+            }
+            Ok(i) => levels[i].qty = change.qty,
+            Err(i) if change.qty > 0 => levels.insert(i, change.clone()),
+            Err(_) => {}
+        }
+    }
+}
+
+/// Statistics on the usage of the arena storing the resting orders, as
+/// returned by [`OrderBook::arena_stats`]. They can be used to choose an arena
+/// capacity large enough to avoid allocating new slots while trading.
+///
+/// [`OrderBook::arena_stats`]: crate::OrderBook::arena_stats
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ArenaStats {
+    /// The number of allocated slots, including the ones allocated past the
+    /// initial capacity.
+    pub capacity: usize,
+    /// The number of orders resting on the order book.
+    pub live_orders: usize,
+    /// The number of slots available for new orders without allocating. Slots
+    /// of canceled orders that are not released yet are neither live nor
+    /// free.
+    pub free_slots: usize,
+    /// The largest number of orders resting at the same time since the order
+    /// book was created or cleared.
+    pub high_water_mark: usize,
+}
+
+/// A snapshot of the configuration of an order book, as returned by
+/// [`OrderBook::config`].
+///
+/// [`OrderBook::config`]: crate::OrderBook::config
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BookConfig {
+    /// The number of orders pre-allocated in the arena when the book was
+    /// created.
+    pub arena_capacity: usize,
+    /// The initial capacity of each queue storing orders at the same price.
+    pub queue_capacity: usize,
+    /// Whether volume and trades tracking is enabled.
+    pub track_stats: bool,
+    /// The maximum number of trades kept in the trade tape.
+    pub tape_capacity: usize,
+    /// Whether new orders are stored in the lowest free arena slot.
+    pub deterministic_arena: bool,
+    /// The self-trade prevention policy, if enabled.
+    pub stp_mode: Option<StpMode>,
+    /// The tick size limit order prices must be a multiple of.
+    pub tick_size: u64,
+    /// The reference price used by the price band check.
+    pub price_reference: Option<u64>,
+    /// The maximum distance between a limit price and the reference price.
+    pub price_band: Option<u64>,
+    /// The offset added to external prices to map them to internal prices.
+    pub price_offset: u64,
+    /// The rule used to allocate incoming orders within a price level.
+    pub matching_policy: MatchingPolicy,
+    /// Whether increasing the quantity of an order keeps its time priority.
+    pub amend_policy: AmendPolicy,
+    /// Whether a cancel-replace places the new order even if the order to be
+    /// canceled is not resting.
+    pub replace_if_missing: bool,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct LimitOrder<Id = u128> {
+    pub id: Id,
+    pub side: Side,
+    /// The total remaining quantity, including the hidden reserve.
     pub qty: u64,
     pub price: u64,
+    pub seq: u64,
+    /// The size of each displayed slice of the order.
+    pub display_qty: u64,
+    /// The remaining quantity of the currently displayed slice.
+    pub visible_qty: u64,
+    /// The quantity the order was queued with, less the filled quantity. The
+    /// order can be amended up to this quantity without losing its time
+    /// priority under [`AmendPolicy::KeepPriorityUpToOriginal`].
+    pub queued_qty: u64,
+    pub owner: Option<OwnerId>,
+    pub tag: Option<u64>,
+    pub all_or_none: bool,
+    /// The time at which the order expires, if it is a good-till-time order.
+    pub expires_at: Option<u64>,
+}
+
+impl<Id: OrderId> From<&LimitOrder<Id>> for OrderInfo<Id> {
+    fn from(order: &LimitOrder<Id>) -> Self {
+        OrderInfo {
+            id: order.id,
+            side: order.side,
+            price: order.price,
+            qty: order.qty,
+            display_qty: order.display_qty,
+            visible_qty: order.visible_qty,
+            seq: order.seq,
+            owner: order.owner,
+            tag: order.tag,
+            all_or_none: order.all_or_none,
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::Side;
+    use super::{BookDepth, PriceScale, RoundingMode, Side, Trade};
 
     #[test]
     fn side_negation() {
         assert_eq!(!Side::Ask, Side::Bid);
         assert_eq!(!Side::Bid, Side::Ask);
     }
+
+    #[test]
+    fn display() {
+        let trade = Trade {
+            total_qty: 5,
+            avg_price: 100.4,
+            last_price: 101,
+            last_qty: 2,
+        };
+        assert_eq!(trade.to_string(), "5 @ 100.4 (last 2 @ 101)");
+
+        let empty = BookDepth {
+            levels: 5,
+            asks: vec![],
+            bids: vec![],
+        };
+        assert_eq!(empty.to_string(), "spread -");
+    }
+
+    #[test]
+    fn price_scale_round_trip() {
+        let scale = PriceScale::from_decimals(2, RoundingMode::Floor).unwrap();
+        assert_eq!(scale.multiplier(), 100);
+        for &(price, ticks) in &[
+            (0.0, 0),
+            (0.01, 1),
+            (1.15, 115),
+            (123.45, 12345),
+            (99_999.99, 9_999_999),
+        ] {
+            assert_eq!(scale.to_ticks(price), Some(ticks));
+            assert_eq!(scale.to_price(ticks), price);
+        }
+
+        let scale = PriceScale::from_multiplier(4, RoundingMode::Ceil).unwrap();
+        assert_eq!(scale.to_ticks(2.25), Some(9));
+        assert_eq!(scale.to_ticks(2.3), Some(10));
+        assert_eq!(scale.to_price(9), 2.25);
+    }
+
+    #[test]
+    fn price_scale_rounding_at_half() {
+        let tick = |rounding, price| {
+            let scale = PriceScale::from_decimals(1, rounding).unwrap();
+            scale.to_ticks(price).unwrap()
+        };
+        assert_eq!(tick(RoundingMode::Floor, 0.25), 2);
+        assert_eq!(tick(RoundingMode::Ceil, 0.25), 3);
+        assert_eq!(tick(RoundingMode::Nearest, 0.25), 3);
+        assert_eq!(tick(RoundingMode::NearestEven, 0.25), 2);
+        assert_eq!(tick(RoundingMode::Nearest, 0.35), 4);
+        assert_eq!(tick(RoundingMode::NearestEven, 0.35), 4);
+        assert_eq!(tick(RoundingMode::NearestEven, 0.45), 4);
+    }
+
+    #[test]
+    fn price_scale_guards() {
+        assert_eq!(PriceScale::from_decimals(20, RoundingMode::Floor), None);
+        assert_eq!(PriceScale::from_multiplier(0, RoundingMode::Floor), None);
+
+        let scale = PriceScale::from_decimals(2, RoundingMode::Floor).unwrap();
+        assert_eq!(scale.to_ticks(-0.01), None);
+        assert_eq!(scale.to_ticks(f64::NAN), None);
+        assert_eq!(scale.to_ticks(f64::INFINITY), None);
+        assert_eq!(scale.to_ticks(u64::MAX as f64), None);
+        assert_eq!(scale.to_ticks(1e17), Some(10_000_000_000_000_000_000));
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod serde_test {
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+
+    use super::*;
+
+    /// Check that `value` survives a JSON round trip, and return its JSON
+    /// representation.
+    fn round_trip<T: Serialize + DeserializeOwned>(value: &T) -> String {
+        let json = serde_json::to_string(value).unwrap();
+        let decoded: T = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&decoded).unwrap(), json);
+        json
+    }
+
+    fn fill() -> FillMetadata {
+        FillMetadata {
+            order_1: 1,
+            order_2: 2,
+            qty: 3,
+            price: 100,
+            taker_side: Side::Bid,
+            total_fill: true,
+            taker_tag: Some(7),
+            maker_tag: None,
+        }
+    }
+
+    #[test]
+    fn side() {
+        assert_eq!(round_trip(&Side::Bid), r#""Bid""#);
+        assert_eq!(round_trip(&Side::Ask), r#""Ask""#);
+    }
+
+    #[test]
+    fn order_type() {
+        let json = round_trip(&OrderType::Limit {
+            id: 1,
+            side: Side::Ask,
+            qty: 10,
+            price: 100,
+            display_qty: Some(2),
+            owner: None,
+            tag: None,
+            reduce_only: None,
+        });
+        assert!(json.starts_with(r#"{"Limit":{"id":1,"#));
+        round_trip(&OrderType::Market {
+            id: 2,
+            side: Side::Bid,
+            qty: 5,
+            tag: None,
+            owner: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        round_trip(&OrderType::Cancel { id: 1 });
+        round_trip(&OrderType::Amend {
+            id: 1,
+            new_qty: 5,
+            new_price: 101,
+        });
+        round_trip(&OrderType::GoodTillTime {
+            id: 3,
+            side: Side::Bid,
+            qty: 5,
+            price: 99,
+            expires_at: 1_000,
+        });
+        round_trip(&OrderType::StopLimit {
+            id: 4,
+            side: Side::Ask,
+            qty: 5,
+            price: 98,
+            trigger: 99,
+        });
+    }
+
+    #[test]
+    fn order_event() {
+        let json = round_trip(&OrderEvent::Filled {
+            id: 1,
+            filled_qty: 3,
+            fills: vec![fill()],
+            trade: Some(Trade {
+                total_qty: 3,
+                avg_price: 100.0,
+                last_qty: 3,
+                last_price: 100,
+            }),
+        });
+        assert!(json.starts_with(r#"{"Filled":{"id":1,"#));
+        round_trip(&OrderEvent::Placed { id: 1 });
+        round_trip(&OrderEvent::Rejected {
+            id: 1,
+            reason: RejectReason::InvalidTick,
+        });
+        round_trip(&OrderEvent::SystemCanceled {
+            id: 1,
+            reason: CancelReason::Expired,
+        });
+        round_trip(&OrderEvent::Triggered {
+            id: 2,
+            event: Box::new(OrderEvent::Placed { id: 2 }),
+        });
+    }
+
+    #[test]
+    fn fill_metadata() {
+        let fill = fill();
+        let json = serde_json::to_string(&fill).unwrap();
+        assert_eq!(serde_json::from_str::<FillMetadata>(&json).unwrap(), fill);
+    }
+
+    #[test]
+    fn book_depth() {
+        let depth = BookDepth {
+            levels: 2,
+            asks: vec![BookLevel { price: 101, qty: 3 }],
+            bids: vec![
+                BookLevel { price: 99, qty: 4 },
+                BookLevel { price: 98, qty: 1 },
+            ],
+        };
+        let json = serde_json::to_string(&depth).unwrap();
+        assert_eq!(serde_json::from_str::<BookDepth>(&json).unwrap(), depth);
+    }
+
+    #[test]
+    fn trade() {
+        let trade = Trade {
+            total_qty: 3,
+            avg_price: 100.1 + 0.2,
+            last_price: 101,
+            last_qty: 1,
+        };
+        let json = round_trip(&trade);
+        let decoded: Trade = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.avg_price.to_bits(), trade.avg_price.to_bits());
+    }
 }
@@ -1,30 +1,354 @@
-use std::collections::BTreeMap;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
 
 use crate::arena::OrderArena;
 use crate::models::{
-    BookDepth, BookLevel, FillMetadata, OrderEvent, OrderType, Side, Trade,
+    AmendPolicy, ArenaStats, BookConfig, BookDelta, BookDepth, BookEvent,
+    BookLevel, BookSnapshot, BookSummary, CancelReason, DetailedBookDepth,
+    DetailedBookLevel, ExecutionError, FillMetadata, LimitOrder,
+    MatchingPolicy, OrderEvent, OrderId, OrderInfo, OrderType, OwnerId,
+    RejectReason, RoundingMode, Side, StpMode, Trade, TradeStats,
+    UnfilledReason,
 };
 
-const DEFAULT_ARENA_CAPACITY: usize = 10_000;
-const DEFAULT_QUEUE_CAPACITY: usize = 10;
+/// The attributes of a limit order that are stored with it while it rests on
+/// the order book.
+#[derive(Debug, Copy, Clone, Default)]
+struct RestingAttrs {
+    display_qty: Option<u64>,
+    owner: Option<OwnerId>,
+    tag: Option<u64>,
+    all_or_none: bool,
+}
+
+/// The incoming order being matched against the resting orders.
+#[derive(Debug, Copy, Clone)]
+struct Taker<Id> {
+    id: Id,
+    side: Side,
+    owner: Option<OwnerId>,
+    tag: Option<u64>,
+    stp_mode: Option<StpMode>,
+}
+
+/// A fill of a resting order, as computed without modifying the order book.
+#[derive(Debug, Copy, Clone)]
+struct MakerFill {
+    /// The slot of the resting order in the arena.
+    idx: usize,
+    qty: u64,
+    /// The remaining quantity of the resting order after the fill.
+    remaining_qty: u64,
+    /// The displayed quantity of the resting order after the fill, once
+    /// replenished from its reserve.
+    visible_qty: u64,
+    /// Whether the displayed quantity was replenished from the reserve, which
+    /// moves the resting order to the back of its queue.
+    replenished: bool,
+}
+
+/// The outcome of matching an incoming order.
+#[derive(Debug)]
+struct Execution<Id> {
+    fills: Vec<FillMetadata<Id>>,
+    filled_qty: u64,
+    /// The quantity left resting on the order book.
+    resting_qty: u64,
+    /// Whether some quantity was left unfilled.
+    partial: bool,
+    /// Whether matching was stopped by self-trade prevention.
+    self_trade: bool,
+    /// Whether the unfilled quantity was canceled instead of resting, because
+    /// the order book is draining.
+    drained: bool,
+}
+
+impl<Id> Execution<Id> {
+    /// Convert the execution to an event, returning `no_fills` if the order
+    /// did not trade and was not canceled by self-trade prevention.
+    fn into_event(self, id: Id, no_fills: OrderEvent<Id>) -> OrderEvent<Id> {
+        let Execution {
+            fills,
+            filled_qty,
+            resting_qty,
+            partial,
+            self_trade,
+            drained,
+        } = self;
+        if fills.is_empty() {
+            if self_trade {
+                OrderEvent::SystemCanceled {
+                    id,
+                    reason: CancelReason::SelfTrade,
+                }
+            } else if drained {
+                OrderEvent::SystemCanceled {
+                    id,
+                    reason: CancelReason::Draining,
+                }
+            } else {
+                no_fills
+            }
+        } else if partial {
+            OrderEvent::PartiallyFilled {
+                id,
+                filled_qty,
+                resting_qty,
+                fills,
+                trade: None,
+            }
+        } else {
+            OrderEvent::Filled {
+                id,
+                filled_qty,
+                fills,
+                trade: None,
+            }
+        }
+    }
+}
+
+pub(crate) const DEFAULT_ARENA_CAPACITY: usize = 10_000;
+pub(crate) const DEFAULT_QUEUE_CAPACITY: usize = 10;
+/// The number of canceled orders that can be left in the price level queues
+/// before they are compacted, if larger than the number of resting orders.
+const MIN_COMPACTION_TOMBSTONES: usize = 64;
+
+/// A user-supplied callback, opaque to `Debug`.
+struct Callback<F: ?Sized>(Box<F>);
+
+impl<F: ?Sized> fmt::Debug for Callback<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Callback")
+    }
+}
+
+type Observer<Id> = Option<Callback<dyn FnMut(BookEvent<Id>)>>;
+
+type RejectHandler<Id> =
+    Option<Callback<dyn FnMut(OrderType<Id>, RejectReason)>>;
+
+type TopOfBookHandler = Option<Callback<dyn FnMut(Option<u64>, Option<u64>)>>;
+
+/// A function matching an incoming order against a queue of resting orders,
+/// returning the filled quantity and whether self-trade prevention stopped
+/// the match.
+type QueueProcessor<Id> = fn(
+    &mut OrderArena<Id>,
+    &mut Vec<usize>,
+    u64,
+    Taker<Id>,
+    &mut Vec<FillMetadata<Id>>,
+    &mut u64,
+    &mut Observer<Id>,
+) -> (u64, bool);
+
+/// Return the side and the trigger price of a stop order.
+fn stop_trigger<Id>(order: &OrderType<Id>) -> Option<(Side, u64)> {
+    match *order {
+        OrderType::StopLimit { side, trigger, .. }
+        | OrderType::StopMarket { side, trigger, .. } => Some((side, trigger)),
+        _ => None,
+    }
+}
+
+/// Cap the quantity of a reduce-only order to the size of the position it
+/// reduces, along with its displayed quantity.
+fn clamp_reduce_only<Id>(mut order: OrderType<Id>) -> OrderType<Id> {
+    match &mut order {
+        OrderType::Market {
+            qty,
+            reduce_only: Some(position),
+            ..
+        } => *qty = (*qty).min(*position),
+        OrderType::Limit {
+            qty,
+            display_qty,
+            reduce_only: Some(position),
+            ..
+        } => {
+            *qty = (*qty).min(*position);
+            if let Some(display_qty) = display_qty {
+                *display_qty = (*display_qty).min(*qty);
+            }
+        }
+        _ => {}
+    }
+    order
+}
+
+/// Split `qty` among orders of the given displayed quantities, proportionally
+/// to their size. The units left by rounding down are given one each to the
+/// orders with the largest fractional part, the earliest order winning ties.
+fn pro_rata_shares(qty: u64, visible: &[u64]) -> Vec<u64> {
+    let total: u64 = visible.iter().sum();
+    if qty >= total {
+        return visible.to_vec();
+    }
+    let (qty, total) = (u128::from(qty), u128::from(total));
+    let mut shares: Vec<u64> = visible
+        .iter()
+        .map(|v| (qty * u128::from(*v) / total) as u64)
+        .collect();
+    let mut leftover = qty as u64 - shares.iter().sum::<u64>();
+    let mut by_fraction: Vec<usize> = (0..visible.len()).collect();
+    // The sort is stable, so orders with the same fraction keep time priority
+    by_fraction.sort_by_key(|i| {
+        std::cmp::Reverse(qty * u128::from(visible[*i]) % total)
+    });
+    for i in by_fraction {
+        if leftover == 0 {
+            break;
+        }
+        shares[i] += 1;
+        leftover -= 1;
+    }
+    shares
+}
+
+/// Return the share of the bid side in the given quantities, or `None` if
+/// either of them is 0.
+fn imbalance_of(bid_qty: u64, ask_qty: u64) -> Option<f64> {
+    if bid_qty == 0 || ask_qty == 0 {
+        return None;
+    }
+    Some(bid_qty as f64 / (bid_qty + ask_qty) as f64)
+}
+
+/// Store the summary of the fills of an event in its `trade` field, if the
+/// event involves any fill.
+fn attach_trade<Id>(event: &mut OrderEvent<Id>) {
+    if let OrderEvent::Replaced { placement, .. } = event {
+        return attach_trade(placement);
+    }
+    if let OrderEvent::Filled { fills, trade, .. }
+    | OrderEvent::PartiallyFilled { fills, trade, .. } = event
+    {
+        let total_qty: u64 = fills.iter().map(|fm| fm.qty).sum();
+        let notional: u128 = fills
+            .iter()
+            .map(|fm| u128::from(fm.price) * u128::from(fm.qty))
+            .sum();
+        *trade = fills.last().map(|last_fill| Trade {
+            total_qty,
+            avg_price: notional as f64 / total_qty as f64,
+            last_qty: last_fill.qty,
+            last_price: last_fill.price,
+        });
+    }
+}
+
+/// Report a mutation of the order book to the observer, if any.
+fn notify<Id>(observer: &mut Observer<Id>, event: BookEvent<Id>) {
+    if let Some(Callback(observer)) = observer {
+        observer(event);
+    }
+}
+
+/// An order book with `u128` order IDs, see [`GenericOrderBook`].
+pub type OrderBook = GenericOrderBook<u128>;
 
 /// An order book that executes orders serially through the [`execute`] method.
 ///
+/// The type of the order IDs is generic: [`OrderBook`] uses `u128`, but any
+/// [`OrderId`] type such as `u32` or `u64` works, and makes the book smaller.
+///
 /// [`execute`]: #method.execute
 #[derive(Debug)]
-pub struct OrderBook {
+pub struct GenericOrderBook<Id = u128> {
     last_trade: Option<Trade>,
+    tape: VecDeque<Trade>,
+    tape_capacity: usize,
     traded_volume: u64,
+    taker_buy_volume: u64,
+    taker_sell_volume: u64,
     min_ask: Option<u64>,
     max_bid: Option<u64>,
+    min_ask_qty: u64,
+    max_bid_qty: u64,
+    ask_count: usize,
+    bid_count: usize,
     asks: BTreeMap<u64, Vec<usize>>,
     bids: BTreeMap<u64, Vec<usize>>,
-    arena: OrderArena,
+    arena: OrderArena<Id>,
+    arena_capacity: usize,
     default_queue_capacity: usize,
     track_stats: bool,
+    seq: u64,
+    time: u64,
+    /// The pending stop orders, keyed by trigger price and placement
+    /// sequence number.
+    stops: BTreeMap<(u64, u64), OrderType<Id>>,
+    stop_seq: u64,
+    triggered: Vec<OrderEvent<Id>>,
+    tick_size: u64,
+    price_reference: Option<u64>,
+    price_band: Option<u64>,
+    price_offset: u64,
+    on_reject: RejectHandler<Id>,
+    on_top_of_book_change: TopOfBookHandler,
+    observer: Observer<Id>,
+    draining: bool,
+    stp_mode: Option<StpMode>,
+    matching_policy: MatchingPolicy,
+    amend_policy: AmendPolicy,
+    replace_if_missing: bool,
+}
+
+impl<Id: OrderId> Clone for GenericOrderBook<Id> {
+    /// Fork the order book: the clone has the same resting orders, statistics
+    /// and configuration, and evolves independently of the original. The
+    /// registered callbacks (see [`on_reject`], [`on_top_of_book_change`] and
+    /// [`set_observer`]) are not cloned, and have to be registered again on the
+    /// clone if needed.
+    ///
+    /// [`on_reject`]: #method.on_reject
+    /// [`on_top_of_book_change`]: #method.on_top_of_book_change
+    /// [`set_observer`]: #method.set_observer
+    fn clone(&self) -> Self {
+        Self {
+            last_trade: self.last_trade,
+            tape: self.tape.clone(),
+            tape_capacity: self.tape_capacity,
+            traded_volume: self.traded_volume,
+            taker_buy_volume: self.taker_buy_volume,
+            taker_sell_volume: self.taker_sell_volume,
+            min_ask: self.min_ask,
+            max_bid: self.max_bid,
+            min_ask_qty: self.min_ask_qty,
+            max_bid_qty: self.max_bid_qty,
+            ask_count: self.ask_count,
+            bid_count: self.bid_count,
+            asks: self.asks.clone(),
+            bids: self.bids.clone(),
+            arena: self.arena.clone(),
+            arena_capacity: self.arena_capacity,
+            default_queue_capacity: self.default_queue_capacity,
+            track_stats: self.track_stats,
+            seq: self.seq,
+            time: self.time,
+            stops: self.stops.clone(),
+            stop_seq: self.stop_seq,
+            triggered: self.triggered.clone(),
+            tick_size: self.tick_size,
+            price_reference: self.price_reference,
+            price_band: self.price_band,
+            price_offset: self.price_offset,
+            on_reject: None,
+            on_top_of_book_change: None,
+            observer: None,
+            draining: self.draining,
+            stp_mode: self.stp_mode,
+            matching_policy: self.matching_policy,
+            amend_policy: self.amend_policy,
+            replace_if_missing: self.replace_if_missing,
+        }
+    }
 }
 
-impl Default for OrderBook {
+impl<Id: OrderId> Default for GenericOrderBook<Id> {
     /// Create an instance representing a single order book, with stats tracking
     /// disabled, a default arena capacity of 10,000 and a default queue
     /// capacity of 10.
@@ -33,7 +357,15 @@ impl Default for OrderBook {
     }
 }
 
-impl OrderBook {
+impl<Id: OrderId> fmt::Display for GenericOrderBook<Id> {
+    /// Render the whole book as a price ladder (see the `Display`
+    /// implementation of [`BookDepth`]).
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.full_depth().fmt(f)
+    }
+}
+
+impl<Id: OrderId> GenericOrderBook<Id> {
     /// Create an instance representing a single order book.
     ///
     /// The `arena_capacity` parameter represents the number of orders that will
@@ -54,27 +386,70 @@ impl OrderBook {
     ) -> Self {
         Self {
             last_trade: None,
+            tape: VecDeque::new(),
+            tape_capacity: 0,
             traded_volume: 0,
+            taker_buy_volume: 0,
+            taker_sell_volume: 0,
             min_ask: None,
             max_bid: None,
+            min_ask_qty: 0,
+            max_bid_qty: 0,
+            ask_count: 0,
+            bid_count: 0,
             asks: BTreeMap::new(),
             bids: BTreeMap::new(),
             arena: OrderArena::new(arena_capacity),
+            arena_capacity,
             default_queue_capacity: queue_capacity,
             track_stats,
+            seq: 0,
+            time: 0,
+            stops: BTreeMap::new(),
+            stop_seq: 0,
+            triggered: Vec::new(),
+            tick_size: 1,
+            price_reference: None,
+            price_band: None,
+            price_offset: 0,
+            on_reject: None,
+            on_top_of_book_change: None,
+            observer: None,
+            draining: false,
+            stp_mode: None,
+            matching_policy: MatchingPolicy::Fifo,
+            amend_policy: AmendPolicy::AlwaysLosePriorityOnIncrease,
+            replace_if_missing: false,
         }
     }
 
     #[cfg(test)]
     #[doc(hidden)]
     pub fn _asks(&self) -> BTreeMap<u64, Vec<usize>> {
-        self.asks.clone()
+        self.without_tombstones(&self.asks)
     }
 
     #[cfg(test)]
     #[doc(hidden)]
     pub fn _bids(&self) -> BTreeMap<u64, Vec<usize>> {
-        self.bids.clone()
+        self.without_tombstones(&self.bids)
+    }
+
+    #[cfg(test)]
+    fn without_tombstones(
+        &self,
+        book: &BTreeMap<u64, Vec<usize>>,
+    ) -> BTreeMap<u64, Vec<usize>> {
+        book.iter()
+            .map(|(price, queue)| {
+                let queue = queue
+                    .iter()
+                    .copied()
+                    .filter(|idx| self.arena[*idx].qty > 0)
+                    .collect();
+                (*price, queue)
+            })
+            .collect()
     }
 
     /// Return the lowest ask price, if present.
@@ -89,14 +464,319 @@ impl OrderBook {
         self.max_bid
     }
 
-    /// Return the difference of the lowest ask and highest bid, if both are
+    /// Return the average of the lowest ask and highest bid, if both are
     /// present.
+    pub fn mid_price(&self) -> Option<f64> {
+        Some((self.min_ask? as f64 + self.max_bid? as f64) / 2.0)
+    }
+
+    /// Return the micro-price, i.e. the average of the lowest ask and highest
+    /// bid weighted by the quantity resting on the opposite side of the touch,
+    /// if both are present. The micro-price leans towards the ask when the bid
+    /// quantity is larger, and vice versa.
+    pub fn micro_price(&self) -> Option<f64> {
+        let ask = self.best_ask()?;
+        let bid = self.best_bid()?;
+        let (ask_qty, bid_qty) = (ask.qty as f64, bid.qty as f64);
+        Some(
+            (ask.price as f64 * bid_qty + bid.price as f64 * ask_qty)
+                / (ask_qty + bid_qty),
+        )
+    }
+
+    /// Return the mid price (see [`mid_price`]) quantized to the tick size
+    /// with the given rounding mode.
+    ///
+    /// [`mid_price`]: #method.mid_price
+    pub fn mid_price_rounded(&self, mode: RoundingMode) -> Option<u64> {
+        self.mid_price()
+            .map(|price| self.round_to_tick(price, mode))
+    }
+
+    /// Return the micro-price (see [`micro_price`]) quantized to the tick size
+    /// with the given rounding mode.
+    ///
+    /// [`micro_price`]: #method.micro_price
+    pub fn micro_price_rounded(&self, mode: RoundingMode) -> Option<u64> {
+        self.micro_price()
+            .map(|price| self.round_to_tick(price, mode))
+    }
+
+    /// Return the lowest ask price and the total quantity resting at that
+    /// price, if present. The quantity is cached and kept up to date as orders
+    /// are added, matched and canceled, so this is a constant-time lookup.
+    #[inline(always)]
+    pub fn best_ask(&self) -> Option<BookLevel> {
+        self.min_ask.map(|price| BookLevel {
+            price,
+            qty: self.min_ask_qty,
+        })
+    }
+
+    /// Return the highest bid price and the total quantity resting at that
+    /// price, if present. The quantity is cached and kept up to date as orders
+    /// are added, matched and canceled, so this is a constant-time lookup.
+    #[inline(always)]
+    pub fn best_bid(&self) -> Option<BookLevel> {
+        self.max_bid.map(|price| BookLevel {
+            price,
+            qty: self.max_bid_qty,
+        })
+    }
+
+    /// Return the difference of the lowest ask and highest bid, if both are
+    /// present and the book is not crossed.
     #[inline(always)]
     pub fn spread(&self) -> Option<u64> {
+        self.min_ask?.checked_sub(self.max_bid?)
+    }
+
+    /// Return whether the best bid is equal to the best ask, i.e. whether the
+    /// book is locked. Like [`is_crossed`], this only happens as a result of
+    /// matching if all-or-none orders are resting (see
+    /// [`OrderType::AllOrNone`]), so it can be used as a cheap health check.
+    ///
+    /// [`is_crossed`]: #method.is_crossed
+    pub fn is_locked(&self) -> bool {
+        match (self.max_bid, self.min_ask) {
+            (Some(b), Some(a)) => b == a,
+            _ => false,
+        }
+    }
+
+    /// Return whether the best bid is above the best ask, i.e. whether the
+    /// book is crossed. This only happens as a result of matching if
+    /// all-or-none orders are resting (see [`OrderType::AllOrNone`]), since an
+    /// incoming order trades against every other resting order at its limit
+    /// price or better before resting.
+    pub fn is_crossed(&self) -> bool {
         match (self.max_bid, self.min_ask) {
-            (Some(b), Some(a)) => Some(a - b),
-            _ => None,
+            (Some(b), Some(a)) => b > a,
+            _ => false,
+        }
+    }
+
+    /// Return the number of orders resting at the best price on the given side
+    /// of the book, or `None` if that side is empty.
+    pub fn touch_queue_len(&self, side: Side) -> Option<usize> {
+        let (price, queues) = match side {
+            Side::Ask => (self.min_ask?, &self.asks),
+            Side::Bid => (self.max_bid?, &self.bids),
+        };
+        queues.get(&price).map(|queue| {
+            queue.iter().filter(|idx| self.arena[**idx].qty > 0).count()
+        })
+    }
+
+    /// Return the number of orders resting on the book.
+    #[inline(always)]
+    pub fn order_count(&self) -> usize {
+        self.arena.len()
+    }
+
+    /// Return the number of orders resting on the ask side of the book.
+    #[inline(always)]
+    pub fn ask_count(&self) -> usize {
+        self.ask_count
+    }
+
+    /// Return the number of orders resting on the bid side of the book.
+    #[inline(always)]
+    pub fn bid_count(&self) -> usize {
+        self.bid_count
+    }
+
+    /// Return the total quantity resting on the ask side of the book.
+    pub fn ask_volume(&self) -> u64 {
+        self.side_levels(Side::Ask).map(|level| level.qty).sum()
+    }
+
+    /// Return the total quantity resting on the bid side of the book.
+    pub fn bid_volume(&self) -> u64 {
+        self.side_levels(Side::Bid).map(|level| level.qty).sum()
+    }
+
+    /// Return up to `levels` price levels of the given side of the book, from
+    /// the best price outwards, as `(price, cumulative_qty)` pairs, where
+    /// `cumulative_qty` is the total displayed quantity at that price or
+    /// better.
+    pub fn cumulative_depth(
+        &self,
+        side: Side,
+        levels: usize,
+    ) -> Vec<(u64, u64)> {
+        self.side_levels(side)
+            .take(levels)
+            .scan(0, |cumulative_qty, level| {
+                *cumulative_qty += level.qty;
+                Some((level.price, *cumulative_qty))
+            })
+            .collect()
+    }
+
+    /// Return the total displayed quantity resting on the given side of the
+    /// book at `price` or better, i.e. at or below `price` for asks and at or
+    /// above `price` for bids.
+    pub fn qty_up_to_price(&self, side: Side, price: u64) -> u64 {
+        self.side_levels(side)
+            .take_while(|level| match side {
+                Side::Ask => level.price <= price,
+                Side::Bid => level.price >= price,
+            })
+            .map(|level| level.qty)
+            .sum()
+    }
+
+    /// Return the quantity a taker on the given `side` would need to execute
+    /// to completely clear the opposite side of the book, i.e. the total
+    /// remaining quantity of the resting asks for a buyer and of the resting
+    /// bids for a seller, including their hidden reserves.
+    pub fn clearing_qty(&self, side: Side) -> u64 {
+        self.side_orders(!side).map(|order| order.qty).sum()
+    }
+
+    /// Return the quantity-weighted average price of the top `levels` price
+    /// levels on the given side of the book, or `None` if that side has fewer
+    /// than `levels` levels (or `levels` is zero).
+    pub fn weighted_price(&self, side: Side, levels: usize) -> Option<f64> {
+        if levels == 0 {
+            return None;
+        }
+        let mut count = 0;
+        let mut qty = 0;
+        let mut notional = 0;
+        for level in self.side_levels(side).take(levels) {
+            count += 1;
+            qty += level.qty;
+            notional += u128::from(level.qty) * u128::from(level.price);
+        }
+        if count < levels {
+            return None;
+        }
+        Some(notional as f64 / qty as f64)
+    }
+
+    /// Return the difference of the quantity-weighted ask and bid prices over
+    /// the top `levels` price levels (see [`weighted_price`]), or `None` if
+    /// either side has fewer than `levels` levels.
+    ///
+    /// [`weighted_price`]: #method.weighted_price
+    pub fn weighted_spread(&self, levels: usize) -> Option<f64> {
+        Some(
+            self.weighted_price(Side::Ask, levels)?
+                - self.weighted_price(Side::Bid, levels)?,
+        )
+    }
+
+    /// Return the share of the bid side in the total displayed quantity of the
+    /// top `levels` price levels of both sides, i.e. `bid_qty / (bid_qty +
+    /// ask_qty)`, between 0 (ask-heavy) and 1 (bid-heavy). Return `None` if
+    /// `levels` is 0 or if either side is empty.
+    pub fn imbalance(&self, levels: usize) -> Option<f64> {
+        imbalance_of(
+            self.top_qty(Side::Bid, levels),
+            self.top_qty(Side::Ask, levels),
+        )
+    }
+
+    /// Return the best prices, the spread, the mid price and the displayed
+    /// quantity and imbalance of the top `levels` price levels (see
+    /// [`imbalance`]), traversing each side of the book once.
+    ///
+    /// [`imbalance`]: #method.imbalance
+    pub fn summary(&self, levels: usize) -> BookSummary {
+        let bid_qty = self.top_qty(Side::Bid, levels);
+        let ask_qty = self.top_qty(Side::Ask, levels);
+        BookSummary {
+            best_bid: self.max_bid,
+            best_ask: self.min_ask,
+            spread: self.spread(),
+            mid_price: self.mid_price(),
+            bid_qty,
+            ask_qty,
+            imbalance: imbalance_of(bid_qty, ask_qty),
+        }
+    }
+
+    /// Return a fair value estimate that shifts the mid price towards the
+    /// heavier side of the book, in proportion to the [`imbalance`] of the top
+    /// `levels` price levels:
+    ///
+    /// ```text
+    /// fair_value = mid_price + (2 * imbalance - 1) * spread / 2
+    /// ```
+    ///
+    /// The result always lies between the best bid and the best ask. With
+    /// `levels == 1`, it is equal to the [`micro_price`]. Return `None` if
+    /// either side of the book is empty.
+    ///
+    /// [`imbalance`]: #method.imbalance
+    /// [`micro_price`]: #method.micro_price
+    pub fn fair_value(&self, levels: usize) -> Option<f64> {
+        let imbalance = self.imbalance(levels)?;
+        let spread = self.spread()? as f64;
+        Some(self.mid_price()? + (2.0 * imbalance - 1.0) * spread / 2.0)
+    }
+
+    /// Return the best price on the given side of the book ignoring all the
+    /// orders belonging to `owner`, i.e. the best price offered by everyone
+    /// else, or `None` if no such order is resting on that side.
+    pub fn best_excluding_owner(
+        &self,
+        side: Side,
+        owner: OwnerId,
+    ) -> Option<u64> {
+        let queues: Box<dyn Iterator<Item = (&u64, &Vec<usize>)>> = match side {
+            Side::Ask => Box::new(self.asks.iter()),
+            Side::Bid => Box::new(self.bids.iter().rev()),
+        };
+        for (price, queue) in queues {
+            if queue.iter().any(|idx| {
+                let order = &self.arena[*idx];
+                order.qty > 0 && order.owner != Some(owner)
+            }) {
+                return Some(*price);
+            }
+        }
+        None
+    }
+
+    /// Return the `(bid_id, ask_id, qty)` pairs that would be matched if a
+    /// crossed book (e.g. one restored from an external state) was uncrossed,
+    /// without modifying the book. Bids and asks are paired by price-time
+    /// priority for as long as the best remaining bid is priced at or above
+    /// the best remaining ask. The result is empty if the book is not crossed.
+    pub fn crossed_pairs(&self) -> Vec<(Id, Id, u64)> {
+        let orders = |side| {
+            self.side_orders(side)
+                .map(|order| (order.id, order.price, order.qty))
+        };
+        let mut bids = orders(Side::Bid);
+        let mut asks = orders(Side::Ask);
+        let mut pairs = Vec::new();
+        let (mut bid, mut ask) = (bids.next(), asks.next());
+        while let (
+            Some((bid_id, bid_price, bid_qty)),
+            Some((ask_id, ask_price, ask_qty)),
+        ) = (bid, ask)
+        {
+            if bid_price < ask_price {
+                break;
+            }
+            let qty = bid_qty.min(ask_qty);
+            pairs.push((bid_id, ask_id, qty));
+            bid = if bid_qty > qty {
+                Some((bid_id, bid_price, bid_qty - qty))
+            } else {
+                bids.next()
+            };
+            ask = if ask_qty > qty {
+                Some((ask_id, ask_price, ask_qty - qty))
+            } else {
+                asks.next()
+            };
         }
+        pairs
     }
 
     /// Return the last trade recorded while stats tracking was active as a
@@ -108,6 +788,35 @@ impl OrderBook {
         self.last_trade
     }
 
+    /// Return a copy of the trade tape, i.e. the most recent trades recorded
+    /// while stats tracking was active, in chronological order. The tape is
+    /// empty unless a capacity is set with [`set_tape_capacity`].
+    ///
+    /// [`set_tape_capacity`]: #method.set_tape_capacity
+    pub fn export_tape(&self) -> Vec<Trade> {
+        self.trades().collect()
+    }
+
+    /// Iterate over the trade tape (see [`export_tape`]) in chronological
+    /// order, without copying it.
+    ///
+    /// [`export_tape`]: #method.export_tape
+    pub fn trades(&self) -> impl Iterator<Item = Trade> + '_ {
+        self.tape.iter().copied()
+    }
+
+    /// Return the volume-weighted average price of the last `n` trades in the
+    /// trade tape (see [`export_tape`]), or `None` if the tape is empty or `n`
+    /// is zero. Each trade is weighted by its total quantity, so the result is
+    /// the average over all the individual fills of those trades.
+    ///
+    /// [`export_tape`]: #method.export_tape
+    pub fn vwap(&self, n: usize) -> Option<f64> {
+        let skip = self.tape.len().saturating_sub(n);
+        TradeStats::from_trades(self.tape.iter().skip(skip))
+            .map(|stats| stats.vwap)
+    }
+
     /// Return the total traded volume for all the trades that occurred while
     /// the stats tracking was active.
     #[inline(always)]
@@ -115,1510 +824,8347 @@ impl OrderBook {
         self.traded_volume
     }
 
+    /// Return the traded volume initiated by buyers (i.e. with a taker on the
+    /// bid side), for all the trades that occurred while the stats tracking
+    /// was active.
+    #[inline(always)]
+    pub fn taker_buy_volume(&self) -> u64 {
+        self.taker_buy_volume
+    }
+
+    /// Return the traded volume initiated by sellers (i.e. with a taker on the
+    /// ask side), for all the trades that occurred while the stats tracking
+    /// was active.
+    #[inline(always)]
+    pub fn taker_sell_volume(&self) -> u64 {
+        self.taker_sell_volume
+    }
+
     /// Return the order book depth as a [`BookDepth`] struct, up to the
     /// specified level. Bids and offers at the same price level are merged in a
-    /// single [`BookLevel`] struct.
+    /// single [`BookLevel`] struct. Each side lists at most `levels` price
+    /// levels, best price first: the asks are in ascending price order and the
+    /// bids in descending price order.
     ///
     /// [`BookDepth`]: struct.BookDepth.html
     /// [`BookLevel`]: struct.BookLevel.html
     pub fn depth(&self, levels: usize) -> BookDepth {
-        let mut asks: Vec<BookLevel> = Vec::with_capacity(levels);
-        let mut bids: Vec<BookLevel> = Vec::with_capacity(levels);
-
-        for (ask_price, queue) in self.asks.iter() {
-            let mut qty = 0;
-            for idx in queue {
-                qty += self.arena[*idx].qty;
-            }
-            if qty > 0 {
-                asks.push(BookLevel {
-                    price: *ask_price,
-                    qty,
-                });
-            }
-        }
-
-        for (bid_price, queue) in self.bids.iter() {
-            let mut qty = 0;
-            for idx in queue {
-                qty += self.arena[*idx].qty;
-            }
-            if qty > 0 {
-                bids.push(BookLevel {
-                    price: *bid_price,
-                    qty,
-                });
-            }
+        let side_depth = |side, book_levels: usize| {
+            let mut depth = Vec::with_capacity(levels.min(book_levels));
+            depth.extend(self.side_levels(side).take(levels));
+            depth
+        };
+        BookDepth {
+            levels,
+            asks: side_depth(Side::Ask, self.asks.len()),
+            bids: side_depth(Side::Bid, self.bids.len()),
         }
-
-        BookDepth { levels, asks, bids }
     }
 
-    /// Toggle the stats tracking on or off, depending on the `track` parameter.
-    pub fn track_stats(&mut self, track: bool) {
-        self.track_stats = track;
+    /// Return all the price levels of each side of the book, best price first:
+    /// the asks are in ascending price order and the bids in descending price
+    /// order. This is equivalent to `depth(usize::MAX)`, and the `levels`
+    /// field of the result is set accordingly, but the result vectors are
+    /// only allocated for the price levels of the book.
+    pub fn full_depth(&self) -> BookDepth {
+        self.depth(usize::MAX)
     }
 
-    /// Execute an order, returning immediately an event indicating the result.
-    pub fn execute(&mut self, event: OrderType) -> OrderEvent {
-        let event = self._execute(event);
-        if !self.track_stats {
-            return event;
-        }
+    /// Return the top `N` price levels of each side of the book, best price
+    /// first, in fixed-size arrays padded with `None`. Unlike [`depth`], this
+    /// does not allocate. The first array contains the asks and the second
+    /// one the bids.
+    ///
+    /// [`depth`]: #method.depth
+    pub fn depth_array<const N: usize>(
+        &self,
+    ) -> ([Option<BookLevel>; N], [Option<BookLevel>; N]) {
+        let mut asks = self.side_levels(Side::Ask);
+        let mut bids = self.side_levels(Side::Bid);
+        (
+            std::array::from_fn(|_| asks.next()),
+            std::array::from_fn(|_| bids.next()),
+        )
+    }
 
-        match event.clone() {
-            OrderEvent::Filled {
-                id: _,
-                filled_qty,
-                fills,
-            } => {
-                self.traded_volume += filled_qty;
-                // If we are here, fills is not empty, so it's safe to unwrap it
-                let last_fill = fills.last().unwrap();
-                self.last_trade = Some(Trade {
-                    total_qty: filled_qty,
-                    avg_price: fills
-                        .iter()
-                        .map(|fm| fm.price * fm.qty)
-                        .sum::<u64>() as f64
-                        / (filled_qty as f64),
-                    last_qty: last_fill.qty,
-                    last_price: last_fill.price,
-                });
-            }
-            OrderEvent::PartiallyFilled {
-                id: _,
-                filled_qty,
-                fills,
-            } => {
-                self.traded_volume += filled_qty;
-                // If we are here, fills is not empty, so it's safe to unwrap it
-                let last_fill = fills.last().unwrap();
-                self.last_trade = Some(Trade {
-                    total_qty: filled_qty,
-                    avg_price: fills
-                        .iter()
-                        .map(|fm| fm.price * fm.qty)
-                        .sum::<u64>() as f64
-                        / (filled_qty as f64),
-                    last_qty: last_fill.qty,
-                    last_price: last_fill.price,
-                });
-            }
-            _ => {}
-        }
-        event
+    /// Return the price level of the given rank on one side of the book,
+    /// where rank 0 is the best price, or `None` if the side has fewer
+    /// levels. Price levels without any resting order are skipped.
+    pub fn level_at(&self, side: Side, rank: usize) -> Option<BookLevel> {
+        self.side_levels(side).nth(rank)
     }
 
-    fn _execute(&mut self, event: OrderType) -> OrderEvent {
-        match event {
-            OrderType::Market { id, side, qty } => {
-                let (fills, partial, filled_qty) = self.market(id, side, qty);
-                if fills.is_empty() {
-                    OrderEvent::Unfilled { id }
-                } else if partial {
-                    OrderEvent::PartiallyFilled {
-                        id,
-                        filled_qty,
-                        fills,
-                    }
-                } else {
-                    OrderEvent::Filled {
-                        id,
-                        filled_qty,
-                        fills,
-                    }
-                }
-            }
-            OrderType::Limit {
-                id,
-                side,
-                qty,
-                price,
-            } => {
-                let (fills, partial, filled_qty) =
-                    self.limit(id, side, qty, price);
-                if fills.is_empty() {
-                    OrderEvent::Placed { id }
-                } else if partial {
-                    OrderEvent::PartiallyFilled {
-                        id,
-                        filled_qty,
-                        fills,
-                    }
-                } else {
-                    OrderEvent::Filled {
-                        id,
-                        filled_qty,
-                        fills,
+    /// Return the top `levels` price levels of each side of the book, best
+    /// price first, like [`depth`], but listing the individual orders resting
+    /// at each price in time priority order instead of their aggregated
+    /// quantity.
+    ///
+    /// [`depth`]: #method.depth
+    pub fn depth_detailed(&self, levels: usize) -> DetailedBookDepth<Id> {
+        let side_depth = |side| {
+            let queues: Box<dyn Iterator<Item = (&u64, &Vec<usize>)>> =
+                match side {
+                    Side::Ask => Box::new(self.asks.iter()),
+                    Side::Bid => Box::new(self.bids.iter().rev()),
+                };
+            queues
+                .filter_map(|(price, queue)| {
+                    let orders: Vec<OrderInfo<Id>> = queue
+                        .iter()
+                        .map(|idx| &self.arena[*idx])
+                        .filter(|order| order.qty > 0)
+                        .map(OrderInfo::from)
+                        .collect();
+                    if orders.is_empty() {
+                        None
+                    } else {
+                        Some(DetailedBookLevel {
+                            price: *price,
+                            orders,
+                        })
                     }
-                }
-            }
-            OrderType::Cancel { id } => {
-                self.cancel(id);
-                OrderEvent::Canceled { id }
-            }
+                })
+                .take(levels)
+                .collect()
+        };
+        DetailedBookDepth {
+            levels,
+            asks: side_depth(Side::Ask),
+            bids: side_depth(Side::Bid),
         }
     }
 
-    fn cancel(&mut self, id: u128) -> bool {
-        if let Some((price, idx)) = self.arena.get(id) {
-            if let Some(ref mut queue) = self.asks.get_mut(&price) {
-                if let Some(i) = queue.iter().position(|i| *i == idx) {
-                    queue.remove(i);
-                }
-                self.update_min_ask();
-            }
-            if let Some(ref mut queue) = self.bids.get_mut(&price) {
-                if let Some(i) = queue.iter().position(|i| *i == idx) {
-                    queue.remove(i);
-                }
-                self.update_max_bid();
+    /// Return the order book depth like [`depth`], with the aggregated
+    /// quantity of every level rounded down (floored) to a multiple of
+    /// `granularity`. A level of 1234 with a granularity of 100 is reported
+    /// as 1200. The rounding only affects the returned snapshot: resting
+    /// orders keep their exact quantity and are matched as usual. A
+    /// `granularity` of 0 or 1 leaves the quantities untouched.
+    ///
+    /// [`depth`]: #method.depth
+    pub fn depth_rounded(&self, levels: usize, granularity: u64) -> BookDepth {
+        let mut depth = self.depth(levels);
+        if granularity > 1 {
+            for level in depth.asks.iter_mut().chain(depth.bids.iter_mut()) {
+                level.qty -= level.qty % granularity;
             }
         }
-        self.arena.delete(&id)
+        depth
     }
 
-    fn market(
-        &mut self,
-        id: u128,
+    /// Return the depth of the book, up to `levels` price points per side and
+    /// best price first, as it would look after a market order of `qty` on
+    /// the given `side` was matched against the opposite side. Resting orders
+    /// are matched like the matching engine does, so the displayed slices
+    /// consumed from hidden reserves are replenished. The book is not
+    /// modified, and the side of the taker is left unchanged.
+    pub fn depth_after_sweep(
+        &self,
         side: Side,
         qty: u64,
-    ) -> (Vec<FillMetadata>, bool, u64) {
-        let mut fills = Vec::new();
-
-        let remaining_qty = match side {
-            Side::Bid => self.match_with_asks(id, qty, &mut fills, None),
-            Side::Ask => self.match_with_bids(id, qty, &mut fills, None),
+        levels: usize,
+    ) -> BookDepth {
+        let (fills, _, _) = self.sweep(side, None, qty, None);
+        // The displayed quantity of each matched order after its last fill
+        let visible: HashMap<usize, u64> = fills
+            .iter()
+            .map(|fill| (fill.idx, fill.visible_qty))
+            .collect();
+        let queues: Box<dyn Iterator<Item = (&u64, &Vec<usize>)>> = match side {
+            Side::Bid => Box::new(self.asks.iter()),
+            Side::Ask => Box::new(self.bids.iter().rev()),
         };
+        let swept = queues
+            .filter_map(|(price, queue)| {
+                let qty = queue
+                    .iter()
+                    .map(|idx| {
+                        visible
+                            .get(idx)
+                            .copied()
+                            .unwrap_or(self.arena[*idx].visible_qty)
+                    })
+                    .sum();
+                Some(BookLevel { price: *price, qty })
+                    .filter(|level| level.qty > 0)
+            })
+            .take(levels)
+            .collect();
+        let untouched = self.side_levels(side).take(levels).collect();
+        let (asks, bids) = match side {
+            Side::Bid => (swept, untouched),
+            Side::Ask => (untouched, swept),
+        };
+        BookDepth { levels, asks, bids }
+    }
 
-        let partial = remaining_qty > 0;
-
-        (fills, partial, qty - remaining_qty)
+    /// Return the volume-weighted average price a taker on the given `side`
+    /// would obtain by executing `qty` against the opposite side of the book,
+    /// or `None` if there is not enough liquidity to fill `qty` completely.
+    pub fn vwap_for_qty(&self, side: Side, qty: u64) -> Option<f64> {
+        self.notional_for_qty(side, qty)
+            .map(|notional| notional as f64 / qty as f64)
     }
 
-    fn limit(
-        &mut self,
-        id: u128,
+    /// Return whether a taker on the given `side` could be completely filled
+    /// for `qty` right now, only matching at `limit_price` or better if
+    /// specified. Resting orders are walked like the matching engine does, so
+    /// their hidden reserves count and all-or-none orders only count when
+    /// they can be filled completely. Self-trade prevention is not taken into
+    /// account.
+    pub fn can_fill(
+        &self,
         side: Side,
         qty: u64,
+        limit_price: Option<u64>,
+    ) -> bool {
+        let (_, remaining_qty, _) = self.sweep(side, None, qty, limit_price);
+        remaining_qty == 0
+    }
+
+    /// Return the price at which a taker on the given `side` would fill one
+    /// more lot after executing `existing_qty` against the opposite side of
+    /// the book, i.e. the price of the level reached at `existing_qty + 1`.
+    /// Return `None` if there is not enough liquidity for that extra lot.
+    pub fn marginal_impact(
+        &self,
+        side: Side,
+        existing_qty: u64,
+    ) -> Option<u64> {
+        let qty = existing_qty.checked_add(1)?;
+        let (fills, remaining_qty, _) = self.sweep(side, None, qty, None);
+        if remaining_qty > 0 {
+            return None;
+        }
+        fills.last().map(|fill| self.arena[fill.idx].price)
+    }
+
+    /// Return the number of distinct price levels a taker on the given `side`
+    /// would consume to execute `qty` against the opposite side of the book,
+    /// or `None` if there is not enough liquidity to fill `qty` completely.
+    pub fn levels_to_fill(&self, side: Side, qty: u64) -> Option<usize> {
+        let (fills, remaining_qty, _) = self.sweep(side, None, qty, None);
+        if remaining_qty > 0 {
+            return None;
+        }
+        let mut prices: Vec<u64> = fills
+            .iter()
+            .map(|fill| self.arena[fill.idx].price)
+            .collect();
+        prices.dedup();
+        Some(prices.len())
+    }
+
+    /// Return the cost of buying `qty` at the ask VWAP and immediately selling
+    /// it back at the bid VWAP, expressed in price points times quantity (e.g.
+    /// `qty * spread` when both sides can fill `qty` from their top level).
+    /// Return `None` if either side cannot fill `qty` completely, or if the
+    /// cost does not fit in a `u64`.
+    pub fn round_trip_cost(&self, qty: u64) -> Option<u64> {
+        let buy = self.notional_for_qty(Side::Bid, qty)?;
+        let sell = self.notional_for_qty(Side::Ask, qty)?;
+        u64::try_from(buy.saturating_sub(sell)).ok()
+    }
+
+    /// Return the sequence number of a resting order, if present. Sequence
+    /// numbers are assigned in increasing order every time an order is added
+    /// to (or moved within) the order book, and reflect its time priority.
+    pub fn order_seq(&self, id: Id) -> Option<u64> {
+        self.arena.get(id).map(|(_, idx)| self.arena[idx].seq)
+    }
+
+    /// Return information about the resting order with the given ID, including
+    /// the side of the book it rests on and its remaining quantity, or `None`
+    /// if no such order is resting in the book.
+    pub fn order(&self, id: Id) -> Option<OrderInfo<Id>> {
+        let idx = self.resting_index(id)?;
+        Some(OrderInfo::from(&self.arena[idx]))
+    }
+
+    /// Return `true` if the order with the given ID is resting in the book,
+    /// i.e. it was neither filled completely nor canceled.
+    pub fn contains(&self, id: Id) -> bool {
+        self.resting_index(id).is_some()
+    }
+
+    /// Iterate over the orders resting at the given price on one side of the
+    /// book, in time priority (matching) order. The iterator is empty if there
+    /// is no such price level.
+    pub fn level_orders(
+        &self,
+        side: Side,
         price: u64,
-    ) -> (Vec<FillMetadata>, bool, u64) {
-        let mut partial = false;
-        let remaining_qty;
-        let mut fills: Vec<FillMetadata> = Vec::new();
+    ) -> impl Iterator<Item = OrderInfo<Id>> + '_ {
+        let queue = match side {
+            Side::Ask => self.asks.get(&price),
+            Side::Bid => self.bids.get(&price),
+        };
+        queue
+            .into_iter()
+            .flatten()
+            .map(move |idx| &self.arena[*idx])
+            .filter(|order| order.qty > 0)
+            .map(OrderInfo::from)
+    }
 
-        match side {
-            Side::Bid => {
-                remaining_qty =
-                    self.match_with_asks(id, qty, &mut fills, Some(price));
-                if remaining_qty > 0 {
-                    partial = true;
-                    let index = self.arena.insert(id, price, remaining_qty);
-                    let queue_capacity = self.default_queue_capacity;
-                    self.bids
-                        .entry(price)
-                        .or_insert_with(|| Vec::with_capacity(queue_capacity))
-                        .push(index);
-                    match self.max_bid {
-                        None => {
-                            self.max_bid = Some(price);
-                        }
-                        Some(b) if price > b => {
-                            self.max_bid = Some(price);
-                        }
-                        _ => {}
-                    };
-                }
+    /// Return the displayed quantity resting ahead of the order with the given
+    /// ID in its price level queue, i.e. the quantity that has to trade before
+    /// the order gets a fill, or `None` if no such order is resting in the
+    /// book. Hidden reserves are not counted, since they are replenished at
+    /// the back of the queue.
+    pub fn queue_ahead(&self, id: Id) -> Option<u64> {
+        let idx = self.resting_index(id)?;
+        let order = &self.arena[idx];
+        let queue = match order.side {
+            Side::Ask => self.asks.get(&order.price)?,
+            Side::Bid => self.bids.get(&order.price)?,
+        };
+        let position = queue.iter().position(|i| *i == idx)?;
+        Some(self.queue_qty(&queue[..position]))
+    }
+
+    /// Move a resting order to the back of its price level queue and assign it
+    /// a new sequence number, leaving its price and quantity unchanged. This
+    /// resets the age of the order, at the cost of losing its time priority.
+    ///
+    /// Return [`OrderEvent::Placed`] if the order was refreshed, or
+    /// [`OrderEvent::Rejected`] if no resting order has the given ID.
+    pub fn refresh(&mut self, id: Id) -> OrderEvent<Id> {
+        if let Some((price, idx)) = self.arena.get(id) {
+            let queue = match self.order_side(price, idx) {
+                Some(Side::Ask) => self.asks.get_mut(&price),
+                Some(Side::Bid) => self.bids.get_mut(&price),
+                None => None,
+            };
+            if let Some(queue) = queue {
+                queue.retain(|i| *i != idx);
+                queue.push(idx);
+                self.arena[idx].seq = self.next_seq();
+                let LimitOrder { side, qty, .. } = self.arena[idx];
+                notify(
+                    &mut self.observer,
+                    BookEvent::OrderUpdated {
+                        id,
+                        side,
+                        price,
+                        qty,
+                    },
+                );
+                return OrderEvent::Placed { id };
             }
-            Side::Ask => {
-                remaining_qty =
-                    self.match_with_bids(id, qty, &mut fills, Some(price));
-                if remaining_qty > 0 {
-                    partial = true;
-                    let index = self.arena.insert(id, price, remaining_qty);
-                    if let Some(a) = self.min_ask {
-                        if price < a {
-                            self.min_ask = Some(price);
-                        }
-                    }
-                    let queue_capacity = self.default_queue_capacity;
-                    self.asks
-                        .entry(price)
-                        .or_insert_with(|| Vec::with_capacity(queue_capacity))
-                        .push(index);
-                    match self.min_ask {
-                        None => {
-                            self.min_ask = Some(price);
-                        }
-                        Some(a) if price < a => {
-                            self.min_ask = Some(price);
-                        }
-                        _ => {}
-                    };
-                }
+        }
+        OrderEvent::Rejected {
+            id,
+            reason: RejectReason::OrderNotFound,
+        }
+    }
+
+    /// Cancel a batch of resting orders, returning one event per ID in the
+    /// same order: [`OrderEvent::Canceled`] if the order was canceled, or
+    /// [`OrderEvent::Rejected`] if no resting order has the given ID. The best
+    /// bid and ask are only recomputed once, after all the cancels.
+    pub fn cancel_many(&mut self, ids: &[Id]) -> Vec<OrderEvent<Id>> {
+        let mut events = Vec::with_capacity(ids.len());
+        for &id in ids {
+            if let Some(idx) = self.resting_index(id) {
+                let LimitOrder { side, price, .. } = self.arena[idx];
+                self.arena.remove(&id);
+                *self.side_count_mut(side) -= 1;
+                notify(
+                    &mut self.observer,
+                    BookEvent::OrderRemoved { id, side, price },
+                );
+                events.push(OrderEvent::Canceled { id });
+            } else {
+                events.push(OrderEvent::Rejected {
+                    id,
+                    reason: RejectReason::OrderNotFound,
+                });
             }
         }
+        self.update_min_ask();
+        self.update_max_bid();
+        self.compact_tombstones();
+        events
+    }
 
-        (fills, partial, qty - remaining_qty)
+    /// Cancel every resting order whose ID is not contained in `keep`,
+    /// returning an [`OrderEvent::Canceled`] event for each canceled order.
+    /// Orders are canceled from the lowest to the highest price, asks first.
+    pub fn cancel_all_except(&mut self, keep: &[Id]) -> Vec<OrderEvent<Id>> {
+        let keep: HashSet<Id> = keep.iter().copied().collect();
+        self.cancel_where(|order| !keep.contains(&order.id))
+            .into_iter()
+            .map(|id| OrderEvent::Canceled { id })
+            .collect()
     }
 
-    fn match_with_asks(
-        &mut self,
-        id: u128,
-        qty: u64,
-        fills: &mut Vec<FillMetadata>,
-        limit_price: Option<u64>,
-    ) -> u64 {
-        let mut remaining_qty = qty;
-        let mut update_bid_ask = false;
-        for (ask_price, queue) in self.asks.iter_mut() {
-            if queue.is_empty() {
-                continue;
-            }
-            if (update_bid_ask || self.min_ask.is_none()) && !queue.is_empty() {
-                self.min_ask = Some(*ask_price);
-                update_bid_ask = false;
-            }
-            if let Some(lp) = limit_price {
-                if lp < *ask_price {
-                    break;
+    /// Cancel every resting order, returning their IDs. Orders are canceled
+    /// from the lowest to the highest price, asks first. Unlike [`clear`],
+    /// the trading statistics and the pending stop orders are kept.
+    ///
+    /// [`clear`]: #method.clear
+    pub fn cancel_all(&mut self) -> Vec<Id> {
+        self.cancel_where(|_| true)
+    }
+
+    /// Cancel every resting order of the given owner, returning their IDs.
+    /// Orders are canceled from the lowest to the highest price, asks first.
+    pub fn cancel_all_for(&mut self, owner: OwnerId) -> Vec<Id> {
+        self.cancel_where(|order| order.owner == Some(owner))
+    }
+
+    /// Cancel every resting order matching `predicate`, returning their IDs
+    /// in cancellation order.
+    fn cancel_where<P>(&mut self, predicate: P) -> Vec<Id>
+    where
+        P: Fn(&LimitOrder<Id>) -> bool,
+    {
+        let mut ids = Vec::new();
+        let arena = &mut self.arena;
+        let observer = &mut self.observer;
+        let (ask_count, bid_count) = (&mut self.ask_count, &mut self.bid_count);
+        for queue in self.asks.values_mut().chain(self.bids.values_mut()) {
+            queue.retain(|idx| {
+                let order = &arena[*idx];
+                if order.qty == 0 {
+                    arena.release(*idx);
+                    return false;
                 }
+                if !predicate(order) {
+                    return true;
+                }
+                let LimitOrder {
+                    id, side, price, ..
+                } = *order;
+                arena.delete(&id);
+                match side {
+                    Side::Ask => *ask_count -= 1,
+                    Side::Bid => *bid_count -= 1,
+                }
+                notify(observer, BookEvent::OrderRemoved { id, side, price });
+                ids.push(id);
+                false
+            });
+        }
+        self.asks.retain(|_, queue| !queue.is_empty());
+        self.bids.retain(|_, queue| !queue.is_empty());
+        self.update_min_ask();
+        self.update_max_bid();
+        ids
+    }
+
+    /// Cancel every order resting at the given price on one side of the book,
+    /// returning their IDs in time priority order. The result is empty if
+    /// there is no such price level.
+    pub fn cancel_level(&mut self, side: Side, price: u64) -> Vec<Id> {
+        let queue = match side {
+            Side::Ask => self.asks.remove(&price),
+            Side::Bid => self.bids.remove(&price),
+        };
+        let mut ids = Vec::new();
+        for idx in queue.into_iter().flatten() {
+            let LimitOrder { id, qty, .. } = self.arena[idx];
+            self.arena.release(idx);
+            if qty == 0 {
+                continue;
             }
-            if remaining_qty == 0 {
-                break;
-            }
-            let filled_qty = Self::process_queue(
-                &mut self.arena,
-                queue,
-                remaining_qty,
-                id,
-                Side::Bid,
-                fills,
+            *self.side_count_mut(side) -= 1;
+            notify(
+                &mut self.observer,
+                BookEvent::OrderRemoved { id, side, price },
             );
-            if queue.is_empty() {
-                update_bid_ask = true;
+            ids.push(id);
+        }
+        match side {
+            Side::Ask => self.update_min_ask(),
+            Side::Bid => self.update_max_bid(),
+        }
+        ids
+    }
+
+    /// Remove all the orders and reset the trading statistics, the trade tape,
+    /// the logical time and drain mode, so that the order book behaves like a
+    /// newly created one. The configuration (see [`config`]) and the
+    /// registered callbacks are kept, and the memory allocated for the arena
+    /// is reused rather than allocated again. The observer is notified of the
+    /// removal of every resting order.
+    ///
+    /// [`config`]: #method.config
+    pub fn clear(&mut self) {
+        let removed: Vec<BookEvent<Id>> = self
+            .side_orders(Side::Ask)
+            .chain(self.side_orders(Side::Bid))
+            .map(|order| BookEvent::OrderRemoved {
+                id: order.id,
+                side: order.side,
+                price: order.price,
+            })
+            .collect();
+        for event in removed {
+            notify(&mut self.observer, event);
+        }
+        for (side, best) in
+            [(Side::Ask, self.min_ask), (Side::Bid, self.max_bid)]
+        {
+            if best.is_some() {
+                notify(
+                    &mut self.observer,
+                    BookEvent::BestPriceChanged { side, price: None },
+                );
             }
-            remaining_qty -= filled_qty;
         }
+        self.last_trade = None;
+        self.tape.clear();
+        self.traded_volume = 0;
+        self.taker_buy_volume = 0;
+        self.taker_sell_volume = 0;
+        self.min_ask = None;
+        self.max_bid = None;
+        self.min_ask_qty = 0;
+        self.max_bid_qty = 0;
+        self.ask_count = 0;
+        self.bid_count = 0;
+        self.asks.clear();
+        self.bids.clear();
+        self.arena.clear();
+        self.seq = 0;
+        self.time = 0;
+        self.stops.clear();
+        self.stop_seq = 0;
+        self.triggered.clear();
+        self.draining = false;
+    }
 
-        self.update_min_ask();
-        remaining_qty
+    /// Return statistics on the usage of the arena storing the resting
+    /// orders, including the peak number of resting orders, to help choosing
+    /// the `arena_capacity` passed to [`new`].
+    ///
+    /// [`new`]: #method.new
+    pub fn arena_stats(&self) -> ArenaStats {
+        self.arena.stats()
     }
 
-    fn match_with_bids(
-        &mut self,
-        id: u128,
-        qty: u64,
-        fills: &mut Vec<FillMetadata>,
-        limit_price: Option<u64>,
-    ) -> u64 {
-        let mut remaining_qty = qty;
-        let mut update_bid_ask = false;
-        for (bid_price, queue) in self.bids.iter_mut().rev() {
-            if queue.is_empty() {
-                continue;
+    /// Reclaim the memory used by the order book after a spike in the number
+    /// of resting orders: canceled orders and empty price levels are dropped,
+    /// the resting orders are moved to the lowest arena slots, and the slots
+    /// allocated past the initial arena capacity are freed. Matching priority
+    /// is unaffected.
+    pub fn compact(&mut self) {
+        self.drop_tombstones();
+        let remap = self.arena.shrink_to_fit();
+        for queue in self.asks.values_mut().chain(self.bids.values_mut()) {
+            for idx in queue.iter_mut() {
+                *idx = remap[*idx];
             }
-            if (update_bid_ask || self.max_bid.is_none()) && !queue.is_empty() {
-                self.max_bid = Some(*bid_price);
-                update_bid_ask = false;
-            }
-            if let Some(lp) = limit_price {
-                if lp > *bid_price {
-                    break;
+        }
+    }
+
+    /// Check the internal consistency of the order book, returning a
+    /// description of the first violated invariant, if any. Every order in the
+    /// price level queues must be stored in the arena on the right side and at
+    /// the right price, the book must not be crossed, and the cached best
+    /// prices and order counts must match the resting orders.
+    ///
+    /// This is meant as an oracle for tests and fuzzing, and is only available
+    /// with the `invariants` feature.
+    #[cfg(any(test, feature = "invariants"))]
+    pub fn validate(&self) -> Result<(), String>
+    where
+        Id: fmt::Debug,
+    {
+        let mut seen = HashSet::new();
+        for (side, book) in [(Side::Ask, &self.asks), (Side::Bid, &self.bids)] {
+            let mut count = 0;
+            for (price, queue) in book {
+                for idx in queue {
+                    let order = &self.arena[*idx];
+                    if !seen.insert(*idx) {
+                        return Err(format!("slot {} queued twice", idx));
+                    }
+                    if order.qty == 0 {
+                        if self.arena.get(order.id).map(|(_, i)| i)
+                            == Some(*idx)
+                        {
+                            return Err(format!(
+                                "order {:?} is empty",
+                                order.id
+                            ));
+                        }
+                        continue;
+                    }
+                    if self.arena.get(order.id) != Some((*price, *idx)) {
+                        return Err(format!(
+                            "order {:?} is not stored at slot {} with price {}",
+                            order.id, idx, price
+                        ));
+                    }
+                    if order.side != side {
+                        return Err(format!(
+                            "order {:?} is queued on the wrong side",
+                            order.id
+                        ));
+                    }
+                    if order.visible_qty == 0 || order.visible_qty > order.qty {
+                        return Err(format!(
+                            "order {:?} has visible quantity {} out of {}",
+                            order.id, order.visible_qty, order.qty
+                        ));
+                    }
+                    count += 1;
                 }
             }
-            if remaining_qty == 0 {
-                break;
-            }
-            let filled_qty = Self::process_queue(
-                &mut self.arena,
-                queue,
-                remaining_qty,
-                id,
-                Side::Ask,
-                fills,
-            );
-            if queue.is_empty() {
-                update_bid_ask = true;
+            let cached = match side {
+                Side::Ask => self.ask_count,
+                Side::Bid => self.bid_count,
+            };
+            if count != cached {
+                return Err(format!(
+                    "{:?} count is {} but {} orders are resting",
+                    side, cached, count
+                ));
             }
-            remaining_qty -= filled_qty;
+        }
+        if self.ask_count + self.bid_count != self.arena.len() {
+            return Err(format!(
+                "the arena stores {} orders but {} are queued",
+                self.arena.len(),
+                self.ask_count + self.bid_count
+            ));
         }
 
-        self.update_max_bid();
-        remaining_qty
+        let best = |side| {
+            self.side_levels(side)
+                .next()
+                .map(|level| (level.price, level.qty))
+        };
+        let (best_ask, best_bid) = (best(Side::Ask), best(Side::Bid));
+        // Only all-or-none orders can be left crossing the opposite side
+        let best_matchable = |side| {
+            self.side_orders(side)
+                .find(|order| !order.all_or_none)
+                .map(|order| order.price)
+        };
+        let matchable = (best_matchable(Side::Ask), best_matchable(Side::Bid));
+        if let (Some(ask), Some(bid)) = matchable {
+            if bid >= ask {
+                return Err(format!(
+                    "the book is crossed: bid {} >= ask {}",
+                    bid, ask
+                ));
+            }
+        }
+        let cached_ask = self.min_ask.map(|price| (price, self.min_ask_qty));
+        if best_ask != cached_ask {
+            return Err(format!(
+                "best ask is {:?} but {:?} is cached",
+                best_ask, cached_ask
+            ));
+        }
+        let cached_bid = self.max_bid.map(|price| (price, self.max_bid_qty));
+        if best_bid != cached_bid {
+            return Err(format!(
+                "best bid is {:?} but {:?} is cached",
+                best_bid, cached_bid
+            ));
+        }
+        Ok(())
     }
 
-    fn update_min_ask(&mut self) {
-        let mut cur_asks = self.asks.iter().filter(|(_, q)| !q.is_empty());
-        self.min_ask = cur_asks.next().map(|(p, _)| *p);
+    /// Return the current logical time of the order book.
+    #[inline(always)]
+    pub fn time(&self) -> u64 {
+        self.time
     }
 
-    fn update_max_bid(&mut self) {
-        let mut cur_bids =
-            self.bids.iter().rev().filter(|(_, q)| !q.is_empty());
-        self.max_bid = cur_bids.next().map(|(p, _)| *p);
+    /// Advance the logical time of the order book to `now`, canceling all the
+    /// [`OrderType::GoodTillTime`] orders that expire at or before `now`. An
+    /// [`OrderEvent::SystemCanceled`] event is returned for each of them, in
+    /// order of expiry.
+    pub fn set_time(&mut self, now: u64) -> Vec<OrderEvent<Id>> {
+        self.time = now;
+        let mut events = Vec::new();
+        for id in self.arena.take_expired(now) {
+            if self.cancel(id) {
+                events.push(OrderEvent::SystemCanceled {
+                    id,
+                    reason: CancelReason::Expired,
+                });
+            }
+        }
+        events
     }
 
-    fn process_queue(
-        arena: &mut OrderArena,
-        opposite_orders: &mut Vec<usize>,
-        remaining_qty: u64,
-        id: u128,
-        side: Side,
-        fills: &mut Vec<FillMetadata>,
-    ) -> u64 {
-        let mut qty_to_fill = remaining_qty;
-        let mut filled_qty = 0;
-        let mut filled_index = None;
+    /// Return a snapshot of the state of the order book, including all the
+    /// resting orders and the trading statistics, from which an identical
+    /// order book can be rebuilt with [`restore`].
+    ///
+    /// [`restore`]: #method.restore
+    pub fn snapshot(&self) -> BookSnapshot<Id> {
+        let orders = [Side::Ask, Side::Bid]
+            .iter()
+            .flat_map(|side| self.side_orders(*side).map(OrderInfo::from))
+            .collect();
+        BookSnapshot {
+            orders,
+            min_ask: self.min_ask,
+            max_bid: self.max_bid,
+            track_stats: self.track_stats,
+            traded_volume: self.traded_volume,
+            taker_buy_volume: self.taker_buy_volume,
+            taker_sell_volume: self.taker_sell_volume,
+            last_trade: self.last_trade,
+            seq: self.seq,
+            time: self.time,
+            expiries: self.arena.expiries().collect(),
+            stops: self.stops.values().copied().collect(),
+        }
+    }
 
-        for (index, head_order_idx) in opposite_orders.iter_mut().enumerate() {
-            if qty_to_fill == 0 {
-                break;
+    /// Return the changes of the order book since a snapshot returned by
+    /// [`snapshot`]: the orders added and removed since, and the minimal set
+    /// of price levels whose displayed quantity changed. Applying the level
+    /// changes to the full depth of the book at the time of the snapshot
+    /// (see [`BookDelta::apply`]) yields the current depth.
+    ///
+    /// [`snapshot`]: #method.snapshot
+    pub fn delta_since(&self, prev: &BookSnapshot<Id>) -> BookDelta<Id> {
+        let prev_ids: HashSet<Id> = prev.orders.iter().map(|o| o.id).collect();
+        let added = [Side::Ask, Side::Bid]
+            .iter()
+            .flat_map(|side| self.side_orders(*side))
+            .map(|order| order.id)
+            .filter(|id| !prev_ids.contains(id))
+            .collect();
+        let removed = prev
+            .orders
+            .iter()
+            .map(|order| order.id)
+            .filter(|id| self.resting_index(*id).is_none())
+            .collect();
+        let level_changes = |side| {
+            let mut prev_levels: BTreeMap<u64, u64> = BTreeMap::new();
+            for order in prev.orders.iter().filter(|o| o.side == side) {
+                *prev_levels.entry(order.price).or_default() +=
+                    order.visible_qty;
             }
-            let head_order = &mut arena[*head_order_idx];
-            let traded_price = head_order.price;
-            let available_qty = head_order.qty;
-            if available_qty == 0 {
-                filled_index = Some(index);
-                continue;
+            let mut changes: Vec<BookLevel> = self
+                .side_levels(side)
+                .filter(|level| {
+                    prev_levels.remove(&level.price) != Some(level.qty)
+                })
+                .collect();
+            changes.extend(
+                prev_levels
+                    .into_keys()
+                    .map(|price| BookLevel { price, qty: 0 }),
+            );
+            match side {
+                Side::Ask => changes.sort_by_key(|level| level.price),
+                Side::Bid => changes.sort_by_key(|level| Reverse(level.price)),
             }
-            let traded_quantity: u64;
-            let filled;
+            changes
+        };
+        BookDelta {
+            added,
+            removed,
+            asks: level_changes(Side::Ask),
+            bids: level_changes(Side::Bid),
+        }
+    }
 
-            if qty_to_fill >= available_qty {
-                traded_quantity = available_qty;
-                qty_to_fill -= available_qty;
-                filled_index = Some(index);
-                filled = true;
-            } else {
-                traded_quantity = qty_to_fill;
-                qty_to_fill = 0;
-                filled = false;
-            }
-            head_order.qty -= traded_quantity;
-            let fill = FillMetadata {
-                order_1: id,
-                order_2: head_order.id,
-                qty: traded_quantity,
-                price: traded_price,
-                taker_side: side,
-                total_fill: filled,
+    /// Rebuild an order book from a snapshot returned by [`snapshot`]. The
+    /// `arena_capacity` and `queue_capacity` parameters have the same meaning
+    /// as in [`new`]; the rest of the configuration has default values.
+    ///
+    /// [`snapshot`]: #method.snapshot
+    /// [`new`]: #method.new
+    pub fn restore(
+        snapshot: BookSnapshot<Id>,
+        arena_capacity: usize,
+        queue_capacity: usize,
+    ) -> Self {
+        let mut ob = Self::new(arena_capacity, queue_capacity, false);
+        for info in snapshot.orders {
+            let index = ob.arena.insert(info.id, info.price, info.qty);
+            *ob.side_count_mut(info.side) += 1;
+            let order = &mut ob.arena[index];
+            order.side = info.side;
+            order.seq = info.seq;
+            order.display_qty = info.display_qty;
+            order.visible_qty = info.visible_qty;
+            order.owner = info.owner;
+            order.tag = info.tag;
+            order.all_or_none = info.all_or_none;
+            let book = match info.side {
+                Side::Ask => &mut ob.asks,
+                Side::Bid => &mut ob.bids,
             };
-            fills.push(fill);
-            filled_qty += traded_quantity;
-        }
-        if let Some(index) = filled_index {
-            opposite_orders.drain(0..index + 1);
+            book.entry(info.price)
+                .or_insert_with(|| Vec::with_capacity(queue_capacity))
+                .push(index);
         }
+        ob.update_min_ask();
+        ob.update_max_bid();
+        debug_assert_eq!(ob.min_ask, snapshot.min_ask);
+        debug_assert_eq!(ob.max_bid, snapshot.max_bid);
 
-        filled_qty
+        ob.track_stats = snapshot.track_stats;
+        ob.traded_volume = snapshot.traded_volume;
+        ob.taker_buy_volume = snapshot.taker_buy_volume;
+        ob.taker_sell_volume = snapshot.taker_sell_volume;
+        ob.last_trade = snapshot.last_trade;
+        ob.seq = snapshot.seq;
+        ob.time = snapshot.time;
+        for (at, id) in snapshot.expiries {
+            if let Some(idx) = ob.resting_index(id) {
+                ob.arena.set_expiry(idx, at);
+            }
+        }
+        for stop in snapshot.stops {
+            if let Some((_, trigger)) = stop_trigger(&stop) {
+                ob.insert_stop(trigger, stop);
+            }
+        }
+        ob
     }
-}
 
-#[cfg(test)]
-mod test {
-    use crate::{
-        BookDepth, BookLevel, FillMetadata, OrderBook, OrderEvent, OrderType,
-        Side, Trade,
-    };
-    use std::collections::BTreeMap;
-
-    const DEFAULT_QUEUE_SIZE: usize = 10;
-    const BID_ASK_COMBINATIONS: [(Side, Side); 2] =
-        [(Side::Bid, Side::Ask), (Side::Ask, Side::Bid)];
-
-    // In general, floating point values cannot be compared for equality. That's
-    // why we don't derive PartialEq in lobster::models, but we do it here for
-    // our tests in some very specific cases.
-    impl PartialEq for Trade {
-        fn eq(&self, other: &Self) -> bool {
-            self.total_qty == other.total_qty
-                && (self.avg_price - other.avg_price).abs() < 1.0e-6
-                && self.last_qty == other.last_qty
-                && self.last_price == other.last_price
+    /// Create an order book already populated with the given resting orders,
+    /// without going through the matching engine. Each order is given as a
+    /// tuple of ID, side, price and quantity, and is queued in the given
+    /// order. The other parameters have the same meaning as in [`new`].
+    ///
+    /// The orders are not validated: the caller must guarantee that their IDs
+    /// are unique, their quantities are positive, and the bids are all lower
+    /// than the asks.
+    ///
+    /// [`new`]: #method.new
+    pub fn from_orders(
+        orders: Vec<(Id, Side, u64, u64)>,
+        arena_capacity: usize,
+        queue_capacity: usize,
+        track_stats: bool,
+    ) -> Self {
+        let mut ob = Self::new(arena_capacity, queue_capacity, track_stats);
+        for (id, side, price, qty) in orders {
+            let index =
+                ob.insert_order(id, side, price, qty, RestingAttrs::default());
+            let book = match side {
+                Side::Ask => &mut ob.asks,
+                Side::Bid => &mut ob.bids,
+            };
+            book.entry(price)
+                .or_insert_with(|| Vec::with_capacity(queue_capacity))
+                .push(index);
         }
+        ob.update_min_ask();
+        ob.update_max_bid();
+        debug_assert!(!ob.is_crossed() && !ob.is_locked());
+        ob
     }
 
-    fn init_ob(events: Vec<OrderType>) -> (OrderBook, Vec<OrderEvent>) {
-        let mut ob = OrderBook::default();
-        ob.track_stats(true);
-        let mut results = Vec::new();
-        for e in events {
-            results.push(ob.execute(e));
+    /// Return a snapshot of the current configuration of the order book.
+    pub fn config(&self) -> BookConfig {
+        BookConfig {
+            arena_capacity: self.arena_capacity,
+            queue_capacity: self.default_queue_capacity,
+            track_stats: self.track_stats,
+            tape_capacity: self.tape_capacity,
+            deterministic_arena: self.arena.sorted_free(),
+            stp_mode: self.stp_mode,
+            tick_size: self.tick_size,
+            price_reference: self.price_reference,
+            price_band: self.price_band,
+            price_offset: self.price_offset,
+            matching_policy: self.matching_policy,
+            amend_policy: self.amend_policy,
+            replace_if_missing: self.replace_if_missing,
         }
-        (ob, results)
     }
 
-    fn init_book(orders: Vec<(u64, usize)>) -> BTreeMap<u64, Vec<usize>> {
-        let mut bk = BTreeMap::new();
-        for (p, i) in orders {
-            bk.entry(p)
-                .or_insert_with(|| Vec::with_capacity(DEFAULT_QUEUE_SIZE))
-                .push(i);
-        }
-        bk
+    /// Toggle the stats tracking on or off, depending on the `track` parameter.
+    pub fn track_stats(&mut self, track: bool) {
+        self.track_stats = track;
     }
 
-    fn init_book_holes(
-        orders: Vec<(u64, usize)>,
-        holes: Vec<u64>,
-    ) -> BTreeMap<u64, Vec<usize>> {
-        let mut bk = init_book(orders);
-        for h in holes {
-            bk.insert(h, Vec::new());
+    /// Set the maximum number of trades kept in the trade tape (see
+    /// [`export_tape`]). When the tape is full, the oldest trade is evicted.
+    /// A capacity of 0 disables the tape.
+    ///
+    /// [`export_tape`]: #method.export_tape
+    pub fn set_tape_capacity(&mut self, capacity: usize) {
+        self.tape_capacity = capacity;
+        while self.tape.len() > capacity {
+            self.tape.pop_front();
         }
-        bk
     }
 
-    #[test]
-    fn empty_book() {
-        let (ob, results) = init_ob(Vec::new());
-        assert_eq!(results, Vec::new());
+    /// Toggle deterministic arena allocation on or off. When enabled, each new
+    /// resting order is stored in the lowest free arena slot, so the arena
+    /// layout only depends on which orders are resting and not on the order in
+    /// which previous orders were canceled. This is useful to get reproducible
+    /// debugging dumps, and does not affect matching.
+    pub fn deterministic_arena(&mut self, deterministic: bool) {
+        self.arena.set_sorted_free(deterministic);
+    }
+
+    /// Set the tick size of the order book. Limit orders whose price is not a
+    /// multiple of the tick size are rejected with
+    /// [`RejectReason::InvalidTick`]. A tick size of 0 or 1 accepts any price.
+    pub fn set_tick_size(&mut self, tick_size: u64) {
+        self.tick_size = tick_size;
+    }
+
+    /// Set the price offset of the order book, used by [`to_internal`] and
+    /// [`to_external`] to map signed external prices (e.g. of spreads that can
+    /// go negative) to the unsigned prices used by the order book:
+    /// `internal = external + offset`. External prices from `-offset` up to
+    /// `u64::MAX - offset` (within the range of `i64`) can be represented.
+    /// Matching is unaffected, as it only deals with internal prices.
+    ///
+    /// [`to_internal`]: #method.to_internal
+    /// [`to_external`]: #method.to_external
+    pub fn set_price_offset(&mut self, offset: u64) {
+        self.price_offset = offset;
+    }
+
+    /// Convert a signed external price to an internal price by adding the
+    /// price offset (see [`set_price_offset`]), or return `None` if the price
+    /// cannot be represented.
+    ///
+    /// [`set_price_offset`]: #method.set_price_offset
+    pub fn to_internal(&self, price: i64) -> Option<u64> {
+        u64::try_from(i128::from(price) + i128::from(self.price_offset)).ok()
+    }
+
+    /// Convert an internal price to a signed external price by subtracting
+    /// the price offset (see [`set_price_offset`]), or return `None` if the
+    /// result does not fit in an `i64`.
+    ///
+    /// [`set_price_offset`]: #method.set_price_offset
+    pub fn to_external(&self, price: u64) -> Option<i64> {
+        i64::try_from(i128::from(price) - i128::from(self.price_offset)).ok()
+    }
+
+    /// Set the reference price (e.g. the last trade price or an external
+    /// index) used by the price band check. See [`set_price_band`].
+    ///
+    /// [`set_price_band`]: #method.set_price_band
+    pub fn set_price_reference(&mut self, price: Option<u64>) {
+        self.price_reference = price;
+    }
+
+    /// Set the maximum distance, in price points, between the limit price of
+    /// an order and the reference price. Limit orders priced further away are
+    /// rejected with [`RejectReason::PriceOutsideBand`]. The check is disabled
+    /// if either the band or the reference price is `None`.
+    pub fn set_price_band(&mut self, band: Option<u64>) {
+        self.price_band = band;
+    }
+
+    /// Set the self-trade prevention policy. When enabled, an incoming order
+    /// never trades against a resting order with the same owner: depending on
+    /// `mode`, either the remaining quantity of the incoming order or the
+    /// resting order is canceled. An incoming order canceled before any fill
+    /// results in an [`OrderEvent::SystemCanceled`] event with
+    /// [`CancelReason::SelfTrade`]. Orders without an owner are never
+    /// affected. `None` disables self-trade prevention.
+    pub fn set_stp_mode(&mut self, mode: Option<StpMode>) {
+        self.stp_mode = mode;
+    }
+
+    /// Set the rule used to allocate incoming orders among the resting orders
+    /// of each price level. The default is [`MatchingPolicy::Fifo`].
+    ///
+    /// With [`MatchingPolicy::ProRata`], self-trade prevention applies to the
+    /// whole level: if the level contains a resting order of the same owner,
+    /// either the incoming order stops matching before the level or all the
+    /// resting orders of the owner at that level are canceled, depending on
+    /// the [`StpMode`].
+    pub fn set_matching_policy(&mut self, policy: MatchingPolicy) {
+        self.matching_policy = policy;
+    }
+
+    /// Set the rule deciding whether increasing the quantity of a resting
+    /// order with [`OrderType::Amend`] keeps its time priority. The default is
+    /// [`AmendPolicy::AlwaysLosePriorityOnIncrease`].
+    pub fn set_amend_policy(&mut self, policy: AmendPolicy) {
+        self.amend_policy = policy;
+    }
+
+    /// Set whether an [`OrderType::CancelReplace`] whose order to be canceled
+    /// is not resting (e.g. because it was already filled) still places the
+    /// new order, returning the event of the placement alone. By default, the
+    /// cancel-replace is rejected with [`RejectReason::OrderNotFound`].
+    pub fn set_replace_if_missing(&mut self, place: bool) {
+        self.replace_if_missing = place;
+    }
+
+    /// Register a callback invoked with the order and the rejection reason
+    /// every time an order is rejected by [`execute`], replacing any
+    /// previously registered callback.
+    ///
+    /// [`execute`]: #method.execute
+    pub fn on_reject<F>(&mut self, callback: F)
+    where
+        F: FnMut(OrderType<Id>, RejectReason) + 'static,
+    {
+        self.on_reject = Some(Callback(Box::new(callback)));
+    }
+
+    /// Register a callback invoked with the best ask and bid prices (see
+    /// [`min_ask`] and [`max_bid`]) every time they change after executing an
+    /// order with [`execute`], replacing any previously registered callback.
+    /// Unlike the [`BookEvent::BestPriceChanged`] events reported to the
+    /// observer, the callback is invoked at most once per order, once it and
+    /// the stop orders it triggers have been executed.
+    ///
+    /// [`min_ask`]: #method.min_ask
+    /// [`max_bid`]: #method.max_bid
+    /// [`execute`]: #method.execute
+    pub fn on_top_of_book_change<F>(&mut self, callback: F)
+    where
+        F: FnMut(Option<u64>, Option<u64>) + 'static,
+    {
+        self.on_top_of_book_change = Some(Callback(Box::new(callback)));
+    }
+
+    /// Register an observer invoked with a [`BookEvent`] every time the order
+    /// book is mutated, i.e. when an order is added, updated or removed, a
+    /// trade happens or the best bid or ask price changes, replacing any
+    /// previously registered observer. Events are reported in the order in
+    /// which the mutations happen while executing an order.
+    pub fn set_observer<F>(&mut self, observer: F)
+    where
+        F: FnMut(BookEvent<Id>) + 'static,
+    {
+        self.observer = Some(Callback(Box::new(observer)));
+    }
+
+    /// Enter drain mode, in which the order book stops accepting new resting
+    /// orders, e.g. before a shutdown or a migration. Limit orders that do not
+    /// cross the opposite side of the book, and amend orders that would
+    /// requeue a resting order, are rejected with [`RejectReason::Draining`].
+    /// Market orders, crossing limit orders, cancels and quantity reductions
+    /// are still accepted. Crossing limit orders take the existing liquidity,
+    /// and their unfilled quantity is canceled instead of resting: they return
+    /// [`OrderEvent::PartiallyFilled`] with a zero `resting_qty`, or
+    /// [`OrderEvent::SystemCanceled`] with [`CancelReason::Draining`] if they
+    /// did not trade at all (e.g. because of self-trade prevention or
+    /// all-or-none orders).
+    pub fn enter_drain_mode(&mut self) {
+        self.draining = true;
+    }
+
+    /// Exit drain mode (see [`enter_drain_mode`]) and resume normal operation.
+    ///
+    /// [`enter_drain_mode`]: #method.enter_drain_mode
+    pub fn exit_drain_mode(&mut self) {
+        self.draining = false;
+    }
+
+    /// Return whether the order book is in drain mode.
+    pub fn is_draining(&self) -> bool {
+        self.draining
+    }
+
+    /// Execute an order, returning immediately an event indicating the result.
+    ///
+    /// Canceling an order that is not resting on the order book is a no-op
+    /// that still returns [`OrderEvent::Canceled`]. Use [`try_execute`] to
+    /// detect this case. No overflow check is performed (see
+    /// [`try_execute_checked`]).
+    ///
+    /// [`try_execute`]: #method.try_execute
+    /// [`try_execute_checked`]: #method.try_execute_checked
+    pub fn execute(&mut self, order: OrderType<Id>) -> OrderEvent<Id> {
+        match self.try_execute(order) {
+            Ok(event) => event,
+            // `try_execute` fails only for cancels of missing orders, since
+            // overflows are only detected by `try_execute_checked`.
+            Err(_) => OrderEvent::Canceled { id: order.id() },
+        }
+    }
+
+    /// Execute a batch of orders in sequence, returning the events in the same
+    /// order. This is equivalent to calling [`execute`] on each order.
+    ///
+    /// [`execute`]: #method.execute
+    pub fn execute_batch(
+        &mut self,
+        orders: &[OrderType<Id>],
+    ) -> Vec<OrderEvent<Id>> {
+        let mut events = Vec::with_capacity(orders.len());
+        for order in orders {
+            events.push(self.execute(*order));
+        }
+        events
+    }
+
+    /// Return the event that executing an order with [`execute`] would
+    /// produce, including all its fills, without modifying the order book.
+    ///
+    /// Stop orders triggered by the trades of the order are not simulated.
+    ///
+    /// [`execute`]: #method.execute
+    pub fn simulate(&self, order: OrderType<Id>) -> OrderEvent<Id> {
+        let mut event = self.simulate_untracked(order);
+        if self.track_stats {
+            attach_trade(&mut event);
+        }
+        event
+    }
+
+    fn simulate_untracked(&self, order: OrderType<Id>) -> OrderEvent<Id> {
+        let order = clamp_reduce_only(order);
+        if let Err(reason) = self.validate_order(&order) {
+            return OrderEvent::Rejected {
+                id: order.id(),
+                reason,
+            };
+        }
+        match order {
+            OrderType::Market {
+                id,
+                side,
+                qty,
+                owner,
+                tag,
+                protection_price,
+                ..
+            } => {
+                let taker = Taker {
+                    id,
+                    side,
+                    owner,
+                    tag,
+                    stp_mode: self.stp_mode,
+                };
+                let (fills, remaining_qty, self_trade) =
+                    self.simulate_match(taker, qty, protection_price);
+                let reason = self.unfilled_reason(side, protection_price);
+                Execution {
+                    fills,
+                    filled_qty: qty - remaining_qty,
+                    resting_qty: 0,
+                    partial: remaining_qty > 0,
+                    self_trade,
+                    drained: false,
+                }
+                .into_event(id, OrderEvent::Unfilled { id, reason })
+            }
+            OrderType::Limit {
+                id,
+                side,
+                qty,
+                price,
+                owner,
+                tag,
+                ..
+            } => self
+                .simulate_limit(id, side, qty, price, owner, tag, false)
+                .into_event(id, OrderEvent::Placed { id }),
+            OrderType::AllOrNone {
+                id,
+                side,
+                qty,
+                price,
+                owner,
+                tag,
+            } => self
+                .simulate_limit(id, side, qty, price, owner, tag, true)
+                .into_event(id, OrderEvent::Placed { id }),
+            OrderType::GoodTillTime {
+                id,
+                side,
+                qty,
+                price,
+                ..
+            } => self
+                .simulate_limit(id, side, qty, price, None, None, false)
+                .into_event(id, OrderEvent::Placed { id }),
+            OrderType::Amend {
+                id,
+                new_qty,
+                new_price,
+            } => {
+                // Validation guarantees that the order is resting
+                let idx = match self.resting_index(id) {
+                    Some(idx) => idx,
+                    None => return OrderEvent::Canceled { id },
+                };
+                if self.keeps_priority(idx, new_qty, new_price) {
+                    return OrderEvent::Amended { id };
+                }
+                let order = &self.arena[idx];
+                self.simulate_limit(
+                    id,
+                    order.side,
+                    new_qty,
+                    new_price,
+                    order.owner,
+                    order.tag,
+                    order.all_or_none,
+                )
+                .into_event(id, OrderEvent::Amended { id })
+            }
+            OrderType::Cancel { id } => OrderEvent::Canceled { id },
+            OrderType::CancelReplace {
+                old_id,
+                new_id,
+                side,
+                qty,
+                price,
+            } => {
+                let RestingAttrs { owner, tag, .. } =
+                    self.replacement_attrs(old_id);
+                let placement = self
+                    .simulate_limit(new_id, side, qty, price, owner, tag, false)
+                    .into_event(new_id, OrderEvent::Placed { id: new_id });
+                if self.resting_index(old_id).is_some() {
+                    OrderEvent::Replaced {
+                        canceled: old_id,
+                        placement: Box::new(placement),
+                    }
+                } else {
+                    placement
+                }
+            }
+            OrderType::StopLimit { id, .. }
+            | OrderType::StopMarket { id, .. } => OrderEvent::Placed { id },
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn simulate_limit(
+        &self,
+        id: Id,
+        side: Side,
+        qty: u64,
+        price: u64,
+        owner: Option<OwnerId>,
+        tag: Option<u64>,
+        all_or_none: bool,
+    ) -> Execution<Id> {
+        let taker = Taker {
+            id,
+            side,
+            owner,
+            tag,
+            stp_mode: self.stp_mode,
+        };
+        let (fills, remaining_qty, self_trade) =
+            self.simulate_match(taker, qty, Some(price));
+        if all_or_none && (remaining_qty > 0 || self_trade) {
+            // The order rests without trading, unless the book is draining
+            return Execution {
+                fills: Vec::new(),
+                filled_qty: 0,
+                resting_qty: if self.draining { 0 } else { qty },
+                partial: true,
+                self_trade: false,
+                drained: self.draining,
+            };
+        }
+        let drained = remaining_qty > 0 && !self_trade && self.draining;
+        Execution {
+            fills,
+            filled_qty: qty - remaining_qty,
+            resting_qty: if self_trade || drained {
+                0
+            } else {
+                remaining_qty
+            },
+            partial: remaining_qty > 0 || self_trade,
+            self_trade,
+            drained,
+        }
+    }
+
+    /// Compute the fills of an incoming order like the matching engine does,
+    /// without modifying the resting orders. Return the fills, the remaining
+    /// quantity and whether matching was stopped by self-trade prevention.
+    fn simulate_match(
+        &self,
+        taker: Taker<Id>,
+        qty: u64,
+        limit_price: Option<u64>,
+    ) -> (Vec<FillMetadata<Id>>, u64, bool) {
+        let (maker_fills, remaining_qty, self_trade) =
+            self.sweep(taker.side, taker.owner, qty, limit_price);
+        let fills = maker_fills
+            .into_iter()
+            .map(|fill| {
+                let maker = &self.arena[fill.idx];
+                FillMetadata {
+                    order_1: taker.id,
+                    order_2: maker.id,
+                    qty: fill.qty,
+                    price: maker.price,
+                    taker_side: taker.side,
+                    total_fill: fill.remaining_qty == 0,
+                    taker_tag: taker.tag,
+                    maker_tag: maker.tag,
+                }
+            })
+            .collect();
+        (fills, remaining_qty, self_trade)
+    }
+
+    /// Walk the resting orders that a taker on the given `side` owned by
+    /// `owner` would match for `qty`, like the matching engine does but
+    /// without modifying them: hidden reserves are replenished, all-or-none
+    /// orders are skipped unless they can be filled completely, and the
+    /// matching policy and self-trade prevention mode of the book apply.
+    /// Return the fills of the resting orders in execution order, the
+    /// remaining quantity and whether matching was stopped by self-trade
+    /// prevention.
+    fn sweep(
+        &self,
+        side: Side,
+        owner: Option<OwnerId>,
+        qty: u64,
+        limit_price: Option<u64>,
+    ) -> (Vec<MakerFill>, u64, bool) {
+        let stp_mode = self.stp_mode.filter(|_| owner.is_some());
+        let mut fills = Vec::new();
+        let mut remaining_qty = qty;
+        let queues: Box<dyn Iterator<Item = (&u64, &Vec<usize>)>> = match side {
+            Side::Bid => Box::new(self.asks.iter()),
+            Side::Ask => Box::new(self.bids.iter().rev()),
+        };
+        for (price, queue) in queues {
+            let crosses = match (side, limit_price) {
+                (_, None) => true,
+                (Side::Bid, Some(lp)) => lp >= *price,
+                (Side::Ask, Some(lp)) => lp <= *price,
+            };
+            if remaining_qty == 0 || !crosses {
+                break;
+            }
+            // The remaining and visible quantity of each order in the queue
+            let mut orders: VecDeque<(usize, u64, u64)> = queue
+                .iter()
+                .map(|idx| {
+                    let order = &self.arena[*idx];
+                    (*idx, order.qty, order.visible_qty)
+                })
+                .filter(|(_, qty, _)| *qty > 0)
+                .collect();
+            if self.matching_policy == MatchingPolicy::ProRata {
+                let orders = Vec::from(orders);
+                let self_trade = self.sweep_pro_rata(
+                    owner,
+                    orders,
+                    &mut remaining_qty,
+                    &mut fills,
+                );
+                if self_trade {
+                    return (fills, remaining_qty, true);
+                }
+                continue;
+            }
+            while remaining_qty > 0 {
+                let (idx, mut maker_qty, mut visible_qty) =
+                    match orders.pop_front() {
+                        Some(order) => order,
+                        None => break,
+                    };
+                let maker = &self.arena[idx];
+                if stp_mode.is_some() && maker.owner == owner {
+                    if stp_mode == Some(StpMode::CancelNewest) {
+                        return (fills, remaining_qty, true);
+                    }
+                    continue;
+                }
+                if maker.all_or_none && maker_qty > remaining_qty {
+                    continue;
+                }
+                let traded_quantity = remaining_qty.min(visible_qty);
+                remaining_qty -= traded_quantity;
+                maker_qty -= traded_quantity;
+                visible_qty -= traded_quantity;
+                let replenished = visible_qty == 0 && maker_qty > 0;
+                if replenished {
+                    // Replenish the displayed slice at the back of the queue
+                    visible_qty = maker.display_qty.min(maker_qty);
+                    orders.push_back((idx, maker_qty, visible_qty));
+                }
+                fills.push(MakerFill {
+                    idx,
+                    qty: traded_quantity,
+                    remaining_qty: maker_qty,
+                    visible_qty,
+                    replenished,
+                });
+            }
+        }
+        (fills, remaining_qty, false)
+    }
+
+    /// Compute the pro-rata fills of an incoming order against the remaining
+    /// and visible quantity of each order in a queue, like
+    /// `process_queue_pro_rata` does. Return whether matching was stopped by
+    /// self-trade prevention.
+    fn sweep_pro_rata(
+        &self,
+        owner: Option<OwnerId>,
+        mut orders: Vec<(usize, u64, u64)>,
+        remaining_qty: &mut u64,
+        fills: &mut Vec<MakerFill>,
+    ) -> bool {
+        if self.stp_mode.is_some() && owner.is_some() {
+            let own = |idx: usize| self.arena[idx].owner == owner;
+            if orders.iter().any(|(idx, _, _)| own(*idx)) {
+                if self.stp_mode == Some(StpMode::CancelNewest) {
+                    return true;
+                }
+                orders.retain(|(idx, _, _)| !own(*idx));
+            }
+        }
+        for (idx, maker_qty, _) in &orders {
+            if self.arena[*idx].all_or_none && *maker_qty <= *remaining_qty {
+                *remaining_qty -= maker_qty;
+                fills.push(MakerFill {
+                    idx: *idx,
+                    qty: *maker_qty,
+                    remaining_qty: 0,
+                    visible_qty: 0,
+                    replenished: false,
+                });
+            }
+        }
+        orders.retain(|(idx, _, _)| !self.arena[*idx].all_or_none);
+        while *remaining_qty > 0 && !orders.is_empty() {
+            let visible: Vec<u64> = orders.iter().map(|o| o.2).collect();
+            let shares = pro_rata_shares(*remaining_qty, &visible);
+            let mut replenished = Vec::new();
+            for (order, traded_quantity) in orders.iter_mut().zip(shares) {
+                let (idx, maker_qty, visible_qty) = order;
+                if traded_quantity == 0 {
+                    continue;
+                }
+                *remaining_qty -= traded_quantity;
+                *maker_qty -= traded_quantity;
+                *visible_qty -= traded_quantity;
+                let mut fill = MakerFill {
+                    idx: *idx,
+                    qty: traded_quantity,
+                    remaining_qty: *maker_qty,
+                    visible_qty: *visible_qty,
+                    replenished: *visible_qty == 0 && *maker_qty > 0,
+                };
+                if fill.replenished {
+                    let display_qty = self.arena[*idx].display_qty;
+                    fill.visible_qty = display_qty.min(*maker_qty);
+                    replenished.push((*idx, *maker_qty, fill.visible_qty));
+                }
+                fills.push(fill);
+            }
+            orders.retain(|(_, _, visible_qty)| *visible_qty > 0);
+            orders.extend(replenished);
+        }
+        false
+    }
+
+    /// Execute an order like [`execute`], but return
+    /// [`ExecutionError::OrderNotFound`] if the order is a cancel targeting an
+    /// order that is not resting on the order book, either because it never
+    /// existed or because it was already filled or canceled.
+    ///
+    /// [`execute`]: #method.execute
+    pub fn try_execute(
+        &mut self,
+        order: OrderType<Id>,
+    ) -> Result<OrderEvent<Id>, ExecutionError> {
+        self.execute_checking(order, false)
+    }
+
+    /// Execute an order like [`try_execute`], but without ever overflowing a
+    /// quantity or a counter of the order book, or aborting because the arena
+    /// or a price level queue cannot grow. If executing the order would do
+    /// so, [`ExecutionError::Overflow`] or [`ExecutionError::OutOfMemory`] is
+    /// returned and the order book is left untouched.
+    ///
+    /// The order is matched beforehand with the same read-only walk as
+    /// [`simulate`], and all the quantities and counters it updates (the
+    /// traded volumes, the sequence numbers, the total quantity of the price
+    /// level it rests at) are computed with checked arithmetic, before the
+    /// order book is modified. The memory needed by the arena and by the
+    /// price level queues is then reserved fallibly. Allocations of new price
+    /// levels, of the returned events and of the trade tape are not covered.
+    ///
+    /// Each stop order triggered by the execution is checked in the same way
+    /// before being executed. If the check fails, that stop order and the
+    /// ones after it are left pending, and are triggered again by the next
+    /// trade.
+    ///
+    /// The total quantity of each price level is only guaranteed to fit in a
+    /// `u64` if all the orders resting at that level were added through this
+    /// method.
+    ///
+    /// [`try_execute`]: #method.try_execute
+    /// [`simulate`]: #method.simulate
+    pub fn try_execute_checked(
+        &mut self,
+        order: OrderType<Id>,
+    ) -> Result<OrderEvent<Id>, ExecutionError> {
+        self.check_execution(order)?;
+        self.execute_checking(order, true)
+    }
+
+    /// Execute an order and the stop orders it triggers, checking the
+    /// triggered stop orders with `check_execution` if `checked` is set.
+    fn execute_checking(
+        &mut self,
+        order: OrderType<Id>,
+        checked: bool,
+    ) -> Result<OrderEvent<Id>, ExecutionError> {
+        let top = (self.min_ask, self.max_bid);
+        let event = self.execute_tracked(order)?;
+        if let Some(fill) = event.fills().last() {
+            self.trigger_stops(fill.price, checked);
+        }
+        if let Some(Callback(callback)) = &mut self.on_top_of_book_change {
+            if (self.min_ask, self.max_bid) != top {
+                callback(self.min_ask, self.max_bid);
+            }
+        }
+        Ok(event)
+    }
+
+    /// Check that executing an order cannot overflow, and reserve the memory
+    /// it needs, without otherwise modifying the order book.
+    fn check_execution(
+        &mut self,
+        order: OrderType<Id>,
+    ) -> Result<(), ExecutionError> {
+        let order = clamp_reduce_only(order);
+        if self.validate_order(&order).is_err() {
+            // Rejected orders leave the order book untouched
+            return Ok(());
+        }
+        // The side, quantity, limit price, owner and all-or-none flag of the
+        // incoming order, whether it may rest and the resting order it
+        // replaces, if any.
+        let (side, qty, limit_price, owner, all_or_none, may_rest, replaced) =
+            match order {
+                OrderType::Market {
+                    side,
+                    qty,
+                    owner,
+                    protection_price,
+                    ..
+                } => (side, qty, protection_price, owner, false, false, None),
+                OrderType::Limit {
+                    side,
+                    qty,
+                    price,
+                    owner,
+                    ..
+                } => (side, qty, Some(price), owner, false, true, None),
+                OrderType::GoodTillTime {
+                    side, qty, price, ..
+                } => (side, qty, Some(price), None, false, true, None),
+                OrderType::AllOrNone {
+                    side,
+                    qty,
+                    price,
+                    owner,
+                    ..
+                } => (side, qty, Some(price), owner, true, true, None),
+                OrderType::CancelReplace {
+                    old_id,
+                    side,
+                    qty,
+                    price,
+                    ..
+                } => {
+                    let attrs = self.replacement_attrs(old_id);
+                    let (owner, aon) = (attrs.owner, attrs.all_or_none);
+                    (side, qty, Some(price), owner, aon, true, Some(old_id))
+                }
+                OrderType::Amend {
+                    id,
+                    new_qty,
+                    new_price,
+                } => {
+                    let idx = self
+                        .resting_index(id)
+                        .ok_or(ExecutionError::OrderNotFound)?;
+                    let order = &self.arena[idx];
+                    if self.keeps_priority(idx, new_qty, new_price) {
+                        return self.check_level_qty(
+                            order.side,
+                            new_price,
+                            new_qty,
+                            Some(id),
+                        );
+                    }
+                    let (side, owner, aon) =
+                        (order.side, order.owner, order.all_or_none);
+                    (side, new_qty, Some(new_price), owner, aon, true, Some(id))
+                }
+                OrderType::StopLimit { .. } | OrderType::StopMarket { .. } => {
+                    self.stop_seq
+                        .checked_add(1)
+                        .ok_or(ExecutionError::Overflow)?;
+                    return Ok(());
+                }
+                OrderType::Cancel { .. } => return Ok(()),
+            };
+
+        let (mut fills, mut remaining_qty, mut self_trade) =
+            self.sweep(side, owner, qty, limit_price);
+        if all_or_none && (remaining_qty > 0 || self_trade) {
+            // The order does not trade at all
+            fills.clear();
+            remaining_qty = qty;
+            self_trade = false;
+        }
+        let rests =
+            may_rest && remaining_qty > 0 && !self_trade && !self.draining;
+
+        let filled_qty = qty - remaining_qty;
+        if self.track_stats {
+            let taker_volume = match side {
+                Side::Bid => self.taker_buy_volume,
+                Side::Ask => self.taker_sell_volume,
+            };
+            taker_volume
+                .checked_add(filled_qty)
+                .and(self.traded_volume.checked_add(filled_qty))
+                .ok_or(ExecutionError::Overflow)?;
+        }
+        let replenished =
+            fills.iter().filter(|fill| fill.replenished).count() as u64;
+        self.seq
+            .checked_add(replenished + u64::from(rests))
+            .ok_or(ExecutionError::Overflow)?;
+        if let (true, Some(price)) = (rests, limit_price) {
+            self.check_level_qty(side, price, remaining_qty, replaced)?;
+        }
+
+        // Replenished orders are pushed again to the back of their queue, so
+        // count them by price level. Fills are ordered by price level.
+        let mut refills: Vec<(u64, usize)> = Vec::new();
+        for fill in fills.iter().filter(|fill| fill.replenished) {
+            let price = self.arena[fill.idx].price;
+            match refills.last_mut() {
+                Some((level, count)) if *level == price => *count += 1,
+                _ => refills.push((price, 1)),
+            }
+        }
+        let opposite = match side {
+            Side::Bid => &mut self.asks,
+            Side::Ask => &mut self.bids,
+        };
+        for (price, count) in refills {
+            if let Some(queue) = opposite.get_mut(&price) {
+                queue
+                    .try_reserve(count)
+                    .map_err(|_| ExecutionError::OutOfMemory)?;
+            }
+        }
+        if let (true, Some(price)) = (rests, limit_price) {
+            self.arena
+                .try_reserve(1)
+                .map_err(|_| ExecutionError::OutOfMemory)?;
+            let book = match side {
+                Side::Ask => &mut self.asks,
+                Side::Bid => &mut self.bids,
+            };
+            if let Some(queue) = book.get_mut(&price) {
+                queue
+                    .try_reserve(1)
+                    .map_err(|_| ExecutionError::OutOfMemory)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the total quantity resting at a price level still fits in a
+    /// `u64` after adding `qty` to it, ignoring the order `replaced`.
+    fn check_level_qty(
+        &self,
+        side: Side,
+        price: u64,
+        qty: u64,
+        replaced: Option<Id>,
+    ) -> Result<(), ExecutionError> {
+        let level_qty: u128 = self
+            .level_orders(side, price)
+            .filter(|order| Some(order.id) != replaced)
+            .map(|order| u128::from(order.qty))
+            .sum();
+        u64::try_from(level_qty + u128::from(qty))
+            .map(|_| ())
+            .map_err(|_| ExecutionError::Overflow)
+    }
+
+    /// Return the events resulting from the execution of the stop orders
+    /// triggered since the last call, as [`OrderEvent::Triggered`] events in
+    /// execution order.
+    ///
+    /// Stop orders ([`OrderType::StopLimit`] and [`OrderType::StopMarket`])
+    /// are triggered by the trades generated by [`execute`]: after each order
+    /// that trades, the pending stop orders whose trigger price has been
+    /// reached by the last fill are executed one at a time, in order of
+    /// trigger price. Stop orders with the same trigger price are executed in
+    /// the order in which they were placed, regardless of their IDs, since
+    /// order IDs are not required to be ordered (see [`OrderId`]). The trades
+    /// of a triggered order can trigger further stop orders in turn.
+    ///
+    /// [`execute`]: #method.execute
+    pub fn take_triggered(&mut self) -> Vec<OrderEvent<Id>> {
+        std::mem::take(&mut self.triggered)
+    }
+
+    /// Execute the pending stop orders triggered by a trade at `last_price`.
+    /// With `checked`, a stop order failing `check_execution` is left pending
+    /// and stops the triggering.
+    fn trigger_stops(&mut self, mut last_price: u64, checked: bool) {
+        while let Some(key) = self.next_triggered_stop(last_price) {
+            let stop = match self.stops.remove(&key) {
+                Some(stop) => stop,
+                None => break,
+            };
+            let order = match stop {
+                OrderType::StopLimit {
+                    id,
+                    side,
+                    qty,
+                    price,
+                    ..
+                } => OrderType::Limit {
+                    id,
+                    side,
+                    qty,
+                    price,
+                    display_qty: None,
+                    owner: None,
+                    tag: None,
+                    reduce_only: None,
+                },
+                OrderType::StopMarket { id, side, qty, .. } => {
+                    OrderType::Market {
+                        id,
+                        side,
+                        qty,
+                        owner: None,
+                        tag: None,
+                        protection_price: None,
+                        reduce_only: None,
+                    }
+                }
+                _ => continue,
+            };
+            if checked && self.check_execution(order).is_err() {
+                self.stops.insert(key, stop);
+                break;
+            }
+            if let Ok(event) = self.execute_tracked(order) {
+                if let Some(fill) = event.fills().last() {
+                    last_price = fill.price;
+                }
+                self.triggered.push(OrderEvent::Triggered {
+                    id: order.id(),
+                    event: Box::new(event),
+                });
+            }
+        }
+    }
+
+    /// Return the key of the first pending stop order, in order of trigger
+    /// price and placement, that is triggered by a trade at `last_price`.
+    fn next_triggered_stop(&self, last_price: u64) -> Option<(u64, u64)> {
+        self.stops
+            .iter()
+            .find(|(_, stop)| match stop_trigger(stop) {
+                Some((Side::Bid, trigger)) => last_price >= trigger,
+                Some((Side::Ask, trigger)) => last_price <= trigger,
+                None => false,
+            })
+            .map(|(key, _)| *key)
+    }
+
+    /// Remove a pending stop order, returning `false` if there is no stop
+    /// order with the given ID.
+    fn cancel_stop(&mut self, id: Id) -> bool {
+        let key = self.stops.iter().find(|(_, stop)| stop.id() == id);
+        match key.map(|(key, _)| *key) {
+            Some(key) => self.stops.remove(&key).is_some(),
+            None => false,
+        }
+    }
+
+    /// Add a pending stop order, after the ones with the same trigger price.
+    fn insert_stop(&mut self, trigger: u64, stop: OrderType<Id>) {
+        self.stops.insert((trigger, self.stop_seq), stop);
+        self.stop_seq += 1;
+    }
+
+    /// Execute an order, updating the statistics and the trade tape.
+    fn execute_tracked(
+        &mut self,
+        order: OrderType<Id>,
+    ) -> Result<OrderEvent<Id>, ExecutionError> {
+        let mut event = self._execute(order)?;
+        if let OrderEvent::Rejected { reason, .. } = event {
+            if let Some(Callback(on_reject)) = &mut self.on_reject {
+                on_reject(order, reason);
+            }
+        }
+        if !self.track_stats {
+            return Ok(event);
+        }
+
+        for fill in event.fills() {
+            match fill.taker_side {
+                Side::Bid => self.taker_buy_volume += fill.qty,
+                Side::Ask => self.taker_sell_volume += fill.qty,
+            }
+        }
+
+        attach_trade(&mut event);
+        if let Some(trade) = event.trade() {
+            self.traded_volume += trade.total_qty;
+            self.last_trade = Some(trade);
+        }
+
+        if self.tape_capacity > 0 && !event.fills().is_empty() {
+            if self.tape.len() == self.tape_capacity {
+                self.tape.pop_front();
+            }
+            self.tape.extend(self.last_trade);
+        }
+        Ok(event)
+    }
+
+    fn validate_order(
+        &self,
+        order: &OrderType<Id>,
+    ) -> Result<(), RejectReason> {
+        match *order {
+            OrderType::Amend { id, .. } if self.resting_index(id).is_none() => {
+                Err(RejectReason::OrderNotFound)
+            }
+            OrderType::Amend { new_qty: 0, .. } => {
+                Err(RejectReason::InvalidQty)
+            }
+            OrderType::CancelReplace { old_id, .. }
+                if !self.replace_if_missing
+                    && self.resting_index(old_id).is_none() =>
+            {
+                Err(RejectReason::OrderNotFound)
+            }
+            OrderType::Limit { price, .. }
+            | OrderType::AllOrNone { price, .. }
+            | OrderType::CancelReplace { price, .. }
+            | OrderType::GoodTillTime { price, .. }
+            | OrderType::StopLimit { price, .. }
+            | OrderType::Amend {
+                new_price: price, ..
+            } if self.tick_size > 1 && price % self.tick_size != 0 => {
+                Err(RejectReason::InvalidTick)
+            }
+            OrderType::StopLimit { trigger, .. }
+            | OrderType::StopMarket { trigger, .. }
+                if self.tick_size > 1 && trigger % self.tick_size != 0 =>
+            {
+                Err(RejectReason::InvalidTick)
+            }
+            OrderType::Limit { price, .. }
+            | OrderType::AllOrNone { price, .. }
+            | OrderType::CancelReplace { price, .. }
+            | OrderType::GoodTillTime { price, .. }
+            | OrderType::Amend {
+                new_price: price, ..
+            } if !self.within_price_band(price) => {
+                Err(RejectReason::PriceOutsideBand)
+            }
+            OrderType::Limit {
+                qty,
+                display_qty: Some(display_qty),
+                ..
+            } if display_qty == 0 || display_qty > qty => {
+                Err(RejectReason::InvalidDisplayQty)
+            }
+            OrderType::Market { qty: 0, .. }
+            | OrderType::Limit { qty: 0, .. }
+            | OrderType::AllOrNone { qty: 0, .. }
+            | OrderType::CancelReplace { qty: 0, .. }
+            | OrderType::GoodTillTime { qty: 0, .. }
+            | OrderType::StopLimit { qty: 0, .. }
+            | OrderType::StopMarket { qty: 0, .. } => {
+                Err(RejectReason::ZeroQuantity)
+            }
+            OrderType::Limit { side, price, .. }
+            | OrderType::AllOrNone { side, price, .. }
+            | OrderType::CancelReplace { side, price, .. }
+            | OrderType::GoodTillTime { side, price, .. }
+                if self.draining && !self.crosses(side, price) =>
+            {
+                Err(RejectReason::Draining)
+            }
+            OrderType::Amend {
+                id,
+                new_qty,
+                new_price,
+            } if self.draining
+                && self.resting_index(id).is_some_and(|idx| {
+                    let order = &self.arena[idx];
+                    new_price != order.price || new_qty > order.qty
+                }) =>
+            {
+                Err(RejectReason::Draining)
+            }
+            OrderType::Cancel { .. } | OrderType::Amend { .. } => Ok(()),
+            // The new order may reuse the ID of the order it replaces
+            OrderType::CancelReplace { old_id, new_id, .. }
+                if new_id == old_id
+                    && !self.stops.values().any(|stop| stop.id() == new_id) =>
+            {
+                Ok(())
+            }
+            _ if self.resting_index(order.id()).is_some()
+                || self.stops.values().any(|stop| stop.id() == order.id()) =>
+            {
+                Err(RejectReason::DuplicateId)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Return whether a limit order at `price` on the given side would trade
+    /// against the best opposite price, if any.
+    fn crosses(&self, side: Side, price: u64) -> bool {
+        match side {
+            Side::Bid => self.min_ask.is_some_and(|ask| price >= ask),
+            Side::Ask => self.max_bid.is_some_and(|bid| price <= bid),
+        }
+    }
+
+    fn within_price_band(&self, price: u64) -> bool {
+        match (self.price_reference, self.price_band) {
+            (Some(reference), Some(band)) => {
+                price.max(reference) - price.min(reference) <= band
+            }
+            _ => true,
+        }
+    }
+
+    fn _execute(
+        &mut self,
+        event: OrderType<Id>,
+    ) -> Result<OrderEvent<Id>, ExecutionError> {
+        let event = clamp_reduce_only(event);
+        if let Err(reason) = self.validate_order(&event) {
+            return Ok(OrderEvent::Rejected {
+                id: event.id(),
+                reason,
+            });
+        }
+        let event = match event {
+            OrderType::Market {
+                id,
+                side,
+                qty,
+                owner,
+                tag,
+                protection_price,
+                ..
+            } => {
+                let reason = self.unfilled_reason(side, protection_price);
+                self.market(id, side, qty, owner, tag, protection_price)
+                    .into_event(id, OrderEvent::Unfilled { id, reason })
+            }
+            OrderType::Limit {
+                id,
+                side,
+                qty,
+                price,
+                display_qty,
+                owner,
+                tag,
+                ..
+            } => {
+                let attrs = RestingAttrs {
+                    display_qty,
+                    owner,
+                    tag,
+                    all_or_none: false,
+                };
+                self.limit(id, side, qty, price, attrs)
+                    .into_event(id, OrderEvent::Placed { id })
+            }
+            OrderType::AllOrNone {
+                id,
+                side,
+                qty,
+                price,
+                owner,
+                tag,
+            } => {
+                let attrs = RestingAttrs {
+                    display_qty: None,
+                    owner,
+                    tag,
+                    all_or_none: true,
+                };
+                self.limit(id, side, qty, price, attrs)
+                    .into_event(id, OrderEvent::Placed { id })
+            }
+            OrderType::Cancel { id } => {
+                if !self.cancel(id) && !self.cancel_stop(id) {
+                    return Err(ExecutionError::OrderNotFound);
+                }
+                OrderEvent::Canceled { id }
+            }
+            OrderType::CancelReplace {
+                old_id,
+                new_id,
+                side,
+                qty,
+                price,
+            } => {
+                let attrs = self.replacement_attrs(old_id);
+                let canceled = self.cancel(old_id);
+                let placement = self
+                    .limit(new_id, side, qty, price, attrs)
+                    .into_event(new_id, OrderEvent::Placed { id: new_id });
+                if canceled {
+                    OrderEvent::Replaced {
+                        canceled: old_id,
+                        placement: Box::new(placement),
+                    }
+                } else {
+                    placement
+                }
+            }
+            OrderType::Amend {
+                id,
+                new_qty,
+                new_price,
+            } => self.amend(id, new_qty, new_price),
+            OrderType::StopLimit { id, trigger, .. }
+            | OrderType::StopMarket { id, trigger, .. } => {
+                self.insert_stop(trigger, event);
+                OrderEvent::Placed { id }
+            }
+            OrderType::GoodTillTime {
+                id,
+                side,
+                qty,
+                price,
+                expires_at,
+            } => {
+                let event = self._execute(OrderType::Limit {
+                    id,
+                    side,
+                    qty,
+                    price,
+                    display_qty: None,
+                    owner: None,
+                    tag: None,
+                    reduce_only: None,
+                })?;
+                if let Some(idx) = self.resting_index(id) {
+                    self.arena.set_expiry(idx, expires_at);
+                }
+                event
+            }
+        };
+        Ok(event)
+    }
+
+    /// Cancel the resting order with the given ID, returning `false` if no
+    /// such order is resting on the order book.
+    ///
+    /// The order is only marked as removed in the arena, and its slot index is
+    /// dropped from the price level queue later, either by the matching engine
+    /// or by [`drop_tombstones`](Self::drop_tombstones), so that canceling is
+    /// constant-time.
+    fn cancel(&mut self, id: Id) -> bool {
+        let idx = match self.resting_index(id) {
+            Some(idx) => idx,
+            None => return false,
+        };
+        let LimitOrder {
+            side,
+            price,
+            visible_qty,
+            ..
+        } = self.arena[idx];
+        self.arena.remove(&id);
+        *self.side_count_mut(side) -= 1;
+        notify(
+            &mut self.observer,
+            BookEvent::OrderRemoved { id, side, price },
+        );
+
+        // Canceled orders at the back of the queue can be dropped right away
+        let book = match side {
+            Side::Ask => &mut self.asks,
+            Side::Bid => &mut self.bids,
+        };
+        if let Some(queue) = book.get_mut(&price) {
+            while let Some(&last) = queue.last() {
+                if self.arena[last].qty > 0 {
+                    break;
+                }
+                queue.pop();
+                self.arena.release(last);
+            }
+        }
+
+        match side {
+            Side::Ask if self.min_ask == Some(price) => {
+                self.min_ask_qty -= visible_qty;
+                if self.min_ask_qty == 0 {
+                    self.update_min_ask();
+                }
+            }
+            Side::Bid if self.max_bid == Some(price) => {
+                self.max_bid_qty -= visible_qty;
+                if self.max_bid_qty == 0 {
+                    self.update_max_bid();
+                }
+            }
+            _ => {}
+        }
+        self.compact_tombstones();
+        true
+    }
+
+    /// Drop the canceled orders from the price level queues once they
+    /// outnumber the resting orders (see [`drop_tombstones`]).
+    ///
+    /// [`drop_tombstones`]: Self::drop_tombstones
+    fn compact_tombstones(&mut self) {
+        let tombstones = self.arena.tombstones();
+        if tombstones > MIN_COMPACTION_TOMBSTONES.max(self.arena.len()) {
+            self.drop_tombstones();
+        }
+    }
+
+    /// Drop the canceled orders from the price level queues and release their
+    /// arena slots. Empty price levels are removed as well.
+    fn drop_tombstones(&mut self) {
+        let arena = &mut self.arena;
+        for book in [&mut self.asks, &mut self.bids] {
+            for queue in book.values_mut() {
+                queue.retain(|idx| {
+                    if arena[*idx].qty == 0 {
+                        arena.release(*idx);
+                        return false;
+                    }
+                    true
+                });
+            }
+            book.retain(|_, queue| !queue.is_empty());
+        }
+    }
+
+    fn amend(&mut self, id: Id, qty: u64, price: u64) -> OrderEvent<Id> {
+        let side = self.arena.get(id).and_then(|(old_price, idx)| {
+            self.order_side(old_price, idx).map(|side| (side, idx))
+        });
+        let (side, idx) = match side {
+            Some(found) => found,
+            None => {
+                return OrderEvent::Rejected {
+                    id,
+                    reason: RejectReason::OrderNotFound,
+                }
+            }
+        };
+
+        let keeps_priority = self.keeps_priority(idx, qty, price);
+        let order = &mut self.arena[idx];
+        if keeps_priority {
+            // An increase within the queued quantity fills the displayed slice
+            // first, and the rest goes to the hidden reserve.
+            if qty > order.qty {
+                order.visible_qty = (order.visible_qty + (qty - order.qty))
+                    .min(order.display_qty);
+            } else {
+                order.visible_qty = order.visible_qty.min(qty);
+            }
+            order.qty = qty;
+            notify(
+                &mut self.observer,
+                BookEvent::OrderUpdated {
+                    id,
+                    side,
+                    price,
+                    qty,
+                },
+            );
+            match side {
+                Side::Ask => self.update_min_ask(),
+                Side::Bid => self.update_max_bid(),
+            }
+            return OrderEvent::Amended { id };
+        }
+
+        let attrs = RestingAttrs {
+            display_qty: Some(order.display_qty)
+                .filter(|d| *d < qty && !order.all_or_none),
+            owner: order.owner,
+            tag: order.tag,
+            all_or_none: order.all_or_none,
+        };
+        let expires_at = order.expires_at;
+        self.cancel(id);
+        let event = self
+            .limit(id, side, qty, price, attrs)
+            .into_event(id, OrderEvent::Amended { id });
+        if let (Some(expires_at), Some(idx)) =
+            (expires_at, self.resting_index(id))
+        {
+            self.arena.set_expiry(idx, expires_at);
+        }
+        event
+    }
+
+    /// Return whether amending the order at arena index `idx` to the given
+    /// quantity and price keeps its time priority.
+    fn keeps_priority(&self, idx: usize, qty: u64, price: u64) -> bool {
+        let order = &self.arena[idx];
+        let max_qty = match self.amend_policy {
+            AmendPolicy::AlwaysLosePriorityOnIncrease => order.qty,
+            AmendPolicy::KeepPriorityUpToOriginal => order.queued_qty,
+        };
+        price == order.price && qty <= max_qty
+    }
+
+    /// Return the attributes inherited by the new order of a cancel-replace
+    /// from the resting order `old_id`, if any.
+    fn replacement_attrs(&self, old_id: Id) -> RestingAttrs {
+        match self.resting_index(old_id) {
+            Some(idx) => RestingAttrs {
+                owner: self.arena[idx].owner,
+                tag: self.arena[idx].tag,
+                ..RestingAttrs::default()
+            },
+            None => RestingAttrs::default(),
+        }
+    }
+
+    /// Return the reason why a market order on the given side would not be
+    /// filled, based on the state of the book before matching it.
+    fn unfilled_reason(
+        &self,
+        side: Side,
+        protection_price: Option<u64>,
+    ) -> UnfilledReason {
+        let best = match side {
+            Side::Bid => self.min_ask,
+            Side::Ask => self.max_bid,
+        };
+        match (best, protection_price) {
+            (Some(best), Some(limit))
+                if (side == Side::Bid && best > limit)
+                    || (side == Side::Ask && best < limit) =>
+            {
+                UnfilledReason::PriceProtection
+            }
+            _ => UnfilledReason::NoLiquidity,
+        }
+    }
+
+    fn market(
+        &mut self,
+        id: Id,
+        side: Side,
+        qty: u64,
+        owner: Option<OwnerId>,
+        tag: Option<u64>,
+        protection_price: Option<u64>,
+    ) -> Execution<Id> {
+        let mut fills = Vec::new();
+
+        let taker = Taker {
+            id,
+            side,
+            owner,
+            tag,
+            stp_mode: self.stp_mode,
+        };
+        let (remaining_qty, self_trade) = match side {
+            Side::Bid => {
+                self.match_with_asks(taker, qty, &mut fills, protection_price)
+            }
+            Side::Ask => {
+                self.match_with_bids(taker, qty, &mut fills, protection_price)
+            }
+        };
+
+        Execution {
+            fills,
+            filled_qty: qty - remaining_qty,
+            resting_qty: 0,
+            partial: remaining_qty > 0,
+            self_trade,
+            drained: false,
+        }
+    }
+
+    fn limit(
+        &mut self,
+        id: Id,
+        side: Side,
+        qty: u64,
+        price: u64,
+        attrs: RestingAttrs,
+    ) -> Execution<Id> {
+        let mut rests = false;
+        let remaining_qty;
+        let self_trade;
+        let mut fills: Vec<FillMetadata<Id>> = Vec::new();
+        let taker = Taker {
+            id,
+            side,
+            owner: attrs.owner,
+            tag: attrs.tag,
+            stp_mode: self.stp_mode,
+        };
+        // An all-or-none order only trades if it can be filled completely
+        let matches = !attrs.all_or_none || {
+            let (_, remaining_qty, self_trade) =
+                self.simulate_match(taker, qty, Some(price));
+            remaining_qty == 0 && !self_trade
+        };
+
+        match side {
+            Side::Bid => {
+                (remaining_qty, self_trade) = if matches {
+                    self.match_with_asks(taker, qty, &mut fills, Some(price))
+                } else {
+                    (qty, false)
+                };
+                if remaining_qty > 0 && !self_trade && !self.draining {
+                    rests = true;
+                    let index = self.insert_order(
+                        id,
+                        side,
+                        price,
+                        remaining_qty,
+                        attrs,
+                    );
+                    let queue_capacity = self.default_queue_capacity;
+                    self.bids
+                        .entry(price)
+                        .or_insert_with(|| Vec::with_capacity(queue_capacity))
+                        .push(index);
+                    notify(
+                        &mut self.observer,
+                        BookEvent::OrderAdded {
+                            id,
+                            side,
+                            price,
+                            qty: remaining_qty,
+                        },
+                    );
+                    let visible_qty = self.arena[index].visible_qty;
+                    match self.max_bid {
+                        Some(b) if price < b => {}
+                        Some(b) if price == b => {
+                            self.max_bid_qty += visible_qty;
+                        }
+                        _ => {
+                            self.max_bid = Some(price);
+                            self.max_bid_qty = visible_qty;
+                            notify(
+                                &mut self.observer,
+                                BookEvent::BestPriceChanged {
+                                    side,
+                                    price: Some(price),
+                                },
+                            );
+                        }
+                    };
+                }
+            }
+            Side::Ask => {
+                (remaining_qty, self_trade) = if matches {
+                    self.match_with_bids(taker, qty, &mut fills, Some(price))
+                } else {
+                    (qty, false)
+                };
+                if remaining_qty > 0 && !self_trade && !self.draining {
+                    rests = true;
+                    let index = self.insert_order(
+                        id,
+                        side,
+                        price,
+                        remaining_qty,
+                        attrs,
+                    );
+                    let queue_capacity = self.default_queue_capacity;
+                    self.asks
+                        .entry(price)
+                        .or_insert_with(|| Vec::with_capacity(queue_capacity))
+                        .push(index);
+                    notify(
+                        &mut self.observer,
+                        BookEvent::OrderAdded {
+                            id,
+                            side,
+                            price,
+                            qty: remaining_qty,
+                        },
+                    );
+                    let visible_qty = self.arena[index].visible_qty;
+                    match self.min_ask {
+                        Some(a) if price > a => {}
+                        Some(a) if price == a => {
+                            self.min_ask_qty += visible_qty;
+                        }
+                        _ => {
+                            self.min_ask = Some(price);
+                            self.min_ask_qty = visible_qty;
+                            notify(
+                                &mut self.observer,
+                                BookEvent::BestPriceChanged {
+                                    side,
+                                    price: Some(price),
+                                },
+                            );
+                        }
+                    };
+                }
+            }
+        }
+
+        Execution {
+            fills,
+            filled_qty: qty - remaining_qty,
+            resting_qty: if rests { remaining_qty } else { 0 },
+            partial: remaining_qty > 0 || self_trade,
+            self_trade,
+            drained: remaining_qty > 0 && !self_trade && !rests,
+        }
+    }
+
+    fn match_with_asks(
+        &mut self,
+        taker: Taker<Id>,
+        qty: u64,
+        fills: &mut Vec<FillMetadata<Id>>,
+        limit_price: Option<u64>,
+    ) -> (u64, bool) {
+        let mut remaining_qty = qty;
+        let mut self_trade = false;
+        let resting = self.arena.len();
+        let process_queue = self.queue_processor();
+        for (ask_price, queue) in self.asks.iter_mut() {
+            if queue.is_empty() {
+                continue;
+            }
+            if let Some(lp) = limit_price {
+                if lp < *ask_price {
+                    break;
+                }
+            }
+            if remaining_qty == 0 {
+                break;
+            }
+            let filled_qty;
+            (filled_qty, self_trade) = process_queue(
+                &mut self.arena,
+                queue,
+                remaining_qty,
+                taker,
+                fills,
+                &mut self.seq,
+                &mut self.observer,
+            );
+            remaining_qty -= filled_qty;
+            if self_trade {
+                break;
+            }
+        }
+
+        // Matching only removes filled (or self-trading) orders from the asks
+        self.ask_count -= resting - self.arena.len();
+        self.update_min_ask();
+        (remaining_qty, self_trade)
+    }
+
+    fn match_with_bids(
+        &mut self,
+        taker: Taker<Id>,
+        qty: u64,
+        fills: &mut Vec<FillMetadata<Id>>,
+        limit_price: Option<u64>,
+    ) -> (u64, bool) {
+        let mut remaining_qty = qty;
+        let mut self_trade = false;
+        let resting = self.arena.len();
+        let process_queue = self.queue_processor();
+        for (bid_price, queue) in self.bids.iter_mut().rev() {
+            if queue.is_empty() {
+                continue;
+            }
+            if let Some(lp) = limit_price {
+                if lp > *bid_price {
+                    break;
+                }
+            }
+            if remaining_qty == 0 {
+                break;
+            }
+            let filled_qty;
+            (filled_qty, self_trade) = process_queue(
+                &mut self.arena,
+                queue,
+                remaining_qty,
+                taker,
+                fills,
+                &mut self.seq,
+                &mut self.observer,
+            );
+            remaining_qty -= filled_qty;
+            if self_trade {
+                break;
+            }
+        }
+
+        // Matching only removes filled (or self-trading) orders from the bids
+        self.bid_count -= resting - self.arena.len();
+        self.update_max_bid();
+        (remaining_qty, self_trade)
+    }
+
+    fn round_to_tick(&self, price: f64, mode: RoundingMode) -> u64 {
+        let tick = self.tick_size.max(1);
+        mode.round(price / tick as f64) as u64 * tick
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    /// Store a new resting order in the arena, returning its index.
+    fn insert_order(
+        &mut self,
+        id: Id,
+        side: Side,
+        price: u64,
+        qty: u64,
+        attrs: RestingAttrs,
+    ) -> usize {
+        let index = self.arena.insert(id, price, qty);
+        *self.side_count_mut(side) += 1;
+        let seq = self.next_seq();
+        let order = &mut self.arena[index];
+        order.side = side;
+        order.seq = seq;
+        order.display_qty = attrs.display_qty.unwrap_or(qty);
+        order.visible_qty = order.display_qty.min(qty);
+        order.owner = attrs.owner;
+        order.tag = attrs.tag;
+        order.all_or_none = attrs.all_or_none;
+        index
+    }
+
+    /// Return the counter of resting orders on the given side of the book.
+    fn side_count_mut(&mut self, side: Side) -> &mut usize {
+        match side {
+            Side::Ask => &mut self.ask_count,
+            Side::Bid => &mut self.bid_count,
+        }
+    }
+
+    /// Return the arena index of a resting order, if present and not yet
+    /// completely filled.
+    fn resting_index(&self, id: Id) -> Option<usize> {
+        self.arena
+            .get(id)
+            .map(|(_, idx)| idx)
+            .filter(|idx| self.arena[*idx].qty > 0)
+    }
+
+    /// Find the side of the book on which the order stored at arena index
+    /// `idx` is resting.
+    fn order_side(&self, price: u64, idx: usize) -> Option<Side> {
+        let order = &self.arena[idx];
+        if order.qty > 0 && order.price == price {
+            Some(order.side)
+        } else {
+            None
+        }
+    }
+
+    /// Iterate over the non-empty price levels of one side of the book, from
+    /// the best price outwards.
+    fn side_levels(
+        &self,
+        side: Side,
+    ) -> Box<dyn Iterator<Item = BookLevel> + '_> {
+        let queues: Box<dyn Iterator<Item = (&u64, &Vec<usize>)>> = match side {
+            Side::Ask => Box::new(self.asks.iter()),
+            Side::Bid => Box::new(self.bids.iter().rev()),
+        };
+        Box::new(queues.filter_map(move |(price, queue)| {
+            let qty = self.queue_qty(queue);
+            if qty > 0 {
+                Some(BookLevel { price: *price, qty })
+            } else {
+                None
+            }
+        }))
+    }
+
+    /// Return the displayed quantity of the top `levels` price levels of one
+    /// side of the book.
+    fn top_qty(&self, side: Side, levels: usize) -> u64 {
+        self.side_levels(side)
+            .take(levels)
+            .map(|level| level.qty)
+            .sum()
+    }
+
+    /// Iterate over the orders resting on one side of the book, in matching
+    /// (price-time priority) order.
+    fn side_orders(
+        &self,
+        side: Side,
+    ) -> Box<dyn Iterator<Item = &LimitOrder<Id>> + '_> {
+        let queues: Box<dyn Iterator<Item = &Vec<usize>>> = match side {
+            Side::Ask => Box::new(self.asks.values()),
+            Side::Bid => Box::new(self.bids.values().rev()),
+        };
+        Box::new(
+            queues
+                .flatten()
+                .map(move |idx| &self.arena[*idx])
+                .filter(|order| order.qty > 0),
+        )
+    }
+
+    /// Compute the total notional (price times quantity) a taker on the given
+    /// side would trade by executing `qty` against the opposite side, or
+    /// `None` if `qty` is zero or cannot be filled completely.
+    fn notional_for_qty(&self, side: Side, qty: u64) -> Option<u128> {
+        let (fills, remaining_qty, _) = self.sweep(side, None, qty, None);
+        if qty == 0 || remaining_qty > 0 {
+            return None;
+        }
+        let notional = fills
+            .iter()
+            .map(|fill| {
+                u128::from(fill.qty) * u128::from(self.arena[fill.idx].price)
+            })
+            .sum();
+        Some(notional)
+    }
+
+    fn update_min_ask(&mut self) {
+        let best = self.side_levels(Side::Ask).next();
+        let price = best.as_ref().map(|level| level.price);
+        self.min_ask_qty = best.map_or(0, |level| level.qty);
+        if price != self.min_ask {
+            self.min_ask = price;
+            notify(
+                &mut self.observer,
+                BookEvent::BestPriceChanged {
+                    side: Side::Ask,
+                    price,
+                },
+            );
+        }
+    }
+
+    fn update_max_bid(&mut self) {
+        let best = self.side_levels(Side::Bid).next();
+        let price = best.as_ref().map(|level| level.price);
+        self.max_bid_qty = best.map_or(0, |level| level.qty);
+        if price != self.max_bid {
+            self.max_bid = price;
+            notify(
+                &mut self.observer,
+                BookEvent::BestPriceChanged {
+                    side: Side::Bid,
+                    price,
+                },
+            );
+        }
+    }
+
+    /// Return the total displayed quantity of the orders in a queue.
+    fn queue_qty(&self, queue: &[usize]) -> u64 {
+        queue.iter().map(|idx| self.arena[*idx].visible_qty).sum()
+    }
+
+    fn queue_processor(&self) -> QueueProcessor<Id> {
+        match self.matching_policy {
+            MatchingPolicy::Fifo => Self::process_queue,
+            MatchingPolicy::ProRata => Self::process_queue_pro_rata,
+        }
+    }
+
+    fn process_queue(
+        arena: &mut OrderArena<Id>,
+        opposite_orders: &mut Vec<usize>,
+        remaining_qty: u64,
+        taker: Taker<Id>,
+        fills: &mut Vec<FillMetadata<Id>>,
+        seq: &mut u64,
+        observer: &mut Observer<Id>,
+    ) -> (u64, bool) {
+        let mut qty_to_fill = remaining_qty;
+        let mut filled_qty = 0;
+        let mut index = 0;
+        let mut skipped = 0;
+        let mut self_trade = false;
+
+        // Orders whose displayed quantity is exhausted are either removed from
+        // the queue (if completely filled) or replenished from their reserve
+        // and moved to the back of the queue. Both cases advance `index`, and
+        // all the orders between the skipped all-or-none orders at the front
+        // and `index` are drained at the end. The slots of filled and canceled
+        // orders are released as soon as they are passed.
+        while index < opposite_orders.len() && qty_to_fill > 0 {
+            let idx = opposite_orders[index];
+            let head_order = &mut arena[idx];
+            if head_order.qty == 0 {
+                arena.release(idx);
+                index += 1;
+                continue;
+            }
+            if taker.stp_mode.is_some()
+                && taker.owner.is_some()
+                && head_order.owner == taker.owner
+            {
+                if taker.stp_mode == Some(StpMode::CancelNewest) {
+                    self_trade = true;
+                    break;
+                }
+                let LimitOrder {
+                    id, side, price, ..
+                } = *head_order;
+                arena.delete(&id);
+                notify(observer, BookEvent::OrderRemoved { id, side, price });
+                index += 1;
+                continue;
+            }
+            if head_order.all_or_none && head_order.qty > qty_to_fill {
+                opposite_orders.swap(skipped, index);
+                skipped += 1;
+                index += 1;
+                continue;
+            }
+            let traded_price = head_order.price;
+            let traded_quantity = qty_to_fill.min(head_order.visible_qty);
+            qty_to_fill -= traded_quantity;
+            head_order.qty -= traded_quantity;
+            head_order.visible_qty -= traded_quantity;
+            head_order.queued_qty -= traded_quantity;
+            let filled = head_order.qty == 0;
+            let fill = FillMetadata {
+                order_1: taker.id,
+                order_2: head_order.id,
+                qty: traded_quantity,
+                price: traded_price,
+                taker_side: taker.side,
+                total_fill: filled,
+                taker_tag: taker.tag,
+                maker_tag: head_order.tag,
+            };
+            let maker_fill = BookEvent::OrderFilled {
+                id: head_order.id,
+                side: head_order.side,
+                price: traded_price,
+                qty: traded_quantity,
+                remaining_qty: head_order.qty,
+            };
+            let removed = BookEvent::OrderRemoved {
+                id: head_order.id,
+                side: head_order.side,
+                price: traded_price,
+            };
+            if head_order.visible_qty == 0 {
+                if !filled {
+                    *seq += 1;
+                    head_order.seq = *seq;
+                    head_order.visible_qty =
+                        head_order.display_qty.min(head_order.qty);
+                    opposite_orders.push(idx);
+                }
+                index += 1;
+            }
+            notify(observer, BookEvent::Trade { fill });
+            notify(observer, maker_fill);
+            if filled {
+                arena.release(idx);
+                notify(observer, removed);
+            }
+            fills.push(fill);
+            filled_qty += traded_quantity;
+        }
+        opposite_orders.drain(skipped..index);
+
+        (filled_qty, self_trade)
+    }
+
+    fn process_queue_pro_rata(
+        arena: &mut OrderArena<Id>,
+        opposite_orders: &mut Vec<usize>,
+        remaining_qty: u64,
+        taker: Taker<Id>,
+        fills: &mut Vec<FillMetadata<Id>>,
+        seq: &mut u64,
+        observer: &mut Observer<Id>,
+    ) -> (u64, bool) {
+        let mut qty_to_fill = remaining_qty;
+        let mut filled_qty = 0;
+
+        // Self-trade prevention applies to the whole level before allocating
+        if taker.stp_mode.is_some() && taker.owner.is_some() {
+            let own: Vec<usize> = opposite_orders
+                .iter()
+                .copied()
+                .filter(|idx| {
+                    arena[*idx].qty > 0 && arena[*idx].owner == taker.owner
+                })
+                .collect();
+            if !own.is_empty() && taker.stp_mode == Some(StpMode::CancelNewest)
+            {
+                return (0, true);
+            }
+            for idx in own {
+                let LimitOrder {
+                    id, side, price, ..
+                } = arena[idx];
+                arena.remove(&id);
+                notify(observer, BookEvent::OrderRemoved { id, side, price });
+            }
+        }
+
+        // The all-or-none orders are filled first in time priority, if the
+        // remaining quantity covers them
+        for idx in opposite_orders.iter().copied() {
+            let maker = &mut arena[idx];
+            if !maker.all_or_none || maker.qty == 0 || maker.qty > qty_to_fill {
+                continue;
+            }
+            let traded_quantity = maker.qty;
+            qty_to_fill -= traded_quantity;
+            maker.qty = 0;
+            maker.visible_qty = 0;
+            maker.queued_qty = 0;
+            let fill = FillMetadata {
+                order_1: taker.id,
+                order_2: maker.id,
+                qty: traded_quantity,
+                price: maker.price,
+                taker_side: taker.side,
+                total_fill: true,
+                taker_tag: taker.tag,
+                maker_tag: maker.tag,
+            };
+            notify(observer, BookEvent::Trade { fill });
+            notify(
+                observer,
+                BookEvent::OrderFilled {
+                    id: maker.id,
+                    side: maker.side,
+                    price: maker.price,
+                    qty: traded_quantity,
+                    remaining_qty: 0,
+                },
+            );
+            notify(
+                observer,
+                BookEvent::OrderRemoved {
+                    id: maker.id,
+                    side: maker.side,
+                    price: maker.price,
+                },
+            );
+            fills.push(fill);
+            filled_qty += traded_quantity;
+        }
+
+        // Each round allocates the remaining quantity across the displayed
+        // slices. A new round only starts if all the slices were consumed, in
+        // which case the replenished orders are moved to the back of the queue.
+        while qty_to_fill > 0 {
+            let makers: Vec<usize> = opposite_orders
+                .iter()
+                .copied()
+                .filter(|idx| arena[*idx].qty > 0 && !arena[*idx].all_or_none)
+                .collect();
+            if makers.is_empty() {
+                break;
+            }
+            let visible: Vec<u64> =
+                makers.iter().map(|idx| arena[*idx].visible_qty).collect();
+            let shares = pro_rata_shares(qty_to_fill, &visible);
+            let mut replenished = Vec::new();
+            for (idx, traded_quantity) in makers.into_iter().zip(shares) {
+                if traded_quantity == 0 {
+                    continue;
+                }
+                let maker = &mut arena[idx];
+                qty_to_fill -= traded_quantity;
+                maker.qty -= traded_quantity;
+                maker.visible_qty -= traded_quantity;
+                maker.queued_qty -= traded_quantity;
+                let filled = maker.qty == 0;
+                let fill = FillMetadata {
+                    order_1: taker.id,
+                    order_2: maker.id,
+                    qty: traded_quantity,
+                    price: maker.price,
+                    taker_side: taker.side,
+                    total_fill: filled,
+                    taker_tag: taker.tag,
+                    maker_tag: maker.tag,
+                };
+                let maker_fill = BookEvent::OrderFilled {
+                    id: maker.id,
+                    side: maker.side,
+                    price: maker.price,
+                    qty: traded_quantity,
+                    remaining_qty: maker.qty,
+                };
+                let removed = BookEvent::OrderRemoved {
+                    id: maker.id,
+                    side: maker.side,
+                    price: maker.price,
+                };
+                if maker.visible_qty == 0 && !filled {
+                    *seq += 1;
+                    maker.seq = *seq;
+                    maker.visible_qty = maker.display_qty.min(maker.qty);
+                    replenished.push(idx);
+                }
+                notify(observer, BookEvent::Trade { fill });
+                notify(observer, maker_fill);
+                if filled {
+                    notify(observer, removed);
+                }
+                fills.push(fill);
+                filled_qty += traded_quantity;
+            }
+            if !replenished.is_empty() {
+                opposite_orders.retain(|idx| !replenished.contains(idx));
+                opposite_orders.extend(replenished);
+            }
+        }
+        opposite_orders.retain(|idx| {
+            let live = arena[*idx].qty > 0;
+            if !live {
+                arena.release(*idx);
+            }
+            live
+        });
+
+        (filled_qty, false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RestingAttrs, MIN_COMPACTION_TOMBSTONES};
+    use crate::{
+        AmendPolicy, BookConfig, BookDelta, BookDepth, BookEvent, BookLevel,
+        BookSummary, CancelReason, DetailedBookLevel, ExecutionError,
+        FillMetadata, FillOrdering, GenericOrderBook, MatchingPolicy,
+        OrderBook, OrderEvent, OrderInfo, OrderType, RejectReason,
+        RoundingMode, Side, StpMode, Trade, TradeStats, UnfilledReason,
+    };
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::rc::Rc;
+
+    const DEFAULT_QUEUE_SIZE: usize = 10;
+    const BID_ASK_COMBINATIONS: [(Side, Side); 2] =
+        [(Side::Bid, Side::Ask), (Side::Ask, Side::Bid)];
+
+    fn init_ob(events: Vec<OrderType>) -> (OrderBook, Vec<OrderEvent>) {
+        let mut ob = OrderBook::default();
+        ob.track_stats(true);
+        let mut results = Vec::new();
+        for e in events {
+            results.push(ob.execute(e));
+        }
+        (ob, results)
+    }
+
+    // Add a resting order to the book without matching it, which allows to
+    // set up crossed books.
+    fn rest(ob: &mut OrderBook, id: u128, side: Side, qty: u64, price: u64) {
+        let index =
+            ob.insert_order(id, side, price, qty, RestingAttrs::default());
+        match side {
+            Side::Bid => ob.bids.entry(price).or_default().push(index),
+            Side::Ask => ob.asks.entry(price).or_default().push(index),
+        }
+        ob.update_min_ask();
+        ob.update_max_bid();
+    }
+
+    fn limit<Id>(id: Id, side: Side, qty: u64, price: u64) -> OrderType<Id> {
+        OrderType::Limit {
+            id,
+            side,
+            qty,
+            price,
+            display_qty: None,
+            owner: None,
+            tag: None,
+            reduce_only: None,
+        }
+    }
+
+    fn init_book(orders: Vec<(u64, usize)>) -> BTreeMap<u64, Vec<usize>> {
+        let mut bk = BTreeMap::new();
+        for (p, i) in orders {
+            bk.entry(p)
+                .or_insert_with(|| Vec::with_capacity(DEFAULT_QUEUE_SIZE))
+                .push(i);
+        }
+        bk
+    }
+
+    fn init_book_holes(
+        orders: Vec<(u64, usize)>,
+        holes: Vec<u64>,
+    ) -> BTreeMap<u64, Vec<usize>> {
+        let mut bk = init_book(orders);
+        for h in holes {
+            bk.insert(h, Vec::new());
+        }
+        bk
+    }
+
+    #[test]
+    fn empty_book() {
+        let (ob, results) = init_ob(Vec::new());
+        assert_eq!(results, Vec::new());
+        assert_eq!(ob.min_ask(), None);
+        assert_eq!(ob.max_bid(), None);
+        assert_eq!(ob._asks(), BTreeMap::new());
+        assert_eq!(ob._bids(), BTreeMap::new());
+        assert_eq!(ob.spread(), None);
+        assert_eq!(ob.traded_volume(), 0);
+        assert_eq!(
+            ob.depth(2),
+            BookDepth {
+                levels: 2,
+                asks: Vec::new(),
+                bids: Vec::new()
+            }
+        );
+        assert_eq!(ob.last_trade(), None);
+    }
+
+    #[test]
+    fn one_resting_order() {
+        for (bid_ask, _) in &BID_ASK_COMBINATIONS {
+            let (ob, results) = init_ob(vec![limit(0, *bid_ask, 12, 395)]);
+            assert_eq!(results, vec![OrderEvent::Placed { id: 0 }]);
+            if *bid_ask == Side::Bid {
+                assert_eq!(ob.min_ask(), None);
+                assert_eq!(ob.max_bid(), Some(395));
+                assert_eq!(ob._asks(), BTreeMap::new());
+                assert_eq!(ob._bids(), init_book(vec![(395, 9999)]));
+                assert_eq!(ob.spread(), None);
+                assert_eq!(ob.traded_volume(), 0);
+                assert_eq!(
+                    ob.depth(3),
+                    BookDepth {
+                        levels: 3,
+                        asks: Vec::new(),
+                        bids: vec![BookLevel {
+                            price: 395,
+                            qty: 12
+                        }],
+                    }
+                );
+                assert_eq!(ob.last_trade(), None);
+            } else {
+                assert_eq!(ob.min_ask(), Some(395));
+                assert_eq!(ob.max_bid(), None);
+                assert_eq!(ob._asks(), init_book(vec![(395, 9999)]));
+                assert_eq!(ob._bids(), BTreeMap::new());
+                assert_eq!(ob.spread(), None);
+                assert_eq!(ob.traded_volume(), 0);
+                assert_eq!(
+                    ob.depth(4),
+                    BookDepth {
+                        levels: 4,
+                        asks: vec![BookLevel {
+                            price: 395,
+                            qty: 12
+                        }],
+                        bids: Vec::new()
+                    }
+                );
+                assert_eq!(ob.last_trade(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn two_resting_orders() {
+        for (bid_ask, ask_bid) in &BID_ASK_COMBINATIONS {
+            let (ob, results) = init_ob(vec![
+                limit(0, *bid_ask, 12, 395),
+                limit(1, *ask_bid, 2, 398),
+            ]);
+            if *bid_ask == Side::Bid {
+                assert_eq!(
+                    results,
+                    vec![
+                        OrderEvent::Placed { id: 0 },
+                        OrderEvent::Placed { id: 1 }
+                    ]
+                );
+                assert_eq!(ob.min_ask(), Some(398));
+                assert_eq!(ob.max_bid(), Some(395));
+                assert_eq!(ob._asks(), init_book(vec![(398, 9998)]));
+                assert_eq!(ob._bids(), init_book(vec![(395, 9999)]));
+                assert_eq!(ob.spread(), Some(3));
+                assert_eq!(ob.traded_volume(), 0);
+                assert_eq!(
+                    ob.depth(4),
+                    BookDepth {
+                        levels: 4,
+                        asks: vec![BookLevel { price: 398, qty: 2 }],
+                        bids: vec![BookLevel {
+                            price: 395,
+                            qty: 12
+                        }],
+                    }
+                );
+                assert_eq!(ob.last_trade(), None);
+            } else {
+                assert_eq!(
+                    results,
+                    vec![
+                        OrderEvent::Placed { id: 0 },
+                        OrderEvent::Filled {
+                            id: 1,
+                            filled_qty: 2,
+                            fills: vec![FillMetadata {
+                                order_1: 1,
+                                order_2: 0,
+                                qty: 2,
+                                price: 395,
+                                taker_side: *ask_bid,
+                                total_fill: false,
+                                taker_tag: None,
+                                maker_tag: None,
+                            }],
+                            trade: Some(Trade {
+                                total_qty: 2,
+                                avg_price: 395.0,
+                                last_qty: 2,
+                                last_price: 395,
+                            }),
+                        }
+                    ]
+                );
+                assert_eq!(ob.min_ask(), Some(395));
+                assert_eq!(ob.max_bid(), None);
+                assert_eq!(ob._asks(), init_book(vec![(395, 9999)]));
+                assert_eq!(ob._bids(), init_book(vec![]));
+                assert_eq!(ob.spread(), None);
+                assert_eq!(ob.traded_volume(), 2);
+                assert_eq!(
+                    ob.depth(4),
+                    BookDepth {
+                        levels: 4,
+                        asks: vec![BookLevel {
+                            price: 395,
+                            qty: 10,
+                        }],
+                        bids: Vec::new(),
+                    }
+                );
+                assert_eq!(
+                    ob.last_trade(),
+                    Some(Trade {
+                        total_qty: 2,
+                        avg_price: 395.0,
+                        last_qty: 2,
+                        last_price: 395,
+                    })
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn two_resting_orders_merged() {
+        for (bid_ask, _) in &BID_ASK_COMBINATIONS {
+            let (ob, results) = init_ob(vec![
+                limit(0, *bid_ask, 12, 395),
+                limit(1, *bid_ask, 2, 395),
+            ]);
+            assert_eq!(
+                results,
+                vec![
+                    OrderEvent::Placed { id: 0 },
+                    OrderEvent::Placed { id: 1 }
+                ]
+            );
+            if *bid_ask == Side::Bid {
+                assert_eq!(ob.min_ask(), None);
+                assert_eq!(ob.max_bid(), Some(395));
+                assert_eq!(ob._asks(), BTreeMap::new());
+                assert_eq!(
+                    ob._bids(),
+                    init_book(vec![(395, 9999), (395, 9998)])
+                );
+                assert_eq!(ob.spread(), None);
+                assert_eq!(ob.traded_volume(), 0);
+                assert_eq!(
+                    ob.depth(3),
+                    BookDepth {
+                        levels: 3,
+                        asks: Vec::new(),
+                        bids: vec![BookLevel {
+                            price: 395,
+                            qty: 14
+                        }],
+                    }
+                );
+                assert_eq!(ob.last_trade(), None);
+            } else {
+                assert_eq!(ob.min_ask(), Some(395));
+                assert_eq!(ob.max_bid(), None);
+                assert_eq!(
+                    ob._asks(),
+                    init_book(vec![(395, 9999), (395, 9998)])
+                );
+                assert_eq!(ob._bids(), BTreeMap::new());
+                assert_eq!(ob.spread(), None);
+                assert_eq!(ob.traded_volume(), 0);
+                assert_eq!(
+                    ob.depth(3),
+                    BookDepth {
+                        levels: 3,
+                        asks: vec![BookLevel {
+                            price: 395,
+                            qty: 14
+                        }],
+                        bids: Vec::new(),
+                    }
+                );
+                assert_eq!(ob.last_trade(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn two_resting_orders_stacked() {
+        for (bid_ask, _) in &BID_ASK_COMBINATIONS {
+            let (ob, results) = init_ob(vec![
+                limit(0, *bid_ask, 12, 395),
+                limit(1, *bid_ask, 2, 398),
+            ]);
+            assert_eq!(
+                results,
+                vec![
+                    OrderEvent::Placed { id: 0 },
+                    OrderEvent::Placed { id: 1 }
+                ]
+            );
+            if *bid_ask == Side::Bid {
+                assert_eq!(ob.min_ask(), None);
+                assert_eq!(ob.max_bid(), Some(398));
+                assert_eq!(ob._asks(), BTreeMap::new());
+                assert_eq!(
+                    ob._bids(),
+                    init_book(vec![(398, 9998), (395, 9999)])
+                );
+                assert_eq!(ob.spread(), None);
+            } else {
+                assert_eq!(ob.min_ask(), Some(395));
+                assert_eq!(ob.max_bid(), None);
+                assert_eq!(
+                    ob._asks(),
+                    init_book(vec![(398, 9998), (395, 9999)])
+                );
+                assert_eq!(ob._bids(), BTreeMap::new());
+                assert_eq!(ob.spread(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn three_resting_orders_stacked() {
+        for (bid_ask, ask_bid) in &BID_ASK_COMBINATIONS {
+            let (ob, results) = init_ob(vec![
+                limit(0, *bid_ask, 12, 395),
+                limit(1, *ask_bid, 2, 399),
+                limit(2, *bid_ask, 2, 398),
+            ]);
+            if *bid_ask == Side::Bid {
+                assert_eq!(
+                    results,
+                    vec![
+                        OrderEvent::Placed { id: 0 },
+                        OrderEvent::Placed { id: 1 },
+                        OrderEvent::Placed { id: 2 }
+                    ]
+                );
+                assert_eq!(ob.min_ask(), Some(399));
+                assert_eq!(ob.max_bid(), Some(398));
+                assert_eq!(ob._asks(), init_book(vec![(399, 9998)]));
+                assert_eq!(
+                    ob._bids(),
+                    init_book(vec![(398, 9997), (395, 9999)])
+                );
+                assert_eq!(ob.spread(), Some(1));
+            } else {
+                assert_eq!(
+                    results,
+                    vec![
+                        OrderEvent::Placed { id: 0 },
+                        OrderEvent::Filled {
+                            id: 1,
+                            filled_qty: 2,
+                            fills: vec![FillMetadata {
+                                order_1: 1,
+                                order_2: 0,
+                                qty: 2,
+                                price: 395,
+                                taker_side: *ask_bid,
+                                total_fill: false,
+                                taker_tag: None,
+                                maker_tag: None,
+                            }],
+                            trade: Some(Trade {
+                                total_qty: 2,
+                                avg_price: 395.0,
+                                last_qty: 2,
+                                last_price: 395,
+                            }),
+                        },
+                        OrderEvent::Placed { id: 2 }
+                    ]
+                );
+                assert_eq!(ob.min_ask(), Some(395));
+                assert_eq!(ob.max_bid(), None);
+                assert_eq!(
+                    ob._asks(),
+                    init_book(vec![(398, 9998), (395, 9999)])
+                );
+                assert_eq!(ob._bids(), init_book(vec![]));
+                assert_eq!(ob.spread(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn crossing_limit_order_partial() {
+        for (bid_ask, ask_bid) in &BID_ASK_COMBINATIONS {
+            let (mut ob, results) = init_ob(vec![
+                limit(0, *bid_ask, 12, 395),
+                limit(1, *ask_bid, 2, 399),
+                limit(2, *bid_ask, 2, 398),
+            ]);
+            let result = ob.execute(limit(3, *ask_bid, 1, 397));
+
+            if *bid_ask == Side::Bid {
+                assert_eq!(
+                    results,
+                    vec![
+                        OrderEvent::Placed { id: 0 },
+                        OrderEvent::Placed { id: 1 },
+                        OrderEvent::Placed { id: 2 }
+                    ]
+                );
+                assert_eq!(
+                    result,
+                    OrderEvent::Filled {
+                        id: 3,
+                        filled_qty: 1,
+                        fills: vec![FillMetadata {
+                            order_1: 3,
+                            order_2: 2,
+                            qty: 1,
+                            price: 398,
+                            taker_side: *ask_bid,
+                            total_fill: false,
+                            taker_tag: None,
+                            maker_tag: None,
+                        }],
+                        trade: Some(Trade {
+                            total_qty: 1,
+                            avg_price: 398.0,
+                            last_qty: 1,
+                            last_price: 398,
+                        }),
+                    }
+                );
+                assert_eq!(ob.min_ask(), Some(399));
+                assert_eq!(ob.max_bid(), Some(398));
+                assert_eq!(ob._asks(), init_book(vec![(399, 9998)]));
+                assert_eq!(
+                    ob._bids(),
+                    init_book(vec![(398, 9997), (395, 9999)])
+                );
+                assert_eq!(ob.spread(), Some(1));
+            } else {
+                assert_eq!(
+                    results,
+                    vec![
+                        OrderEvent::Placed { id: 0 },
+                        OrderEvent::Filled {
+                            id: 1,
+                            filled_qty: 2,
+                            fills: vec![FillMetadata {
+                                order_1: 1,
+                                order_2: 0,
+                                qty: 2,
+                                price: 395,
+                                taker_side: *ask_bid,
+                                total_fill: false,
+                                taker_tag: None,
+                                maker_tag: None,
+                            }],
+                            trade: Some(Trade {
+                                total_qty: 2,
+                                avg_price: 395.0,
+                                last_qty: 2,
+                                last_price: 395,
+                            }),
+                        },
+                        OrderEvent::Placed { id: 2 }
+                    ]
+                );
+                assert_eq!(
+                    result,
+                    OrderEvent::Filled {
+                        id: 3,
+                        filled_qty: 1,
+                        fills: vec![FillMetadata {
+                            order_1: 3,
+                            order_2: 0,
+                            qty: 1,
+                            price: 395,
+                            taker_side: *ask_bid,
+                            total_fill: false,
+                            taker_tag: None,
+                            maker_tag: None,
+                        }],
+                        trade: Some(Trade {
+                            total_qty: 1,
+                            avg_price: 395.0,
+                            last_qty: 1,
+                            last_price: 395,
+                        }),
+                    }
+                );
+                assert_eq!(ob.min_ask(), Some(395));
+                assert_eq!(ob.max_bid(), None);
+                assert_eq!(
+                    ob._asks(),
+                    init_book(vec![(398, 9998), (395, 9999)])
+                );
+                assert_eq!(ob._bids(), init_book(vec![]));
+                assert_eq!(ob.spread(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn crossing_limit_order_matching() {
+        for (bid_ask, ask_bid) in &BID_ASK_COMBINATIONS {
+            let (mut ob, results) = init_ob(vec![
+                limit(0, *bid_ask, 12, 395),
+                limit(1, *ask_bid, 2, 399),
+                limit(2, *bid_ask, 2, 398),
+            ]);
+            let result = ob.execute(limit(3, *ask_bid, 2, 397));
+
+            if *bid_ask == Side::Bid {
+                assert_eq!(
+                    results,
+                    vec![
+                        OrderEvent::Placed { id: 0 },
+                        OrderEvent::Placed { id: 1 },
+                        OrderEvent::Placed { id: 2 }
+                    ]
+                );
+                assert_eq!(
+                    result,
+                    OrderEvent::Filled {
+                        id: 3,
+                        filled_qty: 2,
+                        fills: vec![FillMetadata {
+                            order_1: 3,
+                            order_2: 2,
+                            qty: 2,
+                            price: 398,
+                            taker_side: *ask_bid,
+                            total_fill: true,
+                            taker_tag: None,
+                            maker_tag: None,
+                        }],
+                        trade: Some(Trade {
+                            total_qty: 2,
+                            avg_price: 398.0,
+                            last_qty: 2,
+                            last_price: 398,
+                        }),
+                    }
+                );
+                assert_eq!(ob.min_ask(), Some(399));
+                assert_eq!(ob.max_bid(), Some(395));
+                assert_eq!(ob._asks(), init_book(vec![(399, 9998)]));
+                assert_eq!(
+                    ob._bids(),
+                    init_book_holes(vec![(395, 9999)], vec![398])
+                );
+                assert_eq!(ob.spread(), Some(4));
+            } else {
+                assert_eq!(
+                    results,
+                    vec![
+                        OrderEvent::Placed { id: 0 },
+                        OrderEvent::Filled {
+                            id: 1,
+                            filled_qty: 2,
+                            fills: vec![FillMetadata {
+                                order_1: 1,
+                                order_2: 0,
+                                qty: 2,
+                                price: 395,
+                                taker_side: *ask_bid,
+                                total_fill: false,
+                                taker_tag: None,
+                                maker_tag: None,
+                            }],
+                            trade: Some(Trade {
+                                total_qty: 2,
+                                avg_price: 395.0,
+                                last_qty: 2,
+                                last_price: 395,
+                            }),
+                        },
+                        OrderEvent::Placed { id: 2 }
+                    ]
+                );
+                assert_eq!(
+                    result,
+                    OrderEvent::Filled {
+                        id: 3,
+                        filled_qty: 2,
+                        fills: vec![FillMetadata {
+                            order_1: 3,
+                            order_2: 0,
+                            qty: 2,
+                            price: 395,
+                            taker_side: *ask_bid,
+                            total_fill: false,
+                            taker_tag: None,
+                            maker_tag: None,
+                        }],
+                        trade: Some(Trade {
+                            total_qty: 2,
+                            avg_price: 395.0,
+                            last_qty: 2,
+                            last_price: 395,
+                        }),
+                    }
+                );
+                assert_eq!(ob.min_ask(), Some(395));
+                assert_eq!(ob.max_bid(), None);
+                assert_eq!(
+                    ob._asks(),
+                    init_book(vec![(395, 9999), (398, 9998)])
+                );
+                assert_eq!(ob._bids(), init_book(vec![]));
+                assert_eq!(ob.spread(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn crossing_limit_order_over() {
+        for (bid_ask, ask_bid) in &BID_ASK_COMBINATIONS {
+            let (mut ob, results) = init_ob(vec![
+                limit(0, *bid_ask, 12, 395),
+                limit(1, *ask_bid, 2, 399),
+                limit(2, *bid_ask, 2, 398),
+            ]);
+            let result = ob.execute(limit(3, *ask_bid, 5, 397));
+
+            if *bid_ask == Side::Bid {
+                assert_eq!(
+                    results,
+                    vec![
+                        OrderEvent::Placed { id: 0 },
+                        OrderEvent::Placed { id: 1 },
+                        OrderEvent::Placed { id: 2 }
+                    ]
+                );
+                assert_eq!(
+                    result,
+                    OrderEvent::PartiallyFilled {
+                        id: 3,
+                        filled_qty: 2,
+                        resting_qty: 3,
+                        fills: vec![FillMetadata {
+                            order_1: 3,
+                            order_2: 2,
+                            qty: 2,
+                            price: 398,
+                            taker_side: *ask_bid,
+                            total_fill: true,
+                            taker_tag: None,
+                            maker_tag: None,
+                        }],
+                        trade: Some(Trade {
+                            total_qty: 2,
+                            avg_price: 398.0,
+                            last_qty: 2,
+                            last_price: 398,
+                        }),
+                    }
+                );
+                assert_eq!(ob.min_ask(), Some(397));
+                assert_eq!(ob.max_bid(), Some(395));
+                assert_eq!(
+                    ob._asks(),
+                    init_book(vec![(399, 9998), (397, 9997)])
+                );
+                assert_eq!(
+                    ob._bids(),
+                    init_book_holes(vec![(395, 9999)], vec![398])
+                );
+                assert_eq!(ob.spread(), Some(2));
+            } else {
+                assert_eq!(
+                    results,
+                    vec![
+                        OrderEvent::Placed { id: 0 },
+                        OrderEvent::Filled {
+                            id: 1,
+                            filled_qty: 2,
+                            fills: vec![FillMetadata {
+                                order_1: 1,
+                                order_2: 0,
+                                qty: 2,
+                                price: 395,
+                                taker_side: *ask_bid,
+                                total_fill: false,
+                                taker_tag: None,
+                                maker_tag: None,
+                            }],
+                            trade: Some(Trade {
+                                total_qty: 2,
+                                avg_price: 395.0,
+                                last_qty: 2,
+                                last_price: 395,
+                            }),
+                        },
+                        OrderEvent::Placed { id: 2 }
+                    ]
+                );
+                assert_eq!(
+                    result,
+                    OrderEvent::Filled {
+                        id: 3,
+                        filled_qty: 5,
+                        fills: vec![FillMetadata {
+                            order_1: 3,
+                            order_2: 0,
+                            qty: 5,
+                            price: 395,
+                            taker_side: *ask_bid,
+                            total_fill: false,
+                            taker_tag: None,
+                            maker_tag: None,
+                        }],
+                        trade: Some(Trade {
+                            total_qty: 5,
+                            avg_price: 395.0,
+                            last_qty: 5,
+                            last_price: 395,
+                        }),
+                    }
+                );
+                assert_eq!(ob.min_ask(), Some(395));
+                assert_eq!(ob.max_bid(), None);
+                assert_eq!(
+                    ob._asks(),
+                    init_book(vec![(395, 9999), (398, 9998)])
+                );
+                assert_eq!(ob._bids(), init_book(vec![]));
+                assert_eq!(ob.spread(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn market_order_unfilled() {
+        for (_, ask_bid) in &BID_ASK_COMBINATIONS {
+            let (mut ob, _) = init_ob(vec![]);
+            let result = ob.execute(OrderType::Market {
+                id: 0,
+                side: *ask_bid,
+                qty: 5,
+                tag: None,
+                owner: None,
+                protection_price: None,
+                reduce_only: None,
+            });
+
+            assert_eq!(
+                result,
+                OrderEvent::Unfilled {
+                    id: 0,
+                    reason: UnfilledReason::NoLiquidity,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn market_order_partially_filled() {
+        for (bid_ask, ask_bid) in &BID_ASK_COMBINATIONS {
+            let (mut ob, results) = init_ob(vec![
+                limit(0, *bid_ask, 12, 395),
+                limit(1, *ask_bid, 2, 399),
+                limit(2, *bid_ask, 2, 398),
+            ]);
+            let result = ob.execute(OrderType::Market {
+                id: 3,
+                side: *ask_bid,
+                qty: 15,
+                tag: None,
+                owner: None,
+                protection_price: None,
+                reduce_only: None,
+            });
+
+            if *bid_ask == Side::Bid {
+                assert_eq!(
+                    results,
+                    vec![
+                        OrderEvent::Placed { id: 0 },
+                        OrderEvent::Placed { id: 1 },
+                        OrderEvent::Placed { id: 2 }
+                    ]
+                );
+                assert_eq!(
+                    result,
+                    OrderEvent::PartiallyFilled {
+                        id: 3,
+                        filled_qty: 14,
+                        resting_qty: 0,
+                        fills: vec![
+                            FillMetadata {
+                                order_1: 3,
+                                order_2: 2,
+                                qty: 2,
+                                price: 398,
+                                taker_side: *ask_bid,
+                                total_fill: true,
+                                taker_tag: None,
+                                maker_tag: None,
+                            },
+                            FillMetadata {
+                                order_1: 3,
+                                order_2: 0,
+                                qty: 12,
+                                price: 395,
+                                taker_side: *ask_bid,
+                                total_fill: true,
+                                taker_tag: None,
+                                maker_tag: None,
+                            }
+                        ],
+                        trade: Some(Trade {
+                            total_qty: 14,
+                            avg_price: 395.42857142857144,
+                            last_qty: 12,
+                            last_price: 395,
+                        }),
+                    }
+                );
+                assert_eq!(ob.min_ask(), Some(399));
+                assert_eq!(ob.max_bid(), None);
+                assert_eq!(ob._asks(), init_book(vec![(399, 9998)]));
+                assert_eq!(ob._bids(), init_book_holes(vec![], vec![395, 398]));
+                assert_eq!(ob.spread(), None);
+            } else {
+                assert_eq!(
+                    results,
+                    vec![
+                        OrderEvent::Placed { id: 0 },
+                        OrderEvent::Filled {
+                            id: 1,
+                            filled_qty: 2,
+                            fills: vec![FillMetadata {
+                                order_1: 1,
+                                order_2: 0,
+                                qty: 2,
+                                price: 395,
+                                taker_side: *ask_bid,
+                                total_fill: false,
+                                taker_tag: None,
+                                maker_tag: None,
+                            }],
+                            trade: Some(Trade {
+                                total_qty: 2,
+                                avg_price: 395.0,
+                                last_qty: 2,
+                                last_price: 395,
+                            }),
+                        },
+                        OrderEvent::Placed { id: 2 }
+                    ]
+                );
+                assert_eq!(
+                    result,
+                    OrderEvent::PartiallyFilled {
+                        id: 3,
+                        filled_qty: 12,
+                        resting_qty: 0,
+                        fills: vec![
+                            FillMetadata {
+                                order_1: 3,
+                                order_2: 0,
+                                qty: 10,
+                                price: 395,
+                                taker_side: *ask_bid,
+                                total_fill: true,
+                                taker_tag: None,
+                                maker_tag: None,
+                            },
+                            FillMetadata {
+                                order_1: 3,
+                                order_2: 2,
+                                qty: 2,
+                                price: 398,
+                                taker_side: *ask_bid,
+                                total_fill: true,
+                                taker_tag: None,
+                                maker_tag: None,
+                            }
+                        ],
+                        trade: Some(Trade {
+                            total_qty: 12,
+                            avg_price: 395.5,
+                            last_qty: 2,
+                            last_price: 398,
+                        }),
+                    }
+                );
+                assert_eq!(ob.min_ask(), None);
+                assert_eq!(ob.max_bid(), None);
+                assert_eq!(ob._asks(), init_book_holes(vec![], vec![395, 398]));
+                assert_eq!(ob._bids(), init_book(vec![]));
+                assert_eq!(ob.spread(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn market_order_filled() {
+        for (bid_ask, ask_bid) in &BID_ASK_COMBINATIONS {
+            let (mut ob, results) = init_ob(vec![
+                limit(0, *bid_ask, 12, 395),
+                limit(1, *ask_bid, 2, 399),
+                limit(2, *bid_ask, 2, 398),
+            ]);
+            let result = ob.execute(OrderType::Market {
+                id: 3,
+                side: *ask_bid,
+                qty: 7,
+                tag: None,
+                owner: None,
+                protection_price: None,
+                reduce_only: None,
+            });
+
+            if *bid_ask == Side::Bid {
+                assert_eq!(
+                    results,
+                    vec![
+                        OrderEvent::Placed { id: 0 },
+                        OrderEvent::Placed { id: 1 },
+                        OrderEvent::Placed { id: 2 }
+                    ]
+                );
+                assert_eq!(
+                    result,
+                    OrderEvent::Filled {
+                        id: 3,
+                        filled_qty: 7,
+                        fills: vec![
+                            FillMetadata {
+                                order_1: 3,
+                                order_2: 2,
+                                qty: 2,
+                                price: 398,
+                                taker_side: *ask_bid,
+                                total_fill: true,
+                                taker_tag: None,
+                                maker_tag: None,
+                            },
+                            FillMetadata {
+                                order_1: 3,
+                                order_2: 0,
+                                qty: 5,
+                                price: 395,
+                                taker_side: *ask_bid,
+                                total_fill: false,
+                                taker_tag: None,
+                                maker_tag: None,
+                            }
+                        ],
+                        trade: Some(Trade {
+                            total_qty: 7,
+                            avg_price: 395.85714285714283,
+                            last_qty: 5,
+                            last_price: 395,
+                        }),
+                    }
+                );
+                assert_eq!(ob.min_ask(), Some(399));
+                assert_eq!(ob.max_bid(), Some(395));
+                assert_eq!(ob._asks(), init_book(vec![(399, 9998)]));
+                assert_eq!(
+                    ob._bids(),
+                    init_book_holes(vec![(395, 9999)], vec![398])
+                );
+                assert_eq!(ob.spread(), Some(4));
+            } else {
+                assert_eq!(
+                    results,
+                    vec![
+                        OrderEvent::Placed { id: 0 },
+                        OrderEvent::Filled {
+                            id: 1,
+                            filled_qty: 2,
+                            fills: vec![FillMetadata {
+                                order_1: 1,
+                                order_2: 0,
+                                qty: 2,
+                                price: 395,
+                                taker_side: *ask_bid,
+                                total_fill: false,
+                                taker_tag: None,
+                                maker_tag: None,
+                            }],
+                            trade: Some(Trade {
+                                total_qty: 2,
+                                avg_price: 395.0,
+                                last_qty: 2,
+                                last_price: 395,
+                            }),
+                        },
+                        OrderEvent::Placed { id: 2 }
+                    ]
+                );
+                assert_eq!(
+                    result,
+                    OrderEvent::Filled {
+                        id: 3,
+                        filled_qty: 7,
+                        fills: vec![FillMetadata {
+                            order_1: 3,
+                            order_2: 0,
+                            qty: 7,
+                            price: 395,
+                            taker_side: *ask_bid,
+                            total_fill: false,
+                            taker_tag: None,
+                            maker_tag: None,
+                        }],
+                        trade: Some(Trade {
+                            total_qty: 7,
+                            avg_price: 395.0,
+                            last_qty: 7,
+                            last_price: 395,
+                        }),
+                    }
+                );
+                assert_eq!(ob.min_ask(), Some(395));
+                assert_eq!(ob.max_bid(), None);
+                assert_eq!(
+                    ob._asks(),
+                    init_book(vec![(395, 9999), (398, 9998)])
+                );
+                assert_eq!(ob._bids(), init_book(vec![]));
+                assert_eq!(ob.spread(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn cancel_non_existing_order() {
+        let (mut ob, _) = init_ob(vec![]);
+        let result = ob.execute(OrderType::Cancel { id: 0 });
+        assert_eq!(result, OrderEvent::Canceled { id: 0 });
+        assert_eq!(ob.min_ask(), None);
+        assert_eq!(ob.max_bid(), None);
+        assert_eq!(ob._asks(), BTreeMap::new());
+        assert_eq!(ob._bids(), BTreeMap::new());
+        assert_eq!(ob.spread(), None);
+    }
+
+    #[test]
+    fn cancel_resting_order() {
+        for (bid_ask, _) in &BID_ASK_COMBINATIONS {
+            let (mut ob, results) = init_ob(vec![limit(0, *bid_ask, 12, 395)]);
+            let result = ob.execute(OrderType::Cancel { id: 0 });
+            assert_eq!(results, vec![OrderEvent::Placed { id: 0 }]);
+            assert_eq!(result, OrderEvent::Canceled { id: 0 });
+            assert_eq!(ob.min_ask(), None);
+            assert_eq!(ob.max_bid(), None);
+            if *bid_ask == Side::Bid {
+                assert_eq!(ob._asks(), BTreeMap::new());
+                assert_eq!(ob._bids(), init_book_holes(vec![], vec![395]));
+            } else {
+                assert_eq!(ob._asks(), init_book_holes(vec![], vec![395]));
+                assert_eq!(ob._bids(), BTreeMap::new());
+            }
+            assert_eq!(ob.spread(), None);
+        }
+    }
+
+    #[test]
+    fn cancel_resting_order_of_many() {
+        for (bid_ask, ask_bid) in &BID_ASK_COMBINATIONS {
+            let (mut ob, results) = init_ob(vec![
+                limit(0, *bid_ask, 12, 395),
+                limit(1, *ask_bid, 2, 399),
+                limit(2, *bid_ask, 2, 398),
+            ]);
+            let result = ob.execute(OrderType::Cancel { id: 0 });
+            if *bid_ask == Side::Bid {
+                assert_eq!(
+                    results,
+                    vec![
+                        OrderEvent::Placed { id: 0 },
+                        OrderEvent::Placed { id: 1 },
+                        OrderEvent::Placed { id: 2 }
+                    ]
+                );
+                assert_eq!(result, OrderEvent::Canceled { id: 0 });
+                assert_eq!(ob.min_ask(), Some(399));
+                assert_eq!(ob.max_bid(), Some(398));
+                assert_eq!(ob._asks(), init_book(vec![(399, 9998)]));
+                assert_eq!(
+                    ob._bids(),
+                    init_book_holes(vec![(398, 9997)], vec![395])
+                );
+                assert_eq!(ob.spread(), Some(1));
+            } else {
+                assert_eq!(
+                    results,
+                    vec![
+                        OrderEvent::Placed { id: 0 },
+                        OrderEvent::Filled {
+                            id: 1,
+                            filled_qty: 2,
+                            fills: vec![FillMetadata {
+                                order_1: 1,
+                                order_2: 0,
+                                qty: 2,
+                                price: 395,
+                                taker_side: *ask_bid,
+                                total_fill: false,
+                                taker_tag: None,
+                                maker_tag: None,
+                            }],
+                            trade: Some(Trade {
+                                total_qty: 2,
+                                avg_price: 395.0,
+                                last_qty: 2,
+                                last_price: 395,
+                            }),
+                        },
+                        OrderEvent::Placed { id: 2 }
+                    ]
+                );
+                assert_eq!(result, OrderEvent::Canceled { id: 0 });
+                assert_eq!(ob.min_ask(), Some(398));
+                assert_eq!(ob.max_bid(), None);
+                assert_eq!(
+                    ob._asks(),
+                    init_book_holes(vec![(398, 9998)], vec![395])
+                );
+                assert_eq!(ob._bids(), init_book(vec![]));
+                assert_eq!(ob.spread(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn depth_rounded_to_granularity() {
+        let (mut ob, _) = init_ob(vec![limit(0, Side::Ask, 1234, 395)]);
+        assert_eq!(
+            ob.depth_rounded(1, 100),
+            BookDepth {
+                levels: 1,
+                asks: vec![BookLevel {
+                    price: 395,
+                    qty: 1200
+                }],
+                bids: Vec::new(),
+            }
+        );
+        assert_eq!(ob.depth_rounded(1, 1), ob.depth(1));
+        assert_eq!(ob.depth_rounded(1, 0), ob.depth(1));
+        assert_eq!(
+            ob.execute(OrderType::Market {
+                id: 1,
+                side: Side::Bid,
+                qty: 1234,
+                tag: None,
+                owner: None,
+                protection_price: None,
+                reduce_only: None,
+            }),
+            OrderEvent::Filled {
+                id: 1,
+                filled_qty: 1234,
+                fills: vec![FillMetadata {
+                    order_1: 1,
+                    order_2: 0,
+                    qty: 1234,
+                    price: 395,
+                    taker_side: Side::Bid,
+                    total_fill: true,
+                    taker_tag: None,
+                    maker_tag: None,
+                }],
+                trade: Some(Trade {
+                    total_qty: 1234,
+                    avg_price: 395.0,
+                    last_qty: 1234,
+                    last_price: 395,
+                }),
+            }
+        );
+    }
+
+    #[test]
+    fn vwap_for_qty() {
+        let (ob, _) = init_ob(vec![
+            limit(0, Side::Ask, 2, 100),
+            limit(1, Side::Ask, 2, 103),
+        ]);
+        assert_eq!(ob.vwap_for_qty(Side::Bid, 1), Some(100.0));
+        assert_eq!(ob.vwap_for_qty(Side::Bid, 4), Some(101.5));
+        assert_eq!(ob.vwap_for_qty(Side::Bid, 5), None);
+        assert_eq!(ob.vwap_for_qty(Side::Bid, 0), None);
+        assert_eq!(ob.vwap_for_qty(Side::Ask, 1), None);
+    }
+
+    #[test]
+    fn round_trip_cost() {
+        let (ob, _) = init_ob(vec![
+            limit(0, Side::Bid, 10, 98),
+            limit(1, Side::Ask, 10, 102),
+        ]);
+        assert_eq!(ob.round_trip_cost(5), Some(5 * ob.spread().unwrap()));
+        assert_eq!(ob.round_trip_cost(10), Some(40));
+        assert_eq!(ob.round_trip_cost(11), None);
+        assert_eq!(OrderBook::default().round_trip_cost(1), None);
+    }
+
+    #[test]
+    fn fills_ordered() {
+        let (mut ob, _) = init_ob(vec![
+            limit(3, Side::Bid, 2, 100),
+            limit(1, Side::Bid, 2, 100),
+            limit(2, Side::Bid, 2, 98),
+            limit(0, Side::Bid, 2, 99),
+        ]);
+        let event = ob.execute(OrderType::Market {
+            id: 4,
+            side: Side::Ask,
+            qty: 8,
+            tag: None,
+            owner: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        let key = |fills: Vec<FillMetadata>| -> Vec<(u128, u64)> {
+            fills.iter().map(|fm| (fm.order_2, fm.price)).collect()
+        };
+        assert_eq!(
+            key(event.fills_ordered(FillOrdering::ExecutionTime)),
+            vec![(3, 100), (1, 100), (0, 99), (2, 98)]
+        );
+        assert_eq!(
+            key(event.fills_ordered(FillOrdering::PriceAscending)),
+            vec![(2, 98), (0, 99), (3, 100), (1, 100)]
+        );
+        assert_eq!(
+            key(event.fills_ordered(FillOrdering::PriceDescending)),
+            vec![(3, 100), (1, 100), (0, 99), (2, 98)]
+        );
+        assert_eq!(
+            key(event.fills_ordered(FillOrdering::MakerId)),
+            vec![(0, 99), (1, 100), (2, 98), (3, 100)]
+        );
+        assert_eq!(
+            OrderEvent::Placed { id: 5 }.fills_ordered(FillOrdering::MakerId),
+            Vec::new()
+        );
+    }
+
+    #[test]
+    fn refresh_resting_order() {
+        let (mut ob, _) = init_ob(vec![
+            limit(0, Side::Bid, 2, 395),
+            limit(1, Side::Bid, 3, 395),
+        ]);
+        let seq = ob.order_seq(0).unwrap();
+        assert!(seq < ob.order_seq(1).unwrap());
+        assert_eq!(ob.refresh(0), OrderEvent::Placed { id: 0 });
+        assert!(ob.order_seq(0).unwrap() > ob.order_seq(1).unwrap());
+        assert_eq!(ob._bids(), init_book(vec![(395, 9998), (395, 9999)]));
+        assert_eq!(ob.max_bid(), Some(395));
+        assert_eq!(ob.depth(1).bids, vec![BookLevel { price: 395, qty: 5 }]);
+        let event = ob.execute(OrderType::Market {
+            id: 2,
+            side: Side::Ask,
+            qty: 4,
+            tag: None,
+            owner: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        assert_eq!(
+            event
+                .fills()
+                .iter()
+                .map(|fm| fm.order_2)
+                .collect::<Vec<_>>(),
+            vec![1, 0]
+        );
+        assert_eq!(
+            ob.refresh(7),
+            OrderEvent::Rejected {
+                id: 7,
+                reason: RejectReason::OrderNotFound
+            }
+        );
+    }
+
+    #[test]
+    fn taker_volume_by_side() {
+        let (mut ob, _) = init_ob(vec![
+            limit(0, Side::Ask, 5, 101),
+            limit(1, Side::Bid, 5, 99),
+            OrderType::Market {
+                id: 2,
+                side: Side::Bid,
+                qty: 3,
+                tag: None,
+                owner: None,
+                protection_price: None,
+                reduce_only: None,
+            },
+        ]);
+        assert_eq!(ob.taker_buy_volume(), 3);
+        assert_eq!(ob.taker_sell_volume(), 0);
+        ob.execute(limit(3, Side::Ask, 7, 99));
+        assert_eq!(ob.taker_buy_volume(), 3);
+        assert_eq!(ob.taker_sell_volume(), 5);
+        assert_eq!(
+            ob.taker_buy_volume() + ob.taker_sell_volume(),
+            ob.traded_volume()
+        );
+    }
+
+    #[test]
+    fn trade_print() {
+        let (mut ob, _) = init_ob(vec![
+            limit(0, Side::Ask, 2, 100),
+            limit(1, Side::Ask, 2, 100),
+            limit(2, Side::Ask, 4, 103),
+        ]);
+        let event = ob.execute(limit(3, Side::Bid, 6, 105));
+        assert_eq!(event.fills().len(), 3);
+        let print = event.trade_print().unwrap();
+        assert_eq!(print.total_qty, 6);
+        assert!((print.vwap - 101.0).abs() < 1.0e-6);
+        assert_eq!(print.first_price, 100);
+        assert_eq!(print.last_price, 103);
+        assert!(OrderEvent::Placed { id: 4 }.trade_print().is_none());
+    }
+
+    #[test]
+    fn fully_displayed_order() {
+        let orders = |display_qty| {
+            vec![
+                OrderType::Limit {
+                    id: 0,
+                    side: Side::Ask,
+                    qty: 10,
+                    price: 101,
+                    display_qty,
+                    owner: None,
+                    tag: None,
+                    reduce_only: None,
+                },
+                limit(1, Side::Ask, 4, 101),
+                OrderType::Market {
+                    id: 2,
+                    side: Side::Bid,
+                    qty: 12,
+                    tag: None,
+                    owner: None,
+                    protection_price: None,
+                    reduce_only: None,
+                },
+            ]
+        };
+        let (ob, results) = init_ob(orders(None));
+        let (ob_displayed, results_displayed) = init_ob(orders(Some(10)));
+        assert_eq!(results, results_displayed);
+        assert_eq!(ob.depth(1), ob_displayed.depth(1));
+        assert_eq!(ob._asks(), ob_displayed._asks());
+    }
+
+    #[test]
+    fn partially_displayed_order() {
+        let (mut ob, results) = init_ob(vec![
+            OrderType::Limit {
+                id: 0,
+                side: Side::Ask,
+                qty: 10,
+                price: 101,
+                display_qty: Some(3),
+                owner: None,
+                tag: None,
+                reduce_only: None,
+            },
+            limit(1, Side::Ask, 2, 101),
+            OrderType::Limit {
+                id: 2,
+                side: Side::Ask,
+                qty: 0,
+                price: 102,
+                display_qty: Some(0),
+                owner: None,
+                tag: None,
+                reduce_only: None,
+            },
+            OrderType::Limit {
+                id: 3,
+                side: Side::Ask,
+                qty: 2,
+                price: 102,
+                display_qty: Some(3),
+                owner: None,
+                tag: None,
+                reduce_only: None,
+            },
+        ]);
+        assert_eq!(
+            results[2..],
+            [
+                OrderEvent::Rejected {
+                    id: 2,
+                    reason: RejectReason::InvalidDisplayQty
+                },
+                OrderEvent::Rejected {
+                    id: 3,
+                    reason: RejectReason::InvalidDisplayQty
+                }
+            ]
+        );
+        assert_eq!(ob.depth(1).asks, vec![BookLevel { price: 101, qty: 5 }]);
+        // The first slice is consumed, then the plain order takes priority
+        // over the replenished slice.
+        let event = ob.execute(OrderType::Market {
+            id: 4,
+            side: Side::Bid,
+            qty: 12,
+            tag: None,
+            owner: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        assert_eq!(
+            event.fills(),
+            &[
+                FillMetadata {
+                    order_1: 4,
+                    order_2: 0,
+                    qty: 3,
+                    price: 101,
+                    taker_side: Side::Bid,
+                    total_fill: false,
+                    taker_tag: None,
+                    maker_tag: None,
+                },
+                FillMetadata {
+                    order_1: 4,
+                    order_2: 1,
+                    qty: 2,
+                    price: 101,
+                    taker_side: Side::Bid,
+                    total_fill: true,
+                    taker_tag: None,
+                    maker_tag: None,
+                },
+                FillMetadata {
+                    order_1: 4,
+                    order_2: 0,
+                    qty: 3,
+                    price: 101,
+                    taker_side: Side::Bid,
+                    total_fill: false,
+                    taker_tag: None,
+                    maker_tag: None,
+                },
+                FillMetadata {
+                    order_1: 4,
+                    order_2: 0,
+                    qty: 3,
+                    price: 101,
+                    taker_side: Side::Bid,
+                    total_fill: false,
+                    taker_tag: None,
+                    maker_tag: None,
+                },
+                FillMetadata {
+                    order_1: 4,
+                    order_2: 0,
+                    qty: 1,
+                    price: 101,
+                    taker_side: Side::Bid,
+                    total_fill: true,
+                    taker_tag: None,
+                    maker_tag: None,
+                },
+            ]
+        );
+        assert_eq!(ob.min_ask(), None);
+        assert_eq!(ob.depth(1).asks, Vec::new());
+    }
+
+    #[test]
+    fn touch_queue_len() {
+        let (mut ob, _) = init_ob(vec![
+            limit(0, Side::Bid, 2, 99),
+            limit(1, Side::Bid, 5, 99),
+            limit(2, Side::Bid, 1, 99),
+            limit(3, Side::Bid, 9, 98),
+        ]);
+        assert_eq!(ob.touch_queue_len(Side::Bid), Some(3));
+        assert_eq!(ob.touch_queue_len(Side::Ask), None);
+        ob.execute(OrderType::Market {
+            id: 4,
+            side: Side::Ask,
+            qty: 3,
+            tag: None,
+            owner: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        assert_eq!(ob.touch_queue_len(Side::Bid), Some(2));
+    }
+
+    #[test]
+    fn cancel_all_except() {
+        let (mut ob, _) = init_ob(vec![
+            limit(0, Side::Bid, 2, 99),
+            limit(1, Side::Bid, 5, 98),
+            limit(2, Side::Ask, 1, 101),
+            limit(3, Side::Ask, 9, 103),
+        ]);
+        assert_eq!(
+            ob.cancel_all_except(&[1, 3]),
+            vec![
+                OrderEvent::Canceled { id: 2 },
+                OrderEvent::Canceled { id: 0 }
+            ]
+        );
+        assert_eq!(ob.max_bid(), Some(98));
+        assert_eq!(ob.min_ask(), Some(103));
+        assert_eq!(
+            ob.depth(2),
+            BookDepth {
+                levels: 2,
+                asks: vec![BookLevel { price: 103, qty: 9 }],
+                bids: vec![BookLevel { price: 98, qty: 5 }],
+            }
+        );
+        assert_eq!(ob.cancel_all_except(&[]).len(), 2);
+        assert_eq!(ob.max_bid(), None);
+        assert_eq!(ob.min_ask(), None);
+    }
+
+    #[test]
+    fn levels_to_fill() {
+        let (ob, _) = init_ob(vec![
+            limit(0, Side::Ask, 2, 101),
+            limit(1, Side::Ask, 3, 101),
+            limit(2, Side::Ask, 4, 102),
+        ]);
+        assert_eq!(ob.levels_to_fill(Side::Bid, 0), Some(0));
+        assert_eq!(ob.levels_to_fill(Side::Bid, 4), Some(1));
+        assert_eq!(ob.levels_to_fill(Side::Bid, 5), Some(1));
+        assert_eq!(ob.levels_to_fill(Side::Bid, 9), Some(2));
+        assert_eq!(ob.levels_to_fill(Side::Bid, 10), None);
+        assert_eq!(ob.levels_to_fill(Side::Ask, 1), None);
+    }
+
+    #[test]
+    fn expired_order_system_canceled() {
+        let (mut ob, results) = init_ob(vec![
+            OrderType::GoodTillTime {
+                id: 0,
+                side: Side::Bid,
+                qty: 2,
+                price: 99,
+                expires_at: 10,
+            },
+            limit(1, Side::Bid, 5, 98),
+            OrderType::GoodTillTime {
+                id: 2,
+                side: Side::Bid,
+                qty: 5,
+                price: 97,
+                expires_at: 20,
+            },
+        ]);
+        assert_eq!(results[0], OrderEvent::Placed { id: 0 });
+        assert_eq!(ob.set_time(9), Vec::new());
+        assert_eq!(ob.max_bid(), Some(99));
+        assert_eq!(
+            ob.set_time(10),
+            vec![OrderEvent::SystemCanceled {
+                id: 0,
+                reason: CancelReason::Expired
+            }]
+        );
+        assert_eq!(ob.time(), 10);
+        assert_eq!(ob.max_bid(), Some(98));
+        assert_eq!(
+            ob.execute(OrderType::Cancel { id: 1 }),
+            OrderEvent::Canceled { id: 1 }
+        );
+        assert_eq!(ob.max_bid(), Some(97));
+        assert_eq!(
+            ob.set_time(30),
+            vec![OrderEvent::SystemCanceled {
+                id: 2,
+                reason: CancelReason::Expired
+            }]
+        );
+        assert_eq!(ob.max_bid(), None);
+    }
+
+    #[test]
+    fn removed_order_does_not_expire() {
+        let gtt = |id, expires_at| OrderType::GoodTillTime {
+            id,
+            side: Side::Bid,
+            qty: 2,
+            price: 99,
+            expires_at,
+        };
+        let bid = |id| limit(id, Side::Bid, 5, 98);
+        let mut ob = OrderBook::default();
+        ob.execute(gtt(1, 10));
+        ob.execute(OrderType::Cancel { id: 1 });
+        ob.execute(bid(1));
+        assert!(ob.snapshot().expiries.is_empty());
+        assert_eq!(ob.set_time(10), Vec::new());
+        assert_eq!(ob.order(1).map(|o| o.qty), Some(5));
+
+        // Filled, then the ID is reused
+        ob.execute(gtt(2, 20));
+        ob.execute(OrderType::Market {
+            id: 3,
+            side: Side::Ask,
+            qty: 2,
+            owner: None,
+            tag: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        ob.execute(bid(2));
+        assert_eq!(ob.set_time(20), Vec::new());
+        assert_eq!(ob.order(2).map(|o| o.qty), Some(5));
+
+        // An amended order keeps its expiry
+        ob.execute(gtt(4, 30));
+        ob.execute(OrderType::Amend {
+            id: 4,
+            new_qty: 3,
+            new_price: 97,
+        });
+        assert_eq!(ob.snapshot().expiries, vec![(30, 4)]);
+        assert_eq!(ob.cancel_level(Side::Bid, 98), vec![1, 2]);
+        assert_eq!(
+            ob.set_time(30),
+            vec![OrderEvent::SystemCanceled {
+                id: 4,
+                reason: CancelReason::Expired
+            }]
+        );
+        assert!(ob.snapshot().expiries.is_empty());
+    }
+
+    #[test]
+    fn weighted_spread() {
+        let mut orders = Vec::new();
+        for (id, (side, qty, price)) in [
+            (Side::Bid, 1, 99),
+            (Side::Bid, 2, 98),
+            (Side::Bid, 1, 96),
+            (Side::Ask, 1, 101),
+            (Side::Ask, 1, 102),
+            (Side::Ask, 2, 104),
+        ]
+        .iter()
+        .enumerate()
+        {
+            orders.push(limit(id as u128, *side, *qty, *price));
+        }
+        let (ob, _) = init_ob(orders);
+        assert_eq!(ob.weighted_price(Side::Bid, 3), Some(97.75));
+        assert_eq!(ob.weighted_price(Side::Ask, 3), Some(102.75));
+        assert_eq!(ob.weighted_spread(3), Some(5.0));
+        assert_eq!(ob.weighted_spread(1), Some(2.0));
+        assert_eq!(ob.spread(), Some(2));
+        assert_eq!(ob.weighted_spread(4), None);
+        assert_eq!(ob.weighted_spread(0), None);
+    }
+
+    #[test]
+    fn reject_callback() {
+        let rejected = Rc::new(RefCell::new(Vec::new()));
+        let mut ob = OrderBook::default();
+        ob.set_tick_size(5);
+        let sink = Rc::clone(&rejected);
+        ob.on_reject(move |order, reason| {
+            sink.borrow_mut().push((order.id(), reason))
+        });
+        let orders = [
+            limit(0, Side::Bid, 2, 397),
+            limit(1, Side::Bid, 2, 395),
+            limit(1, Side::Bid, 3, 390),
+            OrderType::Market {
+                id: 1,
+                side: Side::Ask,
+                qty: 1,
+                tag: None,
+                owner: None,
+                protection_price: None,
+                reduce_only: None,
+            },
+            OrderType::GoodTillTime {
+                id: 2,
+                side: Side::Ask,
+                qty: 1,
+                price: 401,
+                expires_at: 10,
+            },
+        ];
+        let results: Vec<_> = orders.iter().map(|o| ob.execute(*o)).collect();
+        assert_eq!(
+            results,
+            vec![
+                OrderEvent::Rejected {
+                    id: 0,
+                    reason: RejectReason::InvalidTick
+                },
+                OrderEvent::Placed { id: 1 },
+                OrderEvent::Rejected {
+                    id: 1,
+                    reason: RejectReason::DuplicateId
+                },
+                OrderEvent::Rejected {
+                    id: 1,
+                    reason: RejectReason::DuplicateId
+                },
+                OrderEvent::Rejected {
+                    id: 2,
+                    reason: RejectReason::InvalidTick
+                },
+            ]
+        );
+        assert_eq!(
+            *rejected.borrow(),
+            vec![
+                (0, RejectReason::InvalidTick),
+                (1, RejectReason::DuplicateId),
+                (1, RejectReason::DuplicateId),
+                (2, RejectReason::InvalidTick),
+            ]
+        );
+        assert_eq!(ob.depth(1).bids, vec![BookLevel { price: 395, qty: 2 }]);
+    }
+
+    #[test]
+    fn can_fill() {
+        for (bid_ask, ask_bid) in &BID_ASK_COMBINATIONS {
+            let (ob, _) = init_ob(vec![
+                limit(0, *ask_bid, 3, 100),
+                OrderType::Limit {
+                    id: 1,
+                    side: *ask_bid,
+                    qty: 4,
+                    price: if *ask_bid == Side::Ask { 102 } else { 98 },
+                    display_qty: None,
+                    owner: None,
+                    tag: None,
+                    reduce_only: None,
+                },
+            ]);
+            assert!(ob.can_fill(*bid_ask, 6, None));
+            assert!(ob.can_fill(*bid_ask, 7, None));
+            assert!(!ob.can_fill(*bid_ask, 8, None));
+            assert!(ob.can_fill(*bid_ask, 3, Some(100)));
+            assert!(!ob.can_fill(*bid_ask, 4, Some(100)));
+            assert!(!ob.can_fill(*ask_bid, 1, None));
+        }
+    }
+
+    #[test]
+    fn clearing_qty() {
+        let (mut ob, _) = init_ob(vec![
+            limit(0, Side::Ask, 3, 100),
+            limit(1, Side::Ask, 4, 102),
+            OrderType::Limit {
+                id: 3,
+                side: Side::Ask,
+                qty: 100,
+                price: 103,
+                display_qty: Some(10),
+                owner: None,
+                tag: None,
+                reduce_only: None,
+            },
+        ]);
+        assert_eq!(ob.ask_volume(), 17);
+        assert_eq!(ob.bid_volume(), 0);
+        assert_eq!(ob.clearing_qty(Side::Bid), 107);
+        assert_eq!(ob.clearing_qty(Side::Ask), 0);
+        let event = ob.execute(OrderType::Market {
+            id: 2,
+            side: Side::Bid,
+            qty: ob.clearing_qty(Side::Bid),
+            tag: None,
+            owner: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        assert!(matches!(event, OrderEvent::Filled { .. }));
+        assert_eq!(ob.clearing_qty(Side::Bid), 0);
+    }
+
+    #[test]
+    fn deterministic_arena() {
+        let layout = |deterministic: bool, cancels: [u128; 2]| {
+            let mut ob = OrderBook::default();
+            ob.deterministic_arena(deterministic);
+            for id in 0..3 {
+                ob.execute(limit(id, Side::Bid, 1, 100 + id as u64));
+            }
+            for id in &cancels {
+                ob.execute(OrderType::Cancel { id: *id });
+            }
+            for id in 3..5 {
+                ob.execute(limit(id, Side::Bid, 1, 100 + id as u64));
+            }
+            ob._bids()
+        };
+        assert_ne!(layout(false, [0, 1]), layout(false, [1, 0]));
+        assert_eq!(layout(true, [0, 1]), layout(true, [1, 0]));
+        assert_eq!(
+            layout(true, [0, 1]),
+            init_book_holes(vec![(102, 2), (103, 0), (104, 1)], vec![100, 101])
+        );
+    }
+
+    #[test]
+    fn price_band() {
+        let mut ob = OrderBook::default();
+        ob.set_price_band(Some(10));
+        let bid = |id, price| limit(id, Side::Bid, 1, price);
+        assert_eq!(ob.execute(bid(0, 50)), OrderEvent::Placed { id: 0 });
+        ob.set_price_reference(Some(100));
+        let outside = OrderEvent::Rejected {
+            id: 1,
+            reason: RejectReason::PriceOutsideBand,
+        };
+        assert_eq!(ob.execute(bid(1, 50)), outside);
+        assert_eq!(ob.execute(bid(1, 89)), outside);
+        assert_eq!(ob.execute(bid(1, 111)), outside);
+        assert_eq!(ob.execute(bid(1, 90)), OrderEvent::Placed { id: 1 });
+        assert_eq!(ob.execute(bid(2, 110)), OrderEvent::Placed { id: 2 });
+        ob.set_price_reference(Some(60));
+        assert_eq!(
+            ob.execute(bid(3, 90)),
+            OrderEvent::Rejected {
+                id: 3,
+                reason: RejectReason::PriceOutsideBand
+            }
+        );
+        assert_eq!(ob.execute(bid(3, 55)), OrderEvent::Placed { id: 3 });
+    }
+
+    #[test]
+    fn export_tape() {
+        let (mut ob, _) = init_ob(vec![
+            limit(0, Side::Ask, 10, 100),
+            limit(1, Side::Ask, 10, 102),
+        ]);
+        ob.set_tape_capacity(2);
+        let mut trades = Vec::new();
+        for (id, qty) in [(2, 4), (3, 8), (4, 2)].iter() {
+            ob.execute(OrderType::Market {
+                id: *id,
+                side: Side::Bid,
+                qty: *qty,
+                tag: None,
+                owner: None,
+                protection_price: None,
+                reduce_only: None,
+            });
+            trades.push(ob.last_trade().unwrap());
+        }
+        assert_eq!(ob.export_tape(), trades[1..].to_vec());
+        assert!(ob.trades().eq(trades[1..].iter().copied()));
+
+        let stats = TradeStats::from_trades(&ob.export_tape()).unwrap();
+        assert_eq!(stats.count, 2);
+        assert_eq!(stats.total_qty, 10);
+        assert!((stats.vwap - 100.8).abs() < 1.0e-6);
+        assert_eq!(stats.last_price, 102);
+        assert!(TradeStats::from_trades(&[]).is_none());
+
+        ob.set_tape_capacity(0);
+        assert_eq!(ob.export_tape(), Vec::new());
+    }
+
+    #[test]
+    fn merge_same_price_fills() {
+        let (mut ob, _) = init_ob(vec![
+            limit(0, Side::Ask, 2, 100),
+            limit(1, Side::Ask, 3, 100),
+            limit(2, Side::Ask, 4, 100),
+            limit(3, Side::Ask, 4, 101),
+        ]);
+        let event = ob.execute(OrderType::Market {
+            id: 4,
+            side: Side::Bid,
+            qty: 8,
+            tag: None,
+            owner: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        assert_eq!(event.fills().len(), 3);
+        assert_eq!(
+            event.merge_same_price_fills(),
+            OrderEvent::Filled {
+                id: 4,
+                filled_qty: 8,
+                fills: vec![FillMetadata {
+                    order_1: 4,
+                    order_2: 0,
+                    qty: 8,
+                    price: 100,
+                    taker_side: Side::Bid,
+                    total_fill: false,
+                    taker_tag: None,
+                    maker_tag: None,
+                }],
+                trade: event.trade(),
+            }
+        );
+        let event = ob.execute(OrderType::Market {
+            id: 5,
+            side: Side::Bid,
+            qty: 5,
+            tag: None,
+            owner: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        assert_eq!(event.merge_same_price_fills(), event);
+        assert_eq!(
+            OrderEvent::Placed { id: 6 }.merge_same_price_fills(),
+            OrderEvent::Placed { id: 6 }
+        );
+    }
+
+    #[test]
+    fn best_excluding_owner() {
+        let (ob, _) = init_ob(vec![
+            OrderType::Limit {
+                id: 0,
+                side: Side::Bid,
+                qty: 2,
+                price: 100,
+                display_qty: None,
+                owner: Some(7),
+                tag: None,
+                reduce_only: None,
+            },
+            OrderType::Limit {
+                id: 1,
+                side: Side::Bid,
+                qty: 2,
+                price: 99,
+                display_qty: None,
+                owner: Some(7),
+                tag: None,
+                reduce_only: None,
+            },
+            limit(2, Side::Bid, 2, 98),
+            OrderType::Limit {
+                id: 3,
+                side: Side::Bid,
+                qty: 2,
+                price: 97,
+                display_qty: None,
+                owner: Some(8),
+                tag: None,
+                reduce_only: None,
+            },
+        ]);
+        assert_eq!(ob.max_bid(), Some(100));
+        assert_eq!(ob.best_excluding_owner(Side::Bid, 7), Some(98));
+        assert_eq!(ob.best_excluding_owner(Side::Bid, 8), Some(100));
+        assert_eq!(ob.best_excluding_owner(Side::Ask, 7), None);
+    }
+
+    #[test]
+    fn slippage() {
+        let (mut ob, _) = init_ob(vec![
+            limit(0, Side::Ask, 2, 101),
+            limit(1, Side::Ask, 2, 104),
+        ]);
+        let event = ob.execute(OrderType::Market {
+            id: 2,
+            side: Side::Bid,
+            qty: 4,
+            tag: None,
+            owner: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        assert_eq!(event.slippage(100), Some(2.5));
+        assert_eq!(event.slippage(105), Some(-2.5));
+        let event = ob.execute(OrderType::Market {
+            id: 3,
+            side: Side::Ask,
+            qty: 4,
+            tag: None,
+            owner: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        assert_eq!(event.slippage(100), None);
+    }
+
+    #[test]
+    fn cached_touch_qty() {
+        let mut ob = OrderBook::default();
+        let check = |ob: &OrderBook| {
+            assert_eq!(ob.best_ask(), ob.side_levels(Side::Ask).next());
+            assert_eq!(ob.best_bid(), ob.side_levels(Side::Bid).next());
+        };
+        check(&ob);
+        for (id, (side, qty, price, display_qty)) in [
+            (Side::Ask, 5, 102, None),
+            (Side::Ask, 3, 102, Some(1)),
+            (Side::Ask, 4, 101, None),
+            (Side::Bid, 2, 98, None),
+            (Side::Bid, 6, 99, None),
+            (Side::Bid, 1, 99, None),
+            (Side::Bid, 7, 101, None),
+            (Side::Ask, 9, 99, None),
+            (Side::Bid, 3, 102, None),
+        ]
+        .iter()
+        .enumerate()
+        {
+            ob.execute(OrderType::Limit {
+                id: id as u128,
+                side: *side,
+                qty: *qty,
+                price: *price,
+                display_qty: *display_qty,
+                owner: None,
+                tag: None,
+                reduce_only: None,
+            });
+            check(&ob);
+        }
+        for cancel in &[0, 3, 1, 7] {
+            ob.execute(OrderType::Cancel { id: *cancel });
+            check(&ob);
+        }
+    }
+
+    #[test]
+    fn crossed_pairs() {
+        let mut ob = OrderBook::default();
+        rest(&mut ob, 0, Side::Bid, 5, 100);
+        rest(&mut ob, 1, Side::Ask, 5, 105);
+        assert_eq!(ob.crossed_pairs(), Vec::new());
+        rest(&mut ob, 2, Side::Bid, 4, 103);
+        rest(&mut ob, 3, Side::Bid, 2, 102);
+        rest(&mut ob, 4, Side::Ask, 3, 101);
+        rest(&mut ob, 5, Side::Ask, 2, 102);
+        rest(&mut ob, 6, Side::Ask, 6, 103);
+        assert_eq!(ob.crossed_pairs(), vec![(2, 4, 3), (2, 5, 1), (3, 5, 1)]);
+        assert_eq!(ob.max_bid(), Some(103));
+        assert_eq!(ob.min_ask(), Some(101));
+    }
+
+    #[test]
+    fn rounded_mid_and_micro_price() {
+        let mut ob = OrderBook::default();
+        assert_eq!(ob.mid_price_rounded(RoundingMode::Floor), None);
+        assert_eq!(ob.micro_price_rounded(RoundingMode::Floor), None);
+        rest(&mut ob, 0, Side::Bid, 1, 100);
+        rest(&mut ob, 1, Side::Ask, 3, 101);
+        assert_eq!(ob.mid_price(), Some(100.5));
+        assert_eq!(ob.mid_price_rounded(RoundingMode::Floor), Some(100));
+        assert_eq!(ob.mid_price_rounded(RoundingMode::Ceil), Some(101));
+        assert_eq!(ob.mid_price_rounded(RoundingMode::Nearest), Some(101));
+        assert_eq!(ob.mid_price_rounded(RoundingMode::NearestEven), Some(100));
+        assert_eq!(ob.micro_price(), Some(100.25));
+        assert_eq!(ob.micro_price_rounded(RoundingMode::Floor), Some(100));
+        assert_eq!(ob.micro_price_rounded(RoundingMode::Ceil), Some(101));
+        assert_eq!(ob.micro_price_rounded(RoundingMode::Nearest), Some(100));
+
+        let mut ob = OrderBook::default();
+        ob.set_tick_size(5);
+        rest(&mut ob, 0, Side::Bid, 3, 100);
+        rest(&mut ob, 1, Side::Ask, 1, 110);
+        assert_eq!(ob.micro_price(), Some(107.5));
+        assert_eq!(ob.micro_price_rounded(RoundingMode::Floor), Some(105));
+        assert_eq!(ob.micro_price_rounded(RoundingMode::Ceil), Some(110));
+        assert_eq!(
+            ob.micro_price_rounded(RoundingMode::NearestEven),
+            Some(110)
+        );
+        assert_eq!(ob.mid_price_rounded(RoundingMode::Nearest), Some(105));
+    }
+
+    #[test]
+    fn config() {
+        assert_eq!(
+            OrderBook::default().config(),
+            BookConfig {
+                arena_capacity: 10_000,
+                queue_capacity: 10,
+                track_stats: false,
+                tape_capacity: 0,
+                deterministic_arena: false,
+                tick_size: 1,
+                price_reference: None,
+                price_band: None,
+                stp_mode: None,
+                price_offset: 0,
+                matching_policy: MatchingPolicy::Fifo,
+                amend_policy: AmendPolicy::AlwaysLosePriorityOnIncrease,
+                replace_if_missing: false,
+            }
+        );
+
+        let mut ob = OrderBook::new(16, 4, true);
+        ob.set_tape_capacity(100);
+        ob.deterministic_arena(true);
+        ob.set_tick_size(5);
+        ob.set_price_reference(Some(1000));
+        ob.set_price_band(Some(50));
+        ob.set_stp_mode(Some(StpMode::CancelResting));
+        assert_eq!(
+            ob.config(),
+            BookConfig {
+                arena_capacity: 16,
+                queue_capacity: 4,
+                track_stats: true,
+                tape_capacity: 100,
+                deterministic_arena: true,
+                tick_size: 5,
+                price_reference: Some(1000),
+                price_band: Some(50),
+                stp_mode: Some(StpMode::CancelResting),
+                price_offset: 0,
+                matching_policy: MatchingPolicy::Fifo,
+                amend_policy: AmendPolicy::AlwaysLosePriorityOnIncrease,
+                replace_if_missing: false,
+            }
+        );
+    }
+
+    #[test]
+    fn order_lookup() {
+        let mut ob = OrderBook::default();
+        ob.execute(OrderType::Limit {
+            id: 0,
+            side: Side::Bid,
+            qty: 10,
+            price: 99,
+            display_qty: None,
+            owner: Some(7),
+            tag: None,
+            reduce_only: None,
+        });
+        ob.execute(limit(1, Side::Ask, 5, 101));
+        ob.execute(OrderType::Limit {
+            id: 2,
+            side: Side::Ask,
+            qty: 8,
+            price: 102,
+            display_qty: Some(2),
+            owner: None,
+            tag: None,
+            reduce_only: None,
+        });
+        ob.execute(OrderType::Market {
+            id: 3,
+            side: Side::Bid,
+            qty: 7,
+            tag: None,
+            owner: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+
+        assert_eq!(
+            ob.order(0),
+            Some(OrderInfo {
+                id: 0,
+                side: Side::Bid,
+                price: 99,
+                qty: 10,
+                display_qty: 10,
+                visible_qty: 10,
+                seq: 1,
+                owner: Some(7),
+                tag: None,
+                all_or_none: false,
+            })
+        );
+        assert_eq!(ob.order(1), None);
+        assert_eq!(
+            ob.order(2),
+            Some(OrderInfo {
+                id: 2,
+                side: Side::Ask,
+                price: 102,
+                qty: 6,
+                display_qty: 2,
+                visible_qty: 2,
+                seq: 4,
+                owner: None,
+                tag: None,
+                all_or_none: false,
+            })
+        );
+        assert_eq!(ob.order(3), None);
+        assert_eq!(ob.order(4), None);
+    }
+
+    #[test]
+    fn cancel_many() {
+        let (mut ob, _) = init_ob(vec![
+            limit(0, Side::Ask, 3, 101),
+            limit(1, Side::Ask, 4, 102),
+            limit(2, Side::Bid, 5, 99),
+            limit(3, Side::Bid, 6, 98),
+        ]);
+        assert_eq!(
+            ob.cancel_many(&[0, 7, 2, 0]),
+            vec![
+                OrderEvent::Canceled { id: 0 },
+                OrderEvent::Rejected {
+                    id: 7,
+                    reason: RejectReason::OrderNotFound
+                },
+                OrderEvent::Canceled { id: 2 },
+                OrderEvent::Rejected {
+                    id: 0,
+                    reason: RejectReason::OrderNotFound
+                },
+            ]
+        );
+        assert_eq!(ob.min_ask(), Some(102));
+        assert_eq!(ob.max_bid(), Some(98));
+        assert_eq!(ob.best_ask(), Some(BookLevel { price: 102, qty: 4 }));
+        assert_eq!(ob.best_bid(), Some(BookLevel { price: 98, qty: 6 }));
+        assert_eq!(ob.order(1).map(|o| o.qty), Some(4));
+        assert_eq!(ob.order(3).map(|o| o.qty), Some(6));
+    }
+
+    #[test]
+    fn try_execute_cancel() {
+        let (mut ob, _) = init_ob(vec![
+            limit(0, Side::Ask, 3, 101),
+            limit(1, Side::Ask, 4, 102),
+            OrderType::Market {
+                id: 2,
+                side: Side::Bid,
+                qty: 3,
+                tag: None,
+                owner: None,
+                protection_price: None,
+                reduce_only: None,
+            },
+        ]);
+        assert_eq!(
+            ob.try_execute(OrderType::Cancel { id: 9 }),
+            Err(ExecutionError::OrderNotFound)
+        );
+        assert_eq!(
+            ob.try_execute(OrderType::Cancel { id: 0 }),
+            Err(ExecutionError::OrderNotFound)
+        );
+        assert_eq!(
+            ob.try_execute(OrderType::Cancel { id: 1 }),
+            Ok(OrderEvent::Canceled { id: 1 })
+        );
+        assert_eq!(
+            ob.try_execute(OrderType::Cancel { id: 1 }),
+            Err(ExecutionError::OrderNotFound)
+        );
+        assert_eq!(ob.min_ask(), None);
+        assert_eq!(
+            ob.execute(OrderType::Cancel { id: 9 }),
+            OrderEvent::Canceled { id: 9 }
+        );
+    }
+
+    #[test]
+    fn depth_after_sweep() {
+        let (ob, _) = init_ob(vec![
+            limit(0, Side::Ask, 3, 101),
+            limit(1, Side::Ask, 4, 102),
+            limit(2, Side::Ask, 5, 103),
+            limit(3, Side::Bid, 6, 99),
+        ]);
+        assert_eq!(
+            ob.depth_after_sweep(Side::Bid, 3, 2),
+            BookDepth {
+                levels: 2,
+                asks: vec![
+                    BookLevel { price: 102, qty: 4 },
+                    BookLevel { price: 103, qty: 5 },
+                ],
+                bids: vec![BookLevel { price: 99, qty: 6 }],
+            }
+        );
+        assert_eq!(
+            ob.depth_after_sweep(Side::Bid, 5, 5).asks,
+            vec![
+                BookLevel { price: 102, qty: 2 },
+                BookLevel { price: 103, qty: 5 },
+            ]
+        );
+        assert_eq!(
+            ob.depth_after_sweep(Side::Ask, 10, 5),
+            BookDepth {
+                levels: 5,
+                asks: vec![
+                    BookLevel { price: 101, qty: 3 },
+                    BookLevel { price: 102, qty: 4 },
+                    BookLevel { price: 103, qty: 5 },
+                ],
+                bids: vec![],
+            }
+        );
+        assert_eq!(ob.min_ask(), Some(101));
+    }
+
+    #[test]
+    fn amend_priority() {
+        let ask = |id, qty, price| limit(id, Side::Ask, qty, price);
+        let (mut ob, _) =
+            init_ob(vec![ask(0, 5, 101), ask(1, 5, 101), ask(2, 5, 102)]);
+        let makers = |event: OrderEvent| {
+            event
+                .fills()
+                .iter()
+                .map(|fm| (fm.order_2, fm.qty))
+                .collect::<Vec<_>>()
+        };
+
+        // Reducing the quantity keeps the queue position
+        assert_eq!(
+            ob.execute(OrderType::Amend {
+                id: 0,
+                new_qty: 3,
+                new_price: 101
+            }),
+            OrderEvent::Amended { id: 0 }
+        );
+        assert_eq!(ob.best_ask(), Some(BookLevel { price: 101, qty: 8 }));
+        let event = ob.execute(OrderType::Market {
+            id: 3,
+            side: Side::Bid,
+            qty: 4,
+            tag: None,
+            owner: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        assert_eq!(makers(event), vec![(0, 3), (1, 1)]);
+
+        // Changing the price moves the order to the back of the new level
+        assert_eq!(
+            ob.execute(OrderType::Amend {
+                id: 1,
+                new_qty: 4,
+                new_price: 102
+            }),
+            OrderEvent::Amended { id: 1 }
+        );
+        assert_eq!(ob.min_ask(), Some(102));
+        let event = ob.execute(OrderType::Market {
+            id: 4,
+            side: Side::Bid,
+            qty: 6,
+            tag: None,
+            owner: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        assert_eq!(makers(event), vec![(2, 5), (1, 1)]);
+
+        // Increasing the quantity also loses the time priority
+        let (mut ob, _) = init_ob(vec![ask(0, 5, 101), ask(1, 5, 101)]);
+        ob.execute(OrderType::Amend {
+            id: 0,
+            new_qty: 6,
+            new_price: 101,
+        });
+        let event = ob.execute(OrderType::Market {
+            id: 2,
+            side: Side::Bid,
+            qty: 6,
+            tag: None,
+            owner: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        assert_eq!(makers(event), vec![(1, 5), (0, 1)]);
+
+        assert_eq!(
+            ob.execute(OrderType::Amend {
+                id: 7,
+                new_qty: 1,
+                new_price: 101
+            }),
+            OrderEvent::Rejected {
+                id: 7,
+                reason: RejectReason::OrderNotFound
+            }
+        );
+        assert_eq!(
+            ob.execute(OrderType::Amend {
+                id: 0,
+                new_qty: 0,
+                new_price: 101
+            }),
+            OrderEvent::Rejected {
+                id: 0,
+                reason: RejectReason::InvalidQty
+            }
+        );
+    }
+
+    #[test]
+    fn amend_crossing() {
+        let (mut ob, _) = init_ob(vec![
+            limit(0, Side::Ask, 3, 101),
+            limit(1, Side::Bid, 5, 99),
+        ]);
+        let event = ob.execute(OrderType::Amend {
+            id: 1,
+            new_qty: 5,
+            new_price: 101,
+        });
+        assert_eq!(
+            event,
+            OrderEvent::PartiallyFilled {
+                id: 1,
+                filled_qty: 3,
+                resting_qty: 2,
+                fills: vec![FillMetadata {
+                    order_1: 1,
+                    order_2: 0,
+                    qty: 3,
+                    price: 101,
+                    taker_side: Side::Bid,
+                    total_fill: true,
+                    taker_tag: None,
+                    maker_tag: None,
+                }],
+                trade: Some(Trade {
+                    total_qty: 3,
+                    avg_price: 101.0,
+                    last_qty: 3,
+                    last_price: 101,
+                }),
+            }
+        );
+        assert_eq!(ob.min_ask(), None);
+        assert_eq!(ob.best_bid(), Some(BookLevel { price: 101, qty: 2 }));
+    }
+
+    #[test]
+    fn fill_tags() {
+        let (mut ob, _) = init_ob(vec![
+            OrderType::Limit {
+                id: 0,
+                side: Side::Ask,
+                qty: 2,
+                price: 101,
+                display_qty: None,
+                owner: None,
+                tag: Some(10),
+                reduce_only: None,
+            },
+            limit(1, Side::Ask, 2, 102),
+            OrderType::Limit {
+                id: 2,
+                side: Side::Bid,
+                qty: 2,
+                price: 99,
+                display_qty: None,
+                owner: None,
+                tag: Some(20),
+                reduce_only: None,
+            },
+        ]);
+        let tags = |event: OrderEvent| {
+            event
+                .fills()
+                .iter()
+                .map(|fm| (fm.order_2, fm.taker_tag, fm.maker_tag))
+                .collect::<Vec<_>>()
+        };
+
+        let event = ob.execute(OrderType::Market {
+            id: 3,
+            side: Side::Bid,
+            qty: 3,
+            tag: Some(30),
+            owner: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        assert_eq!(
+            tags(event),
+            vec![(0, Some(30), Some(10)), (1, Some(30), None)]
+        );
+
+        let event = ob.execute(OrderType::Limit {
+            id: 4,
+            side: Side::Ask,
+            qty: 1,
+            price: 99,
+            display_qty: None,
+            owner: None,
+            tag: Some(40),
+            reduce_only: None,
+        });
+        assert_eq!(tags(event), vec![(2, Some(40), Some(20))]);
+        assert_eq!(ob.order(2).and_then(|o| o.tag), Some(20));
+    }
+
+    #[test]
+    fn marginal_impact() {
+        let (ob, _) = init_ob(vec![
+            limit(0, Side::Ask, 3, 101),
+            limit(1, Side::Ask, 2, 104),
+            limit(2, Side::Bid, 4, 99),
+        ]);
+        assert_eq!(ob.marginal_impact(Side::Bid, 0), Some(101));
+        assert_eq!(ob.marginal_impact(Side::Bid, 2), Some(101));
+        assert_eq!(ob.marginal_impact(Side::Bid, 3), Some(104));
+        assert_eq!(ob.marginal_impact(Side::Bid, 4), Some(104));
+        assert_eq!(ob.marginal_impact(Side::Bid, 5), None);
+        assert_eq!(ob.marginal_impact(Side::Ask, 3), Some(99));
+        assert_eq!(ob.marginal_impact(Side::Ask, 4), None);
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let mut orders = Vec::new();
+        for i in 0..40u64 {
+            let side = if i % 3 == 0 { Side::Bid } else { Side::Ask };
+            let price = if side == Side::Bid {
+                95 + i % 7
+            } else {
+                98 + i % 5
+            };
+            orders.push(match i % 4 {
+                3 => OrderType::Market {
+                    id: i as u128,
+                    side,
+                    qty: 1 + i % 6,
+                    owner: None,
+                    tag: None,
+                    protection_price: None,
+                    reduce_only: None,
+                },
+                _ => OrderType::Limit {
+                    id: i as u128,
+                    side,
+                    qty: 1 + i % 9,
+                    price,
+                    display_qty: if i % 5 == 0 { Some(1) } else { None },
+                    owner: None,
+                    tag: Some(i),
+                    reduce_only: None,
+                },
+            });
+        }
+        orders.push(OrderType::Cancel { id: 4 });
+        let (first, rest) = orders.split_at(20);
+
+        let (mut ob, _) = init_ob(first.to_vec());
+        let snapshot = ob.snapshot();
+        assert_eq!(snapshot.min_ask, ob.min_ask());
+        assert_eq!(snapshot.max_bid, ob.max_bid());
+        let mut restored = OrderBook::restore(snapshot, 16, 4);
+        assert_eq!(restored.depth(10), ob.depth(10));
+        assert_eq!(restored.traded_volume(), ob.traded_volume());
+
+        for order in rest {
+            assert_eq!(restored.execute(*order), ob.execute(*order));
+        }
+        assert_eq!(restored.depth(10), ob.depth(10));
+        assert_eq!(restored.best_ask(), ob.best_ask());
+        assert_eq!(restored.best_bid(), ob.best_bid());
+        assert_eq!(restored.traded_volume(), ob.traded_volume());
+        for id in 0..40 {
+            assert_eq!(restored.order(id), ob.order(id));
+        }
+    }
+
+    #[test]
+    fn best_bid_ask_stacked() {
+        let (mut ob, _) = init_ob(vec![
+            limit(0, Side::Ask, 4, 101),
+            limit(1, Side::Ask, 2, 101),
+            limit(2, Side::Ask, 7, 101),
+            limit(3, Side::Ask, 9, 102),
+            limit(4, Side::Bid, 1, 99),
+            limit(5, Side::Bid, 5, 99),
+            limit(6, Side::Bid, 3, 98),
+            limit(7, Side::Bid, 8, 100),
+        ]);
+        assert_eq!(
+            ob.best_ask(),
+            Some(BookLevel {
+                price: 101,
+                qty: 13
+            })
+        );
+        assert_eq!(ob.best_bid(), Some(BookLevel { price: 100, qty: 8 }));
+
+        // The emptied queue at 100 is skipped
+        ob.execute(OrderType::Cancel { id: 7 });
+        assert_eq!(ob.best_bid(), Some(BookLevel { price: 99, qty: 6 }));
+        ob.execute(OrderType::Market {
+            id: 8,
+            side: Side::Bid,
+            qty: 5,
+            tag: None,
+            owner: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        assert_eq!(ob.best_ask(), Some(BookLevel { price: 101, qty: 8 }));
+    }
+
+    #[test]
+    fn drain_mode() {
+        let (mut ob, _) = init_ob(vec![
+            limit(0, Side::Ask, 4, 101),
+            limit(1, Side::Ask, 2, 102),
+            limit(2, Side::Bid, 5, 99),
+        ]);
+        ob.enter_drain_mode();
+        assert!(ob.is_draining());
+
+        // Orders that would rest are rejected
+        assert_eq!(
+            ob.execute(limit(3, Side::Bid, 1, 100)),
+            OrderEvent::Rejected {
+                id: 3,
+                reason: RejectReason::Draining
+            }
+        );
+        assert_eq!(
+            ob.execute(limit(4, Side::Ask, 5, 103)),
+            OrderEvent::Rejected {
+                id: 4,
+                reason: RejectReason::Draining
+            }
+        );
+        assert_eq!(
+            ob.execute(OrderType::Amend {
+                id: 2,
+                new_qty: 5,
+                new_price: 100
+            }),
+            OrderEvent::Rejected {
+                id: 2,
+                reason: RejectReason::Draining
+            }
+        );
+        assert_eq!(ob.max_bid(), Some(99));
+
+        // Crossing orders can still take the existing liquidity
+        let event = ob.execute(limit(5, Side::Bid, 3, 101));
+        assert_eq!(event.fills().len(), 1);
+        assert!(matches!(event, OrderEvent::Filled { .. }));
+        let event = ob.execute(OrderType::Market {
+            id: 6,
+            side: Side::Bid,
+            qty: 2,
+            tag: None,
+            owner: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        assert!(matches!(event, OrderEvent::Filled { .. }));
+
+        // Cancels and quantity reductions still work
+        assert_eq!(
+            ob.execute(OrderType::Amend {
+                id: 2,
+                new_qty: 3,
+                new_price: 99
+            }),
+            OrderEvent::Amended { id: 2 }
+        );
+        assert_eq!(
+            ob.execute(OrderType::Cancel { id: 1 }),
+            OrderEvent::Canceled { id: 1 }
+        );
         assert_eq!(ob.min_ask(), None);
+
+        ob.exit_drain_mode();
+        assert_eq!(
+            ob.execute(limit(7, Side::Bid, 1, 100)),
+            OrderEvent::Placed { id: 7 }
+        );
+    }
+
+    #[test]
+    fn drain_mode_partial_cross() {
+        let owned = |id, side, qty, price, owner| OrderType::Limit {
+            id,
+            side,
+            qty,
+            price,
+            display_qty: None,
+            owner,
+            tag: None,
+            reduce_only: None,
+        };
+        let mut ob = OrderBook::default();
+        ob.execute(OrderType::Limit {
+            id: 0,
+            side: Side::Ask,
+            qty: 5,
+            price: 101,
+            display_qty: Some(2),
+            owner: None,
+            tag: None,
+            reduce_only: None,
+        });
+        rest(&mut ob, 1, Side::Ask, 2, 102);
+        ob.enter_drain_mode();
+
+        // The hidden reserve is taken too, and the remainder does not rest
+        let order = owned(2, Side::Bid, 6, 101, None);
+        let simulated = ob.simulate(order);
+        let event = ob.execute(order);
+        assert_eq!(event, simulated);
+        match event {
+            OrderEvent::PartiallyFilled {
+                filled_qty,
+                resting_qty,
+                ..
+            } => assert_eq!((filled_qty, resting_qty), (5, 0)),
+            event => panic!("unexpected event {:?}", event),
+        }
         assert_eq!(ob.max_bid(), None);
-        assert_eq!(ob._asks(), BTreeMap::new());
-        assert_eq!(ob._bids(), BTreeMap::new());
-        assert_eq!(ob.spread(), None);
-        assert_eq!(ob.traded_volume(), 0);
+        assert!(!ob.contains(2));
+
+        // Self-trade prevention stops matching before any fill
+        ob.set_stp_mode(Some(StpMode::CancelNewest));
+        rest(&mut ob, 3, Side::Ask, 2, 101);
+        ob.exit_drain_mode();
+        ob.execute(owned(4, Side::Ask, 2, 100, Some(7)));
+        ob.enter_drain_mode();
+        let event = ob.execute(owned(5, Side::Bid, 2, 100, Some(7)));
+        assert_eq!(
+            event,
+            OrderEvent::SystemCanceled {
+                id: 5,
+                reason: CancelReason::SelfTrade
+            }
+        );
+        ob.set_stp_mode(Some(StpMode::CancelResting));
+        let order = owned(6, Side::Bid, 2, 100, Some(7));
+        assert_eq!(
+            ob.simulate(order),
+            OrderEvent::SystemCanceled {
+                id: 6,
+                reason: CancelReason::Draining
+            }
+        );
+        assert_eq!(
+            ob.execute(order),
+            OrderEvent::SystemCanceled {
+                id: 6,
+                reason: CancelReason::Draining
+            }
+        );
+        assert!(!ob.contains(4) && !ob.contains(6));
+        assert_eq!(ob.max_bid(), None);
+    }
+
+    #[test]
+    fn drain_mode_all_or_none() {
+        let aon = |id, side, qty, price| OrderType::AllOrNone {
+            id,
+            side,
+            qty,
+            price,
+            owner: None,
+            tag: None,
+        };
+        let mut ob = OrderBook::default();
+        ob.execute(aon(0, Side::Ask, 5, 100));
+        rest(&mut ob, 1, Side::Ask, 1, 101);
+        ob.enter_drain_mode();
+
+        // The all-or-none maker is skipped, and nothing rests
+        let order = limit(2, Side::Bid, 2, 100);
+        let canceled = OrderEvent::SystemCanceled {
+            id: 2,
+            reason: CancelReason::Draining,
+        };
+        assert_eq!(ob.simulate(order), canceled);
+        assert_eq!(ob.execute(order), canceled);
+        assert!(ob.contains(0));
+        assert_eq!(ob.max_bid(), None);
+
+        // An all-or-none taker that cannot be filled does not rest either
+        let canceled = OrderEvent::SystemCanceled {
+            id: 3,
+            reason: CancelReason::Draining,
+        };
+        let order = aon(3, Side::Bid, 7, 101);
+        assert_eq!(ob.simulate(order), canceled);
+        assert_eq!(ob.execute(order), canceled);
+        assert!(!ob.contains(3));
+        assert!(matches!(
+            ob.execute(aon(4, Side::Bid, 6, 101)),
+            OrderEvent::Filled { filled_qty: 6, .. }
+        ));
+        assert_eq!(ob.min_ask(), None);
+    }
+
+    #[test]
+    fn fair_value() {
+        let mut ob = OrderBook::default();
+        assert_eq!(ob.fair_value(2), None);
+        rest(&mut ob, 0, Side::Bid, 6, 99);
+        rest(&mut ob, 1, Side::Bid, 4, 98);
+        assert_eq!(ob.fair_value(2), None);
+        rest(&mut ob, 2, Side::Ask, 2, 101);
+        rest(&mut ob, 3, Side::Ask, 3, 102);
+
+        // Bid-heavy book: 10 on the bid side and 5 on the ask side
+        assert_eq!(ob.mid_price(), Some(100.0));
+        assert_eq!(ob.fair_value(2), Some(100.0 + 1.0 / 3.0));
+        assert!(ob.fair_value(2).unwrap() > 100.0);
+        assert_eq!(ob.fair_value(1), ob.micro_price());
+
+        // Balanced book
+        rest(&mut ob, 4, Side::Ask, 5, 103);
+        assert_eq!(ob.fair_value(3), Some(100.0));
+        assert_eq!(ob.fair_value(0), None);
+    }
+
+    #[test]
+    fn depth_array() {
+        let mut ob = OrderBook::default();
+        rest(&mut ob, 0, Side::Ask, 2, 101);
+        rest(&mut ob, 1, Side::Ask, 3, 103);
+        rest(&mut ob, 2, Side::Ask, 1, 101);
+        rest(&mut ob, 3, Side::Bid, 4, 98);
+        rest(&mut ob, 4, Side::Bid, 5, 99);
+        rest(&mut ob, 5, Side::Bid, 6, 97);
+
+        let (asks, bids) = ob.depth_array::<3>();
+        assert_eq!(
+            asks,
+            [
+                Some(BookLevel { price: 101, qty: 3 }),
+                Some(BookLevel { price: 103, qty: 3 }),
+                None,
+            ]
+        );
+        assert_eq!(
+            bids,
+            [
+                Some(BookLevel { price: 99, qty: 5 }),
+                Some(BookLevel { price: 98, qty: 4 }),
+                Some(BookLevel { price: 97, qty: 6 }),
+            ]
+        );
+
+        let (asks, bids) = ob.depth_array::<1>();
+        assert_eq!(asks, [Some(BookLevel { price: 101, qty: 3 })]);
+        assert_eq!(bids, [Some(BookLevel { price: 99, qty: 5 })]);
+        assert_eq!(ob.depth_array::<0>(), ([], []));
+    }
+
+    #[test]
+    fn self_trade_prevention() {
+        let ask = |id, qty, price, owner| OrderType::Limit {
+            id,
+            side: Side::Ask,
+            qty,
+            price,
+            display_qty: None,
+            owner,
+            tag: None,
+            reduce_only: None,
+        };
+        let book = || {
+            init_ob(vec![
+                ask(0, 2, 101, Some(1)),
+                ask(1, 3, 101, Some(2)),
+                ask(2, 4, 102, None),
+            ])
+            .0
+        };
+        let makers = |event: &OrderEvent| {
+            event
+                .fills()
+                .iter()
+                .map(|fm| (fm.order_2, fm.qty))
+                .collect::<Vec<_>>()
+        };
+        let buy = |id, qty, owner| OrderType::Market {
+            id,
+            side: Side::Bid,
+            qty,
+            owner,
+            tag: None,
+            protection_price: None,
+            reduce_only: None,
+        };
+
+        // Without STP, orders of the same owner trade with each other
+        let mut ob = book();
+        let event = ob.execute(buy(3, 3, Some(1)));
+        assert_eq!(makers(&event), vec![(0, 2), (1, 1)]);
+
+        // Cancel newest: the incoming order stops at its own resting order
+        let mut ob = book();
+        ob.set_stp_mode(Some(StpMode::CancelNewest));
+        assert_eq!(
+            ob.execute(buy(3, 3, Some(1))),
+            OrderEvent::SystemCanceled {
+                id: 3,
+                reason: CancelReason::SelfTrade
+            }
+        );
+        assert_eq!(ob.best_ask(), Some(BookLevel { price: 101, qty: 5 }));
+        let event = ob.execute(OrderType::Limit {
+            id: 4,
+            side: Side::Bid,
+            qty: 6,
+            price: 102,
+            display_qty: None,
+            owner: Some(2),
+            tag: None,
+            reduce_only: None,
+        });
+        assert_eq!(makers(&event), vec![(0, 2)]);
+        assert!(matches!(event, OrderEvent::PartiallyFilled { .. }));
+        assert_eq!(ob.max_bid(), None);
+        assert_eq!(ob.order(1).map(|o| o.qty), Some(3));
+
+        // Cancel resting: the resting order is removed and matching goes on
+        let mut ob = book();
+        ob.set_stp_mode(Some(StpMode::CancelResting));
+        let event = ob.execute(buy(3, 3, Some(1)));
+        assert_eq!(makers(&event), vec![(1, 3)]);
+        assert_eq!(ob.order(0), None);
+        assert_eq!(ob.best_ask(), Some(BookLevel { price: 102, qty: 4 }));
+
+        // Orders of different owners or without owner still trade
+        let mut ob = book();
+        ob.set_stp_mode(Some(StpMode::CancelNewest));
+        let event = ob.execute(buy(3, 6, Some(3)));
+        assert_eq!(makers(&event), vec![(0, 2), (1, 3), (2, 1)]);
+        let event = ob.execute(buy(4, 1, None));
+        assert_eq!(makers(&event), vec![(2, 1)]);
+    }
+
+    #[test]
+    fn cancel_in_deep_level_compacts_tombstones() {
+        let mut ob = OrderBook::default();
+        for id in 0..200 {
+            rest(&mut ob, id, Side::Ask, 1, 100);
+        }
+        rest(&mut ob, 200, Side::Ask, 5, 101);
+        for id in 0..150 {
+            assert!(ob.cancel(id));
+            assert_eq!(
+                ob.best_ask(),
+                Some(BookLevel {
+                    price: 100,
+                    qty: 199 - id as u64
+                })
+            );
+        }
+        assert!(!ob.cancel(0));
+        assert!(ob.arena.tombstones() < MIN_COMPACTION_TOMBSTONES);
+        assert!(ob.asks[&100].len() < 100);
+        assert_eq!(ob._asks()[&100].len(), 50);
+
+        for id in 150..200 {
+            assert!(ob.cancel(id));
+        }
+        assert_eq!(ob.best_ask(), Some(BookLevel { price: 101, qty: 5 }));
+        let result = ob.execute(OrderType::Market {
+            id: 201,
+            side: Side::Bid,
+            qty: 5,
+            owner: None,
+            tag: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        assert!(matches!(result, OrderEvent::Filled { .. }));
+        assert_eq!(ob.best_ask(), None);
+        assert_eq!(ob.arena.len(), 0);
+    }
+
+    #[test]
+    fn expired_order_does_not_match() {
+        let (mut ob, _) = init_ob(vec![
+            OrderType::GoodTillTime {
+                id: 0,
+                side: Side::Ask,
+                qty: 2,
+                price: 100,
+                expires_at: 10,
+            },
+            OrderType::GoodTillTime {
+                id: 1,
+                side: Side::Ask,
+                qty: 3,
+                price: 101,
+                expires_at: 20,
+            },
+        ]);
+        assert_eq!(ob.set_time(15).len(), 1);
+        assert_eq!(ob.min_ask(), Some(101));
+        assert_eq!(
+            ob.execute(OrderType::Market {
+                id: 2,
+                side: Side::Bid,
+                qty: 5,
+                owner: None,
+                tag: None,
+                protection_price: None,
+                reduce_only: None,
+            }),
+            OrderEvent::PartiallyFilled {
+                id: 2,
+                filled_qty: 3,
+                resting_qty: 0,
+                fills: vec![FillMetadata {
+                    order_1: 2,
+                    order_2: 1,
+                    qty: 3,
+                    price: 101,
+                    taker_side: Side::Bid,
+                    total_fill: true,
+                    taker_tag: None,
+                    maker_tag: None,
+                }],
+                trade: Some(Trade {
+                    total_qty: 3,
+                    avg_price: 101.0,
+                    last_qty: 3,
+                    last_price: 101,
+                }),
+            }
+        );
+        assert_eq!(ob.min_ask(), None);
+        assert_eq!(ob.set_time(20), Vec::new());
+    }
+
+    #[test]
+    fn observer_events_for_crossing_limit_order() {
+        let (mut ob, _) = init_ob(vec![
+            limit(0, Side::Ask, 2, 100),
+            limit(1, Side::Ask, 3, 102),
+        ]);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let observed = Rc::clone(&events);
+        ob.set_observer(move |event| observed.borrow_mut().push(event));
+
+        ob.execute(OrderType::Limit {
+            id: 2,
+            side: Side::Bid,
+            qty: 5,
+            price: 101,
+            display_qty: None,
+            owner: None,
+            tag: Some(7),
+            reduce_only: None,
+        });
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                BookEvent::Trade {
+                    fill: FillMetadata {
+                        order_1: 2,
+                        order_2: 0,
+                        qty: 2,
+                        price: 100,
+                        taker_side: Side::Bid,
+                        total_fill: true,
+                        taker_tag: Some(7),
+                        maker_tag: None,
+                    }
+                },
+                BookEvent::OrderFilled {
+                    id: 0,
+                    side: Side::Ask,
+                    price: 100,
+                    qty: 2,
+                    remaining_qty: 0,
+                },
+                BookEvent::OrderRemoved {
+                    id: 0,
+                    side: Side::Ask,
+                    price: 100
+                },
+                BookEvent::BestPriceChanged {
+                    side: Side::Ask,
+                    price: Some(102)
+                },
+                BookEvent::OrderAdded {
+                    id: 2,
+                    side: Side::Bid,
+                    price: 101,
+                    qty: 3
+                },
+                BookEvent::BestPriceChanged {
+                    side: Side::Bid,
+                    price: Some(101)
+                },
+            ]
+        );
+
+        events.borrow_mut().clear();
+        ob.execute(OrderType::Cancel { id: 2 });
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                BookEvent::OrderRemoved {
+                    id: 2,
+                    side: Side::Bid,
+                    price: 101
+                },
+                BookEvent::BestPriceChanged {
+                    side: Side::Bid,
+                    price: None
+                },
+            ]
+        );
+
+        let updated = BookEvent::OrderUpdated {
+            id: 1,
+            side: Side::Ask,
+            price: 102,
+            qty: 2,
+        };
+        events.borrow_mut().clear();
+        ob.execute(OrderType::Amend {
+            id: 1,
+            new_qty: 2,
+            new_price: 102,
+        });
+        assert_eq!(*events.borrow(), vec![updated]);
+
+        events.borrow_mut().clear();
+        ob.refresh(1);
+        assert_eq!(*events.borrow(), vec![updated]);
+
+        rest(&mut ob, 3, Side::Bid, 4, 99);
+        events.borrow_mut().clear();
+        ob.clear();
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                BookEvent::OrderRemoved {
+                    id: 1,
+                    side: Side::Ask,
+                    price: 102
+                },
+                BookEvent::OrderRemoved {
+                    id: 3,
+                    side: Side::Bid,
+                    price: 99
+                },
+                BookEvent::BestPriceChanged {
+                    side: Side::Ask,
+                    price: None
+                },
+                BookEvent::BestPriceChanged {
+                    side: Side::Bid,
+                    price: None
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn tick_size() {
+        for tick_size in [0, 1] {
+            let mut ob = OrderBook::default();
+            ob.set_tick_size(tick_size);
+            for (id, price) in [(0, 397), (1, 395)] {
+                assert_eq!(
+                    ob.execute(limit(id, Side::Ask, 2, price)),
+                    OrderEvent::Placed { id }
+                );
+            }
+        }
+
+        let mut ob = OrderBook::default();
+        ob.set_tick_size(5);
+        rest(&mut ob, 0, Side::Ask, 2, 397);
+        assert_eq!(
+            ob.execute(OrderType::Market {
+                id: 1,
+                side: Side::Bid,
+                qty: 2,
+                owner: None,
+                tag: None,
+                protection_price: None,
+                reduce_only: None,
+            }),
+            OrderEvent::Filled {
+                id: 1,
+                filled_qty: 2,
+                fills: vec![FillMetadata {
+                    order_1: 1,
+                    order_2: 0,
+                    qty: 2,
+                    price: 397,
+                    taker_side: Side::Bid,
+                    total_fill: true,
+                    taker_tag: None,
+                    maker_tag: None,
+                }],
+                trade: None,
+            }
+        );
+    }
+
+    #[test]
+    fn clear() {
+        let orders = [limit(0, Side::Ask, 2, 101), limit(1, Side::Bid, 5, 102)];
+        let mut ob = OrderBook::new(4, DEFAULT_QUEUE_SIZE, true);
+        for id in 0..6 {
+            rest(&mut ob, id, Side::Bid, 1, 90 + id as u64);
+        }
+        ob.set_time(5);
+        ob.execute(orders[0]);
+        ob.execute(OrderType::Market {
+            id: 6,
+            side: Side::Ask,
+            qty: 2,
+            owner: None,
+            tag: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        assert_eq!(ob.traded_volume(), 2);
+
+        ob.clear();
+        let mut fresh = OrderBook::new(4, DEFAULT_QUEUE_SIZE, true);
+        let results: Vec<_> = orders.iter().map(|o| ob.execute(*o)).collect();
+        let expected: Vec<_> =
+            orders.iter().map(|o| fresh.execute(*o)).collect();
+        assert_eq!(results, expected);
+        assert_eq!(ob.depth(10), fresh.depth(10));
+        assert_eq!(ob.traded_volume(), fresh.traded_volume());
+        assert_eq!(ob.last_trade(), fresh.last_trade());
+        assert_eq!(ob.time(), 0);
+        assert_eq!(ob.config(), fresh.config());
+        // The slots allocated past the initial capacity are kept
+        assert_eq!(ob._bids(), init_book(vec![(102, 5)]));
+        assert_eq!(fresh._bids(), init_book(vec![(102, 3)]));
+    }
+
+    #[test]
+    fn level_orders() {
+        let mut ob = OrderBook::default();
+        for (id, qty) in [(0, 2), (1, 3), (2, 4)] {
+            rest(&mut ob, id, Side::Bid, qty, 100);
+        }
+        rest(&mut ob, 3, Side::Bid, 1, 99);
+        let level = |ob: &OrderBook| -> Vec<_> {
+            ob.level_orders(Side::Bid, 100)
+                .map(|order| (order.id, order.qty))
+                .collect()
+        };
+        assert_eq!(level(&ob), vec![(0, 2), (1, 3), (2, 4)]);
+
+        ob.execute(OrderType::Market {
+            id: 4,
+            side: Side::Ask,
+            qty: 4,
+            owner: None,
+            tag: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        assert_eq!(level(&ob), vec![(1, 1), (2, 4)]);
+        ob.execute(OrderType::Cancel { id: 1 });
+        assert_eq!(level(&ob), vec![(2, 4)]);
+        assert_eq!(ob.level_orders(Side::Bid, 101).count(), 0);
+        assert_eq!(ob.level_orders(Side::Ask, 100).count(), 0);
+    }
+
+    #[test]
+    fn imbalance() {
+        let mut ob = OrderBook::default();
+        assert_eq!(ob.imbalance(1), None);
+        for (id, qty, price) in [(0, 3, 100), (1, 5, 99), (2, 10, 98)] {
+            rest(&mut ob, id, Side::Bid, qty, price);
+        }
+        assert_eq!(ob.imbalance(1), None);
+        for (id, price) in [(3, 101), (4, 102), (5, 103)] {
+            rest(&mut ob, id, Side::Ask, 1, price);
+        }
+        // Leave an empty queue at 102
+        ob.execute(OrderType::Cancel { id: 4 });
+        assert_eq!(ob._asks()[&102], Vec::<usize>::new());
+
+        assert_eq!(ob.imbalance(0), None);
+        assert_eq!(ob.imbalance(1), Some(0.75));
+        assert_eq!(ob.imbalance(2), Some(0.8));
+        assert_eq!(ob.imbalance(10), Some(0.9));
+    }
+
+    #[test]
+    fn buy_stop_triggered_by_upward_trade() {
+        let mut ob = OrderBook::default();
+        rest(&mut ob, 0, Side::Ask, 1, 100);
+        rest(&mut ob, 1, Side::Ask, 1, 101);
+        rest(&mut ob, 2, Side::Ask, 5, 103);
+        let stops = [
+            OrderType::StopMarket {
+                id: 10,
+                side: Side::Bid,
+                qty: 2,
+                trigger: 102,
+            },
+            OrderType::StopLimit {
+                id: 11,
+                side: Side::Bid,
+                qty: 1,
+                price: 104,
+                trigger: 101,
+            },
+            OrderType::StopMarket {
+                id: 12,
+                side: Side::Ask,
+                qty: 1,
+                trigger: 95,
+            },
+        ];
+        for stop in stops {
+            assert_eq!(ob.execute(stop), OrderEvent::Placed { id: stop.id() });
+        }
+        assert_eq!(
+            ob.execute(stops[0]),
+            OrderEvent::Rejected {
+                id: 10,
+                reason: RejectReason::DuplicateId
+            }
+        );
+        let buy = |id| OrderType::Market {
+            id,
+            side: Side::Bid,
+            qty: 1,
+            owner: None,
+            tag: None,
+            protection_price: None,
+            reduce_only: None,
+        };
+        let fill = |order_1, order_2, qty, price, total_fill| FillMetadata {
+            order_1,
+            order_2,
+            qty,
+            price,
+            taker_side: Side::Bid,
+            total_fill,
+            taker_tag: None,
+            maker_tag: None,
+        };
+
+        ob.execute(buy(20));
+        assert_eq!(ob.take_triggered(), Vec::new());
+        assert_eq!(
+            ob.execute(buy(21)),
+            OrderEvent::Filled {
+                id: 21,
+                filled_qty: 1,
+                fills: vec![fill(21, 1, 1, 101, true)],
+                trade: None,
+            }
+        );
+        // The stop limit order trades at 103, triggering the stop market order
+        assert_eq!(
+            ob.take_triggered(),
+            vec![
+                OrderEvent::Triggered {
+                    id: 11,
+                    event: Box::new(OrderEvent::Filled {
+                        id: 11,
+                        filled_qty: 1,
+                        fills: vec![fill(11, 2, 1, 103, false)],
+                        trade: None,
+                    })
+                },
+                OrderEvent::Triggered {
+                    id: 10,
+                    event: Box::new(OrderEvent::Filled {
+                        id: 10,
+                        filled_qty: 2,
+                        fills: vec![fill(10, 2, 2, 103, false)],
+                        trade: None,
+                    })
+                },
+            ]
+        );
+        assert_eq!(ob.take_triggered(), Vec::new());
+        assert_eq!(ob.best_ask(), Some(BookLevel { price: 103, qty: 2 }));
+        assert_eq!(
+            ob.execute(OrderType::Cancel { id: 12 }),
+            OrderEvent::Canceled { id: 12 }
+        );
+        assert_eq!(ob.snapshot().stops.len(), 0);
+    }
+
+    #[test]
+    fn stops_with_same_trigger_in_placement_order() {
+        let mut ob = OrderBook::default();
+        rest(&mut ob, 0, Side::Ask, 10, 100);
+        for id in [7, 3] {
+            ob.execute(OrderType::StopMarket {
+                id,
+                side: Side::Bid,
+                qty: 1,
+                trigger: 100,
+            });
+        }
+        ob.execute(OrderType::StopMarket {
+            id: 1,
+            side: Side::Bid,
+            qty: 1,
+            trigger: 101,
+        });
+        // The restored book keeps the placement order
+        let mut restored = OrderBook::restore(ob.snapshot(), 16, 4);
+        for ob in [&mut ob, &mut restored] {
+            ob.execute(OrderType::Market {
+                id: 2,
+                side: Side::Bid,
+                qty: 1,
+                owner: None,
+                tag: None,
+                protection_price: None,
+                reduce_only: None,
+            });
+            let triggered: Vec<u128> = ob
+                .take_triggered()
+                .iter()
+                .filter_map(|event| match event {
+                    OrderEvent::Triggered { id, .. } => Some(*id),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(triggered, vec![7, 3]);
+        }
+    }
+
+    #[test]
+    fn execute_batch() {
+        let orders = [
+            limit(0, Side::Ask, 2, 101),
+            limit(1, Side::Ask, 3, 102),
+            OrderType::Market {
+                id: 2,
+                side: Side::Bid,
+                qty: 4,
+                owner: None,
+                tag: None,
+                protection_price: None,
+                reduce_only: None,
+            },
+            OrderType::Cancel { id: 0 },
+            limit(3, Side::Bid, 1, 103),
+        ];
+        let (mut expected_ob, expected) = init_ob(orders.to_vec());
+        let mut ob = OrderBook::new(10, DEFAULT_QUEUE_SIZE, true);
+        assert_eq!(ob.execute_batch(&orders), expected);
+        assert_eq!(ob.execute_batch(&[]), Vec::new());
+        assert_eq!(ob.traded_volume(), 5);
+        assert_eq!(ob.last_trade(), expected_ob.last_trade());
+        assert_eq!(ob.depth(10), expected_ob.depth(10));
+        assert_eq!(
+            ob.execute_batch(&orders[3..4]),
+            vec![expected_ob.execute(orders[3])]
+        );
+    }
+
+    #[test]
+    fn market_order_protection_price() {
+        let mut ob = OrderBook::default();
+        rest(&mut ob, 0, Side::Ask, 2, 101);
+        rest(&mut ob, 1, Side::Ask, 2, 102);
+        rest(&mut ob, 2, Side::Ask, 5, 105);
+        rest(&mut ob, 3, Side::Bid, 5, 99);
+        let market = |id, side, protection_price| OrderType::Market {
+            id,
+            side,
+            qty: 6,
+            owner: None,
+            tag: None,
+            protection_price,
+            reduce_only: None,
+        };
+
+        let event = ob.execute(market(4, Side::Bid, Some(102)));
+        assert!(matches!(
+            event,
+            OrderEvent::PartiallyFilled {
+                id: 4,
+                filled_qty: 4,
+                ..
+            }
+        ));
         assert_eq!(
-            ob.depth(2),
-            BookDepth {
-                levels: 2,
-                asks: Vec::new(),
-                bids: Vec::new()
+            event.fills().iter().map(|f| f.price).collect::<Vec<_>>(),
+            vec![101, 102]
+        );
+        // The remaining quantity is discarded rather than resting
+        assert_eq!(ob.order(4), None);
+        assert_eq!(ob.best_ask(), Some(BookLevel { price: 105, qty: 5 }));
+        assert_eq!(ob.best_bid(), Some(BookLevel { price: 99, qty: 5 }));
+
+        assert_eq!(
+            ob.execute(market(5, Side::Ask, Some(100))),
+            OrderEvent::Unfilled {
+                id: 5,
+                reason: UnfilledReason::PriceProtection,
             }
         );
-        assert_eq!(ob.last_trade(), None);
+        assert!(matches!(
+            ob.execute(market(6, Side::Ask, Some(99))),
+            OrderEvent::PartiallyFilled {
+                id: 6,
+                filled_qty: 5,
+                ..
+            }
+        ));
     }
 
     #[test]
-    fn one_resting_order() {
-        for (bid_ask, _) in &BID_ASK_COMBINATIONS {
-            let (ob, results) = init_ob(vec![OrderType::Limit {
+    fn cumulative_depth() {
+        let mut ob = OrderBook::default();
+        for (id, side, qty, price) in [
+            (0, Side::Ask, 2, 101),
+            (1, Side::Ask, 3, 101),
+            (2, Side::Ask, 1, 103),
+            (3, Side::Ask, 4, 106),
+            (4, Side::Ask, 9, 104),
+            (5, Side::Bid, 1, 99),
+            (6, Side::Bid, 2, 96),
+            (7, Side::Bid, 7, 98),
+        ] {
+            rest(&mut ob, id, side, qty, price);
+        }
+        // Leave an empty price level at 104
+        ob.execute(OrderType::Cancel { id: 4 });
+
+        assert_eq!(
+            ob.cumulative_depth(Side::Ask, 10),
+            vec![(101, 5), (103, 6), (106, 10)]
+        );
+        assert_eq!(ob.cumulative_depth(Side::Ask, 2), vec![(101, 5), (103, 6)]);
+        assert_eq!(
+            ob.cumulative_depth(Side::Bid, 10),
+            vec![(99, 1), (98, 8), (96, 10)]
+        );
+        assert_eq!(ob.cumulative_depth(Side::Bid, 0), Vec::new());
+
+        assert_eq!(ob.qty_up_to_price(Side::Ask, 100), 0);
+        assert_eq!(ob.qty_up_to_price(Side::Ask, 101), 5);
+        assert_eq!(ob.qty_up_to_price(Side::Ask, 105), 6);
+        assert_eq!(ob.qty_up_to_price(Side::Ask, 200), 10);
+        assert_eq!(ob.qty_up_to_price(Side::Bid, 100), 0);
+        assert_eq!(ob.qty_up_to_price(Side::Bid, 97), 8);
+        assert_eq!(ob.qty_up_to_price(Side::Bid, 0), 10);
+    }
+
+    #[test]
+    fn limit_order_at_opposite_best_price() {
+        for (bid_ask, ask_bid) in &BID_ASK_COMBINATIONS {
+            let mut ob = OrderBook::default();
+            rest(&mut ob, 0, *bid_ask, 2, 100);
+            assert!(!ob.is_crossed());
+            let event = ob.execute(limit(1, *ask_bid, 3, 100));
+            assert_eq!(
+                event,
+                OrderEvent::PartiallyFilled {
+                    id: 1,
+                    filled_qty: 2,
+                    resting_qty: 1,
+                    fills: vec![FillMetadata {
+                        order_1: 1,
+                        order_2: 0,
+                        qty: 2,
+                        price: 100,
+                        taker_side: *ask_bid,
+                        total_fill: true,
+                        taker_tag: None,
+                        maker_tag: None,
+                    }],
+                    trade: None,
+                }
+            );
+            assert!(!ob.is_crossed());
+            assert_eq!(ob.spread(), None);
+
+            // Books can only be locked or crossed when set up by hand
+            rest(&mut ob, 2, *bid_ask, 1, 100);
+            assert!(ob.is_locked() && !ob.is_crossed());
+            assert_eq!(ob.spread(), Some(0));
+        }
+    }
+
+    #[test]
+    fn queue_ahead() {
+        let mut ob = OrderBook::default();
+        for (id, qty) in [(0, 2), (1, 3), (2, 4)] {
+            rest(&mut ob, id, Side::Ask, qty, 101);
+        }
+        rest(&mut ob, 3, Side::Ask, 5, 100);
+        assert_eq!(ob.queue_ahead(0), Some(0));
+        assert_eq!(ob.queue_ahead(1), Some(2));
+        assert_eq!(ob.queue_ahead(2), Some(5));
+        assert_eq!(ob.queue_ahead(3), Some(0));
+        assert_eq!(ob.queue_ahead(4), None);
+
+        ob.execute(OrderType::Market {
+            id: 4,
+            side: Side::Bid,
+            qty: 6,
+            owner: None,
+            tag: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        assert_eq!(ob.queue_ahead(0), Some(0));
+        assert_eq!(ob.queue_ahead(1), Some(1));
+        assert_eq!(ob.queue_ahead(2), Some(4));
+        ob.execute(OrderType::Cancel { id: 0 });
+        assert_eq!(ob.queue_ahead(0), None);
+        assert_eq!(ob.queue_ahead(1), Some(0));
+    }
+
+    #[test]
+    fn price_offset() {
+        let mut ob = OrderBook::default();
+        ob.set_price_offset(1000);
+        assert_eq!(ob.config().price_offset, 1000);
+        assert_eq!(ob.to_internal(-10), Some(990));
+        assert_eq!(ob.to_internal(-1000), Some(0));
+        assert_eq!(ob.to_internal(-1001), None);
+        assert_eq!(ob.to_external(990), Some(-10));
+        assert_eq!(ob.to_external(u64::MAX), None);
+
+        let price = ob.to_internal(-10).unwrap();
+        ob.execute(limit(0, Side::Ask, 2, price));
+        let event =
+            ob.execute(limit(1, Side::Bid, 2, ob.to_internal(-9).unwrap()));
+        assert_eq!(event.fills().len(), 1);
+        assert_eq!(ob.to_external(event.fills()[0].price), Some(-10));
+        assert_eq!(ob.min_ask(), None);
+    }
+
+    #[test]
+    fn duplicate_id_keeps_first_order() {
+        let bid = |qty, price| limit(7, Side::Bid, qty, price);
+        let (mut ob, results) = init_ob(vec![bid(3, 100), bid(5, 101)]);
+        assert_eq!(
+            results,
+            vec![
+                OrderEvent::Placed { id: 7 },
+                OrderEvent::Rejected {
+                    id: 7,
+                    reason: RejectReason::DuplicateId
+                }
+            ]
+        );
+        assert_eq!(
+            ob.order(7).map(|order| (order.price, order.qty)),
+            Some((100, 3))
+        );
+        assert_eq!(ob.best_bid(), Some(BookLevel { price: 100, qty: 3 }));
+        assert_eq!(
+            ob.try_execute(OrderType::Cancel { id: 7 }),
+            Ok(OrderEvent::Canceled { id: 7 })
+        );
+        assert_eq!(ob.best_bid(), None);
+    }
+
+    #[test]
+    fn order_counts() {
+        let mut ob = OrderBook::default();
+        let counts =
+            |ob: &OrderBook| (ob.order_count(), ob.ask_count(), ob.bid_count());
+        assert_eq!(counts(&ob), (0, 0, 0));
+        for (id, qty, price) in [(0, 2, 101), (1, 3, 101), (2, 4, 102)] {
+            rest(&mut ob, id, Side::Ask, qty, price);
+        }
+        rest(&mut ob, 3, Side::Bid, 1, 99);
+        rest(&mut ob, 4, Side::Bid, 1, 98);
+        assert_eq!(counts(&ob), (5, 3, 2));
+
+        // Fill order 0 completely and order 1 partially
+        ob.execute(OrderType::Market {
+            id: 5,
+            side: Side::Bid,
+            qty: 3,
+            owner: None,
+            tag: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        assert_eq!(counts(&ob), (4, 2, 2));
+        // Fill the asks, then rest the remaining quantity as a bid
+        ob.execute(limit(6, Side::Bid, 10, 102));
+        assert_eq!(counts(&ob), (3, 0, 3));
+        ob.execute(OrderType::Cancel { id: 3 });
+        ob.cancel_many(&[4, 42]);
+        assert_eq!(counts(&ob), (1, 0, 1));
+        ob.cancel_all_except(&[]);
+        assert_eq!(counts(&ob), (0, 0, 0));
+    }
+
+    #[test]
+    fn simulate() {
+        let orders = [
+            OrderType::Limit {
                 id: 0,
-                side: *bid_ask,
+                side: Side::Ask,
+                qty: 10,
+                price: 101,
+                display_qty: Some(3),
+                owner: None,
+                tag: Some(1),
+                reduce_only: None,
+            },
+            OrderType::Limit {
+                id: 1,
+                side: Side::Ask,
+                qty: 2,
+                price: 101,
+                display_qty: None,
+                owner: Some(9),
+                tag: None,
+                reduce_only: None,
+            },
+            limit(2, Side::Ask, 4, 103),
+            limit(3, Side::Bid, 4, 99),
+        ];
+        let takers = [
+            OrderType::Limit {
+                id: 4,
+                side: Side::Bid,
                 qty: 12,
-                price: 395,
-            }]);
-            assert_eq!(results, vec![OrderEvent::Placed { id: 0 }]);
-            if *bid_ask == Side::Bid {
-                assert_eq!(ob.min_ask(), None);
-                assert_eq!(ob.max_bid(), Some(395));
-                assert_eq!(ob._asks(), BTreeMap::new());
-                assert_eq!(ob._bids(), init_book(vec![(395, 9999)]));
-                assert_eq!(ob.spread(), None);
-                assert_eq!(ob.traded_volume(), 0);
-                assert_eq!(
-                    ob.depth(3),
-                    BookDepth {
-                        levels: 3,
-                        asks: Vec::new(),
-                        bids: vec![BookLevel {
-                            price: 395,
-                            qty: 12
-                        }],
-                    }
-                );
-                assert_eq!(ob.last_trade(), None);
-            } else {
-                assert_eq!(ob.min_ask(), Some(395));
-                assert_eq!(ob.max_bid(), None);
-                assert_eq!(ob._asks(), init_book(vec![(395, 9999)]));
-                assert_eq!(ob._bids(), BTreeMap::new());
-                assert_eq!(ob.spread(), None);
-                assert_eq!(ob.traded_volume(), 0);
-                assert_eq!(
-                    ob.depth(4),
-                    BookDepth {
-                        levels: 4,
-                        asks: vec![BookLevel {
-                            price: 395,
-                            qty: 12
-                        }],
-                        bids: Vec::new()
-                    }
-                );
-                assert_eq!(ob.last_trade(), None);
+                price: 102,
+                display_qty: None,
+                owner: None,
+                tag: Some(2),
+                reduce_only: None,
+            },
+            OrderType::Market {
+                id: 5,
+                side: Side::Bid,
+                qty: 20,
+                owner: None,
+                tag: None,
+                protection_price: None,
+                reduce_only: None,
+            },
+            OrderType::Market {
+                id: 6,
+                side: Side::Ask,
+                qty: 1,
+                owner: None,
+                tag: None,
+                protection_price: None,
+                reduce_only: None,
+            },
+            OrderType::Amend {
+                id: 3,
+                new_qty: 4,
+                new_price: 101,
+            },
+            OrderType::Amend {
+                id: 3,
+                new_qty: 2,
+                new_price: 99,
+            },
+            OrderType::Limit {
+                id: 7,
+                side: Side::Bid,
+                qty: 12,
+                price: 102,
+                display_qty: None,
+                owner: Some(9),
+                tag: None,
+                reduce_only: None,
+            },
+            limit(0, Side::Bid, 1, 102),
+            OrderType::Cancel { id: 42 },
+        ];
+        for stp_mode in [None, Some(StpMode::CancelResting)] {
+            for taker in takers {
+                let (mut ob, _) = init_ob(orders.to_vec());
+                ob.set_stp_mode(stp_mode);
+                let depth = ob.depth(10);
+                let simulated = ob.simulate(taker);
+                assert_eq!(ob.depth(10), depth);
+                assert_eq!(ob.order_count(), orders.len());
+                assert_eq!(ob.queue_ahead(1), Some(3));
+                assert_eq!(simulated, ob.execute(taker));
             }
         }
+        let (mut ob, _) = init_ob(orders.to_vec());
+        ob.set_stp_mode(Some(StpMode::CancelNewest));
+        assert_eq!(ob.simulate(takers[5]), ob.execute(takers[5]));
     }
 
     #[test]
-    fn two_resting_orders() {
-        for (bid_ask, ask_bid) in &BID_ASK_COMBINATIONS {
-            let (ob, results) = init_ob(vec![
-                OrderType::Limit {
-                    id: 0,
-                    side: *bid_ask,
-                    qty: 12,
-                    price: 395,
-                },
-                OrderType::Limit {
-                    id: 1,
-                    side: *ask_bid,
-                    qty: 2,
-                    price: 398,
-                },
-            ]);
-            if *bid_ask == Side::Bid {
-                assert_eq!(
-                    results,
-                    vec![
-                        OrderEvent::Placed { id: 0 },
-                        OrderEvent::Placed { id: 1 }
-                    ]
-                );
-                assert_eq!(ob.min_ask(), Some(398));
-                assert_eq!(ob.max_bid(), Some(395));
-                assert_eq!(ob._asks(), init_book(vec![(398, 9998)]));
-                assert_eq!(ob._bids(), init_book(vec![(395, 9999)]));
-                assert_eq!(ob.spread(), Some(3));
-                assert_eq!(ob.traded_volume(), 0);
-                assert_eq!(
-                    ob.depth(4),
-                    BookDepth {
-                        levels: 4,
-                        asks: vec![BookLevel { price: 398, qty: 2 }],
-                        bids: vec![BookLevel {
-                            price: 395,
-                            qty: 12
-                        }],
-                    }
-                );
-                assert_eq!(ob.last_trade(), None);
-            } else {
-                assert_eq!(
-                    results,
-                    vec![
-                        OrderEvent::Placed { id: 0 },
-                        OrderEvent::Filled {
-                            id: 1,
-                            filled_qty: 2,
-                            fills: vec![FillMetadata {
-                                order_1: 1,
-                                order_2: 0,
-                                qty: 2,
-                                price: 395,
-                                taker_side: *ask_bid,
-                                total_fill: false,
-                            }],
-                        }
-                    ]
-                );
-                assert_eq!(ob.min_ask(), Some(395));
-                assert_eq!(ob.max_bid(), None);
-                assert_eq!(ob._asks(), init_book(vec![(395, 9999)]));
-                assert_eq!(ob._bids(), init_book(vec![]));
-                assert_eq!(ob.spread(), None);
-                assert_eq!(ob.traded_volume(), 2);
-                assert_eq!(
-                    ob.depth(4),
-                    BookDepth {
-                        levels: 4,
-                        asks: vec![BookLevel {
-                            price: 395,
-                            qty: 10,
-                        }],
-                        bids: Vec::new(),
-                    }
-                );
-                assert_eq!(
-                    ob.last_trade(),
-                    Some(Trade {
-                        total_qty: 2,
-                        avg_price: 395.0,
-                        last_qty: 2,
-                        last_price: 395,
-                    })
-                );
+    fn compact_after_spike() {
+        let mut ob = OrderBook::new(16, DEFAULT_QUEUE_SIZE, false);
+        for id in 0..1_000 {
+            rest(
+                &mut ob,
+                id,
+                Side::Ask,
+                1 + id as u64 % 3,
+                100 + id as u64 % 7,
+            );
+        }
+        let kept: Vec<u128> = (0..1_000).filter(|id| id % 97 == 5).collect();
+        ob.cancel_all_except(&kept);
+        rest(&mut ob, 1_000, Side::Bid, 2, 90);
+        ob.execute(OrderType::Cancel { id: kept[3] });
+        let kept: Vec<u128> = kept
+            .into_iter()
+            .filter(|id| ob.order(*id).is_some())
+            .collect();
+        let orders: Vec<_> = kept.iter().map(|id| ob.order(*id)).collect();
+        let depth = ob.depth(10);
+        assert_eq!(ob.arena.slots(), 1_000);
+
+        ob.compact();
+        assert_eq!(ob.arena.slots(), 16);
+        assert_eq!(ob.depth(10), depth);
+        for (id, order) in kept.iter().zip(orders) {
+            assert_eq!(ob.order(*id), order);
+        }
+        assert_eq!(ob.order(1_000).map(|order| order.qty), Some(2));
+        assert_eq!(ob.touch_queue_len(Side::Ask), Some(1));
+
+        // Matching priority is preserved
+        let mut expected: Vec<_> = kept
+            .iter()
+            .map(|id| ob.order(*id).unwrap())
+            .map(|order| (order.price, order.seq, order.id))
+            .collect();
+        expected.sort_unstable();
+        let event = ob.execute(OrderType::Market {
+            id: 1_001,
+            side: Side::Bid,
+            qty: 1_000,
+            owner: None,
+            tag: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        let makers: Vec<_> = event.fills().iter().map(|f| f.order_2).collect();
+        assert_eq!(
+            makers,
+            expected.iter().map(|(_, _, id)| *id).collect::<Vec<_>>()
+        );
+        assert_eq!(ob.best_bid(), Some(BookLevel { price: 90, qty: 2 }));
+
+        // The freed slots are reused
+        for id in 2_000..2_020 {
+            rest(&mut ob, id, Side::Ask, 1, 100);
+        }
+        assert_eq!(ob.arena.slots(), 21);
+    }
+
+    fn pro_rata_book(sizes: &[u64]) -> OrderBook {
+        let mut ob = OrderBook::default();
+        ob.set_matching_policy(MatchingPolicy::ProRata);
+        for (id, qty) in (1..).zip(sizes.iter().copied()) {
+            ob.execute(limit(id, Side::Ask, qty, 100));
+        }
+        ob
+    }
+
+    fn pro_rata_fills(ob: &mut OrderBook, qty: u64) -> Vec<(u128, u64)> {
+        let order = OrderType::Market {
+            id: 10,
+            side: Side::Bid,
+            qty,
+            owner: None,
+            tag: None,
+            protection_price: None,
+            reduce_only: None,
+        };
+        let simulated = ob.simulate(order);
+        let event = ob.execute(order);
+        assert_eq!(simulated, event);
+        event.fills().iter().map(|f| (f.order_2, f.qty)).collect()
+    }
+
+    #[test]
+    fn pro_rata_allocation() {
+        let mut ob = pro_rata_book(&[2, 3, 5]);
+        assert_eq!(ob.config().matching_policy, MatchingPolicy::ProRata);
+        let fills = pro_rata_fills(&mut ob, 10);
+        assert_eq!(fills, vec![(1, 2), (2, 3), (3, 5)]);
+        assert_eq!(fills.iter().map(|(_, qty)| qty).sum::<u64>(), 10);
+        assert_eq!(ob.order_count(), 0);
+        assert_eq!(ob.min_ask(), None);
+
+        // Exact shares are 1.4, 2.1 and 3.5: the leftover unit goes to the
+        // order with the largest fractional part
+        let mut ob = pro_rata_book(&[2, 3, 5]);
+        assert_eq!(pro_rata_fills(&mut ob, 7), vec![(1, 1), (2, 2), (3, 4)]);
+        assert_eq!(ob.ask_count(), 3);
+        assert_eq!(ob.best_ask(), Some(BookLevel { price: 100, qty: 3 }));
+
+        // Exact shares are 0.2, 0.3 and 0.5: the leftover unit goes to the
+        // order with the largest fractional part, not the earliest one
+        let mut ob = pro_rata_book(&[2, 3, 5]);
+        assert_eq!(pro_rata_fills(&mut ob, 1), vec![(3, 1)]);
+    }
+
+    #[test]
+    fn pro_rata_ties_by_time_priority() {
+        let mut ob = pro_rata_book(&[4, 4, 4]);
+        // Exact shares are 1.67 each: the two leftover units go to the
+        // earliest orders
+        assert_eq!(pro_rata_fills(&mut ob, 5), vec![(1, 2), (2, 2), (3, 1)]);
+    }
+
+    #[test]
+    fn from_orders() {
+        let orders = vec![
+            (1, Side::Bid, 98, 5),
+            (2, Side::Ask, 101, 2),
+            (3, Side::Bid, 99, 3),
+            (4, Side::Ask, 101, 4),
+            (5, Side::Bid, 98, 1),
+            (6, Side::Ask, 103, 7),
+        ];
+        let ob = OrderBook::from_orders(orders.clone(), 16, 4, false);
+        let mut expected = OrderBook::new(16, 4, false);
+        for (id, side, price, qty) in orders {
+            expected.execute(limit(id, side, qty, price));
+        }
+        assert_eq!(ob.depth(10), expected.depth(10));
+        assert_eq!(ob.min_ask(), Some(101));
+        assert_eq!(ob.max_bid(), Some(99));
+        assert_eq!((ob.ask_count(), ob.bid_count()), (3, 3));
+        let ids: Vec<u128> =
+            ob.level_orders(Side::Ask, 101).map(|o| o.id).collect();
+        assert_eq!(ids, vec![2, 4]);
+    }
+
+    #[test]
+    fn cancel_level() {
+        let (mut ob, _) = init_ob(vec![
+            limit(0, Side::Ask, 2, 101),
+            limit(1, Side::Ask, 3, 101),
+            limit(2, Side::Ask, 4, 101),
+            limit(3, Side::Ask, 5, 102),
+            OrderType::Cancel { id: 1 },
+        ]);
+        assert_eq!(ob.cancel_level(Side::Ask, 101), vec![0, 2]);
+        assert_eq!(ob.min_ask(), Some(102));
+        assert_eq!(ob.best_ask(), Some(BookLevel { price: 102, qty: 5 }));
+        assert_eq!(ob.ask_count(), 1);
+        assert_eq!(ob.order(0), None);
+        assert_eq!(ob.cancel_level(Side::Ask, 101), Vec::<u128>::new());
+        assert_eq!(ob.cancel_level(Side::Bid, 102), Vec::<u128>::new());
+
+        assert_eq!(ob.cancel_level(Side::Ask, 102), vec![3]);
+        assert_eq!(ob.min_ask(), None);
+        assert_eq!(ob.order_count(), 0);
+    }
+
+    #[test]
+    fn cancel_all() {
+        let mut ob = OrderBook::default();
+        for (id, side, price, owner) in [
+            (0, Side::Ask, 101, Some(7)),
+            (1, Side::Ask, 102, None),
+            (2, Side::Bid, 99, Some(7)),
+            (3, Side::Bid, 99, Some(8)),
+        ] {
+            ob.execute(OrderType::Limit {
+                id,
+                side,
+                qty: 2,
+                price,
+                display_qty: None,
+                owner,
+                tag: None,
+                reduce_only: None,
+            });
+        }
+        ob.execute(OrderType::Cancel { id: 3 });
+
+        assert_eq!(ob.cancel_all_for(7), vec![0, 2]);
+        assert_eq!(ob.min_ask(), Some(102));
+        assert_eq!(ob.max_bid(), None);
+        assert_eq!(ob.order_count(), 1);
+
+        assert_eq!(ob.cancel_all(), vec![1]);
+        assert_eq!(ob.cancel_all(), Vec::<u128>::new());
+        assert_eq!(ob.order_count(), 0);
+        assert_eq!(ob.min_ask(), None);
+        assert_eq!(ob.max_bid(), None);
+        let depth = ob.depth(10);
+        assert!(depth.asks.is_empty() && depth.bids.is_empty());
+    }
+
+    #[test]
+    fn vwap() {
+        let (mut ob, _) = init_ob(vec![
+            limit(0, Side::Ask, 3, 100),
+            limit(1, Side::Ask, 10, 104),
+        ]);
+        ob.set_tape_capacity(10);
+        assert_eq!(ob.vwap(3), None);
+        // The first trade fills 2 at 100, the second 1 at 100 and 5 at 104
+        for (id, qty) in [(2, 2), (3, 6), (4, 1)] {
+            ob.execute(OrderType::Market {
+                id,
+                side: Side::Bid,
+                qty,
+                tag: None,
+                owner: None,
+                protection_price: None,
+                reduce_only: None,
+            });
+        }
+        let close = |a: Option<f64>, b: f64| (a.unwrap() - b).abs() < 1.0e-6;
+        assert!(close(ob.vwap(1), 104.0));
+        assert!(close(ob.vwap(2), (100.0 + 5.0 * 104.0 + 104.0) / 7.0));
+        assert!(close(ob.vwap(3), (300.0 + 6.0 * 104.0) / 9.0));
+        assert!(close(ob.vwap(100), (300.0 + 6.0 * 104.0) / 9.0));
+        assert_eq!(ob.vwap(0), None);
+    }
+
+    #[test]
+    fn clone_forks_the_book() {
+        let (mut ob, _) = init_ob(vec![
+            limit(0, Side::Ask, 5, 101),
+            limit(1, Side::Bid, 5, 99),
+        ]);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let sink = Rc::clone(&events);
+        ob.set_observer(move |event| sink.borrow_mut().push(event));
+        let mut fork = ob.clone();
+        let depth = ob.depth(10);
+
+        fork.execute(OrderType::Market {
+            id: 2,
+            side: Side::Bid,
+            qty: 5,
+            owner: None,
+            tag: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        fork.execute(limit(3, Side::Bid, 2, 100));
+        assert_eq!(ob.depth(10), depth);
+        assert_eq!(ob.traded_volume(), 0);
+        // The observer stays with the original book
+        assert!(events.borrow().is_empty());
+
+        let fork_depth = fork.depth(10);
+        ob.execute(OrderType::Cancel { id: 0 });
+        ob.execute(limit(4, Side::Bid, 1, 98));
+        assert_eq!(fork.depth(10), fork_depth);
+        assert_eq!(fork.min_ask(), None);
+        assert_eq!(fork.max_bid(), Some(100));
+        assert_eq!(fork.traded_volume(), 5);
+        // Arena indices in the fork still point to its own orders
+        assert_eq!(fork.order(1).map(|o| o.qty), Some(5));
+        assert_eq!(ob.order(0), None);
+        assert!(!events.borrow().is_empty());
+    }
+
+    #[test]
+    fn random_orders_keep_invariants() {
+        // A xorshift generator, so that failures are reproducible
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move |n: u64| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % n
+        };
+        let configs = [
+            (MatchingPolicy::Fifo, None),
+            (MatchingPolicy::Fifo, Some(StpMode::CancelNewest)),
+            (MatchingPolicy::ProRata, Some(StpMode::CancelResting)),
+        ];
+        for (policy, stp_mode) in configs {
+            let mut ob = OrderBook::new(16, 4, true);
+            ob.set_matching_policy(policy);
+            ob.set_stp_mode(stp_mode);
+            for id in 0..3_000 {
+                let side = [Side::Bid, Side::Ask][next(2) as usize];
+                let qty = 1 + next(10);
+                let price = 90 + next(20);
+                let owner = Some(next(4)).filter(|o| *o < 3);
+                let order = match next(21) {
+                    0..=8 => OrderType::Limit {
+                        id,
+                        side,
+                        qty,
+                        price,
+                        display_qty: Some(1 + next(qty))
+                            .filter(|_| next(4) == 0),
+                        owner,
+                        tag: None,
+                        reduce_only: None,
+                    },
+                    9..=11 => OrderType::Market {
+                        id,
+                        side,
+                        qty: qty * 2,
+                        owner,
+                        tag: None,
+                        protection_price: None,
+                        reduce_only: None,
+                    },
+                    12..=15 => OrderType::Cancel {
+                        id: u128::from(next(id as u64 + 1)),
+                    },
+                    16..=17 => OrderType::Amend {
+                        id: u128::from(next(id as u64 + 1)),
+                        new_qty: qty,
+                        new_price: price,
+                    },
+                    18 => OrderType::GoodTillTime {
+                        id,
+                        side,
+                        qty,
+                        price,
+                        expires_at: ob.time() + next(50),
+                    },
+                    19 => OrderType::AllOrNone {
+                        id,
+                        side,
+                        qty,
+                        price,
+                        owner,
+                        tag: None,
+                    },
+                    _ => OrderType::StopMarket {
+                        id,
+                        side,
+                        qty,
+                        trigger: price,
+                    },
+                };
+                ob.execute(order);
+                if next(10) == 0 {
+                    ob.set_time(ob.time() + 5);
+                }
+                if let Err(err) = ob.validate() {
+                    panic!("{:?} after {:?}: {}", policy, order, err);
+                }
             }
+            assert!(ob.traded_volume() > 0 && ob.order_count() > 0);
         }
     }
 
     #[test]
-    fn two_resting_orders_merged() {
-        for (bid_ask, _) in &BID_ASK_COMBINATIONS {
-            let (ob, results) = init_ob(vec![
-                OrderType::Limit {
-                    id: 0,
-                    side: *bid_ask,
-                    qty: 12,
-                    price: 395,
-                },
-                OrderType::Limit {
-                    id: 1,
-                    side: *bid_ask,
-                    qty: 2,
-                    price: 395,
-                },
-            ]);
+    fn amend_policy() {
+        let ask = |id, qty| limit(id, Side::Ask, qty, 101);
+        let amend = |id, new_qty| OrderType::Amend {
+            id,
+            new_qty,
+            new_price: 101,
+        };
+        let buy = |id, qty| OrderType::Market {
+            id,
+            side: Side::Bid,
+            qty,
+            tag: None,
+            owner: None,
+            protection_price: None,
+            reduce_only: None,
+        };
+        let makers = |event: OrderEvent| {
+            event
+                .fills()
+                .iter()
+                .map(|fm| (fm.order_2, fm.qty))
+                .collect::<Vec<_>>()
+        };
+
+        for (policy, expected) in [
+            (
+                AmendPolicy::AlwaysLosePriorityOnIncrease,
+                vec![(1, 5), (0, 1)],
+            ),
+            (AmendPolicy::KeepPriorityUpToOriginal, vec![(0, 5), (1, 1)]),
+        ] {
+            let (mut ob, _) = init_ob(vec![ask(0, 5), ask(1, 5)]);
+            ob.set_amend_policy(policy);
+            assert_eq!(ob.config().amend_policy, policy);
+            ob.execute(amend(0, 3));
+            let simulated = ob.simulate(amend(0, 5));
+            assert_eq!(ob.execute(amend(0, 5)), simulated);
             assert_eq!(
-                results,
-                vec![
-                    OrderEvent::Placed { id: 0 },
-                    OrderEvent::Placed { id: 1 }
-                ]
+                ob.best_ask(),
+                Some(BookLevel {
+                    price: 101,
+                    qty: 10
+                })
             );
-            if *bid_ask == Side::Bid {
-                assert_eq!(ob.min_ask(), None);
-                assert_eq!(ob.max_bid(), Some(395));
-                assert_eq!(ob._asks(), BTreeMap::new());
-                assert_eq!(
-                    ob._bids(),
-                    init_book(vec![(395, 9999), (395, 9998)])
-                );
-                assert_eq!(ob.spread(), None);
-                assert_eq!(ob.traded_volume(), 0);
-                assert_eq!(
-                    ob.depth(3),
-                    BookDepth {
-                        levels: 3,
-                        asks: Vec::new(),
-                        bids: vec![BookLevel {
-                            price: 395,
-                            qty: 14
-                        }],
-                    }
-                );
-                assert_eq!(ob.last_trade(), None);
-            } else {
-                assert_eq!(ob.min_ask(), Some(395));
-                assert_eq!(ob.max_bid(), None);
-                assert_eq!(
-                    ob._asks(),
-                    init_book(vec![(395, 9999), (395, 9998)])
-                );
-                assert_eq!(ob._bids(), BTreeMap::new());
-                assert_eq!(ob.spread(), None);
-                assert_eq!(ob.traded_volume(), 0);
-                assert_eq!(
-                    ob.depth(3),
-                    BookDepth {
-                        levels: 3,
-                        asks: vec![BookLevel {
-                            price: 395,
-                            qty: 14
-                        }],
-                        bids: Vec::new(),
-                    }
-                );
-                assert_eq!(ob.last_trade(), None);
-            }
+            assert_eq!(makers(ob.execute(buy(2, 6))), expected);
         }
+
+        // Exceeding the queued quantity loses the priority
+        let (mut ob, _) = init_ob(vec![ask(0, 5), ask(1, 5)]);
+        ob.set_amend_policy(AmendPolicy::KeepPriorityUpToOriginal);
+        ob.execute(amend(0, 6));
+        assert_eq!(makers(ob.execute(buy(2, 6))), vec![(1, 5), (0, 1)]);
+
+        // Fills reduce the quantity that can be restored with priority
+        let (mut ob, _) = init_ob(vec![ask(0, 5), ask(1, 5)]);
+        ob.set_amend_policy(AmendPolicy::KeepPriorityUpToOriginal);
+        ob.execute(buy(2, 2));
+        ob.execute(amend(0, 1));
+        ob.execute(amend(0, 3));
+        assert_eq!(makers(ob.execute(buy(3, 4))), vec![(0, 3), (1, 1)]);
+        let (mut ob, _) = init_ob(vec![ask(0, 5), ask(1, 5)]);
+        ob.set_amend_policy(AmendPolicy::KeepPriorityUpToOriginal);
+        ob.execute(buy(2, 2));
+        ob.execute(amend(0, 4));
+        assert_eq!(makers(ob.execute(buy(3, 6))), vec![(1, 5), (0, 1)]);
     }
 
     #[test]
-    fn two_resting_orders_stacked() {
-        for (bid_ask, _) in &BID_ASK_COMBINATIONS {
-            let (ob, results) = init_ob(vec![
-                OrderType::Limit {
-                    id: 0,
-                    side: *bid_ask,
-                    qty: 12,
-                    price: 395,
-                },
-                OrderType::Limit {
-                    id: 1,
-                    side: *bid_ask,
-                    qty: 2,
-                    price: 398,
-                },
-            ]);
-            assert_eq!(
-                results,
-                vec![
-                    OrderEvent::Placed { id: 0 },
-                    OrderEvent::Placed { id: 1 }
-                ]
-            );
-            if *bid_ask == Side::Bid {
-                assert_eq!(ob.min_ask(), None);
-                assert_eq!(ob.max_bid(), Some(398));
-                assert_eq!(ob._asks(), BTreeMap::new());
-                assert_eq!(
-                    ob._bids(),
-                    init_book(vec![(398, 9998), (395, 9999)])
-                );
-                assert_eq!(ob.spread(), None);
-            } else {
-                assert_eq!(ob.min_ask(), Some(395));
-                assert_eq!(ob.max_bid(), None);
-                assert_eq!(
-                    ob._asks(),
-                    init_book(vec![(398, 9998), (395, 9999)])
-                );
-                assert_eq!(ob._bids(), BTreeMap::new());
-                assert_eq!(ob.spread(), None);
-            }
+    fn unfilled_reason() {
+        let mut ob = OrderBook::default();
+        let sell = |id, protection_price| OrderType::Market {
+            id,
+            side: Side::Ask,
+            qty: 2,
+            owner: None,
+            tag: None,
+            protection_price,
+            reduce_only: None,
+        };
+        let unfilled = |id, reason| OrderEvent::Unfilled { id, reason };
+        assert_eq!(
+            ob.execute(sell(0, None)),
+            unfilled(0, UnfilledReason::NoLiquidity)
+        );
+        assert_eq!(
+            ob.execute(sell(1, Some(100))),
+            unfilled(1, UnfilledReason::NoLiquidity)
+        );
+
+        rest(&mut ob, 2, Side::Bid, 1, 99);
+        assert_eq!(
+            ob.simulate(sell(3, Some(100))),
+            ob.execute(sell(3, Some(100)))
+        );
+        assert_eq!(
+            ob.execute(sell(4, Some(100))),
+            unfilled(4, UnfilledReason::PriceProtection)
+        );
+        assert_eq!(ob.max_bid(), Some(99));
+    }
+
+    #[test]
+    fn depth_detailed() {
+        let (mut ob, _) = init_ob(vec![]);
+        for (id, qty) in [(0, 4), (1, 2), (2, 6)] {
+            rest(&mut ob, id, Side::Bid, qty, 100);
         }
+        rest(&mut ob, 3, Side::Bid, 1, 99);
+        rest(&mut ob, 4, Side::Bid, 1, 98);
+        rest(&mut ob, 5, Side::Ask, 3, 101);
+        ob.execute(OrderType::Market {
+            id: 6,
+            side: Side::Ask,
+            qty: 3,
+            owner: None,
+            tag: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+
+        let depth = ob.depth_detailed(2);
+        assert_eq!(depth.levels, 2);
+        let residuals = |level: &DetailedBookLevel| {
+            let orders = level.orders.iter();
+            (
+                level.price,
+                orders.map(|o| (o.id, o.qty)).collect::<Vec<_>>(),
+            )
+        };
+        let bids: Vec<_> = depth.bids.iter().map(residuals).collect();
+        assert_eq!(
+            bids,
+            vec![(100, vec![(0, 1), (1, 2), (2, 6)]), (99, vec![(3, 1)])]
+        );
+        let asks: Vec<_> = depth.asks.iter().map(residuals).collect();
+        assert_eq!(asks, vec![(101, vec![(5, 3)])]);
     }
 
     #[test]
-    fn three_resting_orders_stacked() {
-        for (bid_ask, ask_bid) in &BID_ASK_COMBINATIONS {
-            let (ob, results) = init_ob(vec![
-                OrderType::Limit {
+    fn large_prices_do_not_overflow() {
+        let price = u64::MAX / 2;
+        let (mut ob, _) = init_ob(vec![]);
+        ob.set_tape_capacity(4);
+        rest(&mut ob, 0, Side::Ask, 3, price);
+        rest(&mut ob, 1, Side::Ask, 3, price + 1);
+        let event = ob.execute(OrderType::Market {
+            id: 2,
+            side: Side::Bid,
+            qty: 6,
+            owner: None,
+            tag: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        let trade = event.trade().unwrap();
+        assert_eq!(trade.total_qty, 6);
+        assert_eq!(trade.avg_price, price as f64);
+        assert_eq!(ob.last_trade(), Some(trade));
+        assert_eq!(event.trade_print().unwrap().vwap, price as f64);
+        assert_eq!(ob.vwap(1), Some(price as f64));
+
+        rest(&mut ob, 3, Side::Ask, 3, price);
+        rest(&mut ob, 4, Side::Bid, 3, price - 1);
+        assert_eq!(ob.spread(), Some(1));
+        assert_eq!(ob.weighted_price(Side::Ask, 1), Some(price as f64));
+        assert_eq!(ob.vwap_for_qty(Side::Bid, 3), Some(price as f64));
+        assert_eq!(ob.round_trip_cost(3), Some(3));
+
+        // A crossed book has no spread
+        rest(&mut ob, 5, Side::Bid, 1, price + 1);
+        assert_eq!(ob.spread(), None);
+    }
+
+    #[test]
+    fn level_at() {
+        let mut ob = OrderBook::default();
+        for (id, qty, price) in
+            [(0, 3, 100), (1, 5, 99), (2, 1, 98), (3, 2, 97)]
+        {
+            rest(&mut ob, id, Side::Bid, qty, price);
+        }
+        rest(&mut ob, 4, Side::Ask, 4, 101);
+        // Leave an empty queue at 99
+        ob.execute(OrderType::Cancel { id: 1 });
+        assert_eq!(ob._bids()[&99], Vec::<usize>::new());
+
+        let level = |price, qty| Some(BookLevel { price, qty });
+        assert_eq!(ob.level_at(Side::Bid, 0), level(100, 3));
+        assert_eq!(ob.level_at(Side::Bid, 1), level(98, 1));
+        assert_eq!(ob.level_at(Side::Bid, 2), level(97, 2));
+        assert_eq!(ob.level_at(Side::Bid, 3), None);
+        assert_eq!(ob.level_at(Side::Ask, 0), level(101, 4));
+        assert_eq!(ob.level_at(Side::Ask, 1), None);
+    }
+
+    #[test]
+    fn observer_maker_fills() {
+        let mut ob = OrderBook::default();
+        rest(&mut ob, 0, Side::Bid, 2, 100);
+        rest(&mut ob, 1, Side::Bid, 4, 99);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let observed = Rc::clone(&events);
+        ob.set_observer(move |event| observed.borrow_mut().push(event));
+
+        ob.execute(OrderType::Market {
+            id: 2,
+            side: Side::Ask,
+            qty: 3,
+            owner: None,
+            tag: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        let maker_fills: Vec<_> = events
+            .borrow()
+            .iter()
+            .copied()
+            .filter(|event| matches!(event, BookEvent::OrderFilled { .. }))
+            .collect();
+        assert_eq!(
+            maker_fills,
+            vec![
+                BookEvent::OrderFilled {
                     id: 0,
-                    side: *bid_ask,
-                    qty: 12,
-                    price: 395,
-                },
-                OrderType::Limit {
-                    id: 1,
-                    side: *ask_bid,
+                    side: Side::Bid,
+                    price: 100,
                     qty: 2,
-                    price: 399,
+                    remaining_qty: 0,
                 },
-                OrderType::Limit {
-                    id: 2,
-                    side: *bid_ask,
-                    qty: 2,
-                    price: 398,
+                BookEvent::OrderFilled {
+                    id: 1,
+                    side: Side::Bid,
+                    price: 99,
+                    qty: 1,
+                    remaining_qty: 3,
                 },
-            ]);
-            if *bid_ask == Side::Bid {
-                assert_eq!(
-                    results,
-                    vec![
-                        OrderEvent::Placed { id: 0 },
-                        OrderEvent::Placed { id: 1 },
-                        OrderEvent::Placed { id: 2 }
-                    ]
-                );
-                assert_eq!(ob.min_ask(), Some(399));
-                assert_eq!(ob.max_bid(), Some(398));
-                assert_eq!(ob._asks(), init_book(vec![(399, 9998)]));
-                assert_eq!(
-                    ob._bids(),
-                    init_book(vec![(398, 9997), (395, 9999)])
-                );
-                assert_eq!(ob.spread(), Some(1));
-            } else {
-                assert_eq!(
-                    results,
-                    vec![
-                        OrderEvent::Placed { id: 0 },
-                        OrderEvent::Filled {
-                            id: 1,
-                            filled_qty: 2,
-                            fills: vec![FillMetadata {
-                                order_1: 1,
-                                order_2: 0,
-                                qty: 2,
-                                price: 395,
-                                taker_side: *ask_bid,
-                                total_fill: false,
-                            }],
-                        },
-                        OrderEvent::Placed { id: 2 }
-                    ]
-                );
-                assert_eq!(ob.min_ask(), Some(395));
-                assert_eq!(ob.max_bid(), None);
-                assert_eq!(
-                    ob._asks(),
-                    init_book(vec![(398, 9998), (395, 9999)])
-                );
-                assert_eq!(ob._bids(), init_book(vec![]));
-                assert_eq!(ob.spread(), None);
+            ]
+        );
+    }
+
+    #[test]
+    fn arena_stats() {
+        let mut ob = OrderBook::new(4, DEFAULT_QUEUE_SIZE, false);
+        let stats = ob.arena_stats();
+        assert_eq!((stats.capacity, stats.free_slots), (4, 4));
+        assert_eq!((stats.live_orders, stats.high_water_mark), (0, 0));
+
+        for id in 0..6 {
+            rest(&mut ob, id, Side::Ask, 1, 100 + id as u64);
+        }
+        for id in 0..5 {
+            ob.execute(OrderType::Cancel { id });
+        }
+        let stats = ob.arena_stats();
+        assert_eq!(stats.capacity, 6);
+        assert_eq!(stats.live_orders, 1);
+        assert_eq!(stats.free_slots, 5);
+        assert_eq!(stats.high_water_mark, 6);
+
+        rest(&mut ob, 6, Side::Ask, 1, 100);
+        assert_eq!(ob.arena_stats().high_water_mark, 6);
+        ob.clear();
+        assert_eq!(ob.arena_stats().high_water_mark, 0);
+    }
+
+    #[test]
+    fn contains() {
+        let mut ob = OrderBook::default();
+        assert!(!ob.contains(1));
+        rest(&mut ob, 1, Side::Ask, 2, 100);
+        rest(&mut ob, 2, Side::Ask, 2, 101);
+        assert!(ob.contains(1));
+        assert!(ob.contains(2));
+
+        ob.execute(OrderType::Market {
+            id: 3,
+            side: Side::Bid,
+            qty: 2,
+            owner: None,
+            tag: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        assert!(!ob.contains(1));
+        assert!(!ob.contains(3));
+        assert!(ob.contains(2));
+
+        ob.execute(OrderType::Cancel { id: 2 });
+        assert!(!ob.contains(2));
+    }
+
+    #[test]
+    fn partially_filled_head_keeps_priority() {
+        let market = |ob: &mut OrderBook, id, qty| {
+            ob.execute(OrderType::Market {
+                id,
+                side: Side::Bid,
+                qty,
+                owner: None,
+                tag: None,
+                protection_price: None,
+                reduce_only: None,
+            })
+        };
+        let queue = |ob: &OrderBook| {
+            ob.level_orders(Side::Ask, 100)
+                .map(|o| (o.id, o.qty))
+                .collect::<Vec<_>>()
+        };
+        let cases: [(u64, &[(u128, u64)]); 2] =
+            [(5, &[(2, 5)]), (4, &[(1, 1), (2, 5)])];
+        for &(qty, expected) in &cases {
+            let mut ob = OrderBook::default();
+            rest(&mut ob, 1, Side::Ask, 5, 100);
+            rest(&mut ob, 2, Side::Ask, 5, 100);
+            market(&mut ob, 3, qty);
+            assert_eq!(queue(&ob), expected);
+        }
+
+        let mut ob = OrderBook::default();
+        rest(&mut ob, 1, Side::Ask, 5, 100);
+        rest(&mut ob, 2, Side::Ask, 5, 100);
+        market(&mut ob, 3, 3);
+        let fills = market(&mut ob, 4, 3).fills().to_vec();
+        assert_eq!(
+            fills
+                .iter()
+                .map(|f| (f.order_2, f.qty, f.total_fill))
+                .collect::<Vec<_>>(),
+            vec![(1, 2, true), (2, 1, false)]
+        );
+        assert_eq!(queue(&ob), vec![(2, 4)]);
+    }
+
+    #[test]
+    fn all_or_none_maker() {
+        let aon = |id, qty, price| OrderType::AllOrNone {
+            id,
+            side: Side::Ask,
+            qty,
+            price,
+            owner: None,
+            tag: None,
+        };
+        let buy = |id, qty| OrderType::Market {
+            id,
+            side: Side::Bid,
+            qty,
+            owner: None,
+            tag: None,
+            protection_price: None,
+            reduce_only: None,
+        };
+        let makers = |event: OrderEvent| {
+            event
+                .fills()
+                .iter()
+                .map(|f| (f.order_2, f.qty, f.price))
+                .collect::<Vec<_>>()
+        };
+        let mut ob = OrderBook::default();
+        assert_eq!(ob.execute(aon(1, 10, 100)), OrderEvent::Placed { id: 1 });
+        rest(&mut ob, 2, Side::Ask, 2, 100);
+        rest(&mut ob, 3, Side::Ask, 5, 101);
+
+        assert_eq!(ob.simulate(buy(4, 4)), ob.clone().execute(buy(4, 4)));
+        assert_eq!(
+            makers(ob.execute(buy(4, 4))),
+            vec![(2, 2, 100), (3, 2, 101)]
+        );
+        let queue: Vec<u128> =
+            ob.level_orders(Side::Ask, 100).map(|o| o.id).collect();
+        assert_eq!(queue, vec![1]);
+        assert!(ob.order(1).unwrap().all_or_none);
+
+        assert_eq!(
+            makers(ob.execute(buy(5, 11))),
+            vec![(1, 10, 100), (3, 1, 101)]
+        );
+        assert!(!ob.contains(1));
+        assert!(ob.validate().is_ok());
+
+        let mut ob = OrderBook::default();
+        ob.set_matching_policy(MatchingPolicy::ProRata);
+        rest(&mut ob, 1, Side::Ask, 4, 100);
+        ob.execute(aon(2, 3, 100));
+        ob.execute(aon(3, 6, 100));
+        rest(&mut ob, 4, Side::Ask, 4, 100);
+        assert_eq!(ob.simulate(buy(5, 5)), ob.clone().execute(buy(5, 5)));
+        assert_eq!(
+            makers(ob.execute(buy(5, 5))),
+            vec![(2, 3, 100), (1, 1, 100), (4, 1, 100)]
+        );
+        assert!(ob.order(3).is_some());
+        assert!(ob.validate().is_ok());
+    }
+
+    #[test]
+    fn all_or_none_taker() {
+        let aon = |id, qty, price| OrderType::AllOrNone {
+            id,
+            side: Side::Bid,
+            qty,
+            price,
+            owner: None,
+            tag: None,
+        };
+        let mut ob = OrderBook::default();
+        rest(&mut ob, 1, Side::Ask, 3, 100);
+        assert_eq!(ob.simulate(aon(2, 5, 100)), OrderEvent::Placed { id: 2 });
+        assert_eq!(ob.execute(aon(2, 5, 100)), OrderEvent::Placed { id: 2 });
+        assert!(ob.is_locked());
+        assert_eq!(ob.order(1).map(|o| o.qty), Some(3));
+        assert!(ob.validate().is_ok());
+
+        let event = ob.execute(limit(3, Side::Ask, 2, 100));
+        assert_eq!(event, OrderEvent::Placed { id: 3 });
+
+        let event = ob.execute(aon(4, 5, 100));
+        assert_eq!(event.fills().len(), 2);
+        assert!(matches!(event, OrderEvent::Filled { filled_qty: 5, .. }));
+        assert_eq!(ob.best_ask(), None);
+        assert_eq!(ob.order(2).map(|o| o.qty), Some(5));
+    }
+
+    #[test]
+    fn full_depth() {
+        let mut ob = OrderBook::default();
+        for (id, price) in [(1, 101), (2, 103), (3, 102)] {
+            rest(&mut ob, id, Side::Ask, id as u64, price);
+            rest(&mut ob, id + 3, Side::Bid, id as u64, price - 5);
+        }
+        rest(&mut ob, 7, Side::Bid, 1, 96);
+        ob.execute(OrderType::Cancel { id: 2 });
+        assert_eq!(
+            ob.full_depth(),
+            BookDepth {
+                levels: usize::MAX,
+                asks: vec![
+                    BookLevel { price: 101, qty: 1 },
+                    BookLevel { price: 102, qty: 3 },
+                ],
+                bids: vec![
+                    BookLevel { price: 98, qty: 2 },
+                    BookLevel { price: 97, qty: 3 },
+                    BookLevel { price: 96, qty: 2 },
+                ],
             }
+        );
+        assert_eq!(OrderBook::default().full_depth().asks, vec![]);
+    }
+
+    #[test]
+    fn depth_bids_best_first() {
+        let mut ob = OrderBook::default();
+        for id in 0..5 {
+            rest(&mut ob, id, Side::Bid, 1 + id as u64, 95 + id as u64);
         }
+        assert_eq!(
+            ob.depth(2),
+            BookDepth {
+                levels: 2,
+                asks: vec![],
+                bids: vec![
+                    BookLevel { price: 99, qty: 5 },
+                    BookLevel { price: 98, qty: 4 },
+                ],
+            }
+        );
     }
 
     #[test]
-    fn crossing_limit_order_partial() {
-        for (bid_ask, ask_bid) in &BID_ASK_COMBINATIONS {
-            let (mut ob, results) = init_ob(vec![
-                OrderType::Limit {
-                    id: 0,
-                    side: *bid_ask,
-                    qty: 12,
-                    price: 395,
-                },
-                OrderType::Limit {
-                    id: 1,
-                    side: *ask_bid,
-                    qty: 2,
-                    price: 399,
-                },
-                OrderType::Limit {
-                    id: 2,
-                    side: *bid_ask,
-                    qty: 2,
-                    price: 398,
-                },
-            ]);
-            let result = ob.execute(OrderType::Limit {
-                id: 3,
-                side: *ask_bid,
-                qty: 1,
-                price: 397,
-            });
+    fn depth_honors_levels() {
+        let mut ob = OrderBook::default();
+        for id in 0..50 {
+            rest(&mut ob, id, Side::Ask, 1, 200 + id as u64);
+        }
+        for id in 50..53 {
+            rest(&mut ob, id, Side::Bid, 1, 100 + id as u64);
+        }
+        for levels in [0, 1, 3, 10, 50, 100] {
+            let depth = ob.depth(levels);
+            assert_eq!(depth.levels, levels);
+            assert_eq!(depth.asks.len(), levels.min(50));
+            assert_eq!(depth.bids.len(), levels.min(3));
+        }
+        assert_eq!(ob.depth(1).asks, vec![BookLevel { price: 200, qty: 1 }]);
+        assert_eq!(ob.depth(1).bids, vec![BookLevel { price: 152, qty: 1 }]);
+    }
 
-            if *bid_ask == Side::Bid {
-                assert_eq!(
-                    results,
-                    vec![
-                        OrderEvent::Placed { id: 0 },
-                        OrderEvent::Placed { id: 1 },
-                        OrderEvent::Placed { id: 2 }
-                    ]
-                );
-                assert_eq!(
-                    result,
-                    OrderEvent::Filled {
-                        id: 3,
-                        filled_qty: 1,
-                        fills: vec![FillMetadata {
-                            order_1: 3,
-                            order_2: 2,
-                            qty: 1,
-                            price: 398,
-                            taker_side: *ask_bid,
-                            total_fill: false,
-                        }]
-                    }
-                );
-                assert_eq!(ob.min_ask(), Some(399));
-                assert_eq!(ob.max_bid(), Some(398));
-                assert_eq!(ob._asks(), init_book(vec![(399, 9998)]));
-                assert_eq!(
-                    ob._bids(),
-                    init_book(vec![(398, 9997), (395, 9999)])
-                );
-                assert_eq!(ob.spread(), Some(1));
-            } else {
-                assert_eq!(
-                    results,
-                    vec![
-                        OrderEvent::Placed { id: 0 },
-                        OrderEvent::Filled {
-                            id: 1,
-                            filled_qty: 2,
-                            fills: vec![FillMetadata {
-                                order_1: 1,
-                                order_2: 0,
-                                qty: 2,
-                                price: 395,
-                                taker_side: *ask_bid,
-                                total_fill: false,
-                            }],
-                        },
-                        OrderEvent::Placed { id: 2 }
-                    ]
-                );
-                assert_eq!(
-                    result,
-                    OrderEvent::Filled {
-                        id: 3,
-                        filled_qty: 1,
-                        fills: vec![FillMetadata {
-                            order_1: 3,
-                            order_2: 0,
-                            qty: 1,
-                            price: 395,
-                            taker_side: *ask_bid,
-                            total_fill: false,
-                        }]
-                    }
-                );
-                assert_eq!(ob.min_ask(), Some(395));
-                assert_eq!(ob.max_bid(), None);
-                assert_eq!(
-                    ob._asks(),
-                    init_book(vec![(398, 9998), (395, 9999)])
-                );
-                assert_eq!(ob._bids(), init_book(vec![]));
-                assert_eq!(ob.spread(), None);
-            }
+    #[test]
+    fn display_ladder() {
+        let mut ob = OrderBook::default();
+        rest(&mut ob, 1, Side::Ask, 3, 1002);
+        rest(&mut ob, 2, Side::Ask, 12, 101);
+        rest(&mut ob, 3, Side::Ask, 1, 101);
+        assert_eq!(ob.to_string(), "ask 1002  3\nask  101 13\nspread -");
+
+        rest(&mut ob, 4, Side::Bid, 250, 99);
+        rest(&mut ob, 5, Side::Bid, 2, 97);
+        assert_eq!(
+            ob.to_string(),
+            [
+                "ask 1002   3",
+                "ask  101  13",
+                "spread 2",
+                "bid   99 250",
+                "bid   97   2",
+            ]
+            .join("\n")
+        );
+        assert_eq!(
+            ob.depth(1).to_string(),
+            "ask 101  13\nspread 2\nbid  99 250"
+        );
+    }
+
+    #[test]
+    fn sequence_numbers() {
+        let mut ob = OrderBook::default();
+        let orders = [
+            (1, Side::Ask, 101),
+            (2, Side::Bid, 99),
+            (3, Side::Ask, 102),
+            (4, Side::Ask, 101),
+        ];
+        let mut last = 0;
+        for (id, side, price) in orders {
+            let event = ob.execute(limit(id, side, 5, price));
+            assert_eq!(event, OrderEvent::Placed { id });
+            let seq = ob.order_seq(id).unwrap();
+            assert!(seq > last);
+            assert_eq!(ob.order(id).map(|o| o.seq), Some(seq));
+            last = seq;
         }
+
+        let seq = ob.order_seq(1).unwrap();
+        ob.execute(OrderType::Market {
+            id: 5,
+            side: Side::Bid,
+            qty: 2,
+            owner: None,
+            tag: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        assert_eq!(ob.order(1).map(|o| (o.qty, o.seq)), Some((3, seq)));
+        assert!(ob.order_seq(1) < ob.order_seq(4));
     }
 
     #[test]
-    fn crossing_limit_order_matching() {
-        for (bid_ask, ask_bid) in &BID_ASK_COMBINATIONS {
-            let (mut ob, results) = init_ob(vec![
-                OrderType::Limit {
-                    id: 0,
-                    side: *bid_ask,
-                    qty: 12,
-                    price: 395,
-                },
-                OrderType::Limit {
-                    id: 1,
-                    side: *ask_bid,
-                    qty: 2,
-                    price: 399,
-                },
-                OrderType::Limit {
-                    id: 2,
-                    side: *bid_ask,
-                    qty: 2,
-                    price: 398,
-                },
-            ]);
-            let result = ob.execute(OrderType::Limit {
+    fn cancel_replace() {
+        let replace = |old_id, new_id| OrderType::CancelReplace {
+            old_id,
+            new_id,
+            side: Side::Bid,
+            qty: 5,
+            price: 101,
+        };
+        let mut ob = OrderBook::default();
+        rest(&mut ob, 1, Side::Ask, 3, 101);
+        ob.execute(OrderType::Limit {
+            id: 2,
+            side: Side::Bid,
+            qty: 5,
+            price: 99,
+            display_qty: None,
+            owner: Some(7),
+            tag: Some(3),
+            reduce_only: None,
+        });
+
+        let expected = OrderEvent::Replaced {
+            canceled: 2,
+            placement: Box::new(OrderEvent::PartiallyFilled {
                 id: 3,
-                side: *ask_bid,
-                qty: 2,
-                price: 397,
-            });
+                filled_qty: 3,
+                resting_qty: 2,
+                fills: vec![FillMetadata {
+                    order_1: 3,
+                    order_2: 1,
+                    qty: 3,
+                    price: 101,
+                    taker_side: Side::Bid,
+                    total_fill: true,
+                    taker_tag: Some(3),
+                    maker_tag: None,
+                }],
+                trade: None,
+            }),
+        };
+        assert_eq!(ob.simulate(replace(2, 3)), expected);
+        assert_eq!(ob.execute(replace(2, 3)), expected);
+        assert_eq!(ob.order(2), None);
+        let order = ob.order(3).unwrap();
+        assert_eq!((order.qty, order.price), (2, 101));
+        assert_eq!((order.owner, order.tag), (Some(7), Some(3)));
+        assert_eq!(ob.best_ask(), None);
 
-            if *bid_ask == Side::Bid {
-                assert_eq!(
-                    results,
-                    vec![
-                        OrderEvent::Placed { id: 0 },
-                        OrderEvent::Placed { id: 1 },
-                        OrderEvent::Placed { id: 2 }
-                    ]
-                );
-                assert_eq!(
-                    result,
-                    OrderEvent::Filled {
-                        id: 3,
-                        filled_qty: 2,
-                        fills: vec![FillMetadata {
-                            order_1: 3,
-                            order_2: 2,
-                            qty: 2,
-                            price: 398,
-                            taker_side: *ask_bid,
-                            total_fill: true,
-                        }]
-                    }
-                );
-                assert_eq!(ob.min_ask(), Some(399));
-                assert_eq!(ob.max_bid(), Some(395));
-                assert_eq!(ob._asks(), init_book(vec![(399, 9998)]));
-                assert_eq!(
-                    ob._bids(),
-                    init_book_holes(vec![(395, 9999)], vec![398])
-                );
-                assert_eq!(ob.spread(), Some(4));
-            } else {
-                assert_eq!(
-                    results,
-                    vec![
-                        OrderEvent::Placed { id: 0 },
-                        OrderEvent::Filled {
-                            id: 1,
-                            filled_qty: 2,
-                            fills: vec![FillMetadata {
-                                order_1: 1,
-                                order_2: 0,
-                                qty: 2,
-                                price: 395,
-                                taker_side: *ask_bid,
-                                total_fill: false,
-                            }],
-                        },
-                        OrderEvent::Placed { id: 2 }
-                    ]
-                );
-                assert_eq!(
-                    result,
-                    OrderEvent::Filled {
-                        id: 3,
-                        filled_qty: 2,
-                        fills: vec![FillMetadata {
-                            order_1: 3,
-                            order_2: 0,
-                            qty: 2,
-                            price: 395,
-                            taker_side: *ask_bid,
-                            total_fill: false,
-                        }]
-                    }
-                );
-                assert_eq!(ob.min_ask(), Some(395));
-                assert_eq!(ob.max_bid(), None);
-                assert_eq!(
-                    ob._asks(),
-                    init_book(vec![(395, 9999), (398, 9998)])
-                );
-                assert_eq!(ob._bids(), init_book(vec![]));
-                assert_eq!(ob.spread(), None);
+        let rejected = OrderEvent::Rejected {
+            id: 4,
+            reason: RejectReason::OrderNotFound,
+        };
+        assert_eq!(ob.execute(replace(2, 4)), rejected);
+        assert_eq!(
+            ob.execute(replace(3, 3)),
+            OrderEvent::Replaced {
+                canceled: 3,
+                placement: Box::new(OrderEvent::Placed { id: 3 }),
             }
+        );
+        assert_eq!(ob.order(3).map(|o| o.qty), Some(5));
+        ob.set_replace_if_missing(true);
+        assert!(ob.config().replace_if_missing);
+        assert_eq!(ob.simulate(replace(2, 4)), OrderEvent::Placed { id: 4 });
+        assert_eq!(ob.execute(replace(2, 4)), OrderEvent::Placed { id: 4 });
+        assert!(ob.contains(3) && ob.contains(4));
+    }
+
+    #[test]
+    fn locked_and_crossed() {
+        let mut ob = OrderBook::default();
+        assert!(!ob.is_locked() && !ob.is_crossed());
+        rest(&mut ob, 1, Side::Ask, 2, 101);
+        rest(&mut ob, 2, Side::Ask, 2, 102);
+        assert!(!ob.is_locked() && !ob.is_crossed());
+
+        let event = ob.execute(limit(3, Side::Bid, 4, 102));
+        assert!(matches!(event, OrderEvent::Filled { filled_qty: 4, .. }));
+        assert!(!ob.is_locked() && !ob.is_crossed());
+
+        rest(&mut ob, 4, Side::Bid, 1, 100);
+        assert!(!ob.is_locked() && !ob.is_crossed());
+        rest(&mut ob, 5, Side::Ask, 1, 100);
+        assert!(ob.is_locked() && !ob.is_crossed());
+        rest(&mut ob, 6, Side::Ask, 1, 99);
+        assert!(!ob.is_locked() && ob.is_crossed());
+    }
+
+    #[test]
+    fn reject_zero_qty() {
+        let mut ob = OrderBook::new(1, DEFAULT_QUEUE_SIZE, false);
+        let orders = [
+            limit(1, Side::Bid, 0, 100),
+            OrderType::Market {
+                id: 2,
+                side: Side::Ask,
+                qty: 0,
+                owner: None,
+                tag: None,
+                protection_price: None,
+                reduce_only: None,
+            },
+            OrderType::StopMarket {
+                id: 3,
+                side: Side::Ask,
+                qty: 0,
+                trigger: 100,
+            },
+        ];
+        for order in orders {
+            assert_eq!(
+                ob.execute(order),
+                OrderEvent::Rejected {
+                    id: order.id(),
+                    reason: RejectReason::ZeroQuantity,
+                }
+            );
         }
+        assert_eq!(ob.depth(10).bids, vec![]);
+        assert_eq!(ob.arena_stats().free_slots, 1);
+        assert!(ob.snapshot().stops.is_empty());
     }
 
     #[test]
-    fn crossing_limit_order_over() {
-        for (bid_ask, ask_bid) in &BID_ASK_COMBINATIONS {
-            let (mut ob, results) = init_ob(vec![
-                OrderType::Limit {
-                    id: 0,
-                    side: *bid_ask,
-                    qty: 12,
-                    price: 395,
-                },
-                OrderType::Limit {
-                    id: 1,
-                    side: *ask_bid,
-                    qty: 2,
-                    price: 399,
-                },
-                OrderType::Limit {
-                    id: 2,
-                    side: *bid_ask,
-                    qty: 2,
-                    price: 398,
-                },
-            ]);
-            let result = ob.execute(OrderType::Limit {
-                id: 3,
-                side: *ask_bid,
-                qty: 5,
-                price: 397,
-            });
+    fn delta_since_snapshot() {
+        let mut ob = OrderBook::default();
+        rest(&mut ob, 1, Side::Ask, 2, 101);
+        rest(&mut ob, 2, Side::Ask, 3, 102);
+        rest(&mut ob, 3, Side::Bid, 4, 99);
+        rest(&mut ob, 4, Side::Bid, 1, 98);
+        let prev = ob.snapshot();
+        let mut depth = ob.full_depth();
+        assert_eq!(ob.delta_since(&prev).asks, vec![]);
 
-            if *bid_ask == Side::Bid {
-                assert_eq!(
-                    results,
-                    vec![
-                        OrderEvent::Placed { id: 0 },
-                        OrderEvent::Placed { id: 1 },
-                        OrderEvent::Placed { id: 2 }
-                    ]
-                );
-                assert_eq!(
-                    result,
-                    OrderEvent::PartiallyFilled {
-                        id: 3,
-                        filled_qty: 2,
-                        fills: vec![FillMetadata {
-                            order_1: 3,
-                            order_2: 2,
-                            qty: 2,
-                            price: 398,
-                            taker_side: *ask_bid,
-                            total_fill: true,
-                        }]
-                    }
-                );
-                assert_eq!(ob.min_ask(), Some(397));
-                assert_eq!(ob.max_bid(), Some(395));
-                assert_eq!(
-                    ob._asks(),
-                    init_book(vec![(399, 9998), (397, 9996)])
-                );
-                assert_eq!(
-                    ob._bids(),
-                    init_book_holes(vec![(395, 9999)], vec![398])
-                );
-                assert_eq!(ob.spread(), Some(2));
-            } else {
-                assert_eq!(
-                    results,
-                    vec![
-                        OrderEvent::Placed { id: 0 },
-                        OrderEvent::Filled {
-                            id: 1,
-                            filled_qty: 2,
-                            fills: vec![FillMetadata {
-                                order_1: 1,
-                                order_2: 0,
-                                qty: 2,
-                                price: 395,
-                                taker_side: *ask_bid,
-                                total_fill: false,
-                            }],
-                        },
-                        OrderEvent::Placed { id: 2 }
-                    ]
-                );
-                assert_eq!(
-                    result,
-                    OrderEvent::Filled {
-                        id: 3,
-                        filled_qty: 5,
-                        fills: vec![FillMetadata {
-                            order_1: 3,
-                            order_2: 0,
-                            qty: 5,
-                            price: 395,
-                            taker_side: *ask_bid,
-                            total_fill: false,
-                        }]
-                    }
-                );
-                assert_eq!(ob.min_ask(), Some(395));
-                assert_eq!(ob.max_bid(), None);
-                assert_eq!(
-                    ob._asks(),
-                    init_book(vec![(395, 9999), (398, 9998)])
-                );
-                assert_eq!(ob._bids(), init_book(vec![]));
-                assert_eq!(ob.spread(), None);
+        // Empty the best ask, shrink the best bid and add a bid level
+        ob.execute(OrderType::Cancel { id: 1 });
+        ob.execute(OrderType::Market {
+            id: 5,
+            side: Side::Ask,
+            qty: 1,
+            owner: None,
+            tag: None,
+            protection_price: None,
+            reduce_only: None,
+        });
+        rest(&mut ob, 6, Side::Bid, 5, 97);
+
+        let delta = ob.delta_since(&prev);
+        assert_eq!(
+            delta,
+            BookDelta {
+                added: vec![6],
+                removed: vec![1],
+                asks: vec![BookLevel { price: 101, qty: 0 }],
+                bids: vec![
+                    BookLevel { price: 99, qty: 3 },
+                    BookLevel { price: 97, qty: 5 },
+                ],
             }
-        }
+        );
+        delta.apply(&mut depth);
+        assert_eq!(depth, ob.full_depth());
     }
 
     #[test]
-    fn market_order_unfilled() {
-        for (_, ask_bid) in &BID_ASK_COMBINATIONS {
-            let (mut ob, _) = init_ob(vec![]);
-            let result = ob.execute(OrderType::Market {
-                id: 0,
-                side: *ask_bid,
-                qty: 5,
-            });
+    fn generic_order_ids() {
+        let mut ob = GenericOrderBook::<u32>::default();
+        ob.execute(limit(1, Side::Ask, 5, 100));
+        let event = ob.execute(OrderType::Market {
+            id: 2,
+            side: Side::Bid,
+            qty: 2,
+            protection_price: None,
+            owner: None,
+            tag: None,
+            reduce_only: None,
+        });
+        assert_eq!(event.fills()[0].order_1, 2_u32);
+        assert_eq!(event.fills()[0].order_2, 1_u32);
+        assert_eq!(ob.order(1).map(|o| o.qty), Some(3));
+        assert_eq!(ob.cancel_all(), vec![1_u32]);
+        assert!(!ob.contains(1));
+    }
 
-            assert_eq!(result, OrderEvent::Unfilled { id: 0 });
+    #[test]
+    fn reduce_only_truncated() {
+        let mut ob = OrderBook::default();
+        rest(&mut ob, 1, Side::Ask, 10, 100);
+        let market = OrderType::Market {
+            id: 2,
+            side: Side::Bid,
+            qty: 8,
+            owner: None,
+            tag: None,
+            protection_price: None,
+            reduce_only: Some(3),
+        };
+        assert_eq!(ob.simulate(market).fills()[0].qty, 3);
+        match ob.execute(market) {
+            OrderEvent::Filled { filled_qty, .. } => assert_eq!(filled_qty, 3),
+            event => panic!("unexpected event {:?}", event),
         }
+        ob.execute(OrderType::Limit {
+            id: 3,
+            side: Side::Bid,
+            qty: 5,
+            price: 90,
+            display_qty: Some(4),
+            owner: None,
+            tag: None,
+            reduce_only: Some(2),
+        });
+        let order = ob.order(3).unwrap();
+        assert_eq!((order.qty, order.visible_qty), (2, 2));
+        assert_eq!(
+            ob.execute(OrderType::Market {
+                id: 4,
+                side: Side::Bid,
+                qty: 5,
+                owner: None,
+                tag: None,
+                protection_price: None,
+                reduce_only: Some(0),
+            }),
+            OrderEvent::Rejected {
+                id: 4,
+                reason: RejectReason::ZeroQuantity,
+            }
+        );
+        assert_eq!(ob.order(1).map(|o| o.qty), Some(7));
     }
 
     #[test]
-    fn market_order_partially_filled() {
-        for (bid_ask, ask_bid) in &BID_ASK_COMBINATIONS {
-            let (mut ob, results) = init_ob(vec![
-                OrderType::Limit {
-                    id: 0,
-                    side: *bid_ask,
-                    qty: 12,
-                    price: 395,
-                },
-                OrderType::Limit {
-                    id: 1,
-                    side: *ask_bid,
-                    qty: 2,
-                    price: 399,
-                },
-                OrderType::Limit {
-                    id: 2,
-                    side: *bid_ask,
-                    qty: 2,
-                    price: 398,
-                },
-            ]);
-            let result = ob.execute(OrderType::Market {
-                id: 3,
-                side: *ask_bid,
-                qty: 15,
-            });
+    fn top_of_book_change() {
+        let mut ob = OrderBook::default();
+        let changes = Rc::new(RefCell::new(Vec::new()));
+        let recorded = Rc::clone(&changes);
+        ob.on_top_of_book_change(move |ask, bid| {
+            recorded.borrow_mut().push((ask, bid));
+        });
+        let bid = |id, price| limit(id, Side::Bid, 5, price);
+        ob.execute(bid(1, 100));
+        assert_eq!(*changes.borrow(), vec![(None, Some(100))]);
+        ob.execute(bid(2, 99));
+        ob.execute(bid(3, 100));
+        assert_eq!(changes.borrow().len(), 1);
+        ob.execute(bid(4, 101));
+        assert_eq!(
+            *changes.borrow(),
+            vec![(None, Some(100)), (None, Some(101))]
+        );
+    }
 
-            if *bid_ask == Side::Bid {
-                assert_eq!(
-                    results,
-                    vec![
-                        OrderEvent::Placed { id: 0 },
-                        OrderEvent::Placed { id: 1 },
-                        OrderEvent::Placed { id: 2 }
-                    ]
-                );
-                assert_eq!(
-                    result,
-                    OrderEvent::PartiallyFilled {
-                        id: 3,
-                        filled_qty: 14,
-                        fills: vec![
-                            FillMetadata {
-                                order_1: 3,
-                                order_2: 2,
-                                qty: 2,
-                                price: 398,
-                                taker_side: *ask_bid,
-                                total_fill: true,
-                            },
-                            FillMetadata {
-                                order_1: 3,
-                                order_2: 0,
-                                qty: 12,
-                                price: 395,
-                                taker_side: *ask_bid,
-                                total_fill: true,
-                            }
-                        ]
-                    }
-                );
-                assert_eq!(ob.min_ask(), Some(399));
-                assert_eq!(ob.max_bid(), None);
-                assert_eq!(ob._asks(), init_book(vec![(399, 9998)]));
-                assert_eq!(ob._bids(), init_book_holes(vec![], vec![395, 398]));
-                assert_eq!(ob.spread(), None);
-            } else {
-                assert_eq!(
-                    results,
-                    vec![
-                        OrderEvent::Placed { id: 0 },
-                        OrderEvent::Filled {
-                            id: 1,
-                            filled_qty: 2,
-                            fills: vec![FillMetadata {
-                                order_1: 1,
-                                order_2: 0,
-                                qty: 2,
-                                price: 395,
-                                taker_side: *ask_bid,
-                                total_fill: false,
-                            }],
-                        },
-                        OrderEvent::Placed { id: 2 }
-                    ]
-                );
-                assert_eq!(
-                    result,
-                    OrderEvent::PartiallyFilled {
-                        id: 3,
-                        filled_qty: 12,
-                        fills: vec![
-                            FillMetadata {
-                                order_1: 3,
-                                order_2: 0,
-                                qty: 10,
-                                price: 395,
-                                taker_side: *ask_bid,
-                                total_fill: true,
-                            },
-                            FillMetadata {
-                                order_1: 3,
-                                order_2: 2,
-                                qty: 2,
-                                price: 398,
-                                taker_side: *ask_bid,
-                                total_fill: true,
-                            }
-                        ]
-                    }
-                );
-                assert_eq!(ob.min_ask(), None);
-                assert_eq!(ob.max_bid(), None);
-                assert_eq!(ob._asks(), init_book_holes(vec![], vec![395, 398]));
-                assert_eq!(ob._bids(), init_book(vec![]));
-                assert_eq!(ob.spread(), None);
+    #[test]
+    fn try_execute_checked_overflow() {
+        let mut ob = OrderBook::default();
+        ob.track_stats(true);
+        rest(&mut ob, 1, Side::Ask, u64::MAX - 1, 100);
+        let depth = ob.full_depth();
+        let ask = |id, qty| limit(id, Side::Ask, qty, 100);
+        assert_eq!(
+            ob.try_execute_checked(ask(2, 2)),
+            Err(ExecutionError::Overflow)
+        );
+        assert_eq!(ob.full_depth(), depth);
+        assert!(!ob.contains(2));
+        assert_eq!(
+            ob.try_execute_checked(ask(3, 1)),
+            Ok(OrderEvent::Placed { id: 3 })
+        );
+        assert_eq!(ob.level_orders(Side::Ask, 100).count(), 2);
+
+        // A pending stop order that may never trigger does not count
+        let stop = OrderType::StopMarket {
+            id: 4,
+            side: Side::Bid,
+            qty: u64::MAX,
+            trigger: 200,
+        };
+        assert_eq!(
+            ob.try_execute_checked(stop),
+            Ok(OrderEvent::Placed { id: 4 })
+        );
+        let market = |id, qty| OrderType::Market {
+            id,
+            side: Side::Bid,
+            qty,
+            owner: None,
+            tag: None,
+            protection_price: None,
+            reduce_only: None,
+        };
+        assert!(matches!(
+            ob.try_execute_checked(market(5, 1)),
+            Ok(OrderEvent::Filled { .. })
+        ));
+
+        ob.stop_seq = u64::MAX;
+        let stop = OrderType::StopMarket {
+            id: 6,
+            side: Side::Bid,
+            qty: 1,
+            trigger: 200,
+        };
+        assert_eq!(ob.try_execute_checked(stop), Err(ExecutionError::Overflow));
+        assert_eq!(ob.snapshot().stops.len(), 1);
+
+        ob.seq = u64::MAX;
+        assert_eq!(
+            ob.try_execute_checked(ask(7, 1)),
+            Err(ExecutionError::Overflow)
+        );
+        assert!(!ob.contains(7));
+    }
+
+    #[test]
+    fn try_execute_checked_traded_volume() {
+        let mut ob = OrderBook::default();
+        ob.track_stats(true);
+        rest(&mut ob, 1, Side::Ask, 10, 100);
+        ob.execute(OrderType::StopMarket {
+            id: 2,
+            side: Side::Bid,
+            qty: 5,
+            trigger: 100,
+        });
+        ob.traded_volume = u64::MAX - 2;
+        let depth = ob.full_depth();
+        let market = |id, qty| OrderType::Market {
+            id,
+            side: Side::Bid,
+            qty,
+            owner: None,
+            tag: None,
+            protection_price: None,
+            reduce_only: None,
+        };
+        assert_eq!(
+            ob.try_execute_checked(market(3, 3)),
+            Err(ExecutionError::Overflow)
+        );
+        assert_eq!(ob.full_depth(), depth);
+        assert_eq!(ob.traded_volume(), u64::MAX - 2);
+
+        // The order fits, but the stop order it triggers does not
+        assert!(matches!(
+            ob.try_execute_checked(market(4, 2)),
+            Ok(OrderEvent::Filled { .. })
+        ));
+        assert_eq!(ob.traded_volume(), u64::MAX);
+        assert!(ob.take_triggered().is_empty());
+        assert_eq!(ob.snapshot().stops.len(), 1);
+        assert_eq!(ob.order(1).map(|o| o.qty), Some(8));
+    }
+
+    #[test]
+    fn summary() {
+        let mut ob = OrderBook::default();
+        assert_eq!(
+            ob.summary(2),
+            BookSummary {
+                best_bid: None,
+                best_ask: None,
+                spread: None,
+                mid_price: None,
+                bid_qty: 0,
+                ask_qty: 0,
+                imbalance: None,
             }
+        );
+        rest(&mut ob, 1, Side::Ask, 4, 103);
+        rest(&mut ob, 2, Side::Ask, 2, 101);
+        rest(&mut ob, 3, Side::Ask, 9, 105);
+        rest(&mut ob, 4, Side::Bid, 5, 99);
+        rest(&mut ob, 5, Side::Bid, 1, 99);
+        rest(&mut ob, 6, Side::Bid, 3, 97);
+        let summary = ob.summary(2);
+        assert_eq!(summary.best_bid, Some(99));
+        assert_eq!(summary.best_ask, Some(101));
+        assert_eq!(summary.spread, Some(2));
+        assert_eq!(summary.mid_price, Some(100.0));
+        assert_eq!((summary.bid_qty, summary.ask_qty), (9, 6));
+        assert_eq!(summary.imbalance, Some(0.6));
+        assert_eq!(summary.best_bid, ob.max_bid());
+        assert_eq!(summary.best_ask, ob.min_ask());
+        assert_eq!(summary.spread, ob.spread());
+        assert_eq!(summary.mid_price, ob.mid_price());
+        assert_eq!(summary.imbalance, ob.imbalance(2));
+        for levels in 0..4 {
+            let depth = ob.depth(levels);
+            let summary = ob.summary(levels);
+            let qty = |levels: &[BookLevel]| {
+                levels.iter().map(|level| level.qty).sum::<u64>()
+            };
+            assert_eq!(summary.bid_qty, qty(&depth.bids));
+            assert_eq!(summary.ask_qty, qty(&depth.asks));
+            assert_eq!(summary.imbalance, ob.imbalance(levels));
         }
     }
 
     #[test]
-    fn market_order_filled() {
-        for (bid_ask, ask_bid) in &BID_ASK_COMBINATIONS {
-            let (mut ob, results) = init_ob(vec![
-                OrderType::Limit {
-                    id: 0,
-                    side: *bid_ask,
-                    qty: 12,
-                    price: 395,
-                },
-                OrderType::Limit {
-                    id: 1,
-                    side: *ask_bid,
-                    qty: 2,
-                    price: 399,
-                },
-                OrderType::Limit {
-                    id: 2,
-                    side: *bid_ask,
-                    qty: 2,
-                    price: 398,
-                },
-            ]);
-            let result = ob.execute(OrderType::Market {
-                id: 3,
-                side: *ask_bid,
-                qty: 7,
-            });
+    fn unordered_order_ids() {
+        #[derive(Copy, Clone, PartialEq, Eq, Hash)]
+        struct Key(u32);
 
-            if *bid_ask == Side::Bid {
-                assert_eq!(
-                    results,
-                    vec![
-                        OrderEvent::Placed { id: 0 },
-                        OrderEvent::Placed { id: 1 },
-                        OrderEvent::Placed { id: 2 }
-                    ]
-                );
-                assert_eq!(
-                    result,
-                    OrderEvent::Filled {
-                        id: 3,
-                        filled_qty: 7,
-                        fills: vec![
-                            FillMetadata {
-                                order_1: 3,
-                                order_2: 2,
-                                qty: 2,
-                                price: 398,
-                                taker_side: *ask_bid,
-                                total_fill: true,
-                            },
-                            FillMetadata {
-                                order_1: 3,
-                                order_2: 0,
-                                qty: 5,
-                                price: 395,
-                                taker_side: *ask_bid,
-                                total_fill: false,
-                            }
-                        ]
-                    }
-                );
-                assert_eq!(ob.min_ask(), Some(399));
-                assert_eq!(ob.max_bid(), Some(395));
-                assert_eq!(ob._asks(), init_book(vec![(399, 9998)]));
-                assert_eq!(
-                    ob._bids(),
-                    init_book_holes(vec![(395, 9999)], vec![398])
-                );
-                assert_eq!(ob.spread(), Some(4));
-            } else {
-                assert_eq!(
-                    results,
-                    vec![
-                        OrderEvent::Placed { id: 0 },
-                        OrderEvent::Filled {
-                            id: 1,
-                            filled_qty: 2,
-                            fills: vec![FillMetadata {
-                                order_1: 1,
-                                order_2: 0,
-                                qty: 2,
-                                price: 395,
-                                taker_side: *ask_bid,
-                                total_fill: false,
-                            }],
-                        },
-                        OrderEvent::Placed { id: 2 }
-                    ]
-                );
-                assert_eq!(
-                    result,
-                    OrderEvent::Filled {
-                        id: 3,
-                        filled_qty: 7,
-                        fills: vec![FillMetadata {
-                            order_1: 3,
-                            order_2: 0,
-                            qty: 7,
-                            price: 395,
-                            taker_side: *ask_bid,
-                            total_fill: false,
-                        }]
-                    }
-                );
-                assert_eq!(ob.min_ask(), Some(395));
-                assert_eq!(ob.max_bid(), None);
-                assert_eq!(
-                    ob._asks(),
-                    init_book(vec![(395, 9999), (398, 9998)])
-                );
-                assert_eq!(ob._bids(), init_book(vec![]));
-                assert_eq!(ob.spread(), None);
-            }
+        let mut ob = GenericOrderBook::<Key>::default();
+        ob.execute(limit(Key(10), Side::Ask, 10, 100));
+        for id in [Key(3), Key(2)] {
+            ob.execute(OrderType::StopMarket {
+                id,
+                side: Side::Bid,
+                qty: 1,
+                trigger: 100,
+            });
         }
+        ob.execute(OrderType::GoodTillTime {
+            id: Key(4),
+            side: Side::Ask,
+            qty: 1,
+            price: 105,
+            expires_at: 5,
+        });
+        ob.execute(OrderType::Market {
+            id: Key(1),
+            side: Side::Bid,
+            qty: 1,
+            protection_price: None,
+            owner: None,
+            tag: None,
+            reduce_only: None,
+        });
+
+        // Stops with the same trigger price are executed in placement order
+        let triggered: Vec<Key> = ob
+            .take_triggered()
+            .iter()
+            .filter_map(|event| match event {
+                OrderEvent::Triggered { id, .. } => Some(*id),
+                _ => None,
+            })
+            .collect();
+        assert!(triggered == vec![Key(3), Key(2)]);
+        assert_eq!(ob.order(Key(10)).map(|o| o.qty), Some(7));
+
+        assert_eq!(ob.set_time(5).len(), 1);
+        assert!(!ob.contains(Key(4)));
     }
 
     #[test]
-    fn cancel_non_existing_order() {
-        let (mut ob, _) = init_ob(vec![]);
-        let result = ob.execute(OrderType::Cancel { id: 0 });
-        assert_eq!(result, OrderEvent::Canceled { id: 0 });
-        assert_eq!(ob.min_ask(), None);
-        assert_eq!(ob.max_bid(), None);
-        assert_eq!(ob._asks(), BTreeMap::new());
-        assert_eq!(ob._bids(), BTreeMap::new());
-        assert_eq!(ob.spread(), None);
+    fn can_fill_hidden_reserve() {
+        let mut ob = OrderBook::default();
+        ob.execute(OrderType::Limit {
+            id: 1,
+            side: Side::Ask,
+            qty: 100,
+            price: 100,
+            display_qty: Some(10),
+            owner: None,
+            tag: None,
+            reduce_only: None,
+        });
+        ob.execute(OrderType::AllOrNone {
+            id: 2,
+            side: Side::Ask,
+            qty: 20,
+            price: 101,
+            owner: None,
+            tag: None,
+        });
+        let market = |qty| OrderType::Market {
+            id: 3,
+            side: Side::Bid,
+            qty,
+            owner: None,
+            tag: None,
+            protection_price: None,
+            reduce_only: None,
+        };
+        assert!(ob.can_fill(Side::Bid, 50, None));
+        assert!(matches!(ob.simulate(market(50)), OrderEvent::Filled { .. }));
+        assert!(ob.can_fill(Side::Bid, 100, Some(100)));
+        assert!(!ob.can_fill(Side::Bid, 101, Some(100)));
+        // The all-or-none order cannot be filled for the last 10 lots only
+        assert!(!ob.can_fill(Side::Bid, 110, None));
+        assert!(ob.can_fill(Side::Bid, 120, None));
+        assert!(matches!(
+            ob.simulate(market(120)),
+            OrderEvent::Filled { .. }
+        ));
     }
 
     #[test]
-    fn cancel_resting_order() {
-        for (bid_ask, _) in &BID_ASK_COMBINATIONS {
-            let (mut ob, results) = init_ob(vec![OrderType::Limit {
-                id: 0,
-                side: *bid_ask,
-                qty: 12,
-                price: 395,
-            }]);
-            let result = ob.execute(OrderType::Cancel { id: 0 });
-            assert_eq!(results, vec![OrderEvent::Placed { id: 0 }]);
-            assert_eq!(result, OrderEvent::Canceled { id: 0 });
-            assert_eq!(ob.min_ask(), None);
-            assert_eq!(ob.max_bid(), None);
-            if *bid_ask == Side::Bid {
-                assert_eq!(ob._asks(), BTreeMap::new());
-                assert_eq!(ob._bids(), init_book_holes(vec![], vec![395]));
-            } else {
-                assert_eq!(ob._asks(), init_book_holes(vec![], vec![395]));
-                assert_eq!(ob._bids(), BTreeMap::new());
+    fn depth_after_sweep_hidden_reserve() {
+        let mut ob = OrderBook::default();
+        ob.execute(OrderType::Limit {
+            id: 1,
+            side: Side::Ask,
+            qty: 100,
+            price: 100,
+            display_qty: Some(10),
+            owner: None,
+            tag: None,
+            reduce_only: None,
+        });
+        rest(&mut ob, 2, Side::Ask, 5, 101);
+        assert_eq!(
+            ob.depth_after_sweep(Side::Bid, 55, 5).asks,
+            vec![
+                BookLevel { price: 100, qty: 5 },
+                BookLevel { price: 101, qty: 5 },
+            ]
+        );
+        assert_eq!(
+            ob.depth_after_sweep(Side::Bid, 50, 5).asks,
+            vec![
+                BookLevel {
+                    price: 100,
+                    qty: 10
+                },
+                BookLevel { price: 101, qty: 5 },
+            ]
+        );
+        assert_eq!(
+            ob.depth_after_sweep(Side::Bid, 100, 5).asks,
+            vec![BookLevel { price: 101, qty: 5 }]
+        );
+        assert_eq!(
+            ob.depth(5).asks[0],
+            BookLevel {
+                price: 100,
+                qty: 10
             }
-            assert_eq!(ob.spread(), None);
-        }
+        );
     }
 
     #[test]
-    fn cancel_resting_order_of_many() {
-        for (bid_ask, ask_bid) in &BID_ASK_COMBINATIONS {
-            let (mut ob, results) = init_ob(vec![
-                OrderType::Limit {
-                    id: 0,
-                    side: *bid_ask,
-                    qty: 12,
-                    price: 395,
-                },
-                OrderType::Limit {
-                    id: 1,
-                    side: *ask_bid,
-                    qty: 2,
-                    price: 399,
-                },
-                OrderType::Limit {
-                    id: 2,
-                    side: *bid_ask,
-                    qty: 2,
-                    price: 398,
-                },
-            ]);
-            let result = ob.execute(OrderType::Cancel { id: 0 });
-            if *bid_ask == Side::Bid {
-                assert_eq!(
-                    results,
-                    vec![
-                        OrderEvent::Placed { id: 0 },
-                        OrderEvent::Placed { id: 1 },
-                        OrderEvent::Placed { id: 2 }
-                    ]
-                );
-                assert_eq!(result, OrderEvent::Canceled { id: 0 });
-                assert_eq!(ob.min_ask(), Some(399));
-                assert_eq!(ob.max_bid(), Some(398));
-                assert_eq!(ob._asks(), init_book(vec![(399, 9998)]));
-                assert_eq!(
-                    ob._bids(),
-                    init_book_holes(vec![(398, 9997)], vec![395])
-                );
-                assert_eq!(ob.spread(), Some(1));
-            } else {
-                assert_eq!(
-                    results,
-                    vec![
-                        OrderEvent::Placed { id: 0 },
-                        OrderEvent::Filled {
-                            id: 1,
-                            filled_qty: 2,
-                            fills: vec![FillMetadata {
-                                order_1: 1,
-                                order_2: 0,
-                                qty: 2,
-                                price: 395,
-                                taker_side: *ask_bid,
-                                total_fill: false,
-                            }],
-                        },
-                        OrderEvent::Placed { id: 2 }
-                    ]
-                );
-                assert_eq!(result, OrderEvent::Canceled { id: 0 });
-                assert_eq!(ob.min_ask(), Some(398));
-                assert_eq!(ob.max_bid(), None);
-                assert_eq!(
-                    ob._asks(),
-                    init_book_holes(vec![(398, 9998)], vec![395])
-                );
-                assert_eq!(ob._bids(), init_book(vec![]));
-                assert_eq!(ob.spread(), None);
+    fn levels_to_fill_hidden_reserve() {
+        let mut ob = OrderBook::default();
+        ob.execute(OrderType::Limit {
+            id: 1,
+            side: Side::Ask,
+            qty: 100,
+            price: 100,
+            display_qty: Some(10),
+            owner: None,
+            tag: None,
+            reduce_only: None,
+        });
+        rest(&mut ob, 2, Side::Ask, 5, 101);
+        assert_eq!(ob.levels_to_fill(Side::Bid, 50), Some(1));
+        assert_eq!(ob.levels_to_fill(Side::Bid, 100), Some(1));
+        assert_eq!(ob.levels_to_fill(Side::Bid, 105), Some(2));
+        assert_eq!(ob.levels_to_fill(Side::Bid, 106), None);
+    }
+
+    #[test]
+    fn marginal_impact_hidden_reserve() {
+        let mut ob = OrderBook::default();
+        ob.execute(OrderType::Limit {
+            id: 1,
+            side: Side::Ask,
+            qty: 100,
+            price: 100,
+            display_qty: Some(10),
+            owner: None,
+            tag: None,
+            reduce_only: None,
+        });
+        rest(&mut ob, 2, Side::Ask, 5, 101);
+        assert_eq!(ob.marginal_impact(Side::Bid, 49), Some(100));
+        assert_eq!(ob.marginal_impact(Side::Bid, 99), Some(100));
+        assert_eq!(ob.marginal_impact(Side::Bid, 100), Some(101));
+        assert_eq!(ob.marginal_impact(Side::Bid, 105), None);
+    }
+
+    #[test]
+    fn vwap_for_qty_hidden_reserve() {
+        let mut ob = OrderBook::default();
+        ob.execute(OrderType::Limit {
+            id: 1,
+            side: Side::Ask,
+            qty: 100,
+            price: 100,
+            display_qty: Some(10),
+            owner: None,
+            tag: None,
+            reduce_only: None,
+        });
+        rest(&mut ob, 2, Side::Ask, 100, 102);
+        assert_eq!(ob.vwap_for_qty(Side::Bid, 50), Some(100.0));
+        assert_eq!(ob.vwap_for_qty(Side::Bid, 200), Some(101.0));
+        assert_eq!(ob.vwap_for_qty(Side::Bid, 201), None);
+    }
+
+    #[test]
+    fn round_trip_cost_hidden_reserve() {
+        let mut ob = OrderBook::default();
+        ob.execute(OrderType::Limit {
+            id: 1,
+            side: Side::Ask,
+            qty: 100,
+            price: 100,
+            display_qty: Some(10),
+            owner: None,
+            tag: None,
+            reduce_only: None,
+        });
+        ob.execute(OrderType::Limit {
+            id: 2,
+            side: Side::Bid,
+            qty: 100,
+            price: 98,
+            display_qty: Some(10),
+            owner: None,
+            tag: None,
+            reduce_only: None,
+        });
+        assert_eq!(ob.round_trip_cost(50), Some(100));
+        assert_eq!(ob.round_trip_cost(100), Some(200));
+        assert_eq!(ob.round_trip_cost(101), None);
+    }
+
+    #[test]
+    fn trade_equality_tolerance() {
+        let trade = Trade {
+            total_qty: 7,
+            avg_price: 2768.0 / 7.0,
+            last_qty: 2,
+            last_price: 397,
+        };
+        let close = Trade {
+            avg_price: 395.428_571_4,
+            ..trade
+        };
+        let far = Trade {
+            avg_price: 395.43,
+            ..trade
+        };
+        assert_eq!(trade, close);
+        assert_ne!(trade, far);
+    }
+
+    #[test]
+    fn cancel_replace_same_id() {
+        let mut ob = OrderBook::default();
+        rest(&mut ob, 1, Side::Bid, 5, 99);
+        rest(&mut ob, 2, Side::Bid, 5, 100);
+        let replace = |old_id, new_id| OrderType::CancelReplace {
+            old_id,
+            new_id,
+            side: Side::Bid,
+            qty: 3,
+            price: 98,
+        };
+        let replaced = OrderEvent::Replaced {
+            canceled: 2,
+            placement: Box::new(OrderEvent::Placed { id: 2 }),
+        };
+        assert_eq!(ob.simulate(replace(2, 2)), replaced);
+        assert_eq!(ob.execute(replace(2, 2)), replaced);
+        assert_eq!(ob.order(2).map(|o| (o.qty, o.price)), Some((3, 98)));
+        assert_eq!(ob.max_bid(), Some(99));
+        // Another resting order cannot be replaced with that ID
+        assert_eq!(
+            ob.execute(replace(1, 2)),
+            OrderEvent::Rejected {
+                id: 2,
+                reason: RejectReason::DuplicateId,
             }
-        }
+        );
+        assert!(ob.contains(1));
     }
 }
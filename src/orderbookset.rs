@@ -0,0 +1,178 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::models::{BookDepth, OrderEvent, OrderType};
+use crate::orderbook::{
+    OrderBook, DEFAULT_ARENA_CAPACITY, DEFAULT_QUEUE_CAPACITY,
+};
+
+/// A set of order books, one per symbol, sharing the same capacities. The
+/// order book of a symbol is created the first time an order is executed for
+/// it.
+#[derive(Debug)]
+pub struct OrderBookSet<K> {
+    books: HashMap<K, OrderBook>,
+    arena_capacity: usize,
+    queue_capacity: usize,
+    track_stats: bool,
+}
+
+impl<K: Hash + Eq> Default for OrderBookSet<K> {
+    /// Create an empty set whose order books have stats tracking disabled, a
+    /// default arena capacity of 10,000 and a default queue capacity of 10.
+    fn default() -> Self {
+        Self::new(DEFAULT_ARENA_CAPACITY, DEFAULT_QUEUE_CAPACITY, false)
+    }
+}
+
+impl<K: Hash + Eq> OrderBookSet<K> {
+    /// Create an empty set of order books. The parameters are used to create
+    /// each order book and have the same meaning as in [`OrderBook::new`].
+    pub fn new(
+        arena_capacity: usize,
+        queue_capacity: usize,
+        track_stats: bool,
+    ) -> Self {
+        Self {
+            books: HashMap::new(),
+            arena_capacity,
+            queue_capacity,
+            track_stats,
+        }
+    }
+
+    /// Execute an order on the order book of the given symbol, creating the
+    /// order book if needed, and return an event indicating the result (see
+    /// [`OrderBook::execute`]).
+    pub fn execute(&mut self, symbol: K, order: OrderType) -> OrderEvent {
+        self.book_entry(symbol).execute(order)
+    }
+
+    /// Return the order book of the given symbol, creating it if needed.
+    pub fn book_entry(&mut self, symbol: K) -> &mut OrderBook {
+        let (arena_capacity, queue_capacity, track_stats) =
+            (self.arena_capacity, self.queue_capacity, self.track_stats);
+        self.books.entry(symbol).or_insert_with(|| {
+            OrderBook::new(arena_capacity, queue_capacity, track_stats)
+        })
+    }
+
+    /// Return the order book of the given symbol, if present.
+    pub fn book<Q>(&self, symbol: &Q) -> Option<&OrderBook>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.books.get(symbol)
+    }
+
+    /// Return the order book of the given symbol mutably, if present.
+    pub fn book_mut<Q>(&mut self, symbol: &Q) -> Option<&mut OrderBook>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.books.get_mut(symbol)
+    }
+
+    /// Return the lowest ask price of the given symbol, if present.
+    pub fn min_ask<Q>(&self, symbol: &Q) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.book(symbol)?.min_ask()
+    }
+
+    /// Return the highest bid price of the given symbol, if present.
+    pub fn max_bid<Q>(&self, symbol: &Q) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.book(symbol)?.max_bid()
+    }
+
+    /// Return the depth of the order book of the given symbol up to the
+    /// specified level (see [`OrderBook::depth`]), or `None` if there is no
+    /// order book for the symbol.
+    pub fn depth<Q>(&self, symbol: &Q, levels: usize) -> Option<BookDepth>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.book(symbol).map(|book| book.depth(levels))
+    }
+
+    /// Iterate over the symbols that have an order book, in arbitrary order.
+    pub fn symbols(&self) -> impl Iterator<Item = &K> {
+        self.books.keys()
+    }
+
+    /// Return the number of order books in the set.
+    pub fn len(&self) -> usize {
+        self.books.len()
+    }
+
+    /// Return whether the set contains no order book.
+    pub fn is_empty(&self) -> bool {
+        self.books.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{OrderBookSet, OrderEvent, OrderType, Side};
+
+    fn limit(id: u128, side: Side, price: u64) -> OrderType {
+        OrderType::Limit {
+            id,
+            side,
+            qty: 2,
+            price,
+            display_qty: None,
+            owner: None,
+            tag: None,
+            reduce_only: None,
+        }
+    }
+
+    #[test]
+    fn independent_books() {
+        let mut books = OrderBookSet::new(10, 10, true);
+        assert!(books.is_empty());
+        assert_eq!(
+            books.execute("AAA", limit(0, Side::Ask, 101)),
+            OrderEvent::Placed { id: 0 }
+        );
+        // IDs are only unique within a book
+        assert_eq!(
+            books.execute("BBB", limit(0, Side::Bid, 101)),
+            OrderEvent::Placed { id: 0 }
+        );
+        assert_eq!(books.min_ask("AAA"), Some(101));
+        assert_eq!(books.max_bid("AAA"), None);
+        assert_eq!(books.min_ask("BBB"), None);
+        assert_eq!(books.max_bid("BBB"), Some(101));
+        assert_eq!(books.book("BBB").unwrap().traded_volume(), 0);
+        assert_eq!(books.book("AAA").unwrap().config().arena_capacity, 10);
+
+        let mut symbols: Vec<_> = books.symbols().copied().collect();
+        symbols.sort_unstable();
+        assert_eq!(symbols, vec!["AAA", "BBB"]);
+        assert_eq!(books.len(), 2);
+    }
+
+    #[test]
+    fn unknown_symbol() {
+        let mut books = OrderBookSet::<String>::default();
+        books.execute("AAA".to_string(), limit(0, Side::Ask, 101));
+        assert_eq!(books.min_ask("AAA"), Some(101));
+        assert_eq!(books.min_ask("CCC"), None);
+        assert_eq!(books.max_bid("CCC"), None);
+        assert!(books.depth("CCC", 5).is_none());
+        assert!(books.book_mut("CCC").is_none());
+        assert_eq!(books.len(), 1);
+    }
+}
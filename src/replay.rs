@@ -0,0 +1,131 @@
+//! Reading orders from CSV files, e.g. to replay a recorded order flow.
+//!
+//! The files follow the format of the QuantCup order book challenge: a header
+//! row followed by one `trader_id,side,price,qty` row per order, where `side`
+//! is either `Bid` or `Ask`. A row with a zero price cancels the order whose ID
+//! is given in the `qty` column. Every other row is a limit order, and limit
+//! orders are assigned increasing IDs starting from 1, in file order.
+
+use std::fmt;
+use std::io::Read;
+
+use crate::models::{OrderType, Side};
+
+type Record = (u64, String, u64, u64);
+
+/// An error returned when a CSV row cannot be converted to an order.
+#[derive(Debug)]
+pub enum ReplayError {
+    /// The row could not be read or does not match the expected schema.
+    Csv(csv::Error),
+    /// The side of the order is neither `Bid` nor `Ask`.
+    InvalidSide(String),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Csv(err) => err.fmt(f),
+            ReplayError::InvalidSide(side) => {
+                write!(f, "invalid side {:?}, expected Bid or Ask", side)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl From<csv::Error> for ReplayError {
+    fn from(err: csv::Error) -> Self {
+        ReplayError::Csv(err)
+    }
+}
+
+/// Read the orders of a CSV file, in file order. A malformed row yields an
+/// error, and reading can go on with the rows that follow it.
+pub fn read_orders<R: Read>(
+    reader: R,
+) -> impl Iterator<Item = Result<OrderType, ReplayError>> {
+    let mut last_id = 0;
+    csv::Reader::from_reader(reader)
+        .into_deserialize()
+        .map(move |record| {
+            let (_, side, price, qty): Record = record?;
+            if price == 0 {
+                return Ok(OrderType::Cancel {
+                    id: u128::from(qty),
+                });
+            }
+            let side = match side.as_str() {
+                "Bid" => Side::Bid,
+                "Ask" => Side::Ask,
+                _ => return Err(ReplayError::InvalidSide(side)),
+            };
+            last_id += 1;
+            Ok(OrderType::Limit {
+                id: last_id,
+                side,
+                qty,
+                price,
+                display_qty: None,
+                owner: None,
+                tag: None,
+                reduce_only: None,
+            })
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_orders, ReplayError};
+    use crate::{OrderType, Side};
+
+    #[test]
+    fn read_orders_from_csv() {
+        let data = "trader_id,side,price,qty\n\
+                    8,Bid,4799,500\n\
+                    4,Ask,4801,800\n\
+                    7,Bid,0,2\n\
+                    5,Bid,abc,1\n\
+                    6,Hold,4800,1\n\
+                    3,Ask,4800,100\n";
+        let orders: Vec<_> = read_orders(data.as_bytes()).collect();
+        assert_eq!(orders.len(), 6);
+        assert!(matches!(
+            orders[0],
+            Ok(OrderType::Limit {
+                id: 1,
+                side: Side::Bid,
+                qty: 500,
+                price: 4799,
+                ..
+            })
+        ));
+        assert!(matches!(
+            orders[1],
+            Ok(OrderType::Limit {
+                id: 2,
+                side: Side::Ask,
+                qty: 800,
+                price: 4801,
+                ..
+            })
+        ));
+        assert!(matches!(orders[2], Ok(OrderType::Cancel { id: 2 })));
+        assert!(matches!(orders[3], Err(ReplayError::Csv(_))));
+        assert!(matches!(
+            &orders[4],
+            Err(ReplayError::InvalidSide(side)) if side == "Hold"
+        ));
+        assert!(matches!(
+            orders[5],
+            Ok(OrderType::Limit {
+                id: 3,
+                side: Side::Ask,
+                qty: 100,
+                price: 4800,
+                ..
+            })
+        ));
+    }
+}